@@ -112,7 +112,7 @@ impl MDApi {
         }
 
         if self.config.nm_addr.len() > 0 {
-            debug!("nm_addr is: {}", self.config.front_addr);
+            debug!("nm_addr is: {}", self.config.nm_addr);
             let cs = CString::new(self.config.nm_addr.as_bytes()).unwrap();
              unsafe {self.api.RegisterNameServer(cs.as_ptr() as *mut _);}
         }
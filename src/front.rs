@@ -0,0 +1,127 @@
+//! Front and name server (名字服务器) addresses, and the FENS user info
+//! registered along with a name server.
+
+use crate::sys::*;
+use crate::utils::*;
+
+use serde::{Deserialize, Serialize};
+
+/// What the FENS login is for, sent in `CThostFtdcFensUserInfoField::LoginMode`.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum FensLoginMode {
+    Trade = THOST_FTDC_LM_Trade as _,
+    Transfer = THOST_FTDC_LM_Transfer as _,
+}
+
+/// Check a front or name server address before handing it to the api.
+///
+/// Accepted forms are `tcp://host:port`, `ssl://host:port` and
+/// `socks5://host:port/proxy_host:port`, the proxy part may carry
+/// credentials as `user:pass@proxy_host:port`.
+pub fn validate_address(addr: &str) -> Result<(), String> {
+    let (scheme, rest) = addr.split_once("://").ok_or_else(|| format!("missing protocol in address `{}`", addr))?;
+    let (target, proxy) = match rest.split_once('/') {
+        Some((target, proxy)) => (target, Some(proxy)),
+        None => (rest, None),
+    };
+    check_host_port(target).map_err(|e| format!("invalid address `{}`: {}", addr, e))?;
+
+    match (scheme, proxy) {
+        ("tcp", None) | ("ssl", None) => Ok(()),
+        ("tcp", Some(_)) | ("ssl", Some(_)) => Err(format!("unexpected path in address `{}`", addr)),
+        ("socks5", Some(proxy)) => {
+            let proxy = proxy.rsplit_once('@').map_or(proxy, |(_, host)| host);
+            check_host_port(proxy).map_err(|e| format!("invalid proxy in address `{}`: {}", addr, e))
+        }
+        ("socks5", None) => Err(format!("missing proxy in address `{}`", addr)),
+        _ => Err(format!("unsupported protocol `{}` in address `{}`, expect tcp, ssl or socks5", scheme, addr)),
+    }
+}
+
+fn check_host_port(s: &str) -> Result<(), String> {
+    let (host, port) = s.rsplit_once(':').ok_or("missing port")?;
+    if host.is_empty() {
+        return Err("missing host".into());
+    }
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(()),
+        _ => Err(format!("invalid port `{}`", port)),
+    }
+}
+
+/// Check the addresses of a session: at least one of them, each well formed,
+/// and FENS only along with a name server.
+pub(crate) fn validate_addresses(front_addr: &str, nm_addr: &str, fens: Option<FensLoginMode>) -> Result<(), String> {
+    if front_addr.is_empty() && nm_addr.is_empty() {
+        return Err("either `front_addr` or `nm_addr` is required".into());
+    }
+    for addr in [front_addr, nm_addr] {
+        if !addr.is_empty() {
+            validate_address(addr)?;
+        }
+    }
+    if fens.is_some() && nm_addr.is_empty() {
+        return Err("`fens_login_mode` requires `nm_addr`".into());
+    }
+    Ok(())
+}
+
+pub(crate) fn fens_user_info(broker_id: &str, user_id: &str, mode: FensLoginMode) -> CThostFtdcFensUserInfoField {
+    let mut field: CThostFtdcFensUserInfoField = zeroed();
+    set_string(&mut field.BrokerID, broker_id);
+    set_string(&mut field.UserID, user_id);
+    field.LoginMode = mode as u8 as _;
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_addresses() {
+        for addr in [
+            "tcp://180.168.146.187:10130",
+            "ssl://trade.example.com:443",
+            "socks5://180.168.146.187:10130/127.0.0.1:1080",
+            "socks5://180.168.146.187:10130/user:p@ss@127.0.0.1:1080",
+        ] {
+            assert_eq!(validate_address(addr), Ok(()), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn malformed_addresses() {
+        let error = |addr: &str| validate_address(addr).unwrap_err();
+        assert_eq!(error("180.168.146.187:10130"), "missing protocol in address `180.168.146.187:10130`");
+        assert!(error("udp://180.168.146.187:10130").starts_with("unsupported protocol `udp`"));
+        assert_eq!(error("tcp://180.168.146.187"), "invalid address `tcp://180.168.146.187`: missing port");
+        assert_eq!(error("tcp://:10130"), "invalid address `tcp://:10130`: missing host");
+        assert_eq!(error("tcp://host:0"), "invalid address `tcp://host:0`: invalid port `0`");
+        assert_eq!(error("tcp://host:65536"), "invalid address `tcp://host:65536`: invalid port `65536`");
+        assert!(error("tcp://host:10130/proxy:1080").starts_with("unexpected path"));
+        assert!(error("socks5://host:10130").starts_with("missing proxy"));
+        assert!(error("socks5://host:10130/user:pass@proxy").starts_with("invalid proxy"));
+    }
+
+    #[test]
+    fn session_addresses() {
+        assert!(validate_addresses("", "", None).is_err());
+        assert!(validate_addresses("tcp://host:10130", "", None).is_ok());
+        assert!(validate_addresses("", "tcp://ns:8888", Some(FensLoginMode::Trade)).is_ok());
+        assert_eq!(
+            validate_addresses("tcp://host:10130", "", Some(FensLoginMode::Trade)),
+            Err("`fens_login_mode` requires `nm_addr`".to_string())
+        );
+        assert!(validate_addresses("tcp://host:10130", "ns:8888", None).is_err());
+    }
+
+    #[test]
+    fn fens_user_info_fields() {
+        let field = fens_user_info("9999", "000001", FensLoginMode::Transfer);
+        assert_eq!(to_string(&field.BrokerID), "9999");
+        assert_eq!(to_string(&field.UserID), "000001");
+        assert_eq!(field.LoginMode as u8, THOST_FTDC_LM_Transfer);
+        assert_eq!(fens_user_info("9999", "000001", FensLoginMode::Trade).LoginMode as u8, THOST_FTDC_LM_Trade);
+    }
+}
@@ -1,2 +1,8 @@
-pub mod sys;
+// the generated spi traits hand out raw pointers to safe methods
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 
+pub mod sys;
+pub mod utils;
+pub mod front;
+pub mod md;
+pub mod td;
@@ -0,0 +1,93 @@
+//! Market data helpers on top of `CThostFtdcMdApi`.
+
+/// Implement the listed `Rust_CThostFtdcMdSpi_Trait` methods by forwarding
+/// them to `self.$inner`, for spi adapters wrapping another spi.
+macro_rules! forward_md_spi {
+    ($inner:ident: $($method:ident),* $(,)?) => {
+        $(forward_md_spi!(@method $inner $method);)*
+    };
+    (@method $inner:ident on_front_connected) => {
+        fn on_front_connected(&mut self) {
+            self.$inner.on_front_connected()
+        }
+    };
+    (@method $inner:ident on_front_disconnected) => {
+        fn on_front_disconnected(&mut self, nReason: ::std::os::raw::c_int) {
+            self.$inner.on_front_disconnected(nReason)
+        }
+    };
+    (@method $inner:ident on_heart_beat_warning) => {
+        fn on_heart_beat_warning(&mut self, nTimeLapse: ::std::os::raw::c_int) {
+            self.$inner.on_heart_beat_warning(nTimeLapse)
+        }
+    };
+    (@method $inner:ident on_rsp_user_login) => {
+        fn on_rsp_user_login(&mut self, pRspUserLogin: *mut CThostFtdcRspUserLoginField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_user_login(pRspUserLogin, pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rsp_user_logout) => {
+        fn on_rsp_user_logout(&mut self, pUserLogout: *mut CThostFtdcUserLogoutField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_user_logout(pUserLogout, pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rsp_qry_multicast_instrument) => {
+        fn on_rsp_qry_multicast_instrument(&mut self, pMulticastInstrument: *mut CThostFtdcMulticastInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_qry_multicast_instrument(pMulticastInstrument, pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rsp_error) => {
+        fn on_rsp_error(&mut self, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_error(pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rsp_sub_market_data) => {
+        fn on_rsp_sub_market_data(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_sub_market_data(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rsp_un_sub_market_data) => {
+        fn on_rsp_un_sub_market_data(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_un_sub_market_data(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rsp_sub_for_quote_rsp) => {
+        fn on_rsp_sub_for_quote_rsp(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_sub_for_quote_rsp(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rsp_un_sub_for_quote_rsp) => {
+        fn on_rsp_un_sub_for_quote_rsp(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: ::std::os::raw::c_int, bIsLast: bool) {
+            self.$inner.on_rsp_un_sub_for_quote_rsp(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+        }
+    };
+    (@method $inner:ident on_rtn_depth_market_data) => {
+        fn on_rtn_depth_market_data(&mut self, pDepthMarketData: *mut CThostFtdcDepthMarketDataField) {
+            self.$inner.on_rtn_depth_market_data(pDepthMarketData)
+        }
+    };
+    (@method $inner:ident on_rtn_for_quote_rsp) => {
+        fn on_rtn_for_quote_rsp(&mut self, pForQuoteRsp: *mut CThostFtdcForQuoteRspField) {
+            self.$inner.on_rtn_for_quote_rsp(pForQuoteRsp)
+        }
+    };
+}
+
+mod api;
+pub use api::*;
+mod session;
+pub use session::HealthCount;
+mod shared;
+pub use shared::*;
+mod rcu;
+
+pub mod arbiter;
+pub mod bus;
+pub mod depth;
+pub mod failover;
+pub mod filter;
+pub mod for_quote;
+pub mod multicast;
+pub mod quote;
+pub mod replay;
+pub mod sharded;
@@ -0,0 +1,258 @@
+use crate::front::*;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::*;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Config {
+    pub flowpath: String,
+    pub is_udp: bool,
+    pub is_multicast: bool,
+    pub front_addr: String,
+    pub nm_addr: String,
+
+    #[serde(default)]
+    pub broker_id: String,
+    #[serde(default)]
+    pub user_id: String,
+    #[serde(default)]
+    pub password: String,
+
+    /// Register the FENS user info along with `nm_addr`.
+    #[serde(default)]
+    pub fens_login_mode: Option<FensLoginMode>,
+
+    /// Topics queried by `req_qry_multicast_instrument` in multicast mode.
+    #[serde(default)]
+    pub multicast_topics: Vec<i32>,
+}
+
+/// Transport of the market data, derived from `is_udp` and `is_multicast`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    Tcp,
+    Udp,
+    Multicast,
+}
+
+impl Config {
+    pub fn mode(&self) -> Result<Mode, String> {
+        match (self.is_udp, self.is_multicast) {
+            (false, false) => Ok(Mode::Tcp),
+            (true, false) => Ok(Mode::Udp),
+            (true, true) => Ok(Mode::Multicast),
+            (false, true) => Err("`is_multicast` requires `is_udp`, multicast data is delivered over udp".into()),
+        }
+    }
+
+    /// Check the flags and addresses are consistent before creating the api.
+    pub fn validate(&self) -> Result<Mode, String> {
+        let mode = self.mode()?;
+        validate_addresses(&self.front_addr, &self.nm_addr, self.fens_login_mode)?;
+        if mode != Mode::Multicast && !self.multicast_topics.is_empty() {
+            return Err(format!("`multicast_topics` is set but the mode is {:?}", mode));
+        }
+        if mode == Mode::Multicast && self.multicast_topics.iter().any(|t| *t < 0) {
+            return Err(format!("invalid `multicast_topics`: {:?}", self.multicast_topics));
+        }
+        Ok(mode)
+    }
+}
+
+/// A market data session owning the `CThostFtdcMdApi` and its registered spi.
+pub struct MdApi {
+    api: Rust_CThostFtdcMdApi,
+    spi: Option<(*mut CThostFtdcMdSpi, *mut c_void)>,
+    started: bool,
+    mode: Mode,
+    request_id: c_int,
+
+    pub(crate) config: Config,
+}
+
+impl MdApi {
+    pub fn get_version() -> String {
+        let cs = unsafe { CStr::from_ptr(CThostFtdcMdApi::GetApiVersion()) };
+        cs.to_string_lossy().into()
+    }
+
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let mode = config.validate()?;
+        let cs = CString::new(config.flowpath.as_bytes()).map_err(|e| e.to_string())?;
+        let api = unsafe { *Rust_CThostFtdcMdApi::CreateFtdcMdApi(cs.as_ptr(), config.is_udp, config.is_multicast) };
+        debug!("create mdapi in {:?} mode", mode);
+
+        Ok(Self {
+            api,
+            spi: None,
+            started: false,
+            mode,
+            request_id: 0,
+            config: config.clone(),
+        })
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The underlying api, for requests without a helper here.
+    pub fn inner(&mut self) -> &mut Rust_CThostFtdcMdApi {
+        &mut self.api
+    }
+
+    /// Register `spi`, replacing the one before. Only before [`Self::init`]:
+    /// the api thread may call the registered spi at any time once started.
+    pub fn register<S: Rust_CThostFtdcMdSpi_Trait + Send + 'static>(&mut self, spi: S) -> Result<(), String> {
+        if self.started {
+            return Err("the spi cannot be replaced after `init`".into());
+        }
+        self.drop_spi();
+
+        let spi: Box<Box<dyn Rust_CThostFtdcMdSpi_Trait>> = Box::new(Box::new(spi));
+        let ptr = Box::into_raw(spi) as *mut c_void;
+        unsafe {
+            let stub = Rust_CThostFtdcMdSpi::Create(ptr);
+            self.api.RegisterSpi(stub);
+            self.spi = Some((stub, ptr));
+        }
+        Ok(())
+    }
+
+    /// Register the configured addresses and start the api thread.
+    pub fn init(&mut self) -> Result<(), String> {
+        if self.spi.is_none() {
+            return Err("register a spi before `init`".into());
+        }
+        if self.started {
+            return Err("the api is already started".into());
+        }
+
+        if !self.config.front_addr.is_empty() {
+            debug!("front_addr is: {}", self.config.front_addr);
+            let cs = CString::new(self.config.front_addr.as_bytes()).map_err(|e| e.to_string())?;
+            unsafe { self.api.RegisterFront(cs.as_ptr() as *mut _) };
+        }
+
+        if !self.config.nm_addr.is_empty() {
+            debug!("nm_addr is: {}", self.config.nm_addr);
+            let cs = CString::new(self.config.nm_addr.as_bytes()).map_err(|e| e.to_string())?;
+            unsafe { self.api.RegisterNameServer(cs.as_ptr() as *mut _) };
+        }
+
+        if let Some(mode) = self.config.fens_login_mode {
+            debug!("fens login mode is: {:?}", mode);
+            let mut field = fens_user_info(&self.config.broker_id, &self.config.user_id, mode);
+            unsafe { self.api.RegisterFensUserInfo(&mut field) };
+        }
+
+        self.started = true;
+        unsafe { self.api.Init() };
+        Ok(())
+    }
+
+    pub fn get_trading_day(&mut self) -> String {
+        let cs = unsafe { CStr::from_ptr(self.api.GetTradingDay()) };
+        cs.to_string_lossy().into()
+    }
+
+    pub fn next_request_id(&mut self) -> c_int {
+        self.request_id += 1;
+        self.request_id
+    }
+
+    pub fn req_user_login(&mut self) -> Result<c_int, String> {
+        let mut field: CThostFtdcReqUserLoginField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.UserID, &self.config.user_id);
+        set_string(&mut field.Password, &self.config.password);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqUserLogin(&mut field, request_id) };
+        check_rtn("md_api_req_user_login", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn subscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        let rtn = with_instruments(codes, |ptr, len| unsafe { self.api.SubscribeMarketData(ptr, len) })?;
+        check_rtn("md_api_subscribe_market_data", rtn)
+    }
+
+    pub fn unsubscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        let rtn = with_instruments(codes, |ptr, len| unsafe { self.api.UnSubscribeMarketData(ptr, len) })?;
+        check_rtn("md_api_unsubscribe_market_data", rtn)
+    }
+
+    /// Subscribe for-quote (询价) notices, delivered by `on_rtn_for_quote_rsp`.
+    pub fn subscribe_for_quote_rsp(&mut self, codes: &[&str]) -> Result<(), String> {
+        let rtn = with_instruments(codes, |ptr, len| unsafe { self.api.SubscribeForQuoteRsp(ptr, len) })?;
+        check_rtn("md_api_subscribe_for_quote_rsp", rtn)
+    }
+
+    pub fn unsubscribe_for_quote_rsp(&mut self, codes: &[&str]) -> Result<(), String> {
+        let rtn = with_instruments(codes, |ptr, len| unsafe { self.api.UnSubscribeForQuoteRsp(ptr, len) })?;
+        check_rtn("md_api_unsubscribe_for_quote_rsp", rtn)
+    }
+
+    /// Query the instrument numbers of a multicast topic, answered by
+    /// `on_rsp_qry_multicast_instrument`. An empty `instrument` queries the whole topic.
+    pub fn req_qry_multicast_instrument(&mut self, topic_id: i32, instrument: &str) -> Result<c_int, String> {
+        if self.mode != Mode::Multicast {
+            return Err(format!("multicast instrument query is not available in {:?} mode", self.mode));
+        }
+
+        let mut field: CThostFtdcQryMulticastInstrumentField = zeroed();
+        field.TopicID = topic_id;
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryMulticastInstrument(&mut field, request_id) };
+        check_rtn("md_api_req_qry_multicast_instrument", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query every topic in `multicast_topics`.
+    pub fn req_qry_multicast_topics(&mut self) -> Result<(), String> {
+        for topic in self.config.multicast_topics.clone() {
+            self.req_qry_multicast_instrument(topic, "")?;
+        }
+        Ok(())
+    }
+
+    fn drop_spi(&mut self) {
+        if let Some((stub, ptr)) = self.spi.take() {
+            debug!("drop spi");
+            unsafe { Rust_CThostFtdcMdSpi::Destroy(stub) };
+            Rust_CThostFtdcMdSpi_Trait_Drop(ptr);
+        }
+    }
+}
+
+impl Drop for MdApi {
+    fn drop(&mut self) {
+        debug!("drop api");
+        unsafe { self.api.Release() };
+        self.drop_spi();
+    }
+}
+
+pub(crate) fn with_instruments<F>(codes: &[&str], f: F) -> Result<c_int, String>
+where
+    F: FnOnce(*mut *mut c_char, c_int) -> c_int,
+{
+    let arr_cstring = codes
+        .iter()
+        .map(|s| CString::new(s.as_bytes()).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut arr_cstr: Vec<*mut c_char> = arr_cstring.iter().map(|s| s.as_ptr() as *mut c_char).collect();
+    Ok(f(arr_cstr.as_mut_ptr(), codes.len() as c_int))
+}
@@ -0,0 +1,308 @@
+//! Redundant feeds: several md sessions on different fronts merged into one
+//! stream, keeping the first arrival of every tick.
+//!
+//! A tick is identified by `(InstrumentID, TradingDay, UpdateTime,
+//! UpdateMillisec, Volume)`.
+//! When a feed stalls the others keep winning, so the stream falls back to the
+//! survivors by itself; ticks older than the last forwarded one are dropped, so
+//! a feed catching up on its backlog never replays old data. Volume restarts
+//! with the trading day, so the order is only checked within a day.
+
+use super::session::Session;
+use super::{Config, HealthCount, SharedSpi};
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+// keys remembered per instrument, far more than the ticks a feed can lag behind
+const HISTORY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TickKey {
+    trading_day: String,
+    time: (i64, i32),
+    volume: i32,
+}
+
+#[derive(Debug, Default)]
+struct Book {
+    seen: VecDeque<(TickKey, usize, Instant)>,
+    last: Option<TickKey>,
+}
+
+/// Arrival statistics of one feed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeedStats {
+    /// Ticks received, forwarded or not.
+    pub ticks: u64,
+    /// Ticks this feed delivered first.
+    pub wins: u64,
+    /// Ticks another feed had already delivered.
+    pub duplicates: u64,
+    /// Ticks dropped as older than the stream.
+    pub late: u64,
+    /// How far this feed was ahead when another feed delivered the same tick:
+    /// number of samples, their sum and max.
+    pub leads: u64,
+    pub lead_total: Duration,
+    pub lead_max: Duration,
+    pub last_tick: Option<Instant>,
+}
+
+impl FeedStats {
+    pub fn mean_lead(&self) -> Option<Duration> {
+        if self.leads == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos((self.lead_total.as_nanos() / self.leads as u128) as u64))
+    }
+}
+
+/// Keeps the first arrival of every tick across feeds.
+#[derive(Debug, Default)]
+pub struct Arbiter {
+    books: HashMap<String, Book>,
+    feeds: Vec<FeedStats>,
+}
+
+impl Arbiter {
+    pub fn new(feeds: usize) -> Self {
+        Self {
+            books: HashMap::new(),
+            feeds: vec![Default::default(); feeds],
+        }
+    }
+
+    /// Whether the tick from `feed` is the first arrival and should be forwarded.
+    pub fn accept(&mut self, feed: usize, tick: &CThostFtdcDepthMarketDataField) -> bool {
+        let now = Instant::now();
+        let key = TickKey {
+            trading_day: to_string(&tick.TradingDay),
+            time: (parse_seconds(&to_string(&tick.UpdateTime)).unwrap_or_default(), tick.UpdateMillisec),
+            volume: tick.Volume,
+        };
+        let stats = &mut self.feeds[feed];
+        stats.ticks += 1;
+        stats.last_tick = Some(now);
+
+        let book = self.books.entry(to_string(&tick.InstrumentID)).or_default();
+        if let Some((_, winner, first)) = book.seen.iter().find(|(k, _, _)| *k == key) {
+            let (winner, lead) = (*winner, now.duration_since(*first));
+            self.feeds[feed].duplicates += 1;
+            if winner != feed {
+                let stats = &mut self.feeds[winner];
+                stats.lead_total += lead;
+                stats.lead_max = stats.lead_max.max(lead);
+                stats.leads += 1;
+            }
+            return false;
+        }
+
+        if let Some(last) = &book.last {
+            // a later tick was forwarded already, unless the night session crossed midnight
+            let older = if key.trading_day == last.trading_day {
+                key.volume < last.volume || (key.time < last.time && last.time.0 - key.time.0 < 6 * 3600)
+            } else {
+                key.trading_day < last.trading_day
+            };
+            if older {
+                self.feeds[feed].late += 1;
+                return false;
+            }
+        }
+
+        if book.seen.len() >= HISTORY {
+            book.seen.pop_front();
+        }
+        book.seen.push_back((key.clone(), feed, now));
+        book.last = Some(key);
+        self.feeds[feed].wins += 1;
+        true
+    }
+
+    /// Statistics per feed, in feed order.
+    pub fn stats(&self) -> &[FeedStats] {
+        &self.feeds
+    }
+
+    /// Feeds without a tick for `timeout`, or none at all yet.
+    pub fn stalled(&self, timeout: Duration) -> Vec<usize> {
+        self.feeds
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.last_tick.is_none_or(|t| t.elapsed() > timeout))
+            .map(|(feed, _)| feed)
+            .collect()
+    }
+}
+
+/// Spi of one feed: ticks go through the arbiter, only first arrivals reach the
+/// consumer. Session events are handled by [`RedundantMdApi`] and not forwarded.
+struct FeedSpi<S> {
+    feed: usize,
+    arbiter: Arc<Mutex<Arbiter>>,
+    inner: SharedSpi<S>,
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for FeedSpi<S> {
+    forward_md_spi!(inner: on_rsp_error);
+
+    fn on_front_disconnected(&mut self, nReason: c_int) {
+        warn!("md feed {} disconnected: {}", self.feed, nReason);
+    }
+
+    fn on_rtn_depth_market_data(&mut self, pDepthMarketData: *mut CThostFtdcDepthMarketDataField) {
+        let tick = match unsafe { pDepthMarketData.as_ref() } {
+            Some(tick) => tick,
+            None => return,
+        };
+        if self.arbiter.lock().unwrap().accept(self.feed, tick) {
+            self.inner.on_rtn_depth_market_data(pDepthMarketData)
+        }
+    }
+}
+
+/// One deduplicated stream from a md session per config, each config should
+/// point to a different front.
+///
+/// Call [`RedundantMdApi::check`] periodically to log in and restore
+/// subscriptions on every feed.
+pub struct RedundantMdApi<S> {
+    configs: Vec<Config>,
+    spi: SharedSpi<S>,
+    arbiter: Arc<Mutex<Arbiter>>,
+    sessions: Vec<Session>,
+    subscriptions: BTreeSet<String>,
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait + Send + 'static> RedundantMdApi<S> {
+    pub fn new(configs: &[Config], spi: S) -> Result<Self, String> {
+        if configs.len() < 2 {
+            return Err("redundant feeds need at least two configs".into());
+        }
+        for config in configs {
+            config.validate()?;
+        }
+        Ok(Self {
+            configs: configs.to_vec(),
+            spi: SharedSpi::new(spi),
+            arbiter: Arc::new(Mutex::new(Arbiter::new(configs.len()))),
+            sessions: vec![],
+            subscriptions: BTreeSet::new(),
+        })
+    }
+
+    /// The user spi, shared with the api threads.
+    pub fn spi(&self) -> &SharedSpi<S> {
+        &self.spi
+    }
+
+    pub fn start(&mut self) -> Result<(), String> {
+        for (feed, config) in self.configs.iter().enumerate() {
+            let spi = FeedSpi {
+                feed,
+                arbiter: self.arbiter.clone(),
+                inner: self.spi.clone(),
+            };
+            self.sessions.push(Session::start(config, spi)?);
+        }
+        Ok(())
+    }
+
+    /// Login and resubscribe each feed as needed. A failing feed is logged and
+    /// left to the others, the error is returned only when every feed fails.
+    pub fn check(&mut self) -> Result<(), String> {
+        let mut errors = vec![];
+        for (feed, session) in self.sessions.iter_mut().enumerate() {
+            if let Err(e) = session.drive(&self.subscriptions) {
+                error!("md feed {} on {}: {}", feed, session.front(), e);
+                errors.push(e);
+            }
+        }
+        if !errors.is_empty() && errors.len() == self.sessions.len() {
+            return Err(errors.join("; "));
+        }
+        Ok(())
+    }
+
+    pub fn subscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        self.subscriptions.extend(codes.iter().map(|s| s.to_string()));
+        for session in self.sessions.iter_mut() {
+            session.subscribe_market_data(codes)?;
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        for code in codes {
+            self.subscriptions.remove(*code);
+        }
+        for session in self.sessions.iter_mut() {
+            session.unsubscribe_market_data(codes)?;
+        }
+        Ok(())
+    }
+
+    /// Arrival statistics per feed, in config order.
+    pub fn stats(&self) -> Vec<FeedStats> {
+        self.arbiter.lock().unwrap().stats().to_vec()
+    }
+
+    /// Feeds without a tick for `timeout`.
+    pub fn stalled(&self, timeout: Duration) -> Vec<usize> {
+        self.arbiter.lock().unwrap().stalled(timeout)
+    }
+
+    /// Connection health per feed within `window`.
+    pub fn health(&self, window: Duration) -> Vec<HealthCount> {
+        self.sessions.iter().map(|s| s.health(window)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(day: &str, time: &str, volume: i32) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.InstrumentID, "rb2501");
+        set_string(&mut tick.TradingDay, day);
+        set_string(&mut tick.UpdateTime, time);
+        tick.Volume = volume;
+        tick
+    }
+
+    #[test]
+    fn first_arrival_wins() {
+        let mut arbiter = Arbiter::new(2);
+        assert!(arbiter.accept(0, &tick("20250102", "09:00:00", 10)));
+        assert!(!arbiter.accept(1, &tick("20250102", "09:00:00", 10)));
+        assert!(arbiter.accept(1, &tick("20250102", "09:00:01", 20)));
+        assert!(!arbiter.accept(0, &tick("20250102", "09:00:01", 20)));
+        // feed 0 catching up on a tick older than the stream
+        assert!(!arbiter.accept(0, &tick("20250102", "09:00:00", 15)));
+
+        let stats = arbiter.stats();
+        assert_eq!((stats[0].wins, stats[0].duplicates, stats[0].late, stats[0].leads), (1, 1, 1, 1));
+        assert_eq!((stats[1].wins, stats[1].duplicates, stats[1].late, stats[1].leads), (1, 1, 0, 1));
+        assert!(stats[0].mean_lead().is_some());
+    }
+
+    #[test]
+    fn volume_restarts_with_the_trading_day() {
+        let mut arbiter = Arbiter::new(2);
+        assert!(arbiter.accept(0, &tick("20250102", "14:59:59", 250_000)));
+        assert!(arbiter.accept(0, &tick("20250103", "21:00:00", 0)));
+        assert!(arbiter.accept(1, &tick("20250103", "21:00:01", 40)));
+        assert!(!arbiter.accept(0, &tick("20250103", "21:00:01", 40)));
+        assert!(!arbiter.accept(1, &tick("20250102", "15:00:00", 250_010)));
+        assert_eq!(arbiter.stats()[1].late, 1);
+    }
+}
@@ -0,0 +1,492 @@
+//! Fan-out of depth ticks to many consumers in one process.
+//!
+//! Every subscriber has its own queue and picks what happens when it falls
+//! behind. Publishing never waits on a consumer: the api thread only pushes
+//! into non-blocking queues, each `Block` subscriber is fed by its own thread
+//! which waits on its behalf.
+
+use super::rcu::Rcu;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use crossbeam::queue::{ArrayQueue, SegQueue};
+use crossbeam::sync::{Parker, Unparker};
+use log::*;
+
+/// A published tick, shared by all subscribers.
+pub type Tick = Arc<CThostFtdcDepthMarketDataField>;
+
+/// What a subscriber does when ticks arrive faster than it consumes them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Policy {
+    /// Lossless, the subscriber's thread waits while `capacity` ticks are
+    /// pending. Ticks queue up in the bus meanwhile, a stuck subscriber grows
+    /// memory but holds up no other subscriber.
+    Block(usize),
+    /// Keep the newest `capacity` ticks, drop the oldest when full.
+    DropOldest(usize),
+    /// Keep only the latest pending tick of every instrument.
+    Conflate,
+}
+
+/// How far a subscriber is behind the bus.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lag {
+    /// Ticks published to this subscriber.
+    pub published: u64,
+    /// Ticks taken by the subscriber.
+    pub received: u64,
+    /// Ticks dropped or conflated away.
+    pub dropped: u64,
+    /// Ticks waiting to be taken.
+    pub pending: u64,
+    pub max_pending: u64,
+    /// Time from publish to receive: number of samples, their sum and max.
+    pub delay_total: Duration,
+    pub delay_max: Duration,
+}
+
+impl Lag {
+    pub fn mean_delay(&self) -> Option<Duration> {
+        if self.received == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos((self.delay_total.as_nanos() / self.received as u128) as u64))
+    }
+}
+
+#[derive(Clone)]
+struct Envelope {
+    tick: Tick,
+    at: Instant,
+}
+
+/// The pending tick of one instrument, swapped in and out whole.
+struct Latest {
+    envelope: AtomicPtr<Envelope>,
+}
+
+impl Latest {
+    fn new() -> Self {
+        Self {
+            envelope: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Put `envelope` in, returns the one it replaces.
+    fn swap(&self, envelope: Option<Envelope>) -> Option<Envelope> {
+        let new = envelope.map_or(ptr::null_mut(), |e| Box::into_raw(Box::new(e)));
+        let old = self.envelope.swap(new, Ordering::AcqRel);
+        // a pointer belongs to whoever swapped it out
+        if old.is_null() {
+            None
+        } else {
+            Some(*unsafe { Box::from_raw(old) })
+        }
+    }
+}
+
+impl Drop for Latest {
+    fn drop(&mut self) {
+        self.swap(None);
+    }
+}
+
+/// The latest pending tick per instrument, and the instruments with one
+/// pending in arrival order. An instrument is queued when its tick goes from
+/// none to some, so it is queued at most once.
+struct Conflated {
+    latest: Rcu<HashMap<String, Arc<Latest>>>,
+    order: SegQueue<Arc<Latest>>,
+}
+
+impl Conflated {
+    fn new() -> Self {
+        Self {
+            latest: Rcu::new(HashMap::new()),
+            order: SegQueue::new(),
+        }
+    }
+
+    fn latest(&self, instrument: &str) -> Arc<Latest> {
+        if let Some(latest) = self.latest.read(|latest| latest.get(instrument).cloned()) {
+            return latest;
+        }
+        // first tick of the instrument, the only time a writer lock is taken
+        let mut found = None;
+        self.latest.update(|latest| {
+            if let Some(existing) = latest.get(instrument) {
+                found = Some(existing.clone());
+                return None;
+            }
+            let mut latest = latest.clone();
+            let entry = Arc::new(Latest::new());
+            latest.insert(instrument.to_string(), entry.clone());
+            found = Some(entry);
+            Some(latest)
+        });
+        found.unwrap()
+    }
+
+    /// Whether `envelope` replaced a pending tick.
+    fn push(&self, envelope: Envelope) -> bool {
+        let latest = self.latest(&to_string(&envelope.tick.InstrumentID));
+        if latest.swap(Some(envelope)).is_some() {
+            return true;
+        }
+        self.order.push(latest);
+        false
+    }
+
+    fn pop(&self) -> Option<Envelope> {
+        std::iter::from_fn(|| self.order.pop()).find_map(|latest| latest.swap(None))
+    }
+}
+
+enum Queue {
+    /// Into the subscriber's thread, which feeds its bounded channel.
+    Block(Sender<Envelope>),
+    DropOldest(Box<ArrayQueue<Envelope>>),
+    Conflate(Box<Conflated>),
+}
+
+#[derive(Default)]
+struct Counters {
+    published: AtomicU64,
+    received: AtomicU64,
+    dropped: AtomicU64,
+    max_pending: AtomicU64,
+    delay_total: AtomicU64,
+    delay_max: AtomicU64,
+}
+
+impl Counters {
+    fn pending(&self) -> u64 {
+        let done = self.received.load(Ordering::Relaxed) + self.dropped.load(Ordering::Relaxed);
+        self.published.load(Ordering::Relaxed).saturating_sub(done)
+    }
+
+    fn on_received(&self, envelope: &Envelope) {
+        let delay = envelope.at.elapsed().as_nanos() as u64;
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.delay_total.fetch_add(delay, Ordering::Relaxed);
+        self.delay_max.fetch_max(delay, Ordering::Relaxed);
+    }
+
+    fn lag(&self) -> Lag {
+        Lag {
+            published: self.published.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            pending: self.pending(),
+            max_pending: self.max_pending.load(Ordering::Relaxed),
+            delay_total: Duration::from_nanos(self.delay_total.load(Ordering::Relaxed)),
+            delay_max: Duration::from_nanos(self.delay_max.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+struct Slot {
+    queue: Queue,
+    counters: Arc<Counters>,
+    unparker: Unparker,
+}
+
+impl Slot {
+    /// Queue a tick without waiting.
+    fn offer(&self, envelope: &Envelope) {
+        match &self.queue {
+            Queue::Block(tx) => {
+                // unbounded, fails only once the subscriber's thread is gone
+                let _ = tx.send(envelope.clone());
+            }
+            Queue::DropOldest(queue) => {
+                let mut envelope = envelope.clone();
+                while let Err(back) = queue.push(envelope) {
+                    envelope = back;
+                    if queue.pop().is_some() {
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            Queue::Conflate(queue) => {
+                if queue.push(envelope.clone()) {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        self.counters.max_pending.fetch_max(self.counters.pending(), Ordering::Relaxed);
+        self.unparker.unpark();
+    }
+}
+
+/// The subscriber list, read without locking by the publisher and copied on
+/// every subscribe or unsubscribe.
+type Registry = Rcu<Vec<Arc<Slot>>>;
+
+/// A broadcast bus of depth ticks.
+///
+/// Register it (or a [`BusSpi`] around another spi) with a md api, clones share
+/// the same subscribers.
+#[derive(Clone)]
+pub struct TickBus {
+    registry: Arc<Registry>,
+    published: Arc<AtomicU64>,
+}
+
+impl Default for TickBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TickBus {
+    pub fn new() -> Self {
+        Self {
+            registry: Arc::new(Registry::new(vec![])),
+            published: Default::default(),
+        }
+    }
+
+    /// A `Block` subscriber starts a thread, it ends with the subscriber.
+    pub fn subscribe(&self, policy: Policy) -> Subscriber {
+        let parker = Parker::new();
+        let counters = Arc::new(Counters::default());
+        let (queue, rx) = match policy {
+            Policy::Block(capacity) => {
+                let (inbound, queued) = channel::unbounded();
+                let (tx, rx) = channel::bounded(capacity.max(1));
+                let thread_counters = counters.clone();
+                std::thread::spawn(move || Self::forward(queued, tx, &thread_counters));
+                (Queue::Block(inbound), Some(rx))
+            }
+            Policy::DropOldest(capacity) => (Queue::DropOldest(Box::new(ArrayQueue::new(capacity.max(1)))), None),
+            Policy::Conflate => (Queue::Conflate(Box::new(Conflated::new())), None),
+        };
+        let slot = Arc::new(Slot {
+            queue,
+            counters,
+            unparker: parker.unparker().clone(),
+        });
+        self.registry.update(|slots| {
+            let mut slots = slots.clone();
+            slots.push(slot.clone());
+            Some(slots)
+        });
+        Subscriber {
+            policy,
+            slot,
+            rx,
+            parker,
+            registry: self.registry.clone(),
+        }
+    }
+
+    /// Hand `tick` to every subscriber, never waits.
+    pub fn publish(&self, tick: &CThostFtdcDepthMarketDataField) {
+        let envelope = Envelope {
+            tick: Arc::new(*tick),
+            at: Instant::now(),
+        };
+        self.published.fetch_add(1, Ordering::Relaxed);
+        self.registry.read(|slots| {
+            for slot in slots {
+                slot.counters.published.fetch_add(1, Ordering::Relaxed);
+                slot.offer(&envelope);
+            }
+        });
+    }
+
+    /// Ticks published since the bus was created.
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.registry.read(|slots| slots.len())
+    }
+
+    /// Lag of every subscriber, in subscribe order.
+    pub fn lags(&self) -> Vec<Lag> {
+        self.registry.read(|slots| slots.iter().map(|s| s.counters.lag()).collect())
+    }
+
+    /// Thread of a `Block` subscriber, waits while the subscriber is full.
+    fn forward(queued: Receiver<Envelope>, tx: Sender<Envelope>, counters: &Counters) {
+        for envelope in queued {
+            // fails once the subscriber is dropped
+            if tx.send(envelope).is_err() {
+                break;
+            }
+            counters.max_pending.fetch_max(counters.pending(), Ordering::Relaxed);
+        }
+        debug!("tick bus subscriber thread stopped");
+    }
+}
+
+impl Rust_CThostFtdcMdSpi_Trait for TickBus {
+    fn on_rtn_depth_market_data(&mut self, tick: *mut CThostFtdcDepthMarketDataField) {
+        if let Some(tick) = unsafe { tick.as_ref() } {
+            self.publish(tick)
+        }
+    }
+}
+
+/// Publishes ticks to a [`TickBus`], forwards everything else to `inner`.
+pub struct BusSpi<S> {
+    inner: S,
+    bus: TickBus,
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait> BusSpi<S> {
+    pub fn new(inner: S, bus: TickBus) -> Self {
+        Self { inner, bus }
+    }
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for BusSpi<S> {
+    forward_md_spi!(inner:
+        on_front_connected, on_front_disconnected, on_heart_beat_warning,
+        on_rsp_user_login, on_rsp_user_logout, on_rsp_qry_multicast_instrument, on_rsp_error,
+        on_rsp_sub_market_data, on_rsp_un_sub_market_data,
+        on_rsp_sub_for_quote_rsp, on_rsp_un_sub_for_quote_rsp, on_rtn_for_quote_rsp,
+    );
+
+    fn on_rtn_depth_market_data(&mut self, pDepthMarketData: *mut CThostFtdcDepthMarketDataField) {
+        self.bus.on_rtn_depth_market_data(pDepthMarketData)
+    }
+}
+
+/// One consumer of a [`TickBus`], unsubscribes on drop.
+pub struct Subscriber {
+    policy: Policy,
+    slot: Arc<Slot>,
+    rx: Option<Receiver<Envelope>>,
+    parker: Parker,
+    registry: Arc<Registry>,
+}
+
+impl Subscriber {
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn lag(&self) -> Lag {
+        self.slot.counters.lag()
+    }
+
+    pub fn try_recv(&self) -> Option<Tick> {
+        let envelope = match (&self.rx, &self.slot.queue) {
+            (Some(rx), _) => rx.try_recv().ok(),
+            (None, Queue::DropOldest(queue)) => queue.pop(),
+            (None, Queue::Conflate(queue)) => queue.pop(),
+            (None, Queue::Block(_)) => None,
+        }?;
+        self.slot.counters.on_received(&envelope);
+        Some(envelope.tick)
+    }
+
+    /// Wait up to `timeout` for the next tick.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Tick> {
+        if let Some(rx) = &self.rx {
+            return match rx.recv_timeout(timeout) {
+                Ok(envelope) => {
+                    self.slot.counters.on_received(&envelope);
+                    Some(envelope.tick)
+                }
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+            };
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(tick) = self.try_recv() {
+                return Some(tick);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            self.parker.park_timeout(deadline - now);
+        }
+    }
+
+    /// Take every pending tick without waiting.
+    pub fn drain(&self) -> Vec<Tick> {
+        std::iter::from_fn(|| self.try_recv()).collect()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let slot = &self.slot;
+        self.registry.update(|slots| Some(slots.iter().filter(|s| !Arc::ptr_eq(s, slot)).cloned().collect()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(instrument: &str, volume: i32) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.InstrumentID, instrument);
+        tick.Volume = volume;
+        tick
+    }
+
+    #[test]
+    fn conflate_keeps_the_latest_per_instrument() {
+        let bus = TickBus::new();
+        let sub = bus.subscribe(Policy::Conflate);
+        bus.publish(&tick("rb2501", 1));
+        bus.publish(&tick("hc2501", 1));
+        bus.publish(&tick("rb2501", 2));
+        let ticks: Vec<_> = sub.drain().iter().map(|t| (to_string(&t.InstrumentID), t.Volume)).collect();
+        assert_eq!(ticks, vec![("rb2501".to_string(), 2), ("hc2501".to_string(), 1)]);
+        let lag = sub.lag();
+        assert_eq!((lag.published, lag.received, lag.dropped, lag.pending), (3, 2, 1, 0));
+        bus.publish(&tick("rb2501", 3));
+        assert_eq!(sub.try_recv().map(|t| t.Volume), Some(3));
+    }
+
+    #[test]
+    fn stuck_block_subscriber_holds_up_no_one() {
+        let bus = TickBus::new();
+        let stuck = bus.subscribe(Policy::Block(1));
+        let other = bus.subscribe(Policy::Block(100));
+        for volume in 0..10 {
+            bus.publish(&tick("rb2501", volume));
+        }
+        let mut received = vec![];
+        while received.len() < 10 {
+            match other.recv_timeout(Duration::from_secs(1)) {
+                Some(tick) => received.push(tick.Volume),
+                None => panic!("blocked behind the stuck subscriber"),
+            }
+        }
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        assert_eq!(stuck.recv_timeout(Duration::from_secs(1)).map(|t| t.Volume), Some(0));
+        assert!(other.lag().mean_delay().is_some());
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest() {
+        let bus = TickBus::new();
+        let sub = bus.subscribe(Policy::DropOldest(2));
+        for volume in 0..5 {
+            bus.publish(&tick("rb2501", volume));
+        }
+        let volumes: Vec<_> = sub.drain().iter().map(|t| t.Volume).collect();
+        assert_eq!(volumes, vec![3, 4]);
+        assert_eq!(sub.lag().dropped, 3);
+    }
+}
@@ -0,0 +1,281 @@
+//! Five level order book of `CThostFtdcDepthMarketDataField`.
+
+use crate::sys::*;
+use crate::utils::*;
+
+pub const LEVELS: usize = 5;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Level {
+    pub price: f64,
+    pub volume: i32,
+}
+
+/// Which side of the book is locked at the daily price limit.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LimitLock {
+    /// Bids at `UpperLimitPrice` and no asks.
+    Up,
+    /// Asks at `LowerLimitPrice` and no bids.
+    Down,
+}
+
+/// Book levels of a tick, empty levels (DBL_MAX price or zero volume) are left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DepthSnapshot {
+    pub instrument_id: String,
+    pub exchange_id: String,
+    pub trading_day: String,
+    pub update_time: String,
+    pub update_millisec: i32,
+    pub last_price: Option<f64>,
+    pub upper_limit_price: Option<f64>,
+    pub lower_limit_price: Option<f64>,
+    bids: [Level; LEVELS],
+    asks: [Level; LEVELS],
+    bid_levels: usize,
+    ask_levels: usize,
+}
+
+impl From<&CThostFtdcDepthMarketDataField> for DepthSnapshot {
+    fn from(f: &CThostFtdcDepthMarketDataField) -> Self {
+        let bids = [
+            (f.BidPrice1, f.BidVolume1),
+            (f.BidPrice2, f.BidVolume2),
+            (f.BidPrice3, f.BidVolume3),
+            (f.BidPrice4, f.BidVolume4),
+            (f.BidPrice5, f.BidVolume5),
+        ];
+        let asks = [
+            (f.AskPrice1, f.AskVolume1),
+            (f.AskPrice2, f.AskVolume2),
+            (f.AskPrice3, f.AskVolume3),
+            (f.AskPrice4, f.AskVolume4),
+            (f.AskPrice5, f.AskVolume5),
+        ];
+
+        let mut snapshot = Self {
+            instrument_id: to_string(&f.InstrumentID),
+            exchange_id: to_string(&f.ExchangeID),
+            trading_day: to_string(&f.TradingDay),
+            update_time: to_string(&f.UpdateTime),
+            update_millisec: f.UpdateMillisec,
+            last_price: valid_price(f.LastPrice),
+            upper_limit_price: valid_price(f.UpperLimitPrice),
+            lower_limit_price: valid_price(f.LowerLimitPrice),
+            ..Default::default()
+        };
+        snapshot.bid_levels = fill_levels(&mut snapshot.bids, &bids);
+        snapshot.ask_levels = fill_levels(&mut snapshot.asks, &asks);
+        snapshot
+    }
+}
+
+// levels are contiguous, the book ends at the first empty one
+fn fill_levels(levels: &mut [Level; LEVELS], raw: &[(f64, i32); LEVELS]) -> usize {
+    let mut n = 0;
+    for (price, volume) in raw {
+        match valid_price(*price) {
+            Some(price) if *volume > 0 => {
+                levels[n] = Level { price, volume: *volume };
+                n += 1;
+            }
+            _ => break,
+        }
+    }
+    n
+}
+
+impl DepthSnapshot {
+    /// Bid levels, best first.
+    pub fn bids(&self) -> impl Iterator<Item = &Level> + '_ {
+        self.bids[..self.bid_levels].iter()
+    }
+
+    /// Ask levels, best first.
+    pub fn asks(&self) -> impl Iterator<Item = &Level> + '_ {
+        self.asks[..self.ask_levels].iter()
+    }
+
+    pub fn best_bid(&self) -> Option<&Level> {
+        self.bids().next()
+    }
+
+    pub fn best_ask(&self) -> Option<&Level> {
+        self.asks().next()
+    }
+
+    pub fn spread(&self) -> Option<f64> {
+        Some(self.best_ask()?.price - self.best_bid()?.price)
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        Some((self.best_ask()?.price + self.best_bid()?.price) / 2.0)
+    }
+
+    /// Mid price weighted by the opposite top of book volume.
+    pub fn microprice(&self) -> Option<f64> {
+        let (bid, ask) = (self.best_bid()?, self.best_ask()?);
+        let total = (bid.volume + ask.volume) as f64;
+        Some((bid.price * ask.volume as f64 + ask.price * bid.volume as f64) / total)
+    }
+
+    /// `(bid - ask) / (bid + ask)` volume over the first `levels` levels, in `[-1, 1]`.
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        let bid = self.bid_depth(levels) as f64;
+        let ask = self.ask_depth(levels) as f64;
+        if bid + ask == 0.0 {
+            return None;
+        }
+        Some((bid - ask) / (bid + ask))
+    }
+
+    /// Total bid volume over the first `levels` levels.
+    pub fn bid_depth(&self, levels: usize) -> i64 {
+        self.bids().take(levels).map(|l| l.volume as i64).sum()
+    }
+
+    /// Total ask volume over the first `levels` levels.
+    pub fn ask_depth(&self, levels: usize) -> i64 {
+        self.asks().take(levels).map(|l| l.volume as i64).sum()
+    }
+
+    /// Volume weighted price of the first `levels` bid levels.
+    pub fn bid_vwap(&self, levels: usize) -> Option<f64> {
+        vwap(self.bids().take(levels))
+    }
+
+    /// Volume weighted price of the first `levels` ask levels.
+    pub fn ask_vwap(&self, levels: usize) -> Option<f64> {
+        vwap(self.asks().take(levels))
+    }
+
+    /// `(price, cumulative volume)` down the bid side.
+    pub fn cumulative_bids(&self) -> impl Iterator<Item = (f64, i64)> + '_ {
+        cumulative(self.bids())
+    }
+
+    /// `(price, cumulative volume)` up the ask side.
+    pub fn cumulative_asks(&self) -> impl Iterator<Item = (f64, i64)> + '_ {
+        cumulative(self.asks())
+    }
+
+    /// Whether the book is stuck at a daily price limit.
+    pub fn limit_lock(&self) -> Option<LimitLock> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), None) if self.upper_limit_price.is_some_and(|p| same_price(p, bid.price)) => Some(LimitLock::Up),
+            (None, Some(ask)) if self.lower_limit_price.is_some_and(|p| same_price(p, ask.price)) => Some(LimitLock::Down),
+            _ => None,
+        }
+    }
+
+    pub fn is_limit_locked(&self) -> bool {
+        self.limit_lock().is_some()
+    }
+}
+
+fn vwap<'a>(levels: impl Iterator<Item = &'a Level>) -> Option<f64> {
+    let (mut turnover, mut volume) = (0.0, 0i64);
+    for l in levels {
+        turnover += l.price * l.volume as f64;
+        volume += l.volume as i64;
+    }
+    if volume == 0 {
+        return None;
+    }
+    Some(turnover / volume as f64)
+}
+
+fn cumulative<'a>(levels: impl Iterator<Item = &'a Level> + 'a) -> impl Iterator<Item = (f64, i64)> + 'a {
+    levels.scan(0i64, |total, l| {
+        *total += l.volume as i64;
+        Some((l.price, *total))
+    })
+}
+
+fn same_price(a: f64, b: f64) -> bool {
+    (a - b).abs() < 1e-8 * a.abs().max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(bids: &[(f64, i32)], asks: &[(f64, i32)]) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.InstrumentID, "rb2501");
+        tick.LastPrice = 3500.0;
+        tick.UpperLimitPrice = 3700.0;
+        tick.LowerLimitPrice = 3300.0;
+        let mut book = [(f64::MAX, 0); 10];
+        book[..bids.len()].copy_from_slice(bids);
+        book[5..5 + asks.len()].copy_from_slice(asks);
+        (tick.BidPrice1, tick.BidVolume1) = book[0];
+        (tick.BidPrice2, tick.BidVolume2) = book[1];
+        (tick.BidPrice3, tick.BidVolume3) = book[2];
+        (tick.BidPrice4, tick.BidVolume4) = book[3];
+        (tick.BidPrice5, tick.BidVolume5) = book[4];
+        (tick.AskPrice1, tick.AskVolume1) = book[5];
+        (tick.AskPrice2, tick.AskVolume2) = book[6];
+        (tick.AskPrice3, tick.AskVolume3) = book[7];
+        (tick.AskPrice4, tick.AskVolume4) = book[8];
+        (tick.AskPrice5, tick.AskVolume5) = book[9];
+        tick
+    }
+
+    #[test]
+    fn levels_end_at_the_first_empty_one() {
+        let mut raw = tick(&[(3499.0, 10), (3498.0, 20)], &[(3501.0, 5)]);
+        raw.BidPrice4 = 3496.0;
+        raw.BidVolume4 = 7;
+        raw.AskPrice2 = 3502.0;
+        let depth = DepthSnapshot::from(&raw);
+        assert_eq!(depth.bids().map(|l| l.price).collect::<Vec<_>>(), [3499.0, 3498.0]);
+        assert_eq!(depth.asks().count(), 1);
+        assert_eq!(depth.last_price, Some(3500.0));
+    }
+
+    #[test]
+    fn spread_mid_and_microprice() {
+        let depth = DepthSnapshot::from(&tick(&[(3499.0, 30)], &[(3501.0, 10)]));
+        assert_eq!(depth.spread(), Some(2.0));
+        assert_eq!(depth.mid(), Some(3500.0));
+        // heavier bids pull it towards the ask
+        assert_eq!(depth.microprice(), Some(3500.5));
+
+        let one_sided = DepthSnapshot::from(&tick(&[(3499.0, 30)], &[]));
+        assert_eq!((one_sided.spread(), one_sided.mid(), one_sided.microprice()), (None, None, None));
+    }
+
+    #[test]
+    fn imbalance_and_depth() {
+        let depth = DepthSnapshot::from(&tick(&[(3499.0, 30), (3498.0, 10)], &[(3501.0, 10), (3502.0, 50)]));
+        assert_eq!(depth.imbalance(1), Some(0.5));
+        assert_eq!(depth.imbalance(5), Some(-0.2));
+        assert_eq!((depth.bid_depth(5), depth.ask_depth(1)), (40, 10));
+        assert_eq!(depth.cumulative_asks().collect::<Vec<_>>(), [(3501.0, 10), (3502.0, 60)]);
+        assert_eq!(depth.cumulative_bids().last(), Some((3498.0, 40)));
+        assert_eq!(depth.imbalance(0), None);
+        assert_eq!(DepthSnapshot::from(&tick(&[], &[])).imbalance(5), None);
+    }
+
+    #[test]
+    fn vwap_of_levels() {
+        let depth = DepthSnapshot::from(&tick(&[(3499.0, 30), (3495.0, 10)], &[(3501.0, 10), (3506.0, 40)]));
+        assert_eq!(depth.bid_vwap(1), Some(3499.0));
+        assert_eq!(depth.bid_vwap(5), Some(3498.0));
+        assert_eq!(depth.ask_vwap(2), Some(3505.0));
+        assert_eq!(depth.ask_vwap(0), None);
+        assert_eq!(DepthSnapshot::from(&tick(&[], &[])).bid_vwap(5), None);
+    }
+
+    #[test]
+    fn limit_locked_books() {
+        let up = DepthSnapshot::from(&tick(&[(3700.0, 900)], &[]));
+        assert_eq!(up.limit_lock(), Some(LimitLock::Up));
+        let down = DepthSnapshot::from(&tick(&[], &[(3300.0, 900)]));
+        assert_eq!(down.limit_lock(), Some(LimitLock::Down));
+        assert!(!DepthSnapshot::from(&tick(&[(3699.0, 900)], &[])).is_limit_locked());
+        assert!(!DepthSnapshot::from(&tick(&[(3700.0, 900)], &[(3700.0, 1)])).is_limit_locked());
+    }
+}
@@ -0,0 +1,383 @@
+//! Market data over several fronts, bound to the fastest one and switched
+//! when the active front turns unhealthy.
+//!
+//! The api can not change its front after `Init`, a switch releases the session
+//! and creates a new one, then logs in and subscribes the instruments again.
+//! The candidates can also be name servers, for brokers handing out only those.
+//! Socks5 fronts are probed through their proxy.
+
+use super::session::Session;
+use super::{Config, SharedSpi};
+use crate::front::validate_address;
+use crate::sys::*;
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailoverConfig {
+    /// Candidate fronts, e.g. `tcp://180.168.146.187:10131`.
+    pub fronts: Vec<String>,
+    /// `fronts` are name server addresses, registered as `nm_addr`.
+    #[serde(default)]
+    pub name_servers: bool,
+    #[serde(default = "default_probe_timeout_ms")]
+    pub probe_timeout_ms: u64,
+    /// Heartbeat warnings within `window_secs` before switching.
+    #[serde(default = "default_max_events")]
+    pub max_heartbeat_warnings: usize,
+    /// Disconnects within `window_secs` before switching.
+    #[serde(default = "default_max_events")]
+    pub max_disconnects: usize,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Least time between two probes of the fronts by [`FailoverMdApi::check`].
+    #[serde(default = "default_probe_interval_secs")]
+    pub probe_interval_secs: u64,
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_max_events() -> usize {
+    3
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_probe_interval_secs() -> u64 {
+    10
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            fronts: vec![],
+            name_servers: false,
+            probe_timeout_ms: default_probe_timeout_ms(),
+            max_heartbeat_warnings: default_max_events(),
+            max_disconnects: default_max_events(),
+            window_secs: default_window_secs(),
+            probe_interval_secs: default_probe_interval_secs(),
+        }
+    }
+}
+
+/// Connect time of every front, fastest first, unreachable fronts last.
+pub fn probe_fronts(fronts: &[String], timeout: Duration) -> Vec<(String, Option<Duration>)> {
+    let mut ranked: Vec<(String, Option<Duration>)> = fronts.iter().map(|f| (f.clone(), probe_front(f, timeout))).collect();
+    ranked.sort_by_key(|(_, latency)| latency.unwrap_or(Duration::MAX));
+    ranked
+}
+
+/// Connect time of `front`, up to the proxy's connect for a socks5 front.
+/// `None` when unreachable or not a front address.
+pub fn probe_front(front: &str, timeout: Duration) -> Option<Duration> {
+    let (scheme, rest) = front.split_once("://")?;
+    let started = Instant::now();
+    let connected = match (scheme, rest.split_once('/')) {
+        ("tcp", None) | ("ssl", None) => connect(rest, timeout).map(drop),
+        ("socks5", Some((target, proxy))) => socks5_connect(target, proxy, timeout),
+        _ => return None,
+    };
+    match connected {
+        Ok(()) => Some(started.elapsed()),
+        Err(e) => {
+            debug!("probe {} failed: {}", front, e);
+            None
+        }
+    }
+}
+
+fn refused(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, reason.to_string())
+}
+
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| refused("no address"))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// Have the proxy connect to `target` (RFC 1928), logging in (RFC 1929) when
+/// `proxy` is `user:pass@host:port`.
+fn socks5_connect(target: &str, proxy: &str, timeout: Duration) -> io::Result<()> {
+    let (credentials, proxy) = match proxy.rsplit_once('@') {
+        Some((credentials, proxy)) => (Some(credentials), proxy),
+        None => (None, proxy),
+    };
+    let mut stream = connect(proxy, timeout)?;
+    let method = if credentials.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [5, method] {
+        return Err(refused("proxy takes none of the offered methods"));
+    }
+
+    let field = |s: &str| u8::try_from(s.len()).map_err(|_| refused("field over 255 bytes"));
+    if let Some(credentials) = credentials {
+        let (user, pass) = credentials.split_once(':').unwrap_or((credentials, ""));
+        let mut login = vec![1, field(user)?];
+        login.extend(user.as_bytes());
+        login.push(field(pass)?);
+        login.extend(pass.as_bytes());
+        stream.write_all(&login)?;
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(refused("proxy login refused"));
+        }
+    }
+
+    let (host, port) = target.rsplit_once(':').ok_or_else(|| refused("missing port"))?;
+    let port: u16 = port.parse().map_err(|_| refused("invalid port"))?;
+    let mut request = vec![5, 1, 0, 3, field(host)?];
+    request.extend(host.as_bytes());
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request)?;
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head)?;
+    if head[1] != 0 {
+        return Err(refused(&format!("proxy connect failed with {}", head[1])));
+    }
+    Ok(())
+}
+
+/// A market data session failing over between several fronts.
+///
+/// Call [`FailoverMdApi::check`] periodically, it logs in after every connect,
+/// restores the subscriptions and switches fronts when needed.
+pub struct FailoverMdApi<S> {
+    config: Config,
+    failover: FailoverConfig,
+    spi: SharedSpi<S>,
+    session: Option<Session>,
+    ranked: Vec<(String, Option<Duration>)>,
+    probed: Option<Instant>,
+    subscriptions: BTreeSet<String>,
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait + Send + 'static> FailoverMdApi<S> {
+    /// `config.front_addr` is replaced by the fronts of `failover`.
+    pub fn new(config: &Config, failover: FailoverConfig, spi: S) -> Result<Self, String> {
+        if failover.fronts.is_empty() {
+            return Err("`fronts` is required for failover".into());
+        }
+        if !config.nm_addr.is_empty() {
+            return Err("failover takes every address from `fronts`, `nm_addr` must be empty".into());
+        }
+        for front in &failover.fronts {
+            validate_address(front)?;
+        }
+        Ok(Self {
+            config: config.clone(),
+            failover,
+            spi: SharedSpi::new(spi),
+            session: None,
+            ranked: vec![],
+            probed: None,
+            subscriptions: BTreeSet::new(),
+        })
+    }
+
+    /// The user spi, shared with the api thread.
+    pub fn spi(&self) -> &SharedSpi<S> {
+        &self.spi
+    }
+
+    /// The front currently bound.
+    pub fn active_front(&self) -> Option<&str> {
+        self.session.as_ref().map(|s| s.front())
+    }
+
+    /// Fronts with their latest probed latency, fastest first.
+    pub fn fronts(&self) -> &[(String, Option<Duration>)] {
+        &self.ranked
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.session.as_ref().is_some_and(|s| s.is_logged_in())
+    }
+
+    /// Probe the fronts and bind to the fastest one.
+    pub fn start(&mut self) -> Result<(), String> {
+        self.probe();
+        let front = self.ranked[0].0.clone();
+        self.bind(&front)
+    }
+
+    /// Drive the session: login after connect, resubscribe after login and
+    /// switch when the active front exceeds the configured limits. The fronts
+    /// are probed at most once per `probe_interval_secs` to find a new one.
+    /// Returns the new front after a switch.
+    pub fn check(&mut self) -> Result<Option<String>, String> {
+        let window = Duration::from_secs(self.failover.window_secs);
+        let probe_due = self.probe_due();
+        let session = self.session.as_mut().ok_or("md api is not started")?;
+        let health = session.health(window);
+        let unhealthy = health.heartbeat_warnings >= self.failover.max_heartbeat_warnings
+            || health.disconnects >= self.failover.max_disconnects;
+
+        if !unhealthy || self.failover.fronts.len() < 2 || !probe_due {
+            session.drive(&self.subscriptions)?;
+            return Ok(None);
+        }
+        match self.switch() {
+            Ok(front) => Ok(Some(front)),
+            Err(e) => {
+                // still on the active front, keep it going
+                if let Some(session) = self.session.as_mut() {
+                    session.drive(&self.subscriptions)?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn subscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        self.subscriptions.extend(codes.iter().map(|s| s.to_string()));
+        match self.session.as_mut() {
+            Some(session) => session.subscribe_market_data(codes),
+            None => Ok(()),
+        }
+    }
+
+    pub fn unsubscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        for code in codes {
+            self.subscriptions.remove(*code);
+        }
+        match self.session.as_mut() {
+            Some(session) => session.unsubscribe_market_data(codes),
+            None => Ok(()),
+        }
+    }
+
+    /// Re-probe and move to the fastest reachable front other than the active
+    /// one, staying on the active front when there is none.
+    pub fn switch(&mut self) -> Result<String, String> {
+        self.probe();
+        let active = self.active_front().unwrap_or_default().to_string();
+        let front = self
+            .ranked
+            .iter()
+            .find(|(front, latency)| latency.is_some() && *front != active)
+            .map(|(front, _)| front.clone())
+            .ok_or_else(|| format!("no reachable md front to switch to from {}", active))?;
+        warn!("switch md front from {} to {}", active, front);
+        self.bind(&front)?;
+        Ok(front)
+    }
+
+    fn bind(&mut self, front: &str) -> Result<(), String> {
+        // release the old session before its replacement connects
+        self.session = None;
+
+        let mut config = self.config.clone();
+        if self.failover.name_servers {
+            config.front_addr.clear();
+            config.nm_addr = front.to_string();
+        } else {
+            config.front_addr = front.to_string();
+        }
+        self.session = Some(Session::start(&config, self.spi.clone())?);
+        info!("md bound to front {}", front);
+        Ok(())
+    }
+
+    fn probe(&mut self) {
+        let timeout = Duration::from_millis(self.failover.probe_timeout_ms);
+        self.ranked = probe_fronts(&self.failover.fronts, timeout);
+        self.probed = Some(Instant::now());
+    }
+
+    fn probe_due(&self) -> bool {
+        let interval = Duration::from_secs(self.failover.probe_interval_secs);
+        self.probed.is_none_or(|t| t.elapsed() >= interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// A socks5 proxy answering one connect with `status`, returns its address
+    /// and what it was asked for.
+    fn proxy(login: bool, status: u8) -> (String, std::thread::JoinHandle<(Vec<u8>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, greeting[2]]).unwrap();
+            let mut credentials = vec![];
+            if login {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut user = vec![0u8; len[1] as usize];
+                stream.read_exact(&mut user).unwrap();
+                stream.read_exact(&mut len[..1]).unwrap();
+                let mut pass = vec![0u8; len[0] as usize];
+                stream.read_exact(&mut pass).unwrap();
+                credentials = [user, b":".to_vec(), pass].concat();
+                stream.write_all(&[1, 0]).unwrap();
+            }
+            let mut head = [0u8; 5];
+            stream.read_exact(&mut head).unwrap();
+            let mut host = vec![0u8; head[4] as usize + 2];
+            stream.read_exact(&mut host).unwrap();
+            let port = u16::from_be_bytes([host[host.len() - 2], host[host.len() - 1]]);
+            let target = format!("{}:{}", String::from_utf8_lossy(&host[..host.len() - 2]), port);
+            stream.write_all(&[5, status, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            (credentials, target)
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn probes_tcp_fronts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = format!("tcp://{}", listener.local_addr().unwrap());
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("tcp://{}", listener.local_addr().unwrap())
+        };
+        assert!(probe_front(&open, TIMEOUT).is_some());
+        assert!(probe_front(&closed, TIMEOUT).is_none());
+        assert!(probe_front("udp://127.0.0.1:1", TIMEOUT).is_none());
+
+        let ranked = probe_fronts(&[closed.clone(), open.clone()], TIMEOUT);
+        assert_eq!(ranked[0].0, open);
+        assert_eq!((ranked[1].0.as_str(), ranked[1].1), (closed.as_str(), None));
+    }
+
+    #[test]
+    fn probes_socks5_fronts_through_the_proxy() {
+        let (addr, proxy_thread) = proxy(false, 0);
+        assert!(probe_front(&format!("socks5://180.168.146.187:10131/{}", addr), TIMEOUT).is_some());
+        assert_eq!(proxy_thread.join().unwrap(), (vec![], "180.168.146.187:10131".to_string()));
+
+        let (addr, proxy_thread) = proxy(true, 0);
+        assert!(probe_front(&format!("socks5://md.example.com:41213/user:secret@{}", addr), TIMEOUT).is_some());
+        assert_eq!(proxy_thread.join().unwrap(), (b"user:secret".to_vec(), "md.example.com:41213".to_string()));
+
+        // the proxy can not reach the front
+        let (addr, proxy_thread) = proxy(false, 5);
+        assert!(probe_front(&format!("socks5://180.168.146.187:10131/{}", addr), TIMEOUT).is_none());
+        proxy_thread.join().unwrap();
+    }
+}
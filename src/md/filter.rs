@@ -0,0 +1,296 @@
+//! Tick validation, classifies each tick before strategies see it.
+
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TickStatus {
+    Valid,
+    /// Same `UpdateTime` and `UpdateMillisec` as the previous tick.
+    Duplicate,
+    /// `UpdateTime` outside the trading sessions, e.g. heartbeat snapshots after close.
+    OutOfSession,
+    /// Older than the previous tick, or with less volume, within a trading day.
+    Stale,
+    /// Missing or invalid last price, price outside the limit band, bad time.
+    Corrupt,
+}
+
+/// A trading session in exchange time, `end` before `start` spans midnight.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Session {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Session {
+    /// Parse `HH:MM:SS-HH:MM:SS`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut it = s.split('-');
+        let (start, end) = match (it.next(), it.next(), it.next()) {
+            (Some(start), Some(end), None) => (parse_seconds(start), parse_seconds(end)),
+            _ => (None, None),
+        };
+        match (start, end) {
+            (Some(start), Some(end)) => Ok(Self { start, end }),
+            _ => Err(format!("invalid session: {}", s)),
+        }
+    }
+
+    /// Whether `seconds` since midnight falls in the session, both ends included.
+    pub fn contains(&self, seconds: i64) -> bool {
+        if self.start <= self.end {
+            self.start <= seconds && seconds <= self.end
+        } else {
+            seconds >= self.start || seconds <= self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FilterConfig {
+    /// Sessions as `HH:MM:SS-HH:MM:SS`, no session check when empty.
+    #[serde(default)]
+    pub sessions: Vec<String>,
+    /// Sessions per product, e.g. `rb`, overriding `sessions`.
+    #[serde(default)]
+    pub product_sessions: HashMap<String, Vec<String>>,
+    /// Reject prices outside `[LowerLimitPrice, UpperLimitPrice]`.
+    #[serde(default)]
+    pub check_limits: bool,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub valid: u64,
+    pub duplicate: u64,
+    pub out_of_session: u64,
+    pub stale: u64,
+    pub corrupt: u64,
+}
+
+impl Counters {
+    fn add(&mut self, status: TickStatus) {
+        match status {
+            TickStatus::Valid => self.valid += 1,
+            TickStatus::Duplicate => self.duplicate += 1,
+            TickStatus::OutOfSession => self.out_of_session += 1,
+            TickStatus::Stale => self.stale += 1,
+            TickStatus::Corrupt => self.corrupt += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.valid + self.duplicate + self.out_of_session + self.stale + self.corrupt
+    }
+}
+
+#[derive(Debug, Default)]
+struct Last {
+    trading_day: String,
+    time: (i64, i32),
+    volume: i32,
+}
+
+/// Classifies ticks and counts the outcome per instrument.
+#[derive(Debug, Default)]
+pub struct TickFilter {
+    sessions: Vec<Session>,
+    product_sessions: HashMap<String, Vec<Session>>,
+    check_limits: bool,
+    last: HashMap<String, Last>,
+    counters: HashMap<String, Counters>,
+}
+
+impl TickFilter {
+    pub fn new(config: &FilterConfig) -> Result<Self, String> {
+        let parse = |sessions: &[String]| sessions.iter().map(|s| Session::parse(s)).collect::<Result<Vec<_>, _>>();
+        let mut product_sessions = HashMap::new();
+        for (product, sessions) in &config.product_sessions {
+            product_sessions.insert(product.clone(), parse(sessions)?);
+        }
+        Ok(Self {
+            sessions: parse(&config.sessions)?,
+            product_sessions,
+            check_limits: config.check_limits,
+            ..Default::default()
+        })
+    }
+
+    /// Classify `tick`, only valid ticks update the per instrument history.
+    pub fn check(&mut self, tick: &CThostFtdcDepthMarketDataField) -> TickStatus {
+        let instrument = to_string(&tick.InstrumentID);
+        let status = self.classify(&instrument, tick);
+        if status == TickStatus::Valid {
+            let last = self.last.entry(instrument.clone()).or_default();
+            last.trading_day = to_string(&tick.TradingDay);
+            last.time = (parse_seconds(&to_string(&tick.UpdateTime)).unwrap_or_default(), tick.UpdateMillisec);
+            last.volume = tick.Volume;
+        } else {
+            trace!("{} tick {:?} at {}", instrument, status, to_string(&tick.UpdateTime));
+        }
+        self.counters.entry(instrument).or_default().add(status);
+        status
+    }
+
+    fn classify(&self, instrument: &str, tick: &CThostFtdcDepthMarketDataField) -> TickStatus {
+        let seconds = match parse_seconds(&to_string(&tick.UpdateTime)) {
+            Some(seconds) => seconds,
+            None => return TickStatus::Corrupt,
+        };
+        let price = match valid_price(tick.LastPrice) {
+            Some(price) if price != 0.0 => price,
+            _ => return TickStatus::Corrupt,
+        };
+        if self.check_limits {
+            let upper = valid_price(tick.UpperLimitPrice).filter(|p| *p > 0.0);
+            let lower = valid_price(tick.LowerLimitPrice).filter(|p| *p > 0.0);
+            if upper.is_some_and(|p| price > p) || lower.is_some_and(|p| price < p) {
+                return TickStatus::Corrupt;
+            }
+        }
+
+        let sessions = self.product_sessions.get(product_of(instrument)).unwrap_or(&self.sessions);
+        if !sessions.is_empty() && !sessions.iter().any(|s| s.contains(seconds)) {
+            return TickStatus::OutOfSession;
+        }
+
+        if let Some(last) = self.last.get(instrument) {
+            // volume restarts from 0 with the trading day, only compare within a day
+            let trading_day = to_string(&tick.TradingDay);
+            if trading_day != last.trading_day {
+                if trading_day < last.trading_day {
+                    return TickStatus::Stale;
+                }
+                return TickStatus::Valid;
+            }
+            let time = (seconds, tick.UpdateMillisec);
+            if time == last.time {
+                return TickStatus::Duplicate;
+            }
+            // night sessions cross midnight, only compare times within a few hours
+            let backwards = time < last.time && last.time.0 - time.0 < 6 * 3600;
+            if backwards || tick.Volume < last.volume {
+                return TickStatus::Stale;
+            }
+        }
+        TickStatus::Valid
+    }
+
+    pub fn counters(&self, instrument: &str) -> Option<&Counters> {
+        self.counters.get(instrument)
+    }
+
+    pub fn all_counters(&self) -> &HashMap<String, Counters> {
+        &self.counters
+    }
+
+    /// Forget the history and counters, the history alone is restarted when
+    /// `TradingDay` changes.
+    pub fn reset(&mut self) {
+        self.last.clear();
+        self.counters.clear();
+    }
+}
+
+/// Spi adapter forwarding only valid ticks to the inner spi.
+pub struct FilterSpi<S> {
+    inner: S,
+    filter: Arc<Mutex<TickFilter>>,
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait> FilterSpi<S> {
+    pub fn new(inner: S, filter: TickFilter) -> Self {
+        Self {
+            inner,
+            filter: Arc::new(Mutex::new(filter)),
+        }
+    }
+
+    /// The filter, shared with the api thread, for its counters.
+    pub fn filter(&self) -> Arc<Mutex<TickFilter>> {
+        self.filter.clone()
+    }
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for FilterSpi<S> {
+    forward_md_spi!(inner:
+        on_front_connected, on_front_disconnected, on_heart_beat_warning,
+        on_rsp_user_login, on_rsp_user_logout, on_rsp_qry_multicast_instrument, on_rsp_error,
+        on_rsp_sub_market_data, on_rsp_un_sub_market_data,
+        on_rsp_sub_for_quote_rsp, on_rsp_un_sub_for_quote_rsp,
+        on_rtn_for_quote_rsp,
+    );
+
+    fn on_rtn_depth_market_data(&mut self, pDepthMarketData: *mut CThostFtdcDepthMarketDataField) {
+        let tick = match unsafe { pDepthMarketData.as_ref() } {
+            Some(tick) => tick,
+            None => return,
+        };
+        if self.filter.lock().unwrap().check(tick) == TickStatus::Valid {
+            self.inner.on_rtn_depth_market_data(pDepthMarketData)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(day: &str, time: &str, millis: i32, volume: i32) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.InstrumentID, "rb2501");
+        set_string(&mut tick.TradingDay, day);
+        set_string(&mut tick.UpdateTime, time);
+        tick.UpdateMillisec = millis;
+        tick.LastPrice = 3500.0;
+        tick.Volume = volume;
+        tick
+    }
+
+    #[test]
+    fn classify_within_a_day() {
+        let mut filter = TickFilter::new(&FilterConfig::default()).unwrap();
+        assert_eq!(filter.check(&tick("20250102", "09:00:00", 0, 100)), TickStatus::Valid);
+        assert_eq!(filter.check(&tick("20250102", "09:00:00", 0, 100)), TickStatus::Duplicate);
+        assert_eq!(filter.check(&tick("20250102", "08:59:59", 500, 100)), TickStatus::Stale);
+        assert_eq!(filter.check(&tick("20250102", "09:00:01", 0, 90)), TickStatus::Stale);
+        assert_eq!(filter.check(&tick("20250102", "09:00:01", 0, 120)), TickStatus::Valid);
+        let counters = filter.counters("rb2501").unwrap();
+        assert_eq!((counters.valid, counters.duplicate, counters.stale), (2, 1, 2));
+    }
+
+    #[test]
+    fn volume_restarts_with_the_trading_day() {
+        let mut filter = TickFilter::new(&FilterConfig::default()).unwrap();
+        assert_eq!(filter.check(&tick("20250102", "14:59:59", 500, 250_000)), TickStatus::Valid);
+        // the night session opens the next trading day with the volume back at 0
+        assert_eq!(filter.check(&tick("20250103", "21:00:00", 0, 0)), TickStatus::Valid);
+        assert_eq!(filter.check(&tick("20250103", "21:00:00", 500, 30)), TickStatus::Valid);
+        assert_eq!(filter.check(&tick("20250103", "21:00:01", 0, 60)), TickStatus::Valid);
+        // a late tick of the previous day
+        assert_eq!(filter.check(&tick("20250102", "15:00:00", 0, 250_010)), TickStatus::Stale);
+    }
+
+    #[test]
+    fn corrupt_and_out_of_session() {
+        let config = FilterConfig {
+            sessions: vec!["09:00:00-15:00:00".into()],
+            check_limits: true,
+            ..Default::default()
+        };
+        let mut filter = TickFilter::new(&config).unwrap();
+        assert_eq!(filter.check(&tick("20250102", "15:30:00", 0, 1)), TickStatus::OutOfSession);
+        let mut t = tick("20250102", "10:00:00", 0, 1);
+        t.UpperLimitPrice = 3400.0;
+        assert_eq!(filter.check(&t), TickStatus::Corrupt);
+        assert_eq!(filter.check(&tick("20250102", "bad", 0, 1)), TickStatus::Corrupt);
+    }
+}
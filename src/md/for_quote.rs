@@ -0,0 +1,191 @@
+//! For-quote (询价) notices for option market makers.
+//!
+//! Notices arrive through `on_rtn_for_quote_rsp` of either the md or the trader
+//! spi, feed them to a [`ForQuoteHub`] and consume them per instrument.
+
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::*;
+
+/// Notices a receiver may fall behind by, the newer ones are dropped beyond.
+pub const FOR_QUOTE_QUEUE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForQuote {
+    pub trading_day: String,
+    pub action_day: String,
+    pub instrument_id: String,
+    pub exchange_id: String,
+    /// Quote with this `ForQuoteSysID` to answer the request.
+    pub for_quote_sys_id: String,
+    pub for_quote_time: String,
+    /// Local time the notice was received.
+    pub received: Instant,
+}
+
+impl ForQuote {
+    pub fn new(field: &CThostFtdcForQuoteRspField, received: Instant) -> Self {
+        Self {
+            trading_day: to_string(&field.TradingDay),
+            action_day: to_string(&field.ActionDay),
+            instrument_id: to_string(&field.InstrumentID),
+            exchange_id: to_string(&field.ExchangeID),
+            for_quote_sys_id: to_string(&field.ForQuoteSysID),
+            for_quote_time: to_string(&field.ForQuoteTime),
+            received,
+        }
+    }
+
+    /// Time since the notice was received.
+    pub fn age(&self) -> Duration {
+        self.received.elapsed()
+    }
+
+    /// Time left to answer within `deadline`, `None` once it has passed.
+    pub fn remaining(&self, deadline: Duration) -> Option<Duration> {
+        deadline.checked_sub(self.age())
+    }
+}
+
+#[derive(Default)]
+struct Subscribers {
+    instruments: HashMap<String, Vec<Sender<ForQuote>>>,
+    all: Vec<Sender<ForQuote>>,
+}
+
+/// Fans for-quote notices out to per instrument receivers, cheap to clone.
+///
+/// Sending never blocks the api thread: a receiver holding
+/// [`FOR_QUOTE_QUEUE`] notices misses the next ones, counted by
+/// [`ForQuoteHub::dropped`]. Receivers dropped by the consumer are removed on
+/// the next notice.
+#[derive(Clone, Default)]
+pub struct ForQuoteHub {
+    subscribers: Arc<Mutex<Subscribers>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ForQuoteHub {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Notices of `instrument` only.
+    pub fn subscribe(&self, instrument: &str) -> Receiver<ForQuote> {
+        let (tx, rx) = channel::bounded(FOR_QUOTE_QUEUE);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.instruments.entry(instrument.to_string()).or_default().push(tx);
+        rx
+    }
+
+    /// Notices of every instrument.
+    pub fn subscribe_all(&self) -> Receiver<ForQuote> {
+        let (tx, rx) = channel::bounded(FOR_QUOTE_QUEUE);
+        self.subscribers.lock().unwrap().all.push(tx);
+        rx
+    }
+
+    /// Instruments subscribed on the hub, to pass to `subscribe_for_quote_rsp`.
+    pub fn instruments(&self) -> Vec<String> {
+        let subscribers = self.subscribers.lock().unwrap();
+        subscribers.instruments.keys().cloned().collect()
+    }
+
+    /// Notices dropped for receivers that fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Call from `on_rtn_for_quote_rsp`.
+    pub fn on_rtn_for_quote_rsp(&self, field: &CThostFtdcForQuoteRspField) {
+        let notice = ForQuote::new(field, Instant::now());
+        let send = |tx: &Sender<ForQuote>| match tx.try_send(notice.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    warn!("{} for-quote notices dropped, a receiver is {} behind", dropped, FOR_QUOTE_QUEUE);
+                }
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        };
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(senders) = subscribers.instruments.get_mut(&notice.instrument_id) {
+            senders.retain(send);
+            if senders.is_empty() {
+                subscribers.instruments.remove(&notice.instrument_id);
+            }
+        }
+        subscribers.all.retain(send);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(instrument: &str, sys_id: &str) -> CThostFtdcForQuoteRspField {
+        let mut field: CThostFtdcForQuoteRspField = zeroed();
+        set_string(&mut field.InstrumentID, instrument);
+        set_string(&mut field.ExchangeID, "CFFEX");
+        set_string(&mut field.ForQuoteSysID, sys_id);
+        field
+    }
+
+    #[test]
+    fn notices_go_to_their_instrument() {
+        let hub = ForQuoteHub::new();
+        let io = hub.subscribe("IO2501-C-4000");
+        let ho = hub.subscribe("HO2501-C-2600");
+        let all = hub.subscribe_all();
+        let mut instruments = hub.instruments();
+        instruments.sort();
+        assert_eq!(instruments, ["HO2501-C-2600", "IO2501-C-4000"]);
+
+        hub.on_rtn_for_quote_rsp(&notice("IO2501-C-4000", "1"));
+        hub.on_rtn_for_quote_rsp(&notice("MO2501-C-5800", "2"));
+        assert_eq!(io.try_iter().map(|n| n.for_quote_sys_id).collect::<Vec<_>>(), ["1"]);
+        assert!(ho.try_recv().is_err());
+        assert_eq!(all.try_iter().map(|n| n.instrument_id).collect::<Vec<_>>(), ["IO2501-C-4000", "MO2501-C-5800"]);
+
+        let quote = ForQuote::new(&notice("IO2501-C-4000", "3"), Instant::now());
+        assert!(quote.remaining(Duration::from_secs(60)).is_some());
+        assert_eq!(quote.remaining(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn dropped_receivers_are_removed() {
+        let hub = ForQuoteHub::new();
+        drop(hub.subscribe("IO2501-C-4000"));
+        drop(hub.subscribe_all());
+        hub.on_rtn_for_quote_rsp(&notice("IO2501-C-4000", "1"));
+        assert!(hub.instruments().is_empty());
+        assert!(hub.subscribers.lock().unwrap().all.is_empty());
+        assert_eq!(hub.dropped(), 0);
+    }
+
+    #[test]
+    fn a_full_receiver_misses_the_newer_notices() {
+        let hub = ForQuoteHub::new();
+        let slow = hub.subscribe("IO2501-C-4000");
+        for n in 0..FOR_QUOTE_QUEUE + 2 {
+            hub.on_rtn_for_quote_rsp(&notice("IO2501-C-4000", &n.to_string()));
+        }
+        assert_eq!(hub.dropped(), 2);
+        assert_eq!(slow.len(), FOR_QUOTE_QUEUE);
+        assert_eq!(slow.recv().unwrap().for_quote_sys_id, "0");
+
+        // it stays subscribed and gets notices again once it catches up
+        hub.on_rtn_for_quote_rsp(&notice("IO2501-C-4000", "next"));
+        assert_eq!(slow.try_iter().last().unwrap().for_quote_sys_id, "next");
+    }
+}
@@ -0,0 +1,133 @@
+//! Instrument mapping of exchange multicast market data.
+//!
+//! In multicast mode each topic carries its instruments by `InstrumentNo`, the
+//! mapping is returned by `ReqQryMulticastInstrument`.
+
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::sync::{Arc, RwLock};
+
+use log::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MulticastInstrument {
+    pub topic_id: i32,
+    pub instrument_no: i32,
+    pub instrument_id: String,
+    pub code_price: f64,
+    pub volume_multiple: i32,
+    pub price_tick: f64,
+}
+
+impl From<&CThostFtdcMulticastInstrumentField> for MulticastInstrument {
+    fn from(f: &CThostFtdcMulticastInstrumentField) -> Self {
+        Self {
+            topic_id: f.TopicID,
+            instrument_no: f.InstrumentNo,
+            instrument_id: to_string(&f.InstrumentID),
+            code_price: f.CodePrice,
+            volume_multiple: f.VolumeMultiple,
+            price_tick: f.PriceTick,
+        }
+    }
+}
+
+/// Lookup in both directions between `(TopicID, InstrumentNo)` and `InstrumentID`.
+#[derive(Debug, Clone, Default)]
+pub struct MulticastInstruments {
+    by_no: HashMap<(i32, i32), MulticastInstrument>,
+    by_id: HashMap<String, (i32, i32)>,
+}
+
+impl MulticastInstruments {
+    pub fn insert(&mut self, instrument: MulticastInstrument) {
+        let key = (instrument.topic_id, instrument.instrument_no);
+        if let Some(old) = self.by_no.get(&key) {
+            if old.instrument_id != instrument.instrument_id {
+                warn!(
+                    "multicast topic {} no {} remapped from {} to {}",
+                    key.0, key.1, old.instrument_id, instrument.instrument_id
+                );
+                self.by_id.remove(&old.instrument_id);
+            }
+        }
+        // the instrument moved to another number, drop the old one
+        if let Some(old_key) = self.by_id.get(&instrument.instrument_id).filter(|old_key| **old_key != key) {
+            self.by_no.remove(old_key);
+        }
+        self.by_id.insert(instrument.instrument_id.clone(), key);
+        self.by_no.insert(key, instrument);
+    }
+
+    pub fn get_by_no(&self, topic_id: i32, instrument_no: i32) -> Option<&MulticastInstrument> {
+        self.by_no.get(&(topic_id, instrument_no))
+    }
+
+    pub fn get_by_id(&self, instrument_id: &str) -> Option<&MulticastInstrument> {
+        self.by_id.get(instrument_id).and_then(|key| self.by_no.get(key))
+    }
+
+    /// Instruments carried by `topic_id`.
+    pub fn topic(&self, topic_id: i32) -> impl Iterator<Item = &MulticastInstrument> {
+        self.by_no.values().filter(move |i| i.topic_id == topic_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_no.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_no.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.by_no.clear();
+        self.by_id.clear();
+    }
+}
+
+/// Spi adapter filling a shared [`MulticastInstruments`] from
+/// `on_rsp_qry_multicast_instrument`, every callback is forwarded to the inner spi.
+pub struct MulticastSpi<S> {
+    inner: S,
+    instruments: Arc<RwLock<MulticastInstruments>>,
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait> MulticastSpi<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            instruments: Default::default(),
+        }
+    }
+
+    /// The mapping, shared with the api thread.
+    pub fn instruments(&self) -> Arc<RwLock<MulticastInstruments>> {
+        self.instruments.clone()
+    }
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for MulticastSpi<S> {
+    forward_md_spi!(inner:
+        on_front_connected, on_front_disconnected, on_heart_beat_warning,
+        on_rsp_user_login, on_rsp_user_logout, on_rsp_error,
+        on_rsp_sub_market_data, on_rsp_un_sub_market_data,
+        on_rsp_sub_for_quote_rsp, on_rsp_un_sub_for_quote_rsp,
+        on_rtn_depth_market_data, on_rtn_for_quote_rsp,
+    );
+
+    fn on_rsp_qry_multicast_instrument(&mut self, pMulticastInstrument: *mut CThostFtdcMulticastInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        let failed = unsafe { pRspInfo.as_ref() }.is_some_and(|info| info.ErrorID != 0);
+        if failed {
+            let info = unsafe { &*pRspInfo };
+            error!("multicast instrument query failed: {} {}", info.ErrorID, gbk_string(&info.ErrorMsg));
+        } else if let Some(field) = unsafe { pMulticastInstrument.as_ref() } {
+            self.instruments.write().unwrap().insert(field.into());
+        }
+        self.inner.on_rsp_qry_multicast_instrument(pMulticastInstrument, pRspInfo, nRequestID, bIsLast)
+    }
+}
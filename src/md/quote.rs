@@ -0,0 +1,265 @@
+//! The latest tick of every instrument, updated by the md callback and read
+//! from any thread without locks.
+//!
+//! Before md is connected the store can be seeded from the trader api's
+//! `ReqQryDepthMarketData`, a seeded quote gives way to the first md tick.
+
+use super::depth::DepthSnapshot;
+use super::rcu::Rcu;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Where a quote came from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    Md,
+    /// `OnRspQryDepthMarketData` of the trader api.
+    Query,
+}
+
+/// A consistent copy of the latest tick of one instrument.
+#[derive(Debug, Clone)]
+pub struct Quote {
+    pub tick: CThostFtdcDepthMarketDataField,
+    pub source: Source,
+    /// When the store received the tick.
+    pub received: Instant,
+}
+
+impl Quote {
+    pub fn instrument_id(&self) -> String {
+        to_string(&self.tick.InstrumentID)
+    }
+
+    pub fn age(&self) -> Duration {
+        self.received.elapsed()
+    }
+
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.age() > max_age
+    }
+
+    pub fn last_price(&self) -> Option<f64> {
+        valid_price(self.tick.LastPrice)
+    }
+
+    pub fn bid_price(&self) -> Option<f64> {
+        valid_price(self.tick.BidPrice1).filter(|_| self.tick.BidVolume1 > 0)
+    }
+
+    pub fn ask_price(&self) -> Option<f64> {
+        valid_price(self.tick.AskPrice1).filter(|_| self.tick.AskVolume1 > 0)
+    }
+
+    pub fn upper_limit_price(&self) -> Option<f64> {
+        valid_price(self.tick.UpperLimitPrice)
+    }
+
+    pub fn lower_limit_price(&self) -> Option<f64> {
+        valid_price(self.tick.LowerLimitPrice)
+    }
+
+    pub fn depth(&self) -> DepthSnapshot {
+        DepthSnapshot::from(&self.tick)
+    }
+}
+
+type Entry = Arc<Rcu<Quote>>;
+
+/// Latest quote per instrument, clones share the same store.
+#[derive(Clone)]
+pub struct QuoteStore {
+    entries: Arc<Rcu<HashMap<String, Entry>>>,
+}
+
+impl Default for QuoteStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QuoteStore {
+    pub fn new() -> Self {
+        Self {
+            entries: Arc::new(Rcu::new(HashMap::new())),
+        }
+    }
+
+    /// Record a md tick, it always replaces the previous quote.
+    pub fn on_tick(&self, tick: &CThostFtdcDepthMarketDataField) {
+        self.store(tick, Source::Md)
+    }
+
+    /// Record a queried tick, ignored once md has delivered the instrument.
+    pub fn seed(&self, tick: &CThostFtdcDepthMarketDataField) {
+        self.store(tick, Source::Query)
+    }
+
+    pub fn get(&self, instrument: &str) -> Option<Quote> {
+        let entry = self.entries.read(|entries| entries.get(instrument).cloned())?;
+        Some(entry.read(|quote| quote.clone()))
+    }
+
+    pub fn instruments(&self) -> Vec<String> {
+        self.entries.read(|entries| entries.keys().cloned().collect())
+    }
+
+    /// Instruments whose quote is older than `max_age`.
+    pub fn stale(&self, max_age: Duration) -> Vec<String> {
+        self.entries.read(|entries| {
+            entries
+                .iter()
+                .filter(|(_, entry)| entry.read(|quote| quote.is_stale(max_age)))
+                .map(|(instrument, _)| instrument.clone())
+                .collect()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read(|entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn store(&self, tick: &CThostFtdcDepthMarketDataField, source: Source) {
+        let instrument = to_string(&tick.InstrumentID);
+        if instrument.is_empty() {
+            return;
+        }
+        let quote = Quote {
+            tick: *tick,
+            source,
+            received: Instant::now(),
+        };
+
+        // a seeded quote never replaces one from md
+        let replace = |current: &Quote| !(source == Source::Query && current.source == Source::Md);
+
+        let entry = self.entries.read(|entries| entries.get(&instrument).cloned());
+        match entry {
+            Some(entry) => entry.update(|current| replace(current).then_some(quote)),
+            // a new instrument copies the map, rare after the first ticks
+            None => self.entries.update(|entries| {
+                if let Some(entry) = entries.get(&instrument) {
+                    // added by another writer meanwhile
+                    entry.update(|current| replace(current).then_some(quote));
+                    return None;
+                }
+                let mut entries = entries.clone();
+                entries.insert(instrument, Arc::new(Rcu::new(quote)));
+                Some(entries)
+            }),
+        }
+    }
+}
+
+/// Records every tick into a [`QuoteStore`], forwards every callback to `inner`.
+pub struct QuoteSpi<S> {
+    inner: S,
+    store: QuoteStore,
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait> QuoteSpi<S> {
+    pub fn new(inner: S, store: QuoteStore) -> Self {
+        Self { inner, store }
+    }
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for QuoteSpi<S> {
+    forward_md_spi!(inner:
+        on_front_connected, on_front_disconnected, on_heart_beat_warning,
+        on_rsp_user_login, on_rsp_user_logout, on_rsp_qry_multicast_instrument, on_rsp_error,
+        on_rsp_sub_market_data, on_rsp_un_sub_market_data,
+        on_rsp_sub_for_quote_rsp, on_rsp_un_sub_for_quote_rsp,
+        on_rtn_for_quote_rsp,
+    );
+
+    fn on_rtn_depth_market_data(&mut self, pDepthMarketData: *mut CThostFtdcDepthMarketDataField) {
+        if let Some(tick) = unsafe { pDepthMarketData.as_ref() } {
+            self.store.on_tick(tick);
+        }
+        self.inner.on_rtn_depth_market_data(pDepthMarketData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(instrument: &str, last: f64) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.InstrumentID, instrument);
+        tick.LastPrice = last;
+        tick.BidPrice1 = last - 1.0;
+        tick.BidVolume1 = 2;
+        tick.AskPrice1 = last + 1.0;
+        tick.AskVolume1 = 0;
+        tick.UpperLimitPrice = f64::MAX;
+        tick
+    }
+
+    #[test]
+    fn latest_tick_per_instrument() {
+        let store = QuoteStore::new();
+        assert!(store.is_empty());
+        store.on_tick(&tick("rb2501", 3500.0));
+        store.on_tick(&tick("hc2501", 3600.0));
+        store.on_tick(&tick("rb2501", 3501.0));
+        store.on_tick(&tick("", 1.0));
+        assert_eq!(store.len(), 2);
+
+        let quote = store.get("rb2501").unwrap();
+        assert_eq!(quote.instrument_id(), "rb2501");
+        assert_eq!((quote.last_price(), quote.bid_price()), (Some(3501.0), Some(3500.0)));
+        // no volume or DBL_MAX, no price
+        assert_eq!((quote.ask_price(), quote.upper_limit_price()), (None, None));
+        assert!(store.get("i2501").is_none());
+    }
+
+    #[test]
+    fn seeded_quotes_give_way_to_md() {
+        let store = QuoteStore::new();
+        store.seed(&tick("rb2501", 3500.0));
+        store.seed(&tick("rb2501", 3502.0));
+        assert_eq!(store.get("rb2501").unwrap().source, Source::Query);
+        store.on_tick(&tick("rb2501", 3501.0));
+        store.seed(&tick("rb2501", 3499.0));
+        let quote = store.get("rb2501").unwrap();
+        assert_eq!((quote.source, quote.last_price()), (Source::Md, Some(3501.0)));
+    }
+
+    #[test]
+    fn stale_quotes() {
+        let store = QuoteStore::new();
+        store.on_tick(&tick("rb2501", 3500.0));
+        assert!(store.stale(Duration::from_secs(60)).is_empty());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(store.stale(Duration::ZERO), ["rb2501"]);
+    }
+
+    #[derive(Default)]
+    struct Count(usize);
+
+    impl Rust_CThostFtdcMdSpi_Trait for Count {
+        fn on_rtn_depth_market_data(&mut self, _: *mut CThostFtdcDepthMarketDataField) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn spi_records_and_forwards() {
+        let store = QuoteStore::new();
+        let mut spi = QuoteSpi::new(Count::default(), store.clone());
+        spi.on_rtn_depth_market_data(&mut tick("rb2501", 3500.0));
+        spi.on_rtn_depth_market_data(std::ptr::null_mut());
+        assert_eq!(spi.inner.0, 2);
+        assert_eq!(store.get("rb2501").unwrap().last_price(), Some(3500.0));
+    }
+}
@@ -0,0 +1,118 @@
+//! A value read without locks and replaced as a whole by writers, the old
+//! value is freed once no reader can still see it.
+
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crossbeam::epoch::{self, Atomic, Owned};
+
+pub(crate) struct Rcu<T> {
+    current: Atomic<T>,
+    writer: Mutex<()>,
+}
+
+impl<T: Send + Sync> Rcu<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: Atomic::new(value),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let guard = epoch::pin();
+        // never null, replaced only through `update` which defers the free
+        f(unsafe { self.current.load(Ordering::Acquire, &guard).deref() })
+    }
+
+    /// Replace the value with the one `f` derives from it, `None` keeps it.
+    /// Writers are serialized, readers are never held up.
+    pub fn update(&self, f: impl FnOnce(&T) -> Option<T>) {
+        let _writer = self.writer.lock().unwrap();
+        let guard = epoch::pin();
+        let current = unsafe { self.current.load(Ordering::Acquire, &guard).deref() };
+        if let Some(value) = f(current) {
+            let old = self.current.swap(Owned::new(value), Ordering::AcqRel, &guard);
+            unsafe { guard.defer_destroy(old) };
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        unsafe { drop(self.current.load(Ordering::Relaxed, epoch::unprotected()).into_owned()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    #[test]
+    fn update_replaces_the_whole_value() {
+        let rcu = Rcu::new(vec![1, 2]);
+        rcu.update(|v| Some(v.iter().map(|n| n * 10).collect()));
+        assert_eq!(rcu.read(|v| v.clone()), [10, 20]);
+        rcu.update(|_| None);
+        assert_eq!(rcu.read(|v| v.len()), 2);
+    }
+
+    #[test]
+    fn readers_see_consistent_snapshots() {
+        let rcu = Arc::new(Rcu::new((0u64, 0u64)));
+        let writer = {
+            let rcu = rcu.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10_000 {
+                    rcu.update(|(n, double)| Some((n + 1, double + 2)));
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let rcu = rcu.clone();
+                std::thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..10_000 {
+                        let (n, double) = rcu.read(|pair| *pair);
+                        assert_eq!(double, n * 2);
+                        assert!(n >= last);
+                        last = n;
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        readers.into_iter().for_each(|r| r.join().unwrap());
+        assert_eq!(rcu.read(|pair| *pair), (10_000, 20_000));
+    }
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn every_value_is_freed() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let rcu = Rcu::new(Counted(dropped.clone()));
+        for _ in 0..100 {
+            rcu.update(|_| Some(Counted(dropped.clone())));
+        }
+        drop(rcu);
+        // replaced values are freed once the epoch moves on
+        for _ in 0..100_000 {
+            if dropped.load(Ordering::Relaxed) == 101 {
+                break;
+            }
+            epoch::pin().flush();
+            std::thread::yield_now();
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 101);
+    }
+}
@@ -0,0 +1,419 @@
+//! Replay recorded ticks through `Rust_CThostFtdcMdSpi_Trait`.
+//!
+//! Ticks are stored as CSV, the first line names the `CThostFtdcDepthMarketDataField`
+//! columns, see [`Recorder`]. Unknown columns are rejected, missing columns stay zero.
+
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+macro_rules! depth_fields {
+    ($($kind:ident $name:ident),* $(,)?) => {
+        /// CSV columns, in the order written by [`Recorder`].
+        pub const COLUMNS: &[&str] = &[$(stringify!($name)),*];
+
+        fn format_tick(tick: &CThostFtdcDepthMarketDataField) -> String {
+            let columns: Vec<String> = vec![$(depth_fields!(@format $kind tick.$name)),*];
+            columns.join(",")
+        }
+
+        fn set_field(tick: &mut CThostFtdcDepthMarketDataField, name: &str, value: &str) -> Result<(), String> {
+            match name {
+                $(stringify!($name) => depth_fields!(@parse $kind tick.$name, name, value),)*
+                _ => return Err(format!("unknown column `{}`", name)),
+            }
+            Ok(())
+        }
+    };
+    (@format str $e:expr) => { to_string(&$e) };
+    (@format num $e:expr) => { format!("{:?}", $e) };
+    (@parse str $e:expr, $name:expr, $value:expr) => { set_string(&mut $e, $value) };
+    (@parse num $e:expr, $name:expr, $value:expr) => {
+        $e = if $value.is_empty() {
+            Default::default()
+        } else {
+            $value.parse().map_err(|_| format!("invalid `{}`: {}", $name, $value))?
+        }
+    };
+}
+
+depth_fields! {
+    str TradingDay, str ActionDay, str UpdateTime, num UpdateMillisec,
+    str InstrumentID, str ExchangeID, str ExchangeInstID,
+    num LastPrice, num PreSettlementPrice, num PreClosePrice, num PreOpenInterest,
+    num OpenPrice, num HighestPrice, num LowestPrice, num Volume, num Turnover,
+    num OpenInterest, num ClosePrice, num SettlementPrice,
+    num UpperLimitPrice, num LowerLimitPrice, num PreDelta, num CurrDelta,
+    num BidPrice1, num BidVolume1, num AskPrice1, num AskVolume1,
+    num BidPrice2, num BidVolume2, num AskPrice2, num AskVolume2,
+    num BidPrice3, num BidVolume3, num AskPrice3, num AskVolume3,
+    num BidPrice4, num BidVolume4, num AskPrice4, num AskVolume4,
+    num BidPrice5, num BidVolume5, num AskPrice5, num AskVolume5,
+    num AveragePrice, num BandingUpperPrice, num BandingLowerPrice,
+}
+
+/// Exchange time of a tick in milliseconds, `ActionDay` falls back to `TradingDay`.
+pub fn tick_timestamp(tick: &CThostFtdcDepthMarketDataField) -> Option<i64> {
+    let mut day = to_string(&tick.ActionDay);
+    if day.is_empty() {
+        day = to_string(&tick.TradingDay);
+    }
+    parse_timestamp(&day, &to_string(&tick.UpdateTime), tick.UpdateMillisec)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Speed {
+    /// Keep the recorded gaps between ticks.
+    Realtime,
+    /// Recorded gaps divided by the factor, e.g. `Multiple(10.0)` runs ten times
+    /// faster. A factor not above zero is refused by [`Replay::run`].
+    Multiple(f64),
+    /// No sleeping at all.
+    #[default]
+    Unlimited,
+}
+
+/// Feeds recorded ticks to a spi, so strategy code can run against history
+/// without loading `thostmduserapi_se`.
+///
+/// ```no_run
+/// # use ctp_rs::md::replay::*;
+/// # use ctp_rs::sys::Rust_CThostFtdcMdSpi_Trait;
+/// # fn run(spi: &mut dyn Rust_CThostFtdcMdSpi_Trait) -> Result<(), String> {
+/// Replay::new(&["ticks/20221121.csv"])
+///     .speed(Speed::Multiple(10.0))
+///     .instruments(&["rb2305"])
+///     .time_range("20221121 09:00:00", "20221121 11:30:00")?
+///     .run(spi)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    files: Vec<PathBuf>,
+    speed: Speed,
+    instruments: HashSet<String>,
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+impl Replay {
+    pub fn new<P: AsRef<Path>>(files: &[P]) -> Self {
+        Self {
+            files: files.iter().map(|f| f.as_ref().to_path_buf()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Only replay these instruments, all instruments are replayed by default.
+    pub fn instruments(mut self, codes: &[&str]) -> Self {
+        self.instruments = codes.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Only replay ticks in `[from, to]`, both in `YYYYMMDD HH:MM:SS` format.
+    pub fn time_range(mut self, from: &str, to: &str) -> Result<Self, String> {
+        self.from = Some(parse_datetime(from)?);
+        self.to = Some(parse_datetime(to)?);
+        Ok(self)
+    }
+
+    /// Load the filtered ticks of all files, ordered by exchange time.
+    pub fn load(&self) -> Result<Vec<CThostFtdcDepthMarketDataField>, String> {
+        let mut ticks = Vec::new();
+        for path in &self.files {
+            for tick in read_ticks(path)? {
+                let ts = match tick_timestamp(&tick) {
+                    Some(ts) => ts,
+                    None => {
+                        warn!("skip tick without valid time: {}", to_string(&tick.InstrumentID));
+                        continue;
+                    }
+                };
+                if !self.accept(&tick, ts) {
+                    continue;
+                }
+                ticks.push((ts, tick));
+            }
+        }
+        // stable sort keeps the recorded order of ticks sharing a timestamp
+        ticks.sort_by_key(|(ts, _)| *ts);
+        Ok(ticks.into_iter().map(|(_, tick)| tick).collect())
+    }
+
+    /// Replay into `spi`: `on_front_connected`, `on_rsp_user_login`, the ticks,
+    /// then `on_front_disconnected(0)`. Returns the number of ticks delivered.
+    pub fn run(&self, spi: &mut dyn Rust_CThostFtdcMdSpi_Trait) -> Result<usize, String> {
+        if let Speed::Multiple(factor) = self.speed {
+            if !(factor > 0.0 && factor.is_finite()) {
+                return Err(format!("invalid replay speed: {}", factor));
+            }
+        }
+        let ticks = self.load()?;
+        debug!("replay {} ticks from {} files", ticks.len(), self.files.len());
+
+        spi.on_front_connected();
+        let mut login: CThostFtdcRspUserLoginField = zeroed();
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        if let Some(tick) = ticks.first() {
+            login.TradingDay = tick.TradingDay;
+        }
+        spi.on_rsp_user_login(&mut login, &mut info, 0, true);
+
+        let started = Instant::now();
+        let first = ticks.first().and_then(tick_timestamp).unwrap_or_default();
+        for tick in &ticks {
+            let elapsed = (tick_timestamp(tick).unwrap_or(first) - first).max(0) as f64;
+            let due = match self.speed {
+                Speed::Realtime => Some(elapsed),
+                Speed::Multiple(factor) => Some(elapsed / factor),
+                Speed::Unlimited => None,
+            };
+            if let Some(due) = due {
+                let due = Duration::from_micros((due * 1000.0) as u64);
+                let now = started.elapsed();
+                if due > now {
+                    std::thread::sleep(due - now);
+                }
+            }
+            let mut tick = *tick;
+            spi.on_rtn_depth_market_data(&mut tick);
+        }
+
+        spi.on_front_disconnected(0);
+        Ok(ticks.len())
+    }
+
+    fn accept(&self, tick: &CThostFtdcDepthMarketDataField, ts: i64) -> bool {
+        if !self.instruments.is_empty() && !self.instruments.contains(&to_string(&tick.InstrumentID)) {
+            return false;
+        }
+        if self.from.is_some_and(|from| ts < from) || self.to.is_some_and(|to| ts > to) {
+            return false;
+        }
+        true
+    }
+}
+
+fn parse_datetime(s: &str) -> Result<i64, String> {
+    let mut it = s.split_whitespace();
+    let (date, time) = (it.next().unwrap_or_default(), it.next().unwrap_or("00:00:00"));
+    parse_timestamp(date, time, 0).ok_or_else(|| format!("invalid datetime: {}", s))
+}
+
+/// Read every tick of a recorded CSV file, unfiltered and in file order.
+pub fn read_ticks<P: AsRef<Path>>(path: P) -> Result<Vec<CThostFtdcDepthMarketDataField>, String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("Fail to open {}: {}", path.display(), e))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header = match lines.next() {
+        Some(line) => line.map_err(|e| e.to_string())?,
+        None => return Ok(vec![]),
+    };
+    let columns: Vec<String> = header.split(',').map(|s| s.trim().to_string()).collect();
+
+    let mut ticks = Vec::new();
+    for (no, line) in lines.enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        for (name, value) in columns.iter().zip(line.split(',')) {
+            set_field(&mut tick, name, value.trim())
+                .map_err(|e| format!("{}:{}: {}", path.display(), no + 2, e))?;
+        }
+        ticks.push(tick);
+    }
+
+    Ok(ticks)
+}
+
+/// Writes ticks in the format read by [`Replay`]; as a spi it records every
+/// `on_rtn_depth_market_data`.
+pub struct Recorder<W: Write> {
+    writer: W,
+}
+
+impl Recorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| format!("Fail to create {}: {}", path.display(), e))?;
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut writer: W) -> Result<Self, String> {
+        writeln!(writer, "{}", COLUMNS.join(",")).map_err(|e| e.to_string())?;
+        Ok(Self { writer })
+    }
+
+    pub fn record(&mut self, tick: &CThostFtdcDepthMarketDataField) -> Result<(), String> {
+        writeln!(self.writer, "{}", format_tick(tick)).map_err(|e| e.to_string())
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+impl<W: Write> Rust_CThostFtdcMdSpi_Trait for Recorder<W> {
+    fn on_rtn_depth_market_data(&mut self, tick: *mut CThostFtdcDepthMarketDataField) {
+        if let Some(tick) = unsafe { tick.as_ref() } {
+            if let Err(e) = self.record(tick) {
+                error!("fail to record tick: {}", e);
+            }
+        }
+    }
+
+    fn on_front_disconnected(&mut self, _reason: ::std::os::raw::c_int) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(instrument: &str, time: &str, millisec: i32, last: f64) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.TradingDay, "20221121");
+        set_string(&mut tick.ActionDay, "20221121");
+        set_string(&mut tick.UpdateTime, time);
+        tick.UpdateMillisec = millisec;
+        set_string(&mut tick.InstrumentID, instrument);
+        set_string(&mut tick.ExchangeID, "SHFE");
+        tick.LastPrice = last;
+        tick.Volume = 12;
+        tick.BidPrice1 = last - 1.0;
+        tick.BidVolume1 = 3;
+        tick.AskPrice1 = f64::MAX;
+        tick
+    }
+
+    fn record(name: &str, ticks: &[CThostFtdcDepthMarketDataField]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ctp-replay-{}-{}.csv", name, std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+        for tick in ticks {
+            recorder.record(tick).unwrap();
+        }
+        recorder.flush().unwrap();
+        path
+    }
+
+    #[derive(Default)]
+    struct Collect {
+        events: Vec<String>,
+    }
+
+    impl Rust_CThostFtdcMdSpi_Trait for Collect {
+        fn on_front_connected(&mut self) {
+            self.events.push("connected".to_string());
+        }
+
+        fn on_rsp_user_login(
+            &mut self,
+            login: *mut CThostFtdcRspUserLoginField,
+            _: *mut CThostFtdcRspInfoField,
+            _: ::std::os::raw::c_int,
+            _: bool,
+        ) {
+            let login = unsafe { &*login };
+            self.events.push(format!("login {}", to_string(&login.TradingDay)));
+        }
+
+        fn on_rtn_depth_market_data(&mut self, tick: *mut CThostFtdcDepthMarketDataField) {
+            let tick = unsafe { &*tick };
+            self.events.push(format!("{} {}.{}", to_string(&tick.InstrumentID), to_string(&tick.UpdateTime), tick.UpdateMillisec));
+        }
+
+        fn on_front_disconnected(&mut self, _: ::std::os::raw::c_int) {
+            self.events.push("disconnected".to_string());
+        }
+    }
+
+    #[test]
+    fn recorded_ticks_read_back() {
+        let ticks = [tick("rb2305", "09:00:00", 0, 3700.0), tick("hc2305", "09:00:00", 500, 3800.5)];
+        let path = record("round-trip", &ticks);
+        let read = read_ticks(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read.len(), 2);
+        for (read, tick) in read.iter().zip(&ticks) {
+            assert_eq!(format_tick(read), format_tick(tick));
+        }
+        assert_eq!(read[1].AskPrice1, f64::MAX);
+    }
+
+    #[test]
+    fn unknown_columns_are_rejected() {
+        let path = std::env::temp_dir().join(format!("ctp-replay-columns-{}.csv", std::process::id()));
+        std::fs::write(&path, "InstrumentID,Foo\nrb2305,1\n").unwrap();
+        let err = read_ticks(&path).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert!(err.ends_with(":2: unknown column `Foo`"), "{}", err);
+    }
+
+    #[test]
+    fn replays_in_exchange_time_order() {
+        let first = record("first", &[tick("rb2305", "09:00:01", 0, 3700.0), tick("rb2305", "09:00:03", 0, 3701.0)]);
+        let second = record(
+            "second",
+            &[
+                tick("hc2305", "09:00:01", 0, 3800.0),
+                tick("hc2305", "09:00:02", 500, 3801.0),
+                tick("hc2305", "08:59:59", 0, 3799.0),
+            ],
+        );
+        let replay = Replay::new(&[&first, &second]);
+
+        let mut spi = Collect::default();
+        assert_eq!(replay.run(&mut spi), Ok(5));
+        assert_eq!(
+            spi.events,
+            [
+                "connected",
+                "login 20221121",
+                "hc2305 08:59:59.0",
+                // same time, file order
+                "rb2305 09:00:01.0",
+                "hc2305 09:00:01.0",
+                "hc2305 09:00:02.500",
+                "rb2305 09:00:03.0",
+                "disconnected",
+            ]
+        );
+
+        let filtered = replay
+            .clone()
+            .instruments(&["hc2305"])
+            .time_range("20221121 09:00:00", "20221121 09:00:02")
+            .unwrap()
+            .load()
+            .unwrap();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].LastPrice, 3800.0);
+
+        for factor in [0.0, -1.0, f64::NAN] {
+            let err = replay.clone().speed(Speed::Multiple(factor)).run(&mut Collect::default());
+            assert!(err.is_err());
+        }
+        assert_eq!(replay.speed(Speed::Multiple(1e6)).run(&mut Collect::default()), Ok(5));
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&second);
+    }
+}
@@ -0,0 +1,235 @@
+//! A self driving `MdApi`: tracks connection health, logs in after every
+//! connect and restores subscriptions after every login.
+
+use super::{Config, MdApi};
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{BTreeSet, VecDeque};
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+#[derive(Debug, Default)]
+struct Health {
+    connected: bool,
+    logged_in: bool,
+    login_failed: bool,
+    warnings: VecDeque<Instant>,
+    disconnects: VecDeque<Instant>,
+}
+
+impl Health {
+    fn expire(&mut self, window: Duration) {
+        let now = Instant::now();
+        for events in [&mut self.warnings, &mut self.disconnects] {
+            while events.front().is_some_and(|t| now.duration_since(*t) > window) {
+                events.pop_front();
+            }
+        }
+    }
+}
+
+/// Records connection health, forwards every callback.
+struct HealthSpi<S> {
+    inner: S,
+    health: Arc<Mutex<Health>>,
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for HealthSpi<S> {
+    forward_md_spi!(inner:
+        on_rsp_user_logout, on_rsp_qry_multicast_instrument, on_rsp_error,
+        on_rsp_sub_market_data, on_rsp_un_sub_market_data,
+        on_rsp_sub_for_quote_rsp, on_rsp_un_sub_for_quote_rsp,
+        on_rtn_depth_market_data, on_rtn_for_quote_rsp,
+    );
+
+    fn on_front_connected(&mut self) {
+        {
+            let mut health = self.health.lock().unwrap();
+            health.connected = true;
+            // a new connect gets a new login
+            health.login_failed = false;
+        }
+        self.inner.on_front_connected()
+    }
+
+    fn on_front_disconnected(&mut self, nReason: c_int) {
+        {
+            let mut health = self.health.lock().unwrap();
+            health.connected = false;
+            health.logged_in = false;
+            health.disconnects.push_back(Instant::now());
+        }
+        self.inner.on_front_disconnected(nReason)
+    }
+
+    fn on_heart_beat_warning(&mut self, nTimeLapse: c_int) {
+        self.health.lock().unwrap().warnings.push_back(Instant::now());
+        self.inner.on_heart_beat_warning(nTimeLapse)
+    }
+
+    fn on_rsp_user_login(&mut self, pRspUserLogin: *mut CThostFtdcRspUserLoginField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        {
+            let mut health = self.health.lock().unwrap();
+            match unsafe { pRspInfo.as_ref() } {
+                Some(info) if info.ErrorID != 0 => {
+                    error!("md login failed: {} {}", info.ErrorID, gbk_string(&info.ErrorMsg));
+                    health.login_failed = true;
+                }
+                _ => {
+                    health.logged_in = true;
+                    health.login_failed = false;
+                }
+            }
+        }
+        self.inner.on_rsp_user_login(pRspUserLogin, pRspInfo, nRequestID, bIsLast)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum LoginState {
+    Idle,
+    Requested,
+    Subscribed,
+}
+
+/// Heartbeat warnings and disconnects seen within a window.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct HealthCount {
+    pub heartbeat_warnings: usize,
+    pub disconnects: usize,
+}
+
+pub(crate) struct Session {
+    api: MdApi,
+    health: Arc<Mutex<Health>>,
+    login: LoginState,
+}
+
+impl Session {
+    /// Create the api on `config`, register `spi` behind the health tracking and init.
+    pub fn start<S: Rust_CThostFtdcMdSpi_Trait + Send + 'static>(config: &Config, spi: S) -> Result<Self, String> {
+        let health: Arc<Mutex<Health>> = Default::default();
+        let mut api = MdApi::new(config)?;
+        api.register(HealthSpi {
+            inner: spi,
+            health: health.clone(),
+        })?;
+        api.init()?;
+        Ok(Self {
+            api,
+            health,
+            login: LoginState::Idle,
+        })
+    }
+
+    /// The front, or the name server when bound through one.
+    pub fn front(&self) -> &str {
+        let config = &self.api.config;
+        if config.nm_addr.is_empty() {
+            &config.front_addr
+        } else {
+            &config.nm_addr
+        }
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.health.lock().unwrap().logged_in
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.login == LoginState::Subscribed
+    }
+
+    pub fn health(&self, window: Duration) -> HealthCount {
+        let mut health = self.health.lock().unwrap();
+        health.expire(window);
+        HealthCount {
+            heartbeat_warnings: health.warnings.len(),
+            disconnects: health.disconnects.len(),
+        }
+    }
+
+    /// Login after connect, subscribe `subscriptions` after login.
+    pub fn drive(&mut self, subscriptions: &BTreeSet<String>) -> Result<(), String> {
+        let (connected, logged_in, login_failed) = {
+            let health = self.health.lock().unwrap();
+            (health.connected, health.logged_in, health.login_failed)
+        };
+        if login_failed {
+            // login again once the api reconnects
+            self.login = LoginState::Idle;
+            return Err(format!("md login failed on {}", self.front()));
+        }
+
+        match self.login {
+            LoginState::Idle if connected => {
+                self.api.req_user_login()?;
+                self.login = LoginState::Requested;
+            }
+            LoginState::Requested if logged_in => {
+                if !subscriptions.is_empty() {
+                    let codes: Vec<&str> = subscriptions.iter().map(|s| s.as_str()).collect();
+                    self.api.subscribe_market_data(&codes)?;
+                }
+                self.login = LoginState::Subscribed;
+            }
+            LoginState::Requested | LoginState::Subscribed if !connected => {
+                // the api reconnects by itself, login again once it is back
+                self.login = LoginState::Idle;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Subscribe now when logged in, otherwise `drive` does it after login.
+    pub fn subscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        if self.is_subscribed() {
+            self.api.subscribe_market_data(codes)?;
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        if self.is_subscribed() {
+            self.api.unsubscribe_market_data(codes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Nop;
+
+    impl Rust_CThostFtdcMdSpi_Trait for Nop {}
+
+    #[test]
+    fn a_failed_login_is_cleared_by_the_next_connect() {
+        let health: Arc<Mutex<Health>> = Default::default();
+        let mut spi = HealthSpi {
+            inner: Nop,
+            health: health.clone(),
+        };
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        info.ErrorID = 3;
+        spi.on_front_connected();
+        spi.on_rsp_user_login(std::ptr::null_mut(), &mut info, 1, true);
+        assert!(health.lock().unwrap().login_failed);
+
+        spi.on_front_disconnected(0x1001);
+        spi.on_front_connected();
+        assert!(!health.lock().unwrap().login_failed);
+        spi.on_rsp_user_login(std::ptr::null_mut(), std::ptr::null_mut(), 2, true);
+        let health = health.lock().unwrap();
+        assert!(health.connected && health.logged_in);
+        assert_eq!(health.disconnects.len(), 1);
+    }
+}
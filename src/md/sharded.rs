@@ -0,0 +1,212 @@
+//! Instruments spread over several md sessions, each with its own api thread
+//! and flow path, merged into one stream.
+//!
+//! A new instrument goes to the shard with the fewest instruments, so a large
+//! universe such as every listed option does not back up a single callback thread.
+//! Ticks of every shard go through one bounded queue to a single dispatch
+//! thread, which passes them to the spi, so the shards never wait on one
+//! another. When the spi falls behind and the queue is full, new ticks are
+//! dropped and counted, see [`ShardedMdApi::dropped`].
+
+use super::session::Session;
+use super::{Config, HealthCount, SharedSpi};
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{BTreeSet, HashMap};
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::*;
+
+/// Ticks queued for the spi, across all shards.
+const TICK_QUEUE: usize = 65536;
+
+/// Spi of one shard, queues ticks and forwards subscription responses.
+/// Session events are handled by [`ShardedMdApi`] and not forwarded.
+struct ShardSpi<S> {
+    shard: usize,
+    ticks: Sender<CThostFtdcDepthMarketDataField>,
+    dropped: Arc<AtomicU64>,
+    inner: SharedSpi<S>,
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for ShardSpi<S> {
+    forward_md_spi!(inner:
+        on_rsp_error, on_rsp_sub_market_data, on_rsp_un_sub_market_data,
+        on_rtn_for_quote_rsp,
+    );
+
+    fn on_rtn_depth_market_data(&mut self, pDepthMarketData: *mut CThostFtdcDepthMarketDataField) {
+        if let Some(tick) = unsafe { pDepthMarketData.as_ref() } {
+            if let Err(TrySendError::Full(tick)) = self.ticks.try_send(*tick) {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    warn!("md shard {} dropped tick of {}, {} dropped so far", self.shard, to_string(&tick.InstrumentID), dropped);
+                }
+            }
+        }
+    }
+
+    fn on_front_disconnected(&mut self, nReason: c_int) {
+        warn!("md shard {} disconnected: {}", self.shard, nReason);
+    }
+}
+
+/// Market data over `shards` sessions of the same config.
+///
+/// Call [`ShardedMdApi::check`] periodically to log in and restore
+/// subscriptions on every shard.
+pub struct ShardedMdApi<S> {
+    configs: Vec<Config>,
+    spi: SharedSpi<S>,
+    sessions: Vec<Session>,
+    ticks: Sender<CThostFtdcDepthMarketDataField>,
+    /// Taken by the dispatch thread on start.
+    queued: Option<Receiver<CThostFtdcDepthMarketDataField>>,
+    dropped: Arc<AtomicU64>,
+    subscriptions: Vec<BTreeSet<String>>,
+    assignments: HashMap<String, usize>,
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait + Send + 'static> ShardedMdApi<S> {
+    /// Shard `i` keeps its flow files under `<flowpath>/shard<i>/`.
+    pub fn new(config: &Config, shards: usize, spi: S) -> Result<Self, String> {
+        if shards == 0 {
+            return Err("at least one shard is required".into());
+        }
+        config.validate()?;
+
+        let mut configs = vec![];
+        for shard in 0..shards {
+            let flowpath = Path::new(&config.flowpath).join(format!("shard{}", shard));
+            std::fs::create_dir_all(&flowpath).map_err(|e| format!("Fail to create {}: {}", flowpath.display(), e))?;
+            let mut config = config.clone();
+            // the api takes the flow path as a prefix, keep the separator
+            config.flowpath = format!("{}{}", flowpath.display(), std::path::MAIN_SEPARATOR);
+            configs.push(config);
+        }
+
+        let (ticks, queued) = channel::bounded(TICK_QUEUE);
+        Ok(Self {
+            configs,
+            spi: SharedSpi::new(spi),
+            sessions: vec![],
+            ticks,
+            queued: Some(queued),
+            dropped: Default::default(),
+            subscriptions: vec![BTreeSet::new(); shards],
+            assignments: HashMap::new(),
+        })
+    }
+
+    /// The user spi, shared with the api threads.
+    pub fn spi(&self) -> &SharedSpi<S> {
+        &self.spi
+    }
+
+    pub fn start(&mut self) -> Result<(), String> {
+        let queued = self.queued.take().ok_or("sharded md api already started")?;
+        let spi = self.spi.clone();
+        // ends once the api and every shard session are dropped
+        std::thread::Builder::new()
+            .name("md-shard-ticks".into())
+            .spawn(move || {
+                for mut tick in queued {
+                    spi.lock().on_rtn_depth_market_data(&mut tick);
+                }
+            })
+            .map_err(|e| format!("Fail to spawn the tick dispatch thread: {}", e))?;
+
+        for (shard, config) in self.configs.iter().enumerate() {
+            let spi = ShardSpi {
+                shard,
+                ticks: self.ticks.clone(),
+                dropped: self.dropped.clone(),
+                inner: self.spi.clone(),
+            };
+            self.sessions.push(Session::start(config, spi)?);
+        }
+        Ok(())
+    }
+
+    /// Ticks dropped because the spi fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Login and resubscribe each shard as needed, a failing shard does not
+    /// hold up the others. The errors of every failing shard are returned.
+    pub fn check(&mut self) -> Result<(), String> {
+        let mut errors = vec![];
+        for (shard, (session, subscriptions)) in self.sessions.iter_mut().zip(self.subscriptions.iter()).enumerate() {
+            if let Err(e) = session.drive(subscriptions) {
+                error!("md shard {} on {}: {}", shard, session.front(), e);
+                errors.push(format!("shard {}: {}", shard, e));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.join("; "));
+        }
+        Ok(())
+    }
+
+    pub fn subscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        let mut batches: Vec<Vec<&str>> = vec![vec![]; self.configs.len()];
+        for code in codes {
+            if self.assignments.contains_key(*code) {
+                continue;
+            }
+            let shard = self.least_loaded();
+            self.assignments.insert(code.to_string(), shard);
+            self.subscriptions[shard].insert(code.to_string());
+            batches[shard].push(code);
+        }
+        for (shard, batch) in batches.iter().enumerate() {
+            if let (Some(session), false) = (self.sessions.get_mut(shard), batch.is_empty()) {
+                session.subscribe_market_data(batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe_market_data(&mut self, codes: &[&str]) -> Result<(), String> {
+        let mut batches: Vec<Vec<&str>> = vec![vec![]; self.configs.len()];
+        for code in codes {
+            if let Some(shard) = self.assignments.remove(*code) {
+                self.subscriptions[shard].remove(*code);
+                batches[shard].push(code);
+            }
+        }
+        for (shard, batch) in batches.iter().enumerate() {
+            if let (Some(session), false) = (self.sessions.get_mut(shard), batch.is_empty()) {
+                session.unsubscribe_market_data(batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The shard carrying `code`.
+    pub fn shard_of(&self, code: &str) -> Option<usize> {
+        self.assignments.get(code).copied()
+    }
+
+    /// Number of instruments per shard.
+    pub fn loads(&self) -> Vec<usize> {
+        self.subscriptions.iter().map(|s| s.len()).collect()
+    }
+
+    /// Connection health per shard within `window`.
+    pub fn health(&self, window: Duration) -> Vec<HealthCount> {
+        self.sessions.iter().map(|s| s.health(window)).collect()
+    }
+
+    fn least_loaded(&self) -> usize {
+        (0..self.subscriptions.len()).min_by_key(|i| self.subscriptions[*i].len()).unwrap_or(0)
+    }
+}
@@ -0,0 +1,86 @@
+use crate::sys::*;
+
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A spi shared between several api sessions, each callback locks it.
+///
+/// Register clones of it with every session to feed one consumer.
+pub struct SharedSpi<S> {
+    inner: Arc<Mutex<S>>,
+}
+
+impl<S> Clone for SharedSpi<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: Rust_CThostFtdcMdSpi_Trait> SharedSpi<S> {
+    pub fn new(spi: S) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(spi)),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, S> {
+        self.inner.lock().unwrap()
+    }
+}
+
+#[allow(non_snake_case)]
+impl<S: Rust_CThostFtdcMdSpi_Trait> Rust_CThostFtdcMdSpi_Trait for SharedSpi<S> {
+    fn on_front_connected(&mut self) {
+        self.lock().on_front_connected()
+    }
+
+    fn on_front_disconnected(&mut self, nReason: c_int) {
+        self.lock().on_front_disconnected(nReason)
+    }
+
+    fn on_heart_beat_warning(&mut self, nTimeLapse: c_int) {
+        self.lock().on_heart_beat_warning(nTimeLapse)
+    }
+
+    fn on_rsp_user_login(&mut self, pRspUserLogin: *mut CThostFtdcRspUserLoginField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_user_login(pRspUserLogin, pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rsp_user_logout(&mut self, pUserLogout: *mut CThostFtdcUserLogoutField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_user_logout(pUserLogout, pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rsp_qry_multicast_instrument(&mut self, pMulticastInstrument: *mut CThostFtdcMulticastInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_qry_multicast_instrument(pMulticastInstrument, pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rsp_error(&mut self, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_error(pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rsp_sub_market_data(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_sub_market_data(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rsp_un_sub_market_data(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_un_sub_market_data(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rsp_sub_for_quote_rsp(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_sub_for_quote_rsp(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rsp_un_sub_for_quote_rsp(&mut self, pSpecificInstrument: *mut CThostFtdcSpecificInstrumentField, pRspInfo: *mut CThostFtdcRspInfoField, nRequestID: c_int, bIsLast: bool) {
+        self.lock().on_rsp_un_sub_for_quote_rsp(pSpecificInstrument, pRspInfo, nRequestID, bIsLast)
+    }
+
+    fn on_rtn_depth_market_data(&mut self, pDepthMarketData: *mut CThostFtdcDepthMarketDataField) {
+        self.lock().on_rtn_depth_market_data(pDepthMarketData)
+    }
+
+    fn on_rtn_for_quote_rsp(&mut self, pForQuoteRsp: *mut CThostFtdcForQuoteRspField) {
+        self.lock().on_rtn_for_quote_rsp(pForQuoteRsp)
+    }
+}
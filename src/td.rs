@@ -0,0 +1,18 @@
+//! Trading helpers on top of `CThostFtdcTraderApi`.
+
+mod api;
+pub use api::*;
+
+pub mod account;
+pub mod conditional;
+pub mod exercise;
+pub mod instrument;
+pub mod order;
+pub mod position;
+pub mod query;
+pub mod quoting;
+pub mod rates;
+pub mod risk;
+pub mod settlement;
+pub mod target;
+pub mod throttle;
@@ -0,0 +1,390 @@
+//! Account funds kept live between `ReqQryTradingAccount` snapshots.
+//!
+//! The query gives the baseline. Ticks then move position profit, trades add
+//! commission and carry profit and margin between positions and close profit,
+//! pending open orders freeze margin and commission, pending closes freeze
+//! commission at the close rate of their offset. The live figures are
+//! estimates priced with the [`Rates`] given per instrument, the type the
+//! resolver in [`super::rates`] builds from the rate queries. Re-sync with the
+//! query now and then.
+
+use super::order::{OrderKey, OrderStatus};
+use super::position::PosiDirection;
+use super::rates::Rates;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+/// A live view of the account, in the terms of `CThostFtdcTradingAccountField`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Account {
+    pub balance: f64,
+    pub available: f64,
+    pub close_profit: f64,
+    pub position_profit: f64,
+    pub commission: f64,
+    pub curr_margin: f64,
+    pub frozen_margin: f64,
+    pub frozen_commission: f64,
+    pub frozen_cash: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Holding {
+    volume: i32,
+    profit: f64,
+    margin: f64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Totals {
+    profit: f64,
+    margin: f64,
+    frozen_margin: f64,
+    frozen_commission: f64,
+}
+
+type HoldingKey = (String, PosiDirection);
+
+fn sign(direction: PosiDirection) -> f64 {
+    match direction {
+        PosiDirection::Long => 1.0,
+        PosiDirection::Short => -1.0,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AccountTracker {
+    rates: HashMap<String, Rates>,
+    base: Option<CThostFtdcTradingAccountField>,
+    synced: Option<Instant>,
+    /// Live totals when `base` was taken.
+    at_sync: Totals,
+    close_profit: f64,
+    commission: f64,
+    holdings: HashMap<HoldingKey, Holding>,
+    queried: Option<HashMap<HoldingKey, Holding>>,
+    marks: HashMap<String, f64>,
+    /// Margin and commission frozen by pending orders.
+    freezes: HashMap<OrderKey, (f64, f64)>,
+    trade_ids: HashSet<(String, String, u8)>,
+}
+
+impl AccountTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Rates of `instrument`, fills without them are counted free of
+    /// commission and margin.
+    pub fn set_rates(&mut self, instrument: &str, rates: Rates) {
+        self.rates.insert(instrument.to_string(), rates);
+    }
+
+    /// Take the queried funds as the new baseline.
+    pub fn on_rsp_qry_trading_account(&mut self, account: &CThostFtdcTradingAccountField) {
+        self.base = Some(*account);
+        self.synced = Some(Instant::now());
+        self.at_sync = self.totals();
+        self.close_profit = 0.0;
+        self.commission = 0.0;
+    }
+
+    /// A row of `ReqQryInvestorPosition`, the holdings priced by ticks are
+    /// replaced on the last one.
+    pub fn on_rsp_qry_investor_position(&mut self, row: Option<&CThostFtdcInvestorPositionField>, is_last: bool) {
+        let queried = self.queried.get_or_insert_with(HashMap::new);
+        if let Some(row) = row {
+            if let Some(direction) = PosiDirection::from_pd(row.PosiDirection) {
+                let holding = queried.entry((to_string(&row.InstrumentID), direction)).or_default();
+                holding.volume += row.Position;
+                holding.profit += row.PositionProfit;
+                holding.margin += row.UseMargin;
+            }
+        }
+        if !is_last {
+            return;
+        }
+
+        // keep the account figures continuous, only the funds query moves them
+        let before = self.totals();
+        self.holdings = self.queried.take().unwrap_or_default();
+        self.marks.clear();
+        let after = self.totals();
+        self.at_sync.profit += after.profit - before.profit;
+        self.at_sync.margin += after.margin - before.margin;
+    }
+
+    /// Mark the holdings of the instrument to its last price.
+    pub fn on_tick(&mut self, tick: &CThostFtdcDepthMarketDataField) {
+        let price = match valid_price(tick.LastPrice) {
+            Some(price) => price,
+            None => return,
+        };
+        let instrument = to_string(&tick.InstrumentID);
+        let multiplier = self.rates(&instrument).multiplier;
+        if let Some(mark) = self.marks.insert(instrument.clone(), price) {
+            for direction in [PosiDirection::Long, PosiDirection::Short] {
+                if let Some(holding) = self.holdings.get_mut(&(instrument.clone(), direction)) {
+                    holding.profit += (price - mark) * holding.volume as f64 * multiplier * sign(direction);
+                }
+            }
+        }
+    }
+
+    pub fn on_rtn_trade(&mut self, trade: &CThostFtdcTradeField) {
+        let trade_id = (to_string(&trade.ExchangeID), to_string(&trade.TradeID), trade.Direction as u8);
+        // trades are replayed on resume, count each once
+        if !self.trade_ids.insert(trade_id) {
+            return;
+        }
+        let instrument = to_string(&trade.InstrumentID);
+        let rates = self.rates(&instrument);
+        let offset = trade.OffsetFlag as u8;
+        let (price, volume) = (trade.Price, trade.Volume);
+        self.commission += rates.commission.commission(offset, price, volume, rates.multiplier);
+
+        let direction = PosiDirection::of_order(trade.Direction, trade.OffsetFlag);
+        // without a tick yet the next one marks from the trade price
+        let mark = *self.marks.entry(instrument.clone()).or_insert(price);
+        let moved = (mark - price) * volume as f64 * rates.multiplier * sign(direction);
+        let holding = self.holdings.entry((instrument, direction)).or_default();
+        if offset == THOST_FTDC_OF_Open {
+            holding.volume += volume;
+            holding.profit += moved;
+            holding.margin += rates.margin.margin(direction, price, volume, rates.multiplier);
+        } else {
+            // the closed volume takes its share of profit and margin along
+            let share = if holding.volume > 0 { (volume as f64 / holding.volume as f64).min(1.0) } else { 0.0 };
+            let (profit, margin) = (holding.profit * share, holding.margin * share);
+            holding.volume = (holding.volume - volume).max(0);
+            holding.profit -= profit;
+            holding.margin -= margin;
+            self.close_profit += profit - moved;
+        }
+    }
+
+    /// Freeze commission for the rest of a pending order, and margin too
+    /// when it opens.
+    pub fn on_rtn_order(&mut self, order: &CThostFtdcOrderField) {
+        let offset = order.CombOffsetFlag[0] as u8;
+        let key = OrderKey {
+            front_id: order.FrontID,
+            session_id: order.SessionID,
+            order_ref: to_string(&order.OrderRef),
+        };
+        let finished = order.OrderSubmitStatus as u8 == THOST_FTDC_OSS_InsertRejected
+            || OrderStatus::from_ost(order.OrderStatus).is_some_and(|s| s.is_finished());
+        if finished || order.VolumeTotal <= 0 {
+            self.freezes.remove(&key);
+            return;
+        }
+
+        let rates = self.rates(&to_string(&order.InstrumentID));
+        let (price, volume) = (order.LimitPrice, order.VolumeTotal);
+        let margin = if offset == THOST_FTDC_OF_Open {
+            let direction = PosiDirection::opened_by(order.Direction);
+            rates.margin.margin(direction, price, volume, rates.multiplier)
+        } else {
+            0.0
+        };
+        let commission = rates.commission.commission(offset, price, volume, rates.multiplier);
+        self.freezes.insert(key, (margin, commission));
+    }
+
+    /// The live account, `None` before the first funds query.
+    pub fn account(&self) -> Option<Account> {
+        let base = self.base.as_ref()?;
+        let totals = self.totals();
+        let close_profit = base.CloseProfit + self.close_profit;
+        let position_profit = base.PositionProfit + totals.profit - self.at_sync.profit;
+        let commission = base.Commission + self.commission;
+        let curr_margin = base.CurrMargin + totals.margin - self.at_sync.margin;
+        let frozen_margin = (base.FrozenMargin + totals.frozen_margin - self.at_sync.frozen_margin).max(0.0);
+        let frozen_commission = (base.FrozenCommission + totals.frozen_commission - self.at_sync.frozen_commission).max(0.0);
+
+        let balance = base.Balance + self.close_profit + (position_profit - base.PositionProfit) - self.commission;
+        let available = base.Available + (balance - base.Balance)
+            - (curr_margin - base.CurrMargin)
+            - (frozen_margin - base.FrozenMargin)
+            - (frozen_commission - base.FrozenCommission);
+        Some(Account {
+            balance,
+            available,
+            close_profit,
+            position_profit,
+            commission,
+            curr_margin,
+            frozen_margin,
+            frozen_commission,
+            frozen_cash: base.FrozenCash,
+        })
+    }
+
+    /// When the funds were last queried.
+    pub fn synced_at(&self) -> Option<Instant> {
+        self.synced
+    }
+
+    /// Whether the baseline is older than `interval`, or missing.
+    pub fn needs_sync(&self, interval: Duration) -> bool {
+        self.synced.is_none_or(|t| t.elapsed() >= interval)
+    }
+
+    fn rates(&self, instrument: &str) -> Rates {
+        match self.rates.get(instrument) {
+            Some(rates) => *rates,
+            None => {
+                debug!("no rates of {}, priced as free", instrument);
+                Rates::default()
+            }
+        }
+    }
+
+    fn totals(&self) -> Totals {
+        let mut totals = Totals::default();
+        for holding in self.holdings.values() {
+            totals.profit += holding.profit;
+            totals.margin += holding.margin;
+        }
+        for (margin, commission) in self.freezes.values() {
+            totals.frozen_margin += margin;
+            totals.frozen_commission += commission;
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::td::rates::{CommissionRate, MarginRate};
+
+    fn tracker() -> AccountTracker {
+        let mut tracker = AccountTracker::new();
+        tracker.set_rates(
+            "rb2501",
+            Rates {
+                multiplier: 10.0,
+                margin: MarginRate {
+                    long_by_money: 0.1,
+                    short_by_money: 0.1,
+                    ..Default::default()
+                },
+                commission: CommissionRate {
+                    open_by_volume: 1.0,
+                    close_by_volume: 2.0,
+                    close_today_by_volume: 3.0,
+                    ..Default::default()
+                },
+            },
+        );
+        let mut funds: CThostFtdcTradingAccountField = zeroed();
+        funds.Balance = 100_000.0;
+        funds.Available = 100_000.0;
+        tracker.on_rsp_qry_trading_account(&funds);
+        tracker
+    }
+
+    fn order(order_ref: &str, offset: u8, volume: i32) -> CThostFtdcOrderField {
+        let mut order: CThostFtdcOrderField = zeroed();
+        set_string(&mut order.InstrumentID, "rb2501");
+        set_string(&mut order.OrderRef, order_ref);
+        order.Direction = THOST_FTDC_D_Sell as _;
+        order.CombOffsetFlag[0] = offset as _;
+        order.LimitPrice = 3500.0;
+        order.VolumeTotal = volume;
+        order.OrderStatus = THOST_FTDC_OST_NoTradeQueueing as _;
+        order
+    }
+
+    fn trade(trade_id: &str, direction: u8, offset: u8, price: f64, volume: i32) -> CThostFtdcTradeField {
+        let mut trade: CThostFtdcTradeField = zeroed();
+        set_string(&mut trade.InstrumentID, "rb2501");
+        set_string(&mut trade.ExchangeID, "SHFE");
+        set_string(&mut trade.TradeID, trade_id);
+        trade.Direction = direction as _;
+        trade.OffsetFlag = offset as _;
+        trade.Price = price;
+        trade.Volume = volume;
+        trade
+    }
+
+    fn tick(price: f64) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.InstrumentID, "rb2501");
+        tick.LastPrice = price;
+        tick
+    }
+
+    #[test]
+    fn closing_trades_book_close_profit() {
+        let mut tracker = tracker();
+        tracker.on_rtn_trade(&trade("1", THOST_FTDC_D_Sell, THOST_FTDC_OF_Open, 3500.0, 3));
+        tracker.on_rtn_trade(&trade("2", THOST_FTDC_D_Buy, THOST_FTDC_OF_Close, 3480.0, 3));
+        // replayed on resume
+        tracker.on_rtn_trade(&trade("2", THOST_FTDC_D_Buy, THOST_FTDC_OF_Close, 3480.0, 3));
+        let account = tracker.account().unwrap();
+        assert_eq!(account.close_profit, 20.0 * 3.0 * 10.0);
+        assert_eq!(account.position_profit, 0.0);
+        assert_eq!(account.curr_margin, 0.0);
+        assert_eq!(account.commission, 3.0 + 6.0);
+        assert_eq!(account.balance, 100_000.0 + 600.0 - 9.0);
+    }
+
+    #[test]
+    fn balance_through_open_mark_and_close() {
+        let mut tracker = tracker();
+        tracker.on_rtn_trade(&trade("1", THOST_FTDC_D_Buy, THOST_FTDC_OF_Open, 3500.0, 2));
+        let account = tracker.account().unwrap();
+        assert_eq!((account.curr_margin, account.commission), (7000.0, 2.0));
+        assert_eq!(account.balance, 100_000.0 - 2.0);
+        assert_eq!(account.available, 100_000.0 - 2.0 - 7000.0);
+
+        tracker.on_tick(&tick(3520.0));
+        let account = tracker.account().unwrap();
+        assert_eq!(account.position_profit, 400.0);
+        assert_eq!(account.balance, 100_000.0 + 400.0 - 2.0);
+
+        tracker.on_rtn_trade(&trade("2", THOST_FTDC_D_Sell, THOST_FTDC_OF_CloseToday, 3530.0, 1));
+        let account = tracker.account().unwrap();
+        assert_eq!(account.close_profit, 300.0);
+        assert_eq!(account.position_profit, 200.0);
+        assert_eq!(account.curr_margin, 3500.0);
+        assert_eq!(account.commission, 2.0 + 3.0);
+        assert_eq!(account.balance, 100_000.0 + 300.0 + 200.0 - 5.0);
+        assert_eq!(account.available, account.balance - 3500.0);
+
+        // the query re-bases the live figures
+        let mut funds: CThostFtdcTradingAccountField = zeroed();
+        funds.Balance = account.balance;
+        funds.Available = account.available;
+        funds.CloseProfit = 300.0;
+        funds.PositionProfit = 200.0;
+        funds.CurrMargin = 3500.0;
+        funds.Commission = 5.0;
+        tracker.on_rsp_qry_trading_account(&funds);
+        assert_eq!(tracker.account(), Some(account));
+    }
+
+    #[test]
+    fn pending_orders_freeze_commission_by_offset() {
+        let mut tracker = tracker();
+        tracker.on_rtn_order(&order("1", THOST_FTDC_OF_Open, 2));
+        tracker.on_rtn_order(&order("2", THOST_FTDC_OF_Close, 2));
+        tracker.on_rtn_order(&order("3", THOST_FTDC_OF_CloseToday, 2));
+        let account = tracker.account().unwrap();
+        assert_eq!(account.frozen_commission, 2.0 + 4.0 + 6.0);
+        assert_eq!(account.frozen_margin, 3500.0 * 2.0 * 10.0 * 0.1);
+        assert_eq!(account.available, 100_000.0 - 12.0 - 7000.0);
+
+        let mut done = order("3", THOST_FTDC_OF_CloseToday, 0);
+        done.OrderStatus = THOST_FTDC_OST_AllTraded as _;
+        tracker.on_rtn_order(&done);
+        assert_eq!(tracker.account().unwrap().frozen_commission, 6.0);
+    }
+}
@@ -0,0 +1,652 @@
+use super::instrument::InstrumentFilter;
+use super::query::*;
+use crate::front::*;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::*;
+use std::time::Duration;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Resume {
+    Restart = THOST_TE_RESUME_TYPE_THOST_TERT_RESTART as _,
+    Resume = THOST_TE_RESUME_TYPE_THOST_TERT_RESUME as _,
+    #[default]
+    Quick = THOST_TE_RESUME_TYPE_THOST_TERT_QUICK as _,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Config {
+    pub flowpath: String,
+    pub front_addr: String,
+    pub nm_addr: String,
+    pub user_info: String,
+    pub product_info: String,
+    pub auth_code: String,
+    pub app_id: String,
+    pub public_resume: Resume,
+    pub private_resume: Resume,
+
+    pub broker_id: String,
+    pub user_id: String,
+    pub password: String,
+    /// Defaults to `user_id` when empty.
+    #[serde(default)]
+    pub investor_id: String,
+
+    /// Queries per second allowed by the broker, 1 when unset.
+    #[serde(default)]
+    pub qry_freq: i32,
+
+    /// Register the FENS user info along with `nm_addr`.
+    #[serde(default)]
+    pub fens_login_mode: Option<FensLoginMode>,
+}
+
+impl Config {
+    pub fn investor_id(&self) -> &str {
+        if self.investor_id.is_empty() {
+            &self.user_id
+        } else {
+            &self.investor_id
+        }
+    }
+
+    /// Check the addresses before creating the api.
+    pub fn validate(&self) -> Result<(), String> {
+        validate_addresses(&self.front_addr, &self.nm_addr, self.fens_login_mode)
+    }
+}
+
+/// A trading session owning the `CThostFtdcTraderApi` and its registered spi.
+pub struct TdApi {
+    api: Rust_CThostFtdcTraderApi,
+    spi: Option<(*mut CThostFtdcTraderSpi, *mut c_void)>,
+    started: bool,
+    request_id: c_int,
+    /// Return code of the latest request, tells flow control from other errors.
+    last_rtn: c_int,
+    queries: QueryQueue,
+
+    pub(crate) config: Config,
+}
+
+impl TdApi {
+    pub fn get_version() -> String {
+        let cs = unsafe { CStr::from_ptr(CThostFtdcTraderApi::GetApiVersion()) };
+        cs.to_string_lossy().into()
+    }
+
+    pub fn new(config: &Config) -> Result<Self, String> {
+        config.validate()?;
+        let cs = CString::new(config.flowpath.as_bytes()).map_err(|e| e.to_string())?;
+        let api = unsafe { *Rust_CThostFtdcTraderApi::CreateFtdcTraderApi(cs.as_ptr()) };
+
+        Ok(Self {
+            api,
+            spi: None,
+            started: false,
+            request_id: 0,
+            last_rtn: 0,
+            queries: Default::default(),
+            config: config.clone(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The underlying api, for requests without a helper here.
+    pub fn inner(&mut self) -> &mut Rust_CThostFtdcTraderApi {
+        &mut self.api
+    }
+
+    /// Register `spi`, replacing the one before. Only before [`Self::init`]:
+    /// the api thread may call the registered spi at any time once started.
+    pub fn register<S: Rust_CThostFtdcTraderSpi_Trait + Send + 'static>(&mut self, spi: S) -> Result<(), String> {
+        if self.started {
+            return Err("the spi cannot be replaced after `init`".into());
+        }
+        self.drop_spi();
+
+        let spi: Box<Box<dyn Rust_CThostFtdcTraderSpi_Trait>> = Box::new(Box::new(spi));
+        let ptr = Box::into_raw(spi) as *mut c_void;
+        unsafe {
+            let stub = Rust_CThostFtdcTraderSpi::Create(ptr);
+            self.api.RegisterSpi(stub);
+            self.spi = Some((stub, ptr));
+        }
+        Ok(())
+    }
+
+    /// Register the configured addresses, subscribe the topics and start the api thread.
+    pub fn init(&mut self) -> Result<(), String> {
+        if self.spi.is_none() {
+            return Err("register a spi before `init`".into());
+        }
+        if self.started {
+            return Err("the api is already started".into());
+        }
+
+        if !self.config.front_addr.is_empty() {
+            debug!("front_addr is: {}", self.config.front_addr);
+            let cs = CString::new(self.config.front_addr.as_bytes()).map_err(|e| e.to_string())?;
+            unsafe { self.api.RegisterFront(cs.as_ptr() as *mut _) };
+        }
+
+        if !self.config.nm_addr.is_empty() {
+            debug!("nm_addr is: {}", self.config.nm_addr);
+            let cs = CString::new(self.config.nm_addr.as_bytes()).map_err(|e| e.to_string())?;
+            unsafe { self.api.RegisterNameServer(cs.as_ptr() as *mut _) };
+        }
+
+        if let Some(mode) = self.config.fens_login_mode {
+            debug!("fens login mode is: {:?}", mode);
+            let mut field = fens_user_info(&self.config.broker_id, &self.config.user_id, mode);
+            unsafe { self.api.RegisterFensUserInfo(&mut field) };
+        }
+
+        self.started = true;
+        unsafe {
+            self.api.SubscribePrivateTopic(self.config.private_resume as _);
+            self.api.SubscribePublicTopic(self.config.public_resume as _);
+            self.api.Init();
+        }
+        Ok(())
+    }
+
+    pub fn get_trading_day(&mut self) -> String {
+        let cs = unsafe { CStr::from_ptr(self.api.GetTradingDay()) };
+        cs.to_string_lossy().into()
+    }
+
+    pub fn next_request_id(&mut self) -> c_int {
+        self.request_id += 1;
+        self.request_id
+    }
+
+    pub fn req_authenticate(&mut self) -> Result<c_int, String> {
+        let mut field: CThostFtdcReqAuthenticateField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.UserID, &self.config.user_id);
+        set_string(&mut field.UserProductInfo, &self.config.product_info);
+        set_string(&mut field.AuthCode, &self.config.auth_code);
+        set_string(&mut field.AppID, &self.config.app_id);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqAuthenticate(&mut field, request_id) };
+        self.check_rtn("td_api_req_authenticate", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_user_login(&mut self) -> Result<c_int, String> {
+        let mut field: CThostFtdcReqUserLoginField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.UserID, &self.config.user_id);
+        set_string(&mut field.Password, &self.config.password);
+        set_string(&mut field.UserProductInfo, &self.config.product_info);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqUserLogin(&mut field, request_id) };
+        self.check_rtn("td_api_req_user_login", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Send a for-quote (询价) request. Take `for_quote_ref` from
+    /// [`crate::td::order::OrderManager::next_order_ref`], refs come from there only.
+    pub fn req_for_quote_insert(&mut self, instrument: &str, exchange: &str, for_quote_ref: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcInputForQuoteField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.UserID, &self.config.user_id);
+        set_string(&mut field.InstrumentID, instrument);
+        set_string(&mut field.ExchangeID, exchange);
+        set_string(&mut field.ForQuoteRef, for_quote_ref);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqForQuoteInsert(&mut field, request_id) };
+        self.check_rtn("td_api_req_for_quote_insert", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Send an order, `BrokerID`, `InvestorID` and `UserID` are filled in when empty.
+    /// `OrderRef` is left to the caller, see [`crate::td::order::OrderManager::insert`].
+    pub fn req_order_insert(&mut self, field: &mut CThostFtdcInputOrderField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqOrderInsert(field, field.RequestID) };
+        self.check_rtn("td_api_req_order_insert", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    /// Send an order action, ids are filled in as for `req_order_insert`.
+    pub fn req_order_action(&mut self, field: &mut CThostFtdcInputOrderActionField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqOrderAction(field, field.RequestID) };
+        self.check_rtn("td_api_req_order_action", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    /// Send a two-sided quote, ids are filled in as for `req_order_insert`.
+    /// `QuoteRef` and the leg refs are left to the caller, see
+    /// [`crate::td::quoting::QuoteManager::insert`].
+    pub fn req_quote_insert(&mut self, field: &mut CThostFtdcInputQuoteField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQuoteInsert(field, field.RequestID) };
+        self.check_rtn("td_api_req_quote_insert", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    pub fn req_quote_action(&mut self, field: &mut CThostFtdcInputQuoteActionField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQuoteAction(field, field.RequestID) };
+        self.check_rtn("td_api_req_quote_action", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    /// Send an exercise or abandon instruction, ids are filled in as for `req_order_insert`.
+    pub fn req_exec_order_insert(&mut self, field: &mut CThostFtdcInputExecOrderField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqExecOrderInsert(field, field.RequestID) };
+        self.check_rtn("td_api_req_exec_order_insert", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    pub fn req_exec_order_action(&mut self, field: &mut CThostFtdcInputExecOrderActionField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqExecOrderAction(field, field.RequestID) };
+        self.check_rtn("td_api_req_exec_order_action", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    /// Send a self-close (对冲) instruction.
+    pub fn req_option_self_close_insert(&mut self, field: &mut CThostFtdcInputOptionSelfCloseField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqOptionSelfCloseInsert(field, field.RequestID) };
+        self.check_rtn("td_api_req_option_self_close_insert", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    pub fn req_option_self_close_action(&mut self, field: &mut CThostFtdcInputOptionSelfCloseActionField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqOptionSelfCloseAction(field, field.RequestID) };
+        self.check_rtn("td_api_req_option_self_close_action", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    /// Query the exercise instructions of `instrument`, all of them when empty.
+    pub fn req_qry_exec_order(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryExecOrderField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryExecOrder(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_exec_order", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_qry_option_self_close(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryOptionSelfCloseField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryOptionSelfClose(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_option_self_close", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Park an order at the broker until the session opens or its condition
+    /// holds, ids are filled in as for `req_order_insert`.
+    pub fn req_parked_order_insert(&mut self, field: &mut CThostFtdcParkedOrderField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqParkedOrderInsert(field, field.RequestID) };
+        self.check_rtn("td_api_req_parked_order_insert", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    pub fn req_parked_order_action(&mut self, field: &mut CThostFtdcParkedOrderActionField) -> Result<c_int, String> {
+        self.fill_ids(&mut field.BrokerID, &mut field.InvestorID, &mut field.UserID);
+        field.RequestID = self.next_request_id();
+        let rtn = unsafe { self.api.ReqParkedOrderAction(field, field.RequestID) };
+        self.check_rtn("td_api_req_parked_order_action", rtn)?;
+        Ok(field.RequestID)
+    }
+
+    pub fn req_remove_parked_order(&mut self, field: &mut CThostFtdcRemoveParkedOrderField) -> Result<c_int, String> {
+        if to_string(&field.BrokerID).is_empty() {
+            set_string(&mut field.BrokerID, &self.config.broker_id);
+        }
+        if to_string(&field.InvestorID).is_empty() {
+            set_string(&mut field.InvestorID, self.config.investor_id());
+        }
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqRemoveParkedOrder(field, request_id) };
+        self.check_rtn("td_api_req_remove_parked_order", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_remove_parked_order_action(&mut self, field: &mut CThostFtdcRemoveParkedOrderActionField) -> Result<c_int, String> {
+        if to_string(&field.BrokerID).is_empty() {
+            set_string(&mut field.BrokerID, &self.config.broker_id);
+        }
+        if to_string(&field.InvestorID).is_empty() {
+            set_string(&mut field.InvestorID, self.config.investor_id());
+        }
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqRemoveParkedOrderAction(field, request_id) };
+        self.check_rtn("td_api_req_remove_parked_order_action", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the parked orders of `instrument`, all of them when empty.
+    pub fn req_qry_parked_order(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryParkedOrderField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryParkedOrder(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_parked_order", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_qry_parked_order_action(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryParkedOrderActionField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryParkedOrderAction(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_parked_order_action", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the quotes of `instrument`, all of them when empty.
+    pub fn req_qry_quote(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryQuoteField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryQuote(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_quote", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_qry_trading_account(&mut self) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryTradingAccountField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryTradingAccount(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_trading_account", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the positions of `instrument`, all positions when empty.
+    pub fn req_qry_investor_position(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryInvestorPositionField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryInvestorPosition(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_investor_position", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the open lots of `instrument`, all of them when empty.
+    pub fn req_qry_investor_position_detail(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryInvestorPositionDetailField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryInvestorPositionDetail(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_investor_position_detail", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query instruments, narrowed by whichever of the arguments are not empty.
+    pub fn req_qry_instrument(&mut self, instrument: &str, exchange: &str, product: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryInstrumentField = zeroed();
+        set_string(&mut field.InstrumentID, instrument);
+        set_string(&mut field.ExchangeID, exchange);
+        set_string(&mut field.ProductID, product);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryInstrument(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_instrument", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the instruments `filter` selects, from api 6.5.1 on.
+    pub fn req_qry_classified_instrument(&mut self, filter: &InstrumentFilter) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryClassifiedInstrumentField = zeroed();
+        set_string(&mut field.ExchangeID, &filter.exchange_id);
+        set_string(&mut field.ProductID, &filter.product_id);
+        field.TradingType = filter.trading_type.code() as _;
+        field.ClassType = filter.class_type.code() as _;
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryClassifiedInstrument(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_classified_instrument", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the investor's margin rate of `instrument` for `hedge_flag` (`THOST_FTDC_HF_*`).
+    pub fn req_qry_instrument_margin_rate(&mut self, instrument: &str, hedge_flag: u8) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryInstrumentMarginRateField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+        field.HedgeFlag = hedge_flag as _;
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryInstrumentMarginRate(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_instrument_margin_rate", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_qry_instrument_commission_rate(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryInstrumentCommissionRateField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryInstrumentCommissionRate(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_instrument_commission_rate", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the per order and per cancel fees (申报费) of `instrument`.
+    pub fn req_qry_instrument_order_comm_rate(&mut self, instrument: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryInstrumentOrderCommRateField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.InstrumentID, instrument);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryInstrumentOrderCommRate(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_instrument_order_comm_rate", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_qry_exchange_margin_rate(&mut self, instrument: &str, hedge_flag: u8) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryExchangeMarginRateField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InstrumentID, instrument);
+        field.HedgeFlag = hedge_flag as _;
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryExchangeMarginRate(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_exchange_margin_rate", rtn)?;
+        Ok(request_id)
+    }
+
+    pub fn req_qry_exchange_margin_rate_adjust(&mut self, instrument: &str, hedge_flag: u8) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryExchangeMarginRateAdjustField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InstrumentID, instrument);
+        field.HedgeFlag = hedge_flag as _;
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryExchangeMarginRateAdjust(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_exchange_margin_rate_adjust", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the settlement statement of `trading_day`, the last one when empty.
+    pub fn req_qry_settlement_info(&mut self, trading_day: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQrySettlementInfoField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+        set_string(&mut field.TradingDay, trading_day);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQrySettlementInfo(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_settlement_info", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Confirm the last settlement statement, required once a day before the
+    /// first order.
+    pub fn req_settlement_info_confirm(&mut self) -> Result<c_int, String> {
+        let mut field: CThostFtdcSettlementInfoConfirmField = zeroed();
+        set_string(&mut field.BrokerID, &self.config.broker_id);
+        set_string(&mut field.InvestorID, self.config.investor_id());
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqSettlementInfoConfirm(&mut field, request_id) };
+        self.check_rtn("td_api_req_settlement_info_confirm", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Query the latest tick of `instrument`, all instruments when empty.
+    /// The answer can seed a [`crate::md::quote::QuoteStore`] before md is connected.
+    pub fn req_qry_depth_market_data(&mut self, instrument: &str, exchange: &str) -> Result<c_int, String> {
+        let mut field: CThostFtdcQryDepthMarketDataField = zeroed();
+        set_string(&mut field.InstrumentID, instrument);
+        set_string(&mut field.ExchangeID, exchange);
+
+        let request_id = self.next_request_id();
+        let rtn = unsafe { self.api.ReqQryDepthMarketData(&mut field, request_id) };
+        self.check_rtn("td_api_req_qry_depth_market_data", rtn)?;
+        Ok(request_id)
+    }
+
+    /// Queue a query for [`TdApi::poll_queries`] to send, e.g.
+    /// `api.queue_query(Priority::Urgent, "positions", |api| api.req_qry_investor_position(""))`.
+    pub fn queue_query<F>(&mut self, priority: Priority, name: &str, send: F)
+    where
+        F: FnMut(&mut TdApi) -> Result<c_int, String> + Send + 'static,
+    {
+        self.queries.push(Query {
+            name: name.to_string(),
+            priority,
+            attempts: 0,
+            send: Box::new(send),
+        });
+    }
+
+    /// Send the next queued query when flow control allows, call it periodically.
+    /// Returns the request id of the query sent. A query failing for another
+    /// reason than flow control is dropped with its error.
+    pub fn poll_queries(&mut self) -> Result<Option<c_int>, String> {
+        let interval = Duration::from_secs(1) / self.config.qry_freq.max(1) as u32;
+        let mut query = match self.queries.pop(interval) {
+            Some(query) => query,
+            None => return Ok(None),
+        };
+
+        self.last_rtn = 0;
+        let result = (query.send)(self);
+        match result {
+            Ok(request_id) => {
+                self.queries.sent(Some(request_id), &query.name);
+                Ok(Some(request_id))
+            }
+            Err(_) if matches!(self.last_rtn, -2 | -3) => {
+                self.queries.sent(None, &query.name);
+                self.queries.retry(query, interval);
+                Ok(None)
+            }
+            Err(e) => {
+                self.queries.sent(None, &query.name);
+                Err(format!("query `{}` dropped: {}", query.name, e))
+            }
+        }
+    }
+
+    /// Handle for the spi to mark queries done.
+    pub fn query_done(&self) -> QueryDone {
+        self.queries.done()
+    }
+
+    /// Queries waiting to be sent.
+    pub fn pending_queries(&self) -> usize {
+        self.queries.len()
+    }
+
+    /// Request id of the query waiting for its last page.
+    pub fn query_in_flight(&self) -> Option<c_int> {
+        self.queries.in_flight()
+    }
+
+    fn check_rtn(&mut self, name: &str, rtn: c_int) -> Result<(), String> {
+        self.last_rtn = rtn;
+        check_rtn(name, rtn)
+    }
+
+    fn fill_ids(&self, broker_id: &mut [c_char], investor_id: &mut [c_char], user_id: &mut [c_char]) {
+        if to_string(broker_id).is_empty() {
+            set_string(broker_id, &self.config.broker_id);
+        }
+        if to_string(investor_id).is_empty() {
+            set_string(investor_id, self.config.investor_id());
+        }
+        if to_string(user_id).is_empty() {
+            set_string(user_id, &self.config.user_id);
+        }
+    }
+
+    fn drop_spi(&mut self) {
+        if let Some((stub, ptr)) = self.spi.take() {
+            debug!("drop spi");
+            unsafe { Rust_CThostFtdcTraderSpi::Destroy(stub) };
+            Rust_CThostFtdcTraderSpi_Trait_Drop(ptr);
+        }
+    }
+}
+
+impl Drop for TdApi {
+    fn drop(&mut self) {
+        debug!("drop api");
+        unsafe { self.api.Release() };
+        self.drop_spi();
+    }
+}
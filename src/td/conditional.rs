@@ -0,0 +1,624 @@
+//! Conditional orders (条件单) and parked orders (预埋单), both held by the
+//! broker until they fire.
+//!
+//! A conditional order is a plain `ReqOrderInsert` with a [`Trigger`] in
+//! `ContingentCondition` and `StopPrice`. It stays `NotTouched` in the
+//! [`super::order::OrderManager`] until the price condition holds. When the
+//! touched order fails to insert, `OnRtnErrorConditionalOrder` tells why, see
+//! [`super::order::OrderManager::on_rtn_error_conditional_order`].
+//!
+//! A parked order goes through `ReqParkedOrderInsert` and waits for the
+//! session to open, so orders can be staged before the bell. There is no
+//! return when it is sent, [`ParkedOrders`] learns it from the order that
+//! shows up with its `OrderRef` or from `ReqQryParkedOrder`.
+
+use super::order::rsp_error;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::os::raw::c_char;
+
+use log::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Compare {
+    Greater,
+    GreaterEqual,
+    Lesser,
+    LesserEqual,
+}
+
+impl Compare {
+    fn holds(&self, price: f64, stop_price: f64) -> bool {
+        match self {
+            Self::Greater => price > stop_price,
+            Self::GreaterEqual => price >= stop_price,
+            Self::Lesser => price < stop_price,
+            Self::LesserEqual => price <= stop_price,
+        }
+    }
+}
+
+/// When the broker sends the order, `THOST_FTDC_CC_*` with its stop price.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    Immediately,
+    /// Stop loss.
+    Touch(f64),
+    /// Take profit.
+    TouchProfit(f64),
+    /// At the next session open, parked orders only.
+    Parked,
+    LastPrice(Compare, f64),
+    AskPrice(Compare, f64),
+    BidPrice(Compare, f64),
+}
+
+impl Trigger {
+    /// From `ContingentCondition` and `StopPrice`.
+    #[allow(non_upper_case_globals)]
+    pub fn from_cc(condition: c_char, stop_price: f64) -> Option<Self> {
+        use Compare::*;
+        Some(match condition as u8 {
+            THOST_FTDC_CC_Immediately => Self::Immediately,
+            THOST_FTDC_CC_Touch => Self::Touch(stop_price),
+            THOST_FTDC_CC_TouchProfit => Self::TouchProfit(stop_price),
+            THOST_FTDC_CC_ParkedOrder => Self::Parked,
+            THOST_FTDC_CC_LastPriceGreaterThanStopPrice => Self::LastPrice(Greater, stop_price),
+            THOST_FTDC_CC_LastPriceGreaterEqualStopPrice => Self::LastPrice(GreaterEqual, stop_price),
+            THOST_FTDC_CC_LastPriceLesserThanStopPrice => Self::LastPrice(Lesser, stop_price),
+            THOST_FTDC_CC_LastPriceLesserEqualStopPrice => Self::LastPrice(LesserEqual, stop_price),
+            THOST_FTDC_CC_AskPriceGreaterThanStopPrice => Self::AskPrice(Greater, stop_price),
+            THOST_FTDC_CC_AskPriceGreaterEqualStopPrice => Self::AskPrice(GreaterEqual, stop_price),
+            THOST_FTDC_CC_AskPriceLesserThanStopPrice => Self::AskPrice(Lesser, stop_price),
+            THOST_FTDC_CC_AskPriceLesserEqualStopPrice => Self::AskPrice(LesserEqual, stop_price),
+            THOST_FTDC_CC_BidPriceGreaterThanStopPrice => Self::BidPrice(Greater, stop_price),
+            THOST_FTDC_CC_BidPriceGreaterEqualStopPrice => Self::BidPrice(GreaterEqual, stop_price),
+            THOST_FTDC_CC_BidPriceLesserThanStopPrice => Self::BidPrice(Lesser, stop_price),
+            THOST_FTDC_CC_BidPriceLesserEqualStopPrice => Self::BidPrice(LesserEqual, stop_price),
+            _ => return None,
+        })
+    }
+
+    /// `ContingentCondition` of the trigger.
+    pub fn code(&self) -> u8 {
+        use Compare::*;
+        match self {
+            Self::Immediately => THOST_FTDC_CC_Immediately,
+            Self::Touch(_) => THOST_FTDC_CC_Touch,
+            Self::TouchProfit(_) => THOST_FTDC_CC_TouchProfit,
+            Self::Parked => THOST_FTDC_CC_ParkedOrder,
+            Self::LastPrice(Greater, _) => THOST_FTDC_CC_LastPriceGreaterThanStopPrice,
+            Self::LastPrice(GreaterEqual, _) => THOST_FTDC_CC_LastPriceGreaterEqualStopPrice,
+            Self::LastPrice(Lesser, _) => THOST_FTDC_CC_LastPriceLesserThanStopPrice,
+            Self::LastPrice(LesserEqual, _) => THOST_FTDC_CC_LastPriceLesserEqualStopPrice,
+            Self::AskPrice(Greater, _) => THOST_FTDC_CC_AskPriceGreaterThanStopPrice,
+            Self::AskPrice(GreaterEqual, _) => THOST_FTDC_CC_AskPriceGreaterEqualStopPrice,
+            Self::AskPrice(Lesser, _) => THOST_FTDC_CC_AskPriceLesserThanStopPrice,
+            Self::AskPrice(LesserEqual, _) => THOST_FTDC_CC_AskPriceLesserEqualStopPrice,
+            Self::BidPrice(Greater, _) => THOST_FTDC_CC_BidPriceGreaterThanStopPrice,
+            Self::BidPrice(GreaterEqual, _) => THOST_FTDC_CC_BidPriceGreaterEqualStopPrice,
+            Self::BidPrice(Lesser, _) => THOST_FTDC_CC_BidPriceLesserThanStopPrice,
+            Self::BidPrice(LesserEqual, _) => THOST_FTDC_CC_BidPriceLesserEqualStopPrice,
+        }
+    }
+
+    /// `StopPrice` of the trigger, 0 when it has none.
+    pub fn stop_price(&self) -> f64 {
+        match self {
+            Self::Immediately | Self::Parked => 0.0,
+            Self::Touch(price) | Self::TouchProfit(price) => *price,
+            Self::LastPrice(_, price) | Self::AskPrice(_, price) | Self::BidPrice(_, price) => *price,
+        }
+    }
+
+    /// Turn `input` into a conditional order.
+    pub fn apply(&self, input: &mut CThostFtdcInputOrderField) {
+        input.ContingentCondition = self.code() as _;
+        input.StopPrice = self.stop_price();
+    }
+
+    /// Whether a price condition holds on `tick`, `None` for the triggers the
+    /// broker decides on its own.
+    pub fn holds(&self, tick: &CThostFtdcDepthMarketDataField) -> Option<bool> {
+        let (price, compare, stop_price) = match self {
+            Self::LastPrice(compare, stop_price) => (tick.LastPrice, compare, stop_price),
+            Self::AskPrice(compare, stop_price) => (tick.AskPrice1, compare, stop_price),
+            Self::BidPrice(compare, stop_price) => (tick.BidPrice1, compare, stop_price),
+            _ => return None,
+        };
+        Some(valid_price(price).is_some_and(|price| compare.holds(price, *stop_price)))
+    }
+}
+
+/// A parked order from an order input, `Trigger::Parked` unless `input`
+/// carries another trigger already.
+pub fn parked_order(input: &CThostFtdcInputOrderField) -> CThostFtdcParkedOrderField {
+    let mut field: CThostFtdcParkedOrderField = zeroed();
+    field.BrokerID = input.BrokerID;
+    field.InvestorID = input.InvestorID;
+    field.UserID = input.UserID;
+    field.InstrumentID = input.InstrumentID;
+    field.ExchangeID = input.ExchangeID;
+    field.OrderRef = input.OrderRef;
+    field.OrderPriceType = input.OrderPriceType;
+    field.Direction = input.Direction;
+    field.CombOffsetFlag = input.CombOffsetFlag;
+    field.CombHedgeFlag = input.CombHedgeFlag;
+    field.LimitPrice = input.LimitPrice;
+    field.VolumeTotalOriginal = input.VolumeTotalOriginal;
+    field.TimeCondition = input.TimeCondition;
+    field.GTDDate = input.GTDDate;
+    field.VolumeCondition = input.VolumeCondition;
+    field.MinVolume = input.MinVolume;
+    field.ForceCloseReason = input.ForceCloseReason;
+    field.IsAutoSuspend = input.IsAutoSuspend;
+    field.UserForceClose = input.UserForceClose;
+    field.IsSwapOrder = input.IsSwapOrder;
+    match Trigger::from_cc(input.ContingentCondition, input.StopPrice) {
+        Some(Trigger::Immediately) | None => {
+            field.ContingentCondition = THOST_FTDC_CC_ParkedOrder as _;
+        }
+        Some(trigger) => {
+            field.ContingentCondition = trigger.code() as _;
+            field.StopPrice = trigger.stop_price();
+        }
+    }
+    field
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ParkedStatus {
+    /// Sent, not acknowledged yet.
+    Pending,
+    /// Held by the broker, `THOST_FTDC_PAOS_NotSend`.
+    Parked,
+    /// Sent to the exchange, `THOST_FTDC_PAOS_Send`.
+    Sent,
+    /// Removed, `THOST_FTDC_PAOS_Deleted`.
+    Removed,
+    /// Refused, see the `error`.
+    Failed,
+}
+
+impl ParkedStatus {
+    #[allow(non_upper_case_globals)]
+    fn from_paos(status: c_char) -> Option<Self> {
+        match status as u8 {
+            THOST_FTDC_PAOS_NotSend => Some(Self::Parked),
+            THOST_FTDC_PAOS_Send => Some(Self::Sent),
+            THOST_FTDC_PAOS_Deleted => Some(Self::Removed),
+            _ => None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Sent | Self::Removed | Self::Failed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParkedOrder {
+    /// Empty until the broker acknowledges the order.
+    pub parked_order_id: String,
+    pub order_ref: String,
+    pub instrument_id: String,
+    pub exchange_id: String,
+    pub direction: u8,
+    pub offset: u8,
+    pub price: f64,
+    pub volume: i32,
+    pub trigger: Option<Trigger>,
+    pub status: ParkedStatus,
+    pub error: Option<String>,
+}
+
+impl ParkedOrder {
+    fn new(field: &CThostFtdcParkedOrderField) -> Self {
+        Self {
+            parked_order_id: to_string(&field.ParkedOrderID),
+            order_ref: to_string(&field.OrderRef),
+            instrument_id: to_string(&field.InstrumentID),
+            exchange_id: to_string(&field.ExchangeID),
+            direction: field.Direction as u8,
+            offset: field.CombOffsetFlag[0] as u8,
+            price: field.LimitPrice,
+            volume: field.VolumeTotalOriginal,
+            trigger: Trigger::from_cc(field.ContingentCondition, field.StopPrice),
+            status: ParkedStatus::Pending,
+            error: None,
+        }
+    }
+}
+
+/// A parked cancel of a live order.
+#[derive(Debug, Clone)]
+pub struct ParkedAction {
+    /// Empty until the broker acknowledges the action.
+    pub parked_order_action_id: String,
+    pub order_ref: String,
+    pub exchange_id: String,
+    pub order_sys_id: String,
+    pub instrument_id: String,
+    pub status: ParkedStatus,
+    pub error: Option<String>,
+}
+
+impl ParkedAction {
+    fn new(field: &CThostFtdcParkedOrderActionField) -> Self {
+        Self {
+            parked_order_action_id: to_string(&field.ParkedOrderActionID),
+            order_ref: to_string(&field.OrderRef),
+            exchange_id: to_string(&field.ExchangeID),
+            order_sys_id: to_string(&field.OrderSysID),
+            instrument_id: to_string(&field.InstrumentID),
+            status: ParkedStatus::Pending,
+            error: None,
+        }
+    }
+
+    fn same(&self, field: &CThostFtdcParkedOrderActionField) -> bool {
+        let id = to_string(&field.ParkedOrderActionID);
+        if !self.parked_order_action_id.is_empty() || id.is_empty() {
+            return self.parked_order_action_id == id;
+        }
+        self.order_ref == to_string(&field.OrderRef) && self.order_sys_id == to_string(&field.OrderSysID)
+    }
+}
+
+/// Parked orders and actions of the investor, fed from the trader spi.
+#[derive(Debug, Default)]
+pub struct ParkedOrders {
+    orders: Vec<ParkedOrder>,
+    actions: Vec<ParkedAction>,
+}
+
+impl ParkedOrders {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Track a parked order about to be sent, it needs an `OrderRef`.
+    pub fn insert(&mut self, field: &CThostFtdcParkedOrderField) {
+        self.orders.push(ParkedOrder::new(field));
+    }
+
+    /// Track a parked action about to be sent.
+    pub fn insert_action(&mut self, field: &CThostFtdcParkedOrderActionField) {
+        self.actions.push(ParkedAction::new(field));
+    }
+
+    /// `OnRspParkedOrderInsert`, the order is parked or refused.
+    pub fn on_rsp_parked_order_insert(&mut self, field: &CThostFtdcParkedOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&ParkedOrder> {
+        let i = self.order_index(field)?;
+        let order = &mut self.orders[i];
+        match rsp_error(info) {
+            Some(error) => {
+                warn!("parked order {} refused: {}", order.order_ref, error);
+                order.status = ParkedStatus::Failed;
+                order.error = Some(error);
+            }
+            None => update(order, field),
+        }
+        Some(order)
+    }
+
+    /// `OnRspParkedOrderAction`.
+    pub fn on_rsp_parked_order_action(&mut self, field: &CThostFtdcParkedOrderActionField, info: Option<&CThostFtdcRspInfoField>) -> Option<&ParkedAction> {
+        let action = self.actions.iter_mut().find(|a| a.same(field))?;
+        match rsp_error(info) {
+            Some(error) => {
+                warn!("parked action on {} refused: {}", action.order_ref, error);
+                action.status = ParkedStatus::Failed;
+                action.error = Some(error);
+            }
+            None => update_action(action, field),
+        }
+        Some(action)
+    }
+
+    /// A request to remove a parked order still held by the broker.
+    pub fn remove(&self, parked_order_id: &str) -> Option<CThostFtdcRemoveParkedOrderField> {
+        self.orders.iter().find(|o| o.parked_order_id == parked_order_id && o.status == ParkedStatus::Parked)?;
+        let mut field: CThostFtdcRemoveParkedOrderField = zeroed();
+        set_string(&mut field.ParkedOrderID, parked_order_id);
+        Some(field)
+    }
+
+    pub fn remove_action(&self, parked_order_action_id: &str) -> Option<CThostFtdcRemoveParkedOrderActionField> {
+        self.actions
+            .iter()
+            .find(|a| a.parked_order_action_id == parked_order_action_id && a.status == ParkedStatus::Parked)?;
+        let mut field: CThostFtdcRemoveParkedOrderActionField = zeroed();
+        set_string(&mut field.ParkedOrderActionID, parked_order_action_id);
+        Some(field)
+    }
+
+    /// `OnRspRemoveParkedOrder`, a refused removal leaves the order parked.
+    pub fn on_rsp_remove_parked_order(&mut self, field: &CThostFtdcRemoveParkedOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&ParkedOrder> {
+        let id = to_string(&field.ParkedOrderID);
+        let order = self.orders.iter_mut().find(|o| o.parked_order_id == id)?;
+        match rsp_error(info) {
+            Some(error) => {
+                warn!("removal of parked order {} refused: {}", id, error);
+                order.error = Some(error);
+            }
+            None => order.status = ParkedStatus::Removed,
+        }
+        Some(order)
+    }
+
+    pub fn on_rsp_remove_parked_order_action(
+        &mut self,
+        field: &CThostFtdcRemoveParkedOrderActionField,
+        info: Option<&CThostFtdcRspInfoField>,
+    ) -> Option<&ParkedAction> {
+        let id = to_string(&field.ParkedOrderActionID);
+        let action = self.actions.iter_mut().find(|a| a.parked_order_action_id == id)?;
+        match rsp_error(info) {
+            Some(error) => {
+                warn!("removal of parked action {} refused: {}", id, error);
+                action.error = Some(error);
+            }
+            None => action.status = ParkedStatus::Removed,
+        }
+        Some(action)
+    }
+
+    /// A row of `ReqQryParkedOrder`, orders parked elsewhere are added.
+    pub fn on_rsp_qry_parked_order(&mut self, field: &CThostFtdcParkedOrderField) {
+        let i = match self.order_index(field) {
+            Some(i) => i,
+            None => {
+                self.orders.push(ParkedOrder::new(field));
+                self.orders.len() - 1
+            }
+        };
+        update(&mut self.orders[i], field);
+    }
+
+    pub fn on_rsp_qry_parked_order_action(&mut self, field: &CThostFtdcParkedOrderActionField) {
+        let i = match self.actions.iter().position(|a| a.same(field)) {
+            Some(i) => i,
+            None => {
+                self.actions.push(ParkedAction::new(field));
+                self.actions.len() - 1
+            }
+        };
+        update_action(&mut self.actions[i], field);
+    }
+
+    /// An order showing up with the `OrderRef` of a parked one means the
+    /// broker sent it.
+    pub fn on_rtn_order(&mut self, rtn: &CThostFtdcOrderField) -> Option<&ParkedOrder> {
+        let (order_ref, instrument) = (to_string(&rtn.OrderRef), to_string(&rtn.InstrumentID));
+        let order = self
+            .orders
+            .iter_mut()
+            .find(|o| o.status == ParkedStatus::Parked && o.order_ref == order_ref && o.instrument_id == instrument)?;
+        debug!("parked order {} sent", order.parked_order_id);
+        order.status = ParkedStatus::Sent;
+        Some(order)
+    }
+
+    pub fn get(&self, parked_order_id: &str) -> Option<&ParkedOrder> {
+        self.orders.iter().find(|o| o.parked_order_id == parked_order_id)
+    }
+
+    pub fn orders(&self) -> impl Iterator<Item = &ParkedOrder> {
+        self.orders.iter()
+    }
+
+    /// Orders still waiting at the broker, or for its answer.
+    pub fn waiting(&self) -> impl Iterator<Item = &ParkedOrder> {
+        self.orders.iter().filter(|o| !o.status.is_finished())
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &ParkedAction> {
+        self.actions.iter()
+    }
+
+    fn order_index(&self, field: &CThostFtdcParkedOrderField) -> Option<usize> {
+        let id = to_string(&field.ParkedOrderID);
+        if !id.is_empty() {
+            if let Some(i) = self.orders.iter().position(|o| o.parked_order_id == id) {
+                return Some(i);
+            }
+        }
+        // not acknowledged yet, known by its OrderRef
+        let order_ref = to_string(&field.OrderRef);
+        self.orders
+            .iter()
+            .position(|o| o.parked_order_id.is_empty() && o.order_ref == order_ref && !order_ref.is_empty())
+    }
+}
+
+fn update(order: &mut ParkedOrder, field: &CThostFtdcParkedOrderField) {
+    let id = to_string(&field.ParkedOrderID);
+    if !id.is_empty() {
+        order.parked_order_id = id;
+    }
+    if field.ErrorID != 0 {
+        order.status = ParkedStatus::Failed;
+        order.error = Some(format!("{} {}", field.ErrorID, gbk_string(&field.ErrorMsg)));
+    } else if let Some(status) = ParkedStatus::from_paos(field.Status) {
+        order.status = status;
+    }
+}
+
+fn update_action(action: &mut ParkedAction, field: &CThostFtdcParkedOrderActionField) {
+    let id = to_string(&field.ParkedOrderActionID);
+    if !id.is_empty() {
+        action.parked_order_action_id = id;
+    }
+    if field.ErrorID != 0 {
+        action.status = ParkedStatus::Failed;
+        action.error = Some(format!("{} {}", field.ErrorID, gbk_string(&field.ErrorMsg)));
+    } else if let Some(status) = ParkedStatus::from_paos(field.Status) {
+        action.status = status;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(last: f64, bid: f64, ask: f64) -> CThostFtdcDepthMarketDataField {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        tick.LastPrice = last;
+        tick.BidPrice1 = bid;
+        tick.AskPrice1 = ask;
+        tick
+    }
+
+    fn input() -> CThostFtdcInputOrderField {
+        let mut input: CThostFtdcInputOrderField = zeroed();
+        set_string(&mut input.InstrumentID, "rb2501");
+        set_string(&mut input.ExchangeID, "SHFE");
+        set_string(&mut input.OrderRef, "12");
+        input.Direction = THOST_FTDC_D_Buy as _;
+        input.CombOffsetFlag[0] = THOST_FTDC_OF_Open as _;
+        input.LimitPrice = 3500.0;
+        input.VolumeTotalOriginal = 2;
+        input
+    }
+
+    fn parked(id: &str, status: u8) -> CThostFtdcParkedOrderField {
+        let mut field = parked_order(&input());
+        set_string(&mut field.ParkedOrderID, id);
+        field.Status = status as _;
+        field
+    }
+
+    #[test]
+    fn triggers_round_trip_their_codes() {
+        use Compare::*;
+        let triggers = [
+            Trigger::Immediately,
+            Trigger::Touch(3400.0),
+            Trigger::TouchProfit(3600.0),
+            Trigger::Parked,
+            Trigger::LastPrice(Greater, 1.0),
+            Trigger::LastPrice(GreaterEqual, 2.0),
+            Trigger::LastPrice(Lesser, 3.0),
+            Trigger::LastPrice(LesserEqual, 4.0),
+            Trigger::AskPrice(Greater, 5.0),
+            Trigger::AskPrice(GreaterEqual, 6.0),
+            Trigger::AskPrice(Lesser, 7.0),
+            Trigger::AskPrice(LesserEqual, 8.0),
+            Trigger::BidPrice(Greater, 9.0),
+            Trigger::BidPrice(GreaterEqual, 10.0),
+            Trigger::BidPrice(Lesser, 11.0),
+            Trigger::BidPrice(LesserEqual, 12.0),
+        ];
+        for trigger in triggers {
+            let mut input = input();
+            trigger.apply(&mut input);
+            assert_eq!(Trigger::from_cc(input.ContingentCondition, input.StopPrice), Some(trigger));
+        }
+        assert_eq!(Trigger::Parked.stop_price(), 0.0);
+        assert_eq!(Trigger::from_cc(b'z' as _, 1.0), None);
+    }
+
+    #[test]
+    fn price_conditions_hold_on_valid_prices() {
+        use Compare::*;
+        let tick = tick(3500.0, 3499.0, f64::MAX);
+        assert_eq!(Trigger::LastPrice(GreaterEqual, 3500.0).holds(&tick), Some(true));
+        assert_eq!(Trigger::LastPrice(Greater, 3500.0).holds(&tick), Some(false));
+        assert_eq!(Trigger::BidPrice(Lesser, 3500.0).holds(&tick), Some(true));
+        assert_eq!(Trigger::BidPrice(LesserEqual, 3498.0).holds(&tick), Some(false));
+        // no ask on the tick
+        assert_eq!(Trigger::AskPrice(Greater, 0.0).holds(&tick), Some(false));
+        assert_eq!(Trigger::Touch(3400.0).holds(&tick), None);
+    }
+
+    #[test]
+    fn parked_orders_default_to_the_session_open() {
+        let field = parked_order(&input());
+        assert_eq!(field.ContingentCondition as u8, THOST_FTDC_CC_ParkedOrder);
+        assert_eq!(field.StopPrice, 0.0);
+        assert_eq!(to_string(&field.OrderRef), "12");
+        assert_eq!(field.VolumeTotalOriginal, 2);
+
+        let mut stop = input();
+        Trigger::LastPrice(Compare::LesserEqual, 3400.0).apply(&mut stop);
+        let field = parked_order(&stop);
+        assert_eq!(field.ContingentCondition as u8, THOST_FTDC_CC_LastPriceLesserEqualStopPrice);
+        assert_eq!(field.StopPrice, 3400.0);
+    }
+
+    #[test]
+    fn parked_then_sent() {
+        let mut parked_orders = ParkedOrders::new();
+        parked_orders.insert(&parked_order(&input()));
+        assert_eq!(parked_orders.waiting().next().unwrap().status, ParkedStatus::Pending);
+
+        let order = parked_orders.on_rsp_parked_order_insert(&parked("P1", THOST_FTDC_PAOS_NotSend), None).unwrap();
+        assert_eq!((order.parked_order_id.as_str(), order.status), ("P1", ParkedStatus::Parked));
+
+        let mut rtn: CThostFtdcOrderField = zeroed();
+        set_string(&mut rtn.OrderRef, "12");
+        set_string(&mut rtn.InstrumentID, "rb2501");
+        assert_eq!(parked_orders.on_rtn_order(&rtn).unwrap().status, ParkedStatus::Sent);
+        assert!(parked_orders.remove("P1").is_none());
+        assert_eq!(parked_orders.waiting().count(), 0);
+    }
+
+    #[test]
+    fn parked_then_removed() {
+        let mut parked_orders = ParkedOrders::new();
+        parked_orders.insert(&parked_order(&input()));
+        parked_orders.on_rsp_parked_order_insert(&parked("P1", THOST_FTDC_PAOS_NotSend), None);
+        let remove = parked_orders.remove("P1").unwrap();
+
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        info.ErrorID = 1;
+        set_string(&mut info.ErrorMsg, "busy");
+        let order = parked_orders.on_rsp_remove_parked_order(&remove, Some(&info)).unwrap();
+        assert_eq!((order.status, order.error.as_deref()), (ParkedStatus::Parked, Some("1 busy")));
+
+        let order = parked_orders.on_rsp_remove_parked_order(&remove, None).unwrap();
+        assert_eq!(order.status, ParkedStatus::Removed);
+    }
+
+    #[test]
+    fn refused_or_failed_parked_orders() {
+        let mut parked_orders = ParkedOrders::new();
+        parked_orders.insert(&parked_order(&input()));
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        info.ErrorID = 15;
+        set_string(&mut info.ErrorMsg, "no such instrument");
+        let order = parked_orders.on_rsp_parked_order_insert(&parked("", 0), Some(&info)).unwrap();
+        assert_eq!((order.status, order.error.as_deref()), (ParkedStatus::Failed, Some("15 no such instrument")));
+
+        // parked elsewhere, failed at the open
+        let mut row = parked("P9", THOST_FTDC_PAOS_NotSend);
+        set_string(&mut row.OrderRef, "40");
+        row.ErrorID = 31;
+        set_string(&mut row.ErrorMsg, "no funds");
+        parked_orders.on_rsp_qry_parked_order(&row);
+        let order = parked_orders.get("P9").unwrap();
+        assert_eq!((order.status, order.error.as_deref()), (ParkedStatus::Failed, Some("31 no funds")));
+        assert_eq!(parked_orders.orders().count(), 2);
+    }
+
+    #[test]
+    fn parked_actions() {
+        let mut parked_orders = ParkedOrders::new();
+        let mut field: CThostFtdcParkedOrderActionField = zeroed();
+        set_string(&mut field.OrderRef, "12");
+        set_string(&mut field.OrderSysID, "  1001");
+        parked_orders.insert_action(&field);
+
+        set_string(&mut field.ParkedOrderActionID, "A1");
+        field.Status = THOST_FTDC_PAOS_NotSend as _;
+        let action = parked_orders.on_rsp_parked_order_action(&field, None).unwrap();
+        assert_eq!((action.parked_order_action_id.as_str(), action.status), ("A1", ParkedStatus::Parked));
+
+        let remove = parked_orders.remove_action("A1").unwrap();
+        let action = parked_orders.on_rsp_remove_parked_order_action(&remove, None).unwrap();
+        assert_eq!(action.status, ParkedStatus::Removed);
+        assert!(parked_orders.remove_action("A1").is_none());
+    }
+}
@@ -0,0 +1,783 @@
+//! Option exercise (行权), abandon (放弃行权) and self-close (对冲) instructions.
+//!
+//! Both kinds are known by `(FrontID, SessionID, ref)` like orders, the ref
+//! being `ExecOrderRef` or `OptionSelfCloseRef`, and by their sys id once the
+//! exchange accepts them. An accepted exercise stays `Accepted` until the
+//! exchange runs it at expiry, `ExecResult` then tells how it went.
+
+use super::order::{rsp_error, OrderKey};
+use super::position::PosiDirection;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::os::raw::c_char;
+
+use log::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ExecAction {
+    Exercise,
+    Abandon,
+}
+
+impl ExecAction {
+    /// `THOST_FTDC_ACTP_*`.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Exercise => THOST_FTDC_ACTP_Exec,
+            Self::Abandon => THOST_FTDC_ACTP_Abandon,
+        }
+    }
+}
+
+/// What a self-close instruction does, `THOST_FTDC_OSCF_*`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SelfCloseFlag {
+    /// Close the opposite option positions against each other.
+    CloseSelfOption,
+    ReserveOption,
+    /// Close the futures positions the exercise opens against each other.
+    CloseSelfFuture,
+    ReserveFuture,
+}
+
+impl SelfCloseFlag {
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::CloseSelfOption => THOST_FTDC_OSCF_CloseSelfOptionPosition,
+            Self::ReserveOption => THOST_FTDC_OSCF_ReserveOptionPosition,
+            Self::CloseSelfFuture => THOST_FTDC_OSCF_SellCloseSelfFuturePosition,
+            Self::ReserveFuture => THOST_FTDC_OSCF_ReserveFuturePosition,
+        }
+    }
+}
+
+/// An exercise or abandon instruction, on the long side, speculation and
+/// neither reserving nor closing the resulting position by default.
+#[derive(Debug, Clone)]
+pub struct ExecOrderBuilder {
+    instrument_id: String,
+    exchange_id: String,
+    volume: i32,
+    action: ExecAction,
+    offset: u8,
+    hedge_flag: u8,
+    direction: PosiDirection,
+    reserve: bool,
+    close: bool,
+}
+
+impl ExecOrderBuilder {
+    pub fn new(action: ExecAction, instrument_id: &str, exchange_id: &str, volume: i32) -> Self {
+        Self {
+            instrument_id: instrument_id.to_string(),
+            exchange_id: exchange_id.to_string(),
+            volume,
+            action,
+            offset: THOST_FTDC_OF_Close,
+            hedge_flag: THOST_FTDC_HF_Speculation,
+            direction: PosiDirection::Long,
+            reserve: false,
+            close: false,
+        }
+    }
+
+    pub fn exercise(instrument_id: &str, exchange_id: &str, volume: i32) -> Self {
+        Self::new(ExecAction::Exercise, instrument_id, exchange_id, volume)
+    }
+
+    pub fn abandon(instrument_id: &str, exchange_id: &str, volume: i32) -> Self {
+        Self::new(ExecAction::Abandon, instrument_id, exchange_id, volume)
+    }
+
+    /// `THOST_FTDC_OF_*`, SHFE tells today's positions with `CloseToday`.
+    pub fn offset(mut self, offset: u8) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// `THOST_FTDC_HF_*`.
+    pub fn hedge_flag(mut self, hedge_flag: u8) -> Self {
+        self.hedge_flag = hedge_flag;
+        self
+    }
+
+    pub fn direction(mut self, direction: PosiDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Keep the option position once exercised.
+    pub fn reserve_position(mut self, reserve: bool) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// Close the futures position the exercise opens.
+    pub fn close_future(mut self, close: bool) -> Self {
+        self.close = close;
+        self
+    }
+
+    /// The request, to go through [`ExecOrderBook::insert_exec`] and
+    /// `TdApi::req_exec_order_insert`.
+    pub fn build(&self) -> CThostFtdcInputExecOrderField {
+        let mut field: CThostFtdcInputExecOrderField = zeroed();
+        set_string(&mut field.InstrumentID, &self.instrument_id);
+        set_string(&mut field.ExchangeID, &self.exchange_id);
+        field.Volume = self.volume;
+        field.ActionType = self.action.code() as _;
+        field.OffsetFlag = self.offset as _;
+        field.HedgeFlag = self.hedge_flag as _;
+        field.PosiDirection = match self.direction {
+            PosiDirection::Long => THOST_FTDC_PD_Long,
+            PosiDirection::Short => THOST_FTDC_PD_Short,
+        } as _;
+        field.ReservePositionFlag = if self.reserve { THOST_FTDC_EOPF_Reserve } else { THOST_FTDC_EOPF_UnReserve } as _;
+        field.CloseFlag = if self.close { THOST_FTDC_EOCF_AutoClose } else { THOST_FTDC_EOCF_NotToClose } as _;
+        field
+    }
+}
+
+/// A self-close instruction, speculation by default.
+#[derive(Debug, Clone)]
+pub struct SelfCloseBuilder {
+    instrument_id: String,
+    exchange_id: String,
+    volume: i32,
+    flag: SelfCloseFlag,
+    hedge_flag: u8,
+}
+
+impl SelfCloseBuilder {
+    pub fn new(instrument_id: &str, exchange_id: &str, volume: i32, flag: SelfCloseFlag) -> Self {
+        Self {
+            instrument_id: instrument_id.to_string(),
+            exchange_id: exchange_id.to_string(),
+            volume,
+            flag,
+            hedge_flag: THOST_FTDC_HF_Speculation,
+        }
+    }
+
+    /// `THOST_FTDC_HF_*`.
+    pub fn hedge_flag(mut self, hedge_flag: u8) -> Self {
+        self.hedge_flag = hedge_flag;
+        self
+    }
+
+    pub fn build(&self) -> CThostFtdcInputOptionSelfCloseField {
+        let mut field: CThostFtdcInputOptionSelfCloseField = zeroed();
+        set_string(&mut field.InstrumentID, &self.instrument_id);
+        set_string(&mut field.ExchangeID, &self.exchange_id);
+        field.Volume = self.volume;
+        field.HedgeFlag = self.hedge_flag as _;
+        field.OptSelfCloseFlag = self.flag.code() as _;
+        field
+    }
+}
+
+/// `THOST_FTDC_OER_*`, plus the local states before the first return.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ExecStatus {
+    /// Sent, nothing heard back yet.
+    Pending,
+    /// Refused by CTP or the exchange, see [`Instruction::reject_reason`].
+    Rejected,
+    /// Accepted, waiting to be run by the exchange.
+    Accepted,
+    Executed,
+    Canceled,
+    /// Run and failed, with the reason from `ExecResult`.
+    Failed(String),
+}
+
+impl ExecStatus {
+    /// `None` for `Unknown`.
+    #[allow(non_upper_case_globals)]
+    pub fn from_oer(result: c_char) -> Option<Self> {
+        Some(match result as u8 {
+            THOST_FTDC_OER_NoExec => Self::Accepted,
+            THOST_FTDC_OER_Canceled => Self::Canceled,
+            THOST_FTDC_OER_OK => Self::Executed,
+            THOST_FTDC_OER_NoPosition => Self::Failed("no position".into()),
+            THOST_FTDC_OER_NoDeposit => Self::Failed("no deposit".into()),
+            THOST_FTDC_OER_NoParticipant => Self::Failed("no participant".into()),
+            THOST_FTDC_OER_NoClient => Self::Failed("no client".into()),
+            THOST_FTDC_OER_NoInstrument => Self::Failed("no instrument".into()),
+            THOST_FTDC_OER_NoRight => Self::Failed("no right".into()),
+            THOST_FTDC_OER_InvalidVolume => Self::Failed("invalid volume".into()),
+            THOST_FTDC_OER_NoEnoughHistoryTrade => Self::Failed("not enough history trade".into()),
+            _ => return None,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, Self::Pending | Self::Accepted)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum InstructionKind {
+    Exec(ExecAction),
+    SelfClose(SelfCloseFlag),
+}
+
+/// An exercise, abandon or self-close instruction.
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    /// `order_ref` is `ExecOrderRef` or `OptionSelfCloseRef`.
+    pub key: OrderKey,
+    pub kind: InstructionKind,
+    pub exchange_id: String,
+    pub instrument_id: String,
+    /// `ExecOrderSysID` or `OptionSelfCloseSysID`, empty until accepted.
+    pub sys_id: String,
+    pub volume: i32,
+    pub hedge_flag: u8,
+    pub status: ExecStatus,
+    pub reject_reason: Option<String>,
+    /// Reason of the latest refused cancel.
+    pub cancel_error: Option<String>,
+    pub status_msg: String,
+    pub insert_time: String,
+}
+
+impl Instruction {
+    fn new(key: OrderKey, kind: InstructionKind) -> Self {
+        Self {
+            key,
+            kind,
+            exchange_id: String::new(),
+            instrument_id: String::new(),
+            sys_id: String::new(),
+            volume: 0,
+            hedge_flag: 0,
+            status: ExecStatus::Pending,
+            reject_reason: None,
+            cancel_error: None,
+            status_msg: String::new(),
+            insert_time: String::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.status.is_finished()
+    }
+
+    fn update(&mut self, submit_status: c_char, result: c_char, sys_id: String, status_msg: String, insert_time: String) {
+        self.status_msg = status_msg;
+        self.insert_time = insert_time;
+        if !sys_id.is_empty() {
+            self.sys_id = sys_id;
+        }
+        if submit_status as u8 == THOST_FTDC_OSS_InsertRejected {
+            self.status = ExecStatus::Rejected;
+            self.reject_reason = Some(self.status_msg.clone());
+        } else if let Some(status) = ExecStatus::from_oer(result) {
+            // a late return must not revive a finished instruction
+            if !self.status.is_finished() || status.is_finished() {
+                self.status = status;
+            }
+        }
+    }
+}
+
+/// Exercise and self-close instructions of one trading session, fed from the
+/// trader spi. Query rows go through the same feeds as the returns.
+#[derive(Debug, Default)]
+pub struct ExecOrderBook {
+    front_id: i32,
+    session_id: i32,
+    exec_ref: i32,
+    self_close_ref: i32,
+    execs: HashMap<OrderKey, Instruction>,
+    self_closes: HashMap<OrderKey, Instruction>,
+}
+
+impl ExecOrderBook {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Take `FrontID`, `SessionID` and continue both refs after `MaxOrderRef`.
+    pub fn on_rsp_user_login(&mut self, login: &CThostFtdcRspUserLoginField) {
+        self.front_id = login.FrontID;
+        self.session_id = login.SessionID;
+        self.exec_ref = to_string(&login.MaxOrderRef).trim().parse().unwrap_or(0);
+        self.self_close_ref = self.exec_ref;
+    }
+
+    /// Track an exercise or abandon about to be sent, its `ExecOrderRef` is
+    /// allocated when empty.
+    pub fn insert_exec(&mut self, input: &mut CThostFtdcInputExecOrderField) -> OrderKey {
+        if to_string(&input.ExecOrderRef).is_empty() {
+            self.exec_ref += 1;
+            set_string(&mut input.ExecOrderRef, &self.exec_ref.to_string());
+        }
+        let key = self.own_key(&input.ExecOrderRef);
+        let exec = self.execs.entry(key.clone()).or_insert_with(|| Instruction::new(key.clone(), exec_kind(input.ActionType)));
+        set_exec_input(exec, input);
+        key
+    }
+
+    /// Track a self-close about to be sent, its `OptionSelfCloseRef` is
+    /// allocated when empty.
+    pub fn insert_self_close(&mut self, input: &mut CThostFtdcInputOptionSelfCloseField) -> OrderKey {
+        if to_string(&input.OptionSelfCloseRef).is_empty() {
+            self.self_close_ref += 1;
+            set_string(&mut input.OptionSelfCloseRef, &self.self_close_ref.to_string());
+        }
+        let key = self.own_key(&input.OptionSelfCloseRef);
+        let kind = self_close_kind(input.OptSelfCloseFlag);
+        let instruction = self.self_closes.entry(key.clone()).or_insert_with(|| Instruction::new(key.clone(), kind));
+        set_self_close_input(instruction, input);
+        key
+    }
+
+    /// A cancel request for the exercise `key`, to complete with broker and
+    /// investor ids.
+    pub fn cancel_exec(&self, key: &OrderKey) -> Option<CThostFtdcInputExecOrderActionField> {
+        let exec = self.execs.get(key).filter(|e| e.is_active())?;
+        let mut action: CThostFtdcInputExecOrderActionField = zeroed();
+        action.FrontID = key.front_id;
+        action.SessionID = key.session_id;
+        set_string(&mut action.ExecOrderRef, &key.order_ref);
+        set_string(&mut action.ExchangeID, &exec.exchange_id);
+        set_string(&mut action.ExecOrderSysID, &exec.sys_id);
+        set_string(&mut action.InstrumentID, &exec.instrument_id);
+        action.ActionFlag = THOST_FTDC_AF_Delete as _;
+        Some(action)
+    }
+
+    pub fn cancel_self_close(&self, key: &OrderKey) -> Option<CThostFtdcInputOptionSelfCloseActionField> {
+        let instruction = self.self_closes.get(key).filter(|e| e.is_active())?;
+        let mut action: CThostFtdcInputOptionSelfCloseActionField = zeroed();
+        action.FrontID = key.front_id;
+        action.SessionID = key.session_id;
+        set_string(&mut action.OptionSelfCloseRef, &key.order_ref);
+        set_string(&mut action.ExchangeID, &instruction.exchange_id);
+        set_string(&mut action.OptionSelfCloseSysID, &instruction.sys_id);
+        set_string(&mut action.InstrumentID, &instruction.instrument_id);
+        action.ActionFlag = THOST_FTDC_AF_Delete as _;
+        Some(action)
+    }
+
+    /// `OnRspExecOrderInsert`, only called when CTP refuses the instruction.
+    pub fn on_rsp_exec_order_insert(&mut self, input: &CThostFtdcInputExecOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        self.reject_exec(input, info)
+    }
+
+    /// `OnErrRtnExecOrderInsert`, the exchange refused the instruction.
+    pub fn on_err_rtn_exec_order_insert(&mut self, input: &CThostFtdcInputExecOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        self.reject_exec(input, info)
+    }
+
+    /// `OnRtnExecOrder`, and the rows of `OnRspQryExecOrder`.
+    pub fn on_rtn_exec_order(&mut self, rtn: &CThostFtdcExecOrderField) -> Option<&Instruction> {
+        let key = OrderKey {
+            front_id: rtn.FrontID,
+            session_id: rtn.SessionID,
+            order_ref: to_string(&rtn.ExecOrderRef),
+        };
+        let exec = self.execs.entry(key.clone()).or_insert_with(|| Instruction::new(key.clone(), exec_kind(rtn.ActionType)));
+        exec.exchange_id = to_string(&rtn.ExchangeID);
+        exec.instrument_id = to_string(&rtn.InstrumentID);
+        exec.volume = rtn.Volume;
+        exec.hedge_flag = rtn.HedgeFlag as u8;
+        exec.update(
+            rtn.OrderSubmitStatus,
+            rtn.ExecResult,
+            to_string(&rtn.ExecOrderSysID),
+            gbk_string(&rtn.StatusMsg),
+            to_string(&rtn.InsertTime),
+        );
+        debug!("exec order {:?} {:?}", key, exec.status);
+        Some(exec)
+    }
+
+    /// `OnRspExecOrderAction`, only called when CTP refuses the cancel.
+    pub fn on_rsp_exec_order_action(&mut self, action: &CThostFtdcInputExecOrderActionField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        let key = action_key(&self.execs, action.FrontID, action.SessionID, &action.ExecOrderRef, &action.ExchangeID, &action.ExecOrderSysID)?;
+        cancel_failed(&mut self.execs, &key, info)
+    }
+
+    /// `OnErrRtnExecOrderAction`, the exchange refused the cancel.
+    pub fn on_err_rtn_exec_order_action(&mut self, action: &CThostFtdcExecOrderActionField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        let key = action_key(&self.execs, action.FrontID, action.SessionID, &action.ExecOrderRef, &action.ExchangeID, &action.ExecOrderSysID)?;
+        cancel_failed(&mut self.execs, &key, info)
+    }
+
+    /// `OnRspOptionSelfCloseInsert`, only called when CTP refuses the instruction.
+    pub fn on_rsp_option_self_close_insert(&mut self, input: &CThostFtdcInputOptionSelfCloseField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        self.reject_self_close(input, info)
+    }
+
+    /// `OnErrRtnOptionSelfCloseInsert`, the exchange refused the instruction.
+    pub fn on_err_rtn_option_self_close_insert(&mut self, input: &CThostFtdcInputOptionSelfCloseField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        self.reject_self_close(input, info)
+    }
+
+    /// `OnRtnOptionSelfClose`, and the rows of `OnRspQryOptionSelfClose`.
+    pub fn on_rtn_option_self_close(&mut self, rtn: &CThostFtdcOptionSelfCloseField) -> Option<&Instruction> {
+        let key = OrderKey {
+            front_id: rtn.FrontID,
+            session_id: rtn.SessionID,
+            order_ref: to_string(&rtn.OptionSelfCloseRef),
+        };
+        let kind = self_close_kind(rtn.OptSelfCloseFlag);
+        let instruction = self.self_closes.entry(key.clone()).or_insert_with(|| Instruction::new(key.clone(), kind));
+        instruction.exchange_id = to_string(&rtn.ExchangeID);
+        instruction.instrument_id = to_string(&rtn.InstrumentID);
+        instruction.volume = rtn.Volume;
+        instruction.hedge_flag = rtn.HedgeFlag as u8;
+        instruction.update(
+            rtn.OrderSubmitStatus,
+            rtn.ExecResult,
+            to_string(&rtn.OptionSelfCloseSysID),
+            gbk_string(&rtn.StatusMsg),
+            to_string(&rtn.InsertTime),
+        );
+        debug!("self close {:?} {:?}", key, instruction.status);
+        Some(instruction)
+    }
+
+    /// `OnRspOptionSelfCloseAction`, only called when CTP refuses the cancel.
+    pub fn on_rsp_option_self_close_action(
+        &mut self,
+        action: &CThostFtdcInputOptionSelfCloseActionField,
+        info: Option<&CThostFtdcRspInfoField>,
+    ) -> Option<&Instruction> {
+        let key = action_key(
+            &self.self_closes,
+            action.FrontID,
+            action.SessionID,
+            &action.OptionSelfCloseRef,
+            &action.ExchangeID,
+            &action.OptionSelfCloseSysID,
+        )?;
+        cancel_failed(&mut self.self_closes, &key, info)
+    }
+
+    /// `OnErrRtnOptionSelfCloseAction`, the exchange refused the cancel.
+    pub fn on_err_rtn_option_self_close_action(
+        &mut self,
+        action: &CThostFtdcOptionSelfCloseActionField,
+        info: Option<&CThostFtdcRspInfoField>,
+    ) -> Option<&Instruction> {
+        let key = action_key(
+            &self.self_closes,
+            action.FrontID,
+            action.SessionID,
+            &action.OptionSelfCloseRef,
+            &action.ExchangeID,
+            &action.OptionSelfCloseSysID,
+        )?;
+        cancel_failed(&mut self.self_closes, &key, info)
+    }
+
+    /// `OnRspQryExecOrder`, one row per call.
+    pub fn on_rsp_qry_exec_order(&mut self, field: Option<&CThostFtdcExecOrderField>) {
+        if let Some(field) = field {
+            self.on_rtn_exec_order(field);
+        }
+    }
+
+    /// `OnRspQryOptionSelfClose`, one row per call.
+    pub fn on_rsp_qry_option_self_close(&mut self, field: Option<&CThostFtdcOptionSelfCloseField>) {
+        if let Some(field) = field {
+            self.on_rtn_option_self_close(field);
+        }
+    }
+
+    pub fn get_exec(&self, key: &OrderKey) -> Option<&Instruction> {
+        self.execs.get(key)
+    }
+
+    pub fn get_self_close(&self, key: &OrderKey) -> Option<&Instruction> {
+        self.self_closes.get(key)
+    }
+
+    /// Exercise and abandon instructions.
+    pub fn execs(&self) -> impl Iterator<Item = &Instruction> {
+        self.execs.values()
+    }
+
+    pub fn self_closes(&self) -> impl Iterator<Item = &Instruction> {
+        self.self_closes.values()
+    }
+
+    /// Instructions of both kinds not finished yet.
+    pub fn active(&self) -> impl Iterator<Item = &Instruction> {
+        self.execs.values().chain(self.self_closes.values()).filter(|i| i.is_active())
+    }
+
+    fn own_key(&self, order_ref: &[c_char]) -> OrderKey {
+        OrderKey {
+            front_id: self.front_id,
+            session_id: self.session_id,
+            order_ref: to_string(order_ref),
+        }
+    }
+
+    fn reject_exec(&mut self, input: &CThostFtdcInputExecOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        let key = self.own_key(&input.ExecOrderRef);
+        let exec = self.execs.entry(key.clone()).or_insert_with(|| Instruction::new(key.clone(), exec_kind(input.ActionType)));
+        if exec.volume == 0 {
+            set_exec_input(exec, input);
+        }
+        reject(exec, info);
+        Some(exec)
+    }
+
+    fn reject_self_close(&mut self, input: &CThostFtdcInputOptionSelfCloseField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Instruction> {
+        let key = self.own_key(&input.OptionSelfCloseRef);
+        let kind = self_close_kind(input.OptSelfCloseFlag);
+        let instruction = self.self_closes.entry(key.clone()).or_insert_with(|| Instruction::new(key.clone(), kind));
+        if instruction.volume == 0 {
+            set_self_close_input(instruction, input);
+        }
+        reject(instruction, info);
+        Some(instruction)
+    }
+}
+
+fn exec_kind(action_type: c_char) -> InstructionKind {
+    if action_type as u8 == THOST_FTDC_ACTP_Abandon {
+        InstructionKind::Exec(ExecAction::Abandon)
+    } else {
+        InstructionKind::Exec(ExecAction::Exercise)
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn self_close_kind(flag: c_char) -> InstructionKind {
+    InstructionKind::SelfClose(match flag as u8 {
+        THOST_FTDC_OSCF_ReserveOptionPosition => SelfCloseFlag::ReserveOption,
+        THOST_FTDC_OSCF_SellCloseSelfFuturePosition => SelfCloseFlag::CloseSelfFuture,
+        THOST_FTDC_OSCF_ReserveFuturePosition => SelfCloseFlag::ReserveFuture,
+        _ => SelfCloseFlag::CloseSelfOption,
+    })
+}
+
+fn set_exec_input(exec: &mut Instruction, input: &CThostFtdcInputExecOrderField) {
+    exec.exchange_id = to_string(&input.ExchangeID);
+    exec.instrument_id = to_string(&input.InstrumentID);
+    exec.volume = input.Volume;
+    exec.hedge_flag = input.HedgeFlag as u8;
+}
+
+fn set_self_close_input(instruction: &mut Instruction, input: &CThostFtdcInputOptionSelfCloseField) {
+    instruction.exchange_id = to_string(&input.ExchangeID);
+    instruction.instrument_id = to_string(&input.InstrumentID);
+    instruction.volume = input.Volume;
+    instruction.hedge_flag = input.HedgeFlag as u8;
+}
+
+fn reject(instruction: &mut Instruction, info: Option<&CThostFtdcRspInfoField>) {
+    instruction.status = ExecStatus::Rejected;
+    instruction.reject_reason = Some(rsp_error(info).unwrap_or_else(|| "rejected".into()));
+    warn!("{:?} {:?} rejected: {:?}", instruction.kind, instruction.key, instruction.reject_reason);
+}
+
+fn action_key(
+    instructions: &HashMap<OrderKey, Instruction>,
+    front_id: i32,
+    session_id: i32,
+    order_ref: &[c_char],
+    exchange_id: &[c_char],
+    sys_id: &[c_char],
+) -> Option<OrderKey> {
+    let key = OrderKey {
+        front_id,
+        session_id,
+        order_ref: to_string(order_ref),
+    };
+    if instructions.contains_key(&key) {
+        return Some(key);
+    }
+    let (exchange_id, sys_id) = (to_string(exchange_id), to_string(sys_id));
+    instructions
+        .values()
+        .find(|i| !sys_id.is_empty() && i.exchange_id == exchange_id && i.sys_id == sys_id)
+        .map(|i| i.key.clone())
+}
+
+fn cancel_failed<'a>(
+    instructions: &'a mut HashMap<OrderKey, Instruction>,
+    key: &OrderKey,
+    info: Option<&CThostFtdcRspInfoField>,
+) -> Option<&'a Instruction> {
+    let instruction = instructions.get_mut(key)?;
+    instruction.cancel_error = Some(rsp_error(info).unwrap_or_else(|| "cancel rejected".into()));
+    warn!("cancel of {:?} {:?} rejected: {:?}", instruction.kind, key, instruction.cancel_error);
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged_in() -> ExecOrderBook {
+        let mut login: CThostFtdcRspUserLoginField = zeroed();
+        login.FrontID = 1;
+        login.SessionID = 2;
+        set_string(&mut login.MaxOrderRef, "20");
+        let mut book = ExecOrderBook::new();
+        book.on_rsp_user_login(&login);
+        book
+    }
+
+    fn rtn(key: &OrderKey, sys_id: &str, result: u8) -> CThostFtdcExecOrderField {
+        let mut rtn: CThostFtdcExecOrderField = zeroed();
+        rtn.FrontID = key.front_id;
+        rtn.SessionID = key.session_id;
+        set_string(&mut rtn.ExecOrderRef, &key.order_ref);
+        set_string(&mut rtn.ExchangeID, "DCE");
+        set_string(&mut rtn.InstrumentID, "m2501-C-3000");
+        set_string(&mut rtn.ExecOrderSysID, sys_id);
+        rtn.ActionType = THOST_FTDC_ACTP_Exec as _;
+        rtn.Volume = 2;
+        rtn.OrderSubmitStatus = THOST_FTDC_OSS_Accepted as _;
+        rtn.ExecResult = result as _;
+        rtn
+    }
+
+    fn info(id: i32, msg: &str) -> CThostFtdcRspInfoField {
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        info.ErrorID = id;
+        set_string(&mut info.ErrorMsg, msg);
+        info
+    }
+
+    #[test]
+    fn builders_fill_the_requests() {
+        let field = ExecOrderBuilder::exercise("m2501-C-3000", "DCE", 2).build();
+        assert_eq!(to_string(&field.InstrumentID), "m2501-C-3000");
+        assert_eq!(field.Volume, 2);
+        assert_eq!(field.ActionType as u8, THOST_FTDC_ACTP_Exec);
+        assert_eq!(field.OffsetFlag as u8, THOST_FTDC_OF_Close);
+        assert_eq!(field.HedgeFlag as u8, THOST_FTDC_HF_Speculation);
+        assert_eq!(field.PosiDirection as u8, THOST_FTDC_PD_Long);
+        assert_eq!(field.ReservePositionFlag as u8, THOST_FTDC_EOPF_UnReserve);
+        assert_eq!(field.CloseFlag as u8, THOST_FTDC_EOCF_NotToClose);
+
+        let field = ExecOrderBuilder::abandon("au2502C600", "SHFE", 1)
+            .offset(THOST_FTDC_OF_CloseToday)
+            .hedge_flag(THOST_FTDC_HF_Hedge)
+            .direction(PosiDirection::Short)
+            .reserve_position(true)
+            .close_future(true)
+            .build();
+        assert_eq!(field.ActionType as u8, THOST_FTDC_ACTP_Abandon);
+        assert_eq!(field.OffsetFlag as u8, THOST_FTDC_OF_CloseToday);
+        assert_eq!(field.HedgeFlag as u8, THOST_FTDC_HF_Hedge);
+        assert_eq!(field.PosiDirection as u8, THOST_FTDC_PD_Short);
+        assert_eq!(field.ReservePositionFlag as u8, THOST_FTDC_EOPF_Reserve);
+        assert_eq!(field.CloseFlag as u8, THOST_FTDC_EOCF_AutoClose);
+
+        let field = SelfCloseBuilder::new("IO2501-C-4000", "CFFEX", 3, SelfCloseFlag::CloseSelfFuture).build();
+        assert_eq!(field.Volume, 3);
+        assert_eq!(field.HedgeFlag as u8, THOST_FTDC_HF_Speculation);
+        assert_eq!(field.OptSelfCloseFlag as u8, THOST_FTDC_OSCF_SellCloseSelfFuturePosition);
+    }
+
+    #[test]
+    fn status_follows_the_exec_result() {
+        let mut book = logged_in();
+        let mut input = ExecOrderBuilder::exercise("m2501-C-3000", "DCE", 2).build();
+        let key = book.insert_exec(&mut input);
+        assert_eq!(to_string(&input.ExecOrderRef), "21");
+        assert_eq!(book.get_exec(&key).unwrap().status, ExecStatus::Pending);
+
+        let exec = book.on_rtn_exec_order(&rtn(&key, "  E1", THOST_FTDC_OER_NoExec)).unwrap();
+        assert_eq!((exec.status.clone(), exec.sys_id.as_str()), (ExecStatus::Accepted, "  E1"));
+        assert_eq!(book.active().count(), 1);
+
+        let exec = book.on_rtn_exec_order(&rtn(&key, "", THOST_FTDC_OER_NoPosition)).unwrap();
+        assert_eq!(exec.status, ExecStatus::Failed("no position".into()));
+        assert_eq!(exec.sys_id, "  E1");
+        // a late return does not revive it
+        let exec = book.on_rtn_exec_order(&rtn(&key, "  E1", THOST_FTDC_OER_NoExec)).unwrap();
+        assert!(!exec.is_active());
+        assert!(book.cancel_exec(&key).is_none());
+
+        assert_eq!(ExecStatus::from_oer(THOST_FTDC_OER_OK as _), Some(ExecStatus::Executed));
+        assert_eq!(ExecStatus::from_oer(THOST_FTDC_OER_Unknown as _), None);
+    }
+
+    #[test]
+    fn rejected_instructions() {
+        let mut book = logged_in();
+        let mut input = ExecOrderBuilder::exercise("m2501-C-3000", "DCE", 2).build();
+        let key = book.insert_exec(&mut input);
+        let exec = book.on_rsp_exec_order_insert(&input, Some(&info(15, "no right"))).unwrap();
+        assert_eq!(exec.key, key);
+        assert_eq!((exec.status.clone(), exec.reject_reason.as_deref()), (ExecStatus::Rejected, Some("15 no right")));
+
+        let mut input = SelfCloseBuilder::new("IO2501-C-4000", "CFFEX", 1, SelfCloseFlag::ReserveOption).build();
+        let key = book.insert_self_close(&mut input);
+        assert_eq!(to_string(&input.OptionSelfCloseRef), "21");
+        let instruction = book.on_err_rtn_option_self_close_insert(&input, None).unwrap();
+        assert_eq!(instruction.key, key);
+        assert_eq!(instruction.kind, InstructionKind::SelfClose(SelfCloseFlag::ReserveOption));
+        assert_eq!(instruction.reject_reason.as_deref(), Some("rejected"));
+
+        let mut rejected = rtn(&book.own_key(&input.OptionSelfCloseRef), "", 0);
+        set_string(&mut rejected.ExecOrderRef, "30");
+        rejected.OrderSubmitStatus = THOST_FTDC_OSS_InsertRejected as _;
+        set_string(&mut rejected.StatusMsg, "closed");
+        let exec = book.on_rtn_exec_order(&rejected).unwrap();
+        assert_eq!((exec.status.clone(), exec.reject_reason.as_deref()), (ExecStatus::Rejected, Some("closed")));
+    }
+
+    #[test]
+    fn refused_cancels_keep_the_instruction() {
+        let mut book = logged_in();
+        let mut input = ExecOrderBuilder::exercise("m2501-C-3000", "DCE", 2).build();
+        let key = book.insert_exec(&mut input);
+        book.on_rtn_exec_order(&rtn(&key, "  E1", THOST_FTDC_OER_NoExec));
+
+        let mut action = book.cancel_exec(&key).unwrap();
+        assert_eq!(to_string(&action.ExecOrderSysID), "  E1");
+        assert_eq!(action.ActionFlag as u8, THOST_FTDC_AF_Delete);
+        // found by its sys id
+        action.SessionID = 9;
+        let exec = book.on_rsp_exec_order_action(&action, Some(&info(26, "too late"))).unwrap();
+        assert_eq!(exec.cancel_error.as_deref(), Some("26 too late"));
+        assert_eq!(exec.status, ExecStatus::Accepted);
+
+        let mut err: CThostFtdcExecOrderActionField = zeroed();
+        err.FrontID = key.front_id;
+        err.SessionID = key.session_id;
+        set_string(&mut err.ExecOrderRef, &key.order_ref);
+        let exec = book.on_err_rtn_exec_order_action(&err, None).unwrap();
+        assert_eq!(exec.cancel_error.as_deref(), Some("cancel rejected"));
+    }
+
+    #[test]
+    fn query_rows_add_instructions_of_other_sessions() {
+        let mut book = logged_in();
+        let other = OrderKey {
+            front_id: 5,
+            session_id: 6,
+            order_ref: "3".to_string(),
+        };
+        book.on_rsp_qry_exec_order(Some(&rtn(&other, "  E7", THOST_FTDC_OER_OK)));
+        book.on_rsp_qry_exec_order(None);
+        let exec = book.get_exec(&other).unwrap();
+        assert_eq!(exec.status, ExecStatus::Executed);
+        assert_eq!(exec.kind, InstructionKind::Exec(ExecAction::Exercise));
+
+        let mut row: CThostFtdcOptionSelfCloseField = zeroed();
+        row.FrontID = 5;
+        row.SessionID = 6;
+        set_string(&mut row.OptionSelfCloseRef, "4");
+        row.OptSelfCloseFlag = THOST_FTDC_OSCF_CloseSelfOptionPosition as _;
+        row.ExecResult = THOST_FTDC_OER_NoExec as _;
+        book.on_rsp_qry_option_self_close(Some(&row));
+        assert_eq!(book.self_closes().count(), 1);
+        assert_eq!(book.active().count(), 1);
+    }
+}
@@ -0,0 +1,718 @@
+//! Instruments from `ReqQryInstrument`, typed and kept one snapshot per
+//! trading day.
+//!
+//! The full query runs to many pages and takes a while under flow control,
+//! [`InstrumentCatalog::save`] writes the loaded catalog as CSV so a restart
+//! on the same trading day can [`InstrumentCatalog::load`] it instead.
+//!
+//! With an [`InstrumentFilter`] only part of the instruments is queried, via
+//! `ReqQryClassifiedInstrument` where the api has it (6.5.1 on) and the full
+//! `ReqQryInstrument` filtered here otherwise, or when the front refuses it.
+
+use super::query::Priority;
+use super::TdApi;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ProductClass {
+    Futures,
+    Options,
+    Combination,
+    Spot,
+    Efp,
+    SpotOption,
+    Tas,
+    Index,
+    Other(u8),
+}
+
+impl ProductClass {
+    /// From `THOST_FTDC_PC_*`.
+    #[allow(non_upper_case_globals)]
+    pub fn from_pc(class: c_char) -> Self {
+        match class as u8 {
+            THOST_FTDC_PC_Futures => Self::Futures,
+            THOST_FTDC_PC_Options => Self::Options,
+            THOST_FTDC_PC_Combination => Self::Combination,
+            THOST_FTDC_PC_Spot => Self::Spot,
+            THOST_FTDC_PC_EFP => Self::Efp,
+            THOST_FTDC_PC_SpotOption => Self::SpotOption,
+            THOST_FTDC_PC_TAS => Self::Tas,
+            THOST_FTDC_PC_MI => Self::Index,
+            other => Self::Other(other),
+        }
+    }
+
+    /// The `THOST_FTDC_PC_*` value.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Futures => THOST_FTDC_PC_Futures,
+            Self::Options => THOST_FTDC_PC_Options,
+            Self::Combination => THOST_FTDC_PC_Combination,
+            Self::Spot => THOST_FTDC_PC_Spot,
+            Self::Efp => THOST_FTDC_PC_EFP,
+            Self::SpotOption => THOST_FTDC_PC_SpotOption,
+            Self::Tas => THOST_FTDC_PC_TAS,
+            Self::Index => THOST_FTDC_PC_MI,
+            Self::Other(code) => *code,
+        }
+    }
+
+    pub fn is_option(&self) -> bool {
+        matches!(self, Self::Options | Self::SpotOption)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum OptionsType {
+    Call,
+    Put,
+}
+
+impl OptionsType {
+    /// From `THOST_FTDC_CP_*`, `None` for anything but options.
+    #[allow(non_upper_case_globals)]
+    pub fn from_cp(options_type: c_char) -> Option<Self> {
+        match options_type as u8 {
+            THOST_FTDC_CP_CallOptions => Some(Self::Call),
+            THOST_FTDC_CP_PutOptions => Some(Self::Put),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Call => THOST_FTDC_CP_CallOptions,
+            Self::Put => THOST_FTDC_CP_PutOptions,
+        }
+    }
+}
+
+/// `THOST_FTDC_TD_*` of the classified query.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub enum TradingType {
+    #[default]
+    All,
+    Trading,
+    NotTrading,
+}
+
+impl TradingType {
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::All => THOST_FTDC_TD_ALL,
+            Self::Trading => THOST_FTDC_TD_TRADE,
+            Self::NotTrading => THOST_FTDC_TD_UNTRADE,
+        }
+    }
+}
+
+/// `THOST_FTDC_INS_*` of the classified query.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub enum ClassType {
+    #[default]
+    All,
+    Futures,
+    Options,
+    Combination,
+}
+
+impl ClassType {
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::All => THOST_FTDC_INS_ALL,
+            Self::Futures => THOST_FTDC_INS_FUTURE,
+            Self::Options => THOST_FTDC_INS_OPTION,
+            Self::Combination => THOST_FTDC_INS_COMB,
+        }
+    }
+
+    fn contains(&self, class: ProductClass) -> bool {
+        match self {
+            Self::All => true,
+            Self::Futures => class == ProductClass::Futures,
+            Self::Options => class.is_option(),
+            Self::Combination => class == ProductClass::Combination,
+        }
+    }
+}
+
+/// Which instruments to query, empty ids match any.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
+pub struct InstrumentFilter {
+    pub exchange_id: String,
+    pub product_id: String,
+    pub trading_type: TradingType,
+    pub class_type: ClassType,
+}
+
+impl InstrumentFilter {
+    /// Options of one product, its `ProductID` being e.g. `m_o` on DCE.
+    pub fn options(product_id: &str) -> Self {
+        Self {
+            product_id: product_id.to_string(),
+            class_type: ClassType::Options,
+            ..Default::default()
+        }
+    }
+
+    pub fn futures() -> Self {
+        Self {
+            class_type: ClassType::Futures,
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, instrument: &Instrument) -> bool {
+        (self.exchange_id.is_empty() || self.exchange_id == instrument.exchange_id)
+            && (self.product_id.is_empty() || self.product_id == instrument.product_id)
+            && self.class_type.contains(instrument.product_class)
+            && match self.trading_type {
+                TradingType::All => true,
+                TradingType::Trading => instrument.is_trading,
+                TradingType::NotTrading => !instrument.is_trading,
+            }
+    }
+
+    fn is_all(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Whether an api of `version` (`TdApi::get_version`, e.g. `v6.7.2_20230913 ...`)
+/// has `ReqQryClassifiedInstrument`.
+pub fn supports_classified_query(version: &str) -> bool {
+    let numbers: Vec<u32> = version
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .split(|c: char| c == '_' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map_while(|n| n.parse().ok())
+        .collect();
+    match numbers.as_slice() {
+        [major, minor, patch, ..] => (*major, *minor, *patch) >= (6, 5, 1),
+        [major, minor] => (*major, *minor) > (6, 5),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    pub instrument_id: String,
+    pub exchange_id: String,
+    pub exchange_inst_id: String,
+    pub product_id: String,
+    pub product_class: ProductClass,
+    pub delivery_year: i32,
+    pub delivery_month: i32,
+    pub max_market_order_volume: i32,
+    pub min_market_order_volume: i32,
+    pub max_limit_order_volume: i32,
+    pub min_limit_order_volume: i32,
+    pub volume_multiple: i32,
+    pub price_tick: f64,
+    pub create_date: Option<Date>,
+    pub open_date: Option<Date>,
+    pub expire_date: Option<Date>,
+    pub start_deliv_date: Option<Date>,
+    pub end_deliv_date: Option<Date>,
+    pub is_trading: bool,
+    pub long_margin_ratio: f64,
+    pub short_margin_ratio: f64,
+    /// Zero for anything but options.
+    pub strike_price: f64,
+    pub options_type: Option<OptionsType>,
+    pub underlying_multiple: f64,
+    /// Underlying of an option, empty otherwise.
+    pub underlying_instr_id: String,
+}
+
+fn ratio(value: f64) -> f64 {
+    valid_price(value).unwrap_or(0.0)
+}
+
+impl From<&CThostFtdcInstrumentField> for Instrument {
+    fn from(field: &CThostFtdcInstrumentField) -> Self {
+        Self {
+            instrument_id: to_string(&field.InstrumentID),
+            exchange_id: to_string(&field.ExchangeID),
+            exchange_inst_id: to_string(&field.ExchangeInstID),
+            product_id: to_string(&field.ProductID),
+            product_class: ProductClass::from_pc(field.ProductClass),
+            delivery_year: field.DeliveryYear,
+            delivery_month: field.DeliveryMonth,
+            max_market_order_volume: field.MaxMarketOrderVolume,
+            min_market_order_volume: field.MinMarketOrderVolume,
+            max_limit_order_volume: field.MaxLimitOrderVolume,
+            min_limit_order_volume: field.MinLimitOrderVolume,
+            volume_multiple: field.VolumeMultiple,
+            price_tick: field.PriceTick,
+            create_date: Date::parse(&to_string(&field.CreateDate)),
+            open_date: Date::parse(&to_string(&field.OpenDate)),
+            expire_date: Date::parse(&to_string(&field.ExpireDate)),
+            start_deliv_date: Date::parse(&to_string(&field.StartDelivDate)),
+            end_deliv_date: Date::parse(&to_string(&field.EndDelivDate)),
+            is_trading: field.IsTrading != 0,
+            long_margin_ratio: ratio(field.LongMarginRatio),
+            short_margin_ratio: ratio(field.ShortMarginRatio),
+            strike_price: ratio(field.StrikePrice),
+            options_type: OptionsType::from_cp(field.OptionsType),
+            underlying_multiple: ratio(field.UnderlyingMultiple),
+            underlying_instr_id: to_string(&field.UnderlyingInstrID),
+        }
+    }
+}
+
+/// CSV columns of a snapshot, in the order written by [`InstrumentCatalog::save`].
+pub const COLUMNS: &[&str] = &[
+    "InstrumentID",
+    "ExchangeID",
+    "ExchangeInstID",
+    "ProductID",
+    "ProductClass",
+    "DeliveryYear",
+    "DeliveryMonth",
+    "MaxMarketOrderVolume",
+    "MinMarketOrderVolume",
+    "MaxLimitOrderVolume",
+    "MinLimitOrderVolume",
+    "VolumeMultiple",
+    "PriceTick",
+    "CreateDate",
+    "OpenDate",
+    "ExpireDate",
+    "StartDelivDate",
+    "EndDelivDate",
+    "IsTrading",
+    "LongMarginRatio",
+    "ShortMarginRatio",
+    "StrikePrice",
+    "OptionsType",
+    "UnderlyingMultiple",
+    "UnderlyingInstrID",
+];
+
+fn format_date(date: Option<Date>) -> String {
+    date.map(|d| d.to_string()).unwrap_or_default()
+}
+
+impl Instrument {
+    fn to_row(&self) -> String {
+        let columns = [
+            self.instrument_id.clone(),
+            self.exchange_id.clone(),
+            self.exchange_inst_id.clone(),
+            self.product_id.clone(),
+            self.product_class.code().to_string(),
+            self.delivery_year.to_string(),
+            self.delivery_month.to_string(),
+            self.max_market_order_volume.to_string(),
+            self.min_market_order_volume.to_string(),
+            self.max_limit_order_volume.to_string(),
+            self.min_limit_order_volume.to_string(),
+            self.volume_multiple.to_string(),
+            format!("{:?}", self.price_tick),
+            format_date(self.create_date),
+            format_date(self.open_date),
+            format_date(self.expire_date),
+            format_date(self.start_deliv_date),
+            format_date(self.end_deliv_date),
+            (self.is_trading as i32).to_string(),
+            format!("{:?}", self.long_margin_ratio),
+            format!("{:?}", self.short_margin_ratio),
+            format!("{:?}", self.strike_price),
+            self.options_type.map(|t| t.code().to_string()).unwrap_or_default(),
+            format!("{:?}", self.underlying_multiple),
+            self.underlying_instr_id.clone(),
+        ];
+        columns.join(",")
+    }
+
+    fn from_row(line: &str) -> Result<Self, String> {
+        let columns: Vec<&str> = line.split(',').collect();
+        if columns.len() != COLUMNS.len() {
+            return Err(format!("expected {} columns, got {}", COLUMNS.len(), columns.len()));
+        }
+        let num = |i: usize| -> Result<f64, String> {
+            columns[i].parse().map_err(|_| format!("invalid `{}`: {}", COLUMNS[i], columns[i]))
+        };
+        let int = |i: usize| -> Result<i32, String> {
+            columns[i].parse().map_err(|_| format!("invalid `{}`: {}", COLUMNS[i], columns[i]))
+        };
+        let code = |i: usize| -> Result<u8, String> {
+            columns[i].parse().map_err(|_| format!("invalid `{}`: {}", COLUMNS[i], columns[i]))
+        };
+        Ok(Self {
+            instrument_id: columns[0].to_string(),
+            exchange_id: columns[1].to_string(),
+            exchange_inst_id: columns[2].to_string(),
+            product_id: columns[3].to_string(),
+            product_class: ProductClass::from_pc(code(4)? as _),
+            delivery_year: int(5)?,
+            delivery_month: int(6)?,
+            max_market_order_volume: int(7)?,
+            min_market_order_volume: int(8)?,
+            max_limit_order_volume: int(9)?,
+            min_limit_order_volume: int(10)?,
+            volume_multiple: int(11)?,
+            price_tick: num(12)?,
+            create_date: Date::parse(columns[13]),
+            open_date: Date::parse(columns[14]),
+            expire_date: Date::parse(columns[15]),
+            start_deliv_date: Date::parse(columns[16]),
+            end_deliv_date: Date::parse(columns[17]),
+            is_trading: int(18)? != 0,
+            long_margin_ratio: num(19)?,
+            short_margin_ratio: num(20)?,
+            strike_price: num(21)?,
+            options_type: if columns[22].is_empty() { None } else { OptionsType::from_cp(code(22)? as _) },
+            underlying_multiple: num(23)?,
+            underlying_instr_id: columns[24].to_string(),
+        })
+    }
+}
+
+/// All instruments of a trading day, fed from `OnRspQryInstrument` or a snapshot.
+#[derive(Debug, Default)]
+pub struct InstrumentCatalog {
+    trading_day: String,
+    instruments: BTreeMap<String, Instrument>,
+    loading: Option<BTreeMap<String, Instrument>>,
+    /// What the running query asked for.
+    filter: InstrumentFilter,
+    /// The front refused the classified query, use the full one.
+    classified_refused: bool,
+}
+
+impl InstrumentCatalog {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Path of the snapshot of `trading_day` under `dir`.
+    pub fn snapshot_path<P: AsRef<Path>>(dir: P, trading_day: &str) -> PathBuf {
+        dir.as_ref().join(format!("instruments.{}.csv", trading_day))
+    }
+
+    /// Load the snapshot of `trading_day` from `dir`, `Ok(false)` when there is none.
+    pub fn load<P: AsRef<Path>>(&mut self, dir: P, trading_day: &str) -> Result<bool, String> {
+        let path = Self::snapshot_path(dir, trading_day);
+        if !path.exists() {
+            return Ok(false);
+        }
+        let file = File::open(&path).map_err(|e| format!("Fail to open {}: {}", path.display(), e))?;
+        let mut lines = BufReader::new(file).lines();
+        let header = lines.next().transpose().map_err(|e| e.to_string())?.unwrap_or_default();
+        if header != COLUMNS.join(",") {
+            return Err(format!("{} has unknown columns", path.display()));
+        }
+
+        let mut instruments = BTreeMap::new();
+        for (n, line) in lines.enumerate() {
+            let line = line.map_err(|e| format!("Fail to read {}: {}", path.display(), e))?;
+            if line.is_empty() {
+                continue;
+            }
+            let instrument = Instrument::from_row(&line).map_err(|e| format!("{}:{}: {}", path.display(), n + 2, e))?;
+            instruments.insert(instrument.instrument_id.clone(), instrument);
+        }
+        info!("{} instruments of {} loaded from {}", instruments.len(), trading_day, path.display());
+        self.trading_day = trading_day.to_string();
+        self.instruments = instruments;
+        Ok(true)
+    }
+
+    /// Write the catalog as the snapshot of its trading day under `dir`.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<PathBuf, String> {
+        if self.trading_day.is_empty() {
+            return Err("no trading day to save the instruments of".to_string());
+        }
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).map_err(|e| format!("Fail to create {}: {}", dir.display(), e))?;
+        let path = Self::snapshot_path(dir, &self.trading_day);
+        // write aside and rename, a reader never sees half a snapshot
+        let tmp = path.with_extension("csv.tmp");
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            writeln!(writer, "{}", COLUMNS.join(","))?;
+            for instrument in self.instruments.values() {
+                writeln!(writer, "{}", instrument.to_row())?;
+            }
+            writer.flush()?;
+            std::fs::rename(&tmp, &path)
+        };
+        write().map_err(|e| format!("Fail to write {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    /// Queue the query of the instruments `filter` selects, answers go to
+    /// [`Self::on_rsp_qry_instrument`] or [`Self::on_rsp_qry_classified_instrument`].
+    pub fn queue_query(&mut self, api: &mut TdApi, filter: InstrumentFilter) {
+        if self.start_query(filter.clone(), &TdApi::get_version()) {
+            api.queue_query(Priority::Bulk, "classified instruments", move |api| api.req_qry_classified_instrument(&filter));
+        } else {
+            // narrow what the full query can, the rest is filtered here
+            let (exchange, product) = (filter.exchange_id.clone(), filter.product_id.clone());
+            api.queue_query(Priority::Bulk, "instruments", move |api| api.req_qry_instrument("", &exchange, &product));
+        }
+    }
+
+    /// Start a query of `filter` with an api of `version`, whether it is the classified one.
+    fn start_query(&mut self, filter: InstrumentFilter, version: &str) -> bool {
+        let classified = !filter.is_all() && !self.classified_refused && supports_classified_query(version);
+        self.filter = filter;
+        self.loading = None;
+        classified
+    }
+
+    /// Whether `info` refuses the classified query, which is then not used again.
+    fn refuses_classified(&mut self, info: Option<&CThostFtdcRspInfoField>) -> bool {
+        match info.filter(|info| info.ErrorID != 0) {
+            Some(info) => {
+                warn!("classified instrument query refused: {} {}, fall back to the full query", info.ErrorID, gbk_string(&info.ErrorMsg));
+                self.classified_refused = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A page of the instrument query. On the last one the instruments the
+    /// query was for are replaced, the others are kept.
+    pub fn on_rsp_qry_instrument(&mut self, trading_day: &str, field: Option<&CThostFtdcInstrumentField>, is_last: bool) {
+        let loading = self.loading.get_or_insert_with(BTreeMap::new);
+        if let Some(field) = field {
+            let instrument = Instrument::from(field);
+            if self.filter.matches(&instrument) {
+                loading.insert(instrument.instrument_id.clone(), instrument);
+            }
+        }
+        if !is_last {
+            return;
+        }
+
+        let loaded = self.loading.take().unwrap_or_default();
+        if self.trading_day != trading_day {
+            self.instruments.clear();
+        }
+        let filter = &self.filter;
+        self.instruments.retain(|_, i| !filter.matches(i));
+        info!("{} instruments of {} queried with {:?}", loaded.len(), trading_day, filter);
+        self.instruments.extend(loaded);
+        self.trading_day = trading_day.to_string();
+    }
+
+    /// A page of the classified query. When the front refuses it the full
+    /// query is queued in its place, and used from then on.
+    pub fn on_rsp_qry_classified_instrument(
+        &mut self,
+        api: &mut TdApi,
+        trading_day: &str,
+        field: Option<&CThostFtdcInstrumentField>,
+        info: Option<&CThostFtdcRspInfoField>,
+        is_last: bool,
+    ) {
+        if self.refuses_classified(info) {
+            self.queue_query(api, self.filter.clone());
+            return;
+        }
+        self.on_rsp_qry_instrument(trading_day, field, is_last);
+    }
+
+    /// Trading day of the catalog, empty before it is loaded.
+    pub fn trading_day(&self) -> &str {
+        &self.trading_day
+    }
+
+    pub fn get(&self, instrument: &str) -> Option<&Instrument> {
+        self.instruments.get(instrument)
+    }
+
+    /// `VolumeMultiple` of `instrument`.
+    pub fn multiplier(&self, instrument: &str) -> Option<f64> {
+        self.get(instrument).map(|i| i.volume_multiple as f64)
+    }
+
+    pub fn instruments(&self) -> impl Iterator<Item = &Instrument> {
+        self.instruments.values()
+    }
+
+    pub fn by_product<'a>(&'a self, product: &'a str) -> impl Iterator<Item = &'a Instrument> {
+        self.instruments().filter(move |i| i.product_id == product)
+    }
+
+    pub fn by_exchange<'a>(&'a self, exchange: &'a str) -> impl Iterator<Item = &'a Instrument> {
+        self.instruments().filter(move |i| i.exchange_id == exchange)
+    }
+
+    /// Options on `underlying`.
+    pub fn by_underlying<'a>(&'a self, underlying: &'a str) -> impl Iterator<Item = &'a Instrument> {
+        self.instruments().filter(move |i| i.underlying_instr_id == underlying)
+    }
+
+    pub fn by_expiry(&self, expire_date: Date) -> impl Iterator<Item = &Instrument> {
+        self.instruments().filter(move |i| i.expire_date == Some(expire_date))
+    }
+
+    /// Instruments expiring in `[from, to]`.
+    pub fn expiring(&self, from: Date, to: Date) -> impl Iterator<Item = &Instrument> {
+        self.instruments().filter(move |i| i.expire_date.is_some_and(|d| from <= d && d <= to))
+    }
+
+    pub fn len(&self) -> usize {
+        self.instruments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instruments.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(instrument: &str, exchange: &str, product: &str, class: u8) -> CThostFtdcInstrumentField {
+        let mut field: CThostFtdcInstrumentField = zeroed();
+        set_string(&mut field.InstrumentID, instrument);
+        set_string(&mut field.ExchangeID, exchange);
+        set_string(&mut field.ExchangeInstID, instrument);
+        set_string(&mut field.ProductID, product);
+        field.ProductClass = class as _;
+        field.DeliveryYear = 2025;
+        field.DeliveryMonth = 1;
+        field.MaxLimitOrderVolume = 500;
+        field.MinLimitOrderVolume = 1;
+        field.VolumeMultiple = 10;
+        field.PriceTick = 0.5;
+        set_string(&mut field.ExpireDate, "20250107");
+        field.IsTrading = 1;
+        field.LongMarginRatio = 0.08;
+        field.ShortMarginRatio = f64::MAX;
+        field
+    }
+
+    fn option(instrument: &str, underlying: &str) -> CThostFtdcInstrumentField {
+        let mut field = field(instrument, "DCE", "m_o", THOST_FTDC_PC_Options);
+        field.StrikePrice = 3000.0;
+        field.OptionsType = THOST_FTDC_CP_PutOptions as _;
+        field.UnderlyingMultiple = 1.0;
+        set_string(&mut field.UnderlyingInstrID, underlying);
+        field
+    }
+
+    #[test]
+    fn classified_query_by_api_version() {
+        assert!(supports_classified_query("v6.7.2_20230913 11:02:48.4375"));
+        assert!(supports_classified_query("v6.5.1_20200908 10:25:08"));
+        assert!(supports_classified_query("v6.6_20210812"));
+        assert!(!supports_classified_query("v6.3.19_P1_20200106 15:20:38"));
+        assert!(!supports_classified_query("v6.3.15_20190220 9:39:53"));
+        assert!(!supports_classified_query("v6.5_20200331"));
+        assert!(!supports_classified_query(""));
+        assert!(!supports_classified_query("unknown"));
+    }
+
+    #[test]
+    fn a_refused_classified_query_falls_back_to_the_full_one() {
+        let version = "v6.7.2_20230913 11:02:48.4375";
+        let mut catalog = InstrumentCatalog::new();
+        assert!(!catalog.start_query(InstrumentFilter::default(), version));
+        assert!(!catalog.start_query(InstrumentFilter::futures(), "v6.3.15_20190220 9:39:53"));
+        assert!(catalog.start_query(InstrumentFilter::options("m_o"), version));
+        assert!(!catalog.refuses_classified(None));
+
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        assert!(!catalog.refuses_classified(Some(&info)));
+        info.ErrorID = 90;
+        assert!(catalog.refuses_classified(Some(&info)));
+        assert!(!catalog.start_query(InstrumentFilter::options("m_o"), version));
+
+        // the full query answers every instrument, only those of the filter are taken
+        catalog.on_rsp_qry_instrument("20250106", Some(&field("m2501", "DCE", "m", THOST_FTDC_PC_Futures)), false);
+        catalog.on_rsp_qry_instrument("20250106", Some(&option("m2501-P-3000", "m2501")), true);
+        assert_eq!(catalog.instruments().map(|i| i.instrument_id.as_str()).collect::<Vec<_>>(), ["m2501-P-3000"]);
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ctp-instruments-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn rows_round_trip() {
+        for field in [field("rb2501", "SHFE", "rb", THOST_FTDC_PC_Futures), option("m2501-P-3000", "m2501")] {
+            let instrument = Instrument::from(&field);
+            assert_eq!(Instrument::from_row(&instrument.to_row()), Ok(instrument));
+        }
+        let instrument = Instrument::from(&option("m2501-P-3000", "m2501"));
+        assert_eq!(instrument.short_margin_ratio, 0.0);
+        assert_eq!(instrument.options_type, Some(OptionsType::Put));
+        assert_eq!(instrument.expire_date, Date::parse("20250107"));
+        assert!(Instrument::from_row("rb2501,SHFE").is_err());
+        let row = instrument.to_row().replacen(",10,", ",ten,", 1);
+        assert_eq!(Instrument::from_row(&row), Err("invalid `VolumeMultiple`: ten".to_string()));
+    }
+
+    #[test]
+    fn snapshots_save_and_load() {
+        let dir = scratch_dir("snapshot");
+        let mut catalog = InstrumentCatalog::new();
+        assert!(catalog.save(&dir).is_err());
+        catalog.on_rsp_qry_instrument("20250106", Some(&field("rb2501", "SHFE", "rb", THOST_FTDC_PC_Futures)), false);
+        catalog.on_rsp_qry_instrument("20250106", Some(&option("m2501-P-3000", "m2501")), true);
+        let path = catalog.save(&dir).unwrap();
+        assert_eq!(path, InstrumentCatalog::snapshot_path(&dir, "20250106"));
+
+        let mut loaded = InstrumentCatalog::new();
+        assert_eq!(loaded.load(&dir, "20250107"), Ok(false));
+        assert_eq!(loaded.load(&dir, "20250106"), Ok(true));
+        assert_eq!(loaded.trading_day(), "20250106");
+        assert_eq!(loaded.instruments().collect::<Vec<_>>(), catalog.instruments().collect::<Vec<_>>());
+        assert_eq!(loaded.by_underlying("m2501").count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn snapshots_with_other_columns_are_rejected() {
+        let dir = scratch_dir("header");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(InstrumentCatalog::snapshot_path(&dir, "20250106"), "InstrumentID,ExchangeID\nrb2501,SHFE\n").unwrap();
+        let mut catalog = InstrumentCatalog::new();
+        assert!(catalog.load(&dir, "20250106").unwrap_err().ends_with("has unknown columns"));
+        assert!(catalog.is_empty());
+        assert_eq!(catalog.trading_day(), "");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_new_trading_day_clears_the_old_instruments() {
+        let mut catalog = InstrumentCatalog::new();
+        catalog.on_rsp_qry_instrument("20250106", Some(&field("rb2501", "SHFE", "rb", THOST_FTDC_PC_Futures)), true);
+        catalog.on_rsp_qry_instrument("20250106", Some(&field("hc2501", "SHFE", "hc", THOST_FTDC_PC_Futures)), true);
+        assert_eq!(catalog.len(), 1);
+        assert!(catalog.get("hc2501").is_some());
+
+        // a filtered query on the same day keeps what it was not for
+        catalog.filter = InstrumentFilter::options("m_o");
+        catalog.on_rsp_qry_instrument("20250106", Some(&field("rb2501", "SHFE", "rb", THOST_FTDC_PC_Futures)), false);
+        catalog.on_rsp_qry_instrument("20250106", Some(&option("m2501-P-3000", "m2501")), true);
+        assert_eq!(catalog.len(), 2);
+        assert!(catalog.get("rb2501").is_none());
+
+        catalog.on_rsp_qry_instrument("20250107", Some(&option("m2501-P-3100", "m2501")), true);
+        assert_eq!(catalog.instruments().map(|i| i.instrument_id.as_str()).collect::<Vec<_>>(), ["m2501-P-3100"]);
+        assert_eq!(catalog.trading_day(), "20250107");
+    }
+}
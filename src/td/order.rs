@@ -0,0 +1,584 @@
+//! Order bookkeeping: `OrderRef` allocation and the lifecycle of every order
+//! folded from the insert, order, trade and action callbacks.
+//!
+//! An order is known by `(FrontID, SessionID, OrderRef)` from the start and by
+//! `(ExchangeID, OrderSysID)` once the exchange accepts it. Trades are matched
+//! by the latter. A trade arriving before that is held when its `OrderRef` is
+//! one of this session's orders not linked yet, until the order links or
+//! finishes; other trades of unknown orders are not tracked.
+
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{HashMap, HashSet};
+use std::os::raw::c_char;
+
+use log::*;
+
+/// `THOST_FTDC_OST_*`, plus the local states before the first `OnRtnOrder`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum OrderStatus {
+    /// Sent, nothing heard back yet.
+    Pending,
+    /// Refused by CTP or the exchange, see [`Order::reject_reason`].
+    Rejected,
+    AllTraded,
+    PartTradedQueueing,
+    PartTradedNotQueueing,
+    NoTradeQueueing,
+    NoTradeNotQueueing,
+    Canceled,
+    Unknown,
+    NotTouched,
+    Touched,
+}
+
+impl OrderStatus {
+    #[allow(non_upper_case_globals)]
+    pub fn from_ost(status: c_char) -> Option<Self> {
+        Some(match status as u8 {
+            THOST_FTDC_OST_AllTraded => Self::AllTraded,
+            THOST_FTDC_OST_PartTradedQueueing => Self::PartTradedQueueing,
+            THOST_FTDC_OST_PartTradedNotQueueing => Self::PartTradedNotQueueing,
+            THOST_FTDC_OST_NoTradeQueueing => Self::NoTradeQueueing,
+            THOST_FTDC_OST_NoTradeNotQueueing => Self::NoTradeNotQueueing,
+            THOST_FTDC_OST_Canceled => Self::Canceled,
+            THOST_FTDC_OST_Unknown => Self::Unknown,
+            THOST_FTDC_OST_NotTouched => Self::NotTouched,
+            THOST_FTDC_OST_Touched => Self::Touched,
+            _ => return None,
+        })
+    }
+
+    /// No more fills or state changes are expected.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            Self::Rejected | Self::AllTraded | Self::PartTradedNotQueueing | Self::NoTradeNotQueueing | Self::Canceled
+        )
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct OrderKey {
+    pub front_id: i32,
+    pub session_id: i32,
+    pub order_ref: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Order {
+    pub key: OrderKey,
+    pub exchange_id: String,
+    /// Empty until the exchange accepts the order.
+    pub order_sys_id: String,
+    pub instrument_id: String,
+    pub direction: u8,
+    pub offset: u8,
+    pub price: f64,
+    pub volume: i32,
+    pub status: OrderStatus,
+    /// `VolumeTraded` of the latest `OnRtnOrder`.
+    pub volume_traded: i32,
+    /// Volume and turnover of the trades seen.
+    pub trade_volume: i32,
+    pub trade_turnover: f64,
+    pub trade_ids: HashSet<String>,
+    pub reject_reason: Option<String>,
+    /// Reason of the latest refused cancel.
+    pub cancel_error: Option<String>,
+    pub status_msg: String,
+    pub insert_time: String,
+}
+
+impl Order {
+    fn new(key: OrderKey) -> Self {
+        Self {
+            key,
+            exchange_id: String::new(),
+            order_sys_id: String::new(),
+            instrument_id: String::new(),
+            direction: 0,
+            offset: 0,
+            price: 0.0,
+            volume: 0,
+            status: OrderStatus::Pending,
+            volume_traded: 0,
+            trade_volume: 0,
+            trade_turnover: 0.0,
+            trade_ids: HashSet::new(),
+            reject_reason: None,
+            cancel_error: None,
+            status_msg: String::new(),
+            insert_time: String::new(),
+        }
+    }
+
+    fn set_input(&mut self, input: &CThostFtdcInputOrderField) {
+        self.exchange_id = to_string(&input.ExchangeID);
+        self.instrument_id = to_string(&input.InstrumentID);
+        self.direction = input.Direction as u8;
+        self.offset = input.CombOffsetFlag[0] as u8;
+        self.price = input.LimitPrice;
+        self.volume = input.VolumeTotalOriginal;
+    }
+
+    /// Filled volume, trades may lag behind the order status or the reverse.
+    pub fn filled(&self) -> i32 {
+        self.volume_traded.max(self.trade_volume)
+    }
+
+    pub fn remaining(&self) -> i32 {
+        if self.status.is_finished() {
+            return 0;
+        }
+        (self.volume - self.filled()).max(0)
+    }
+
+    /// Volume weighted price of the trades seen.
+    pub fn avg_price(&self) -> Option<f64> {
+        if self.trade_volume == 0 {
+            return None;
+        }
+        Some(self.trade_turnover / self.trade_volume as f64)
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.status.is_finished()
+    }
+}
+
+pub(super) fn rsp_error(info: Option<&CThostFtdcRspInfoField>) -> Option<String> {
+    match info {
+        Some(info) if info.ErrorID != 0 => Some(format!("{} {}", info.ErrorID, gbk_string(&info.ErrorMsg))),
+        _ => None,
+    }
+}
+
+/// All orders of one trading session, fed from the trader spi.
+#[derive(Debug, Default)]
+pub struct OrderManager {
+    front_id: i32,
+    session_id: i32,
+    order_ref: i32,
+    orders: HashMap<OrderKey, Order>,
+    by_sys_id: HashMap<(String, String), OrderKey>,
+    /// Trades of own orders not linked to their `OrderSysID` yet.
+    early_trades: HashMap<OrderKey, Vec<CThostFtdcTradeField>>,
+}
+
+impl OrderManager {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Take `FrontID`, `SessionID` and continue `OrderRef` after `MaxOrderRef`.
+    pub fn on_rsp_user_login(&mut self, login: &CThostFtdcRspUserLoginField) {
+        self.front_id = login.FrontID;
+        self.session_id = login.SessionID;
+        self.order_ref = to_string(&login.MaxOrderRef).trim().parse().unwrap_or(0);
+        debug!("order session {}/{}, max order ref {}", self.front_id, self.session_id, self.order_ref);
+    }
+
+    /// The next `OrderRef` of the session. Every ref sent, `ForQuoteRef`
+    /// and quote leg refs included, comes from here so none repeats.
+    pub fn next_order_ref(&mut self) -> String {
+        self.order_ref += 1;
+        self.order_ref.to_string()
+    }
+
+    /// Track an order about to be sent, its `OrderRef` is allocated when empty.
+    pub fn insert(&mut self, input: &mut CThostFtdcInputOrderField) -> OrderKey {
+        if to_string(&input.OrderRef).is_empty() {
+            let order_ref = self.next_order_ref();
+            set_string(&mut input.OrderRef, &order_ref);
+        }
+        let key = self.own_key(&to_string(&input.OrderRef));
+        let mut order = Order::new(key.clone());
+        order.set_input(input);
+        self.orders.insert(key.clone(), order);
+        key
+    }
+
+    /// A cancel request for `key`, to complete with broker and investor ids.
+    pub fn cancel(&self, key: &OrderKey) -> Option<CThostFtdcInputOrderActionField> {
+        let order = self.orders.get(key).filter(|o| o.is_active())?;
+        let mut action: CThostFtdcInputOrderActionField = zeroed();
+        action.FrontID = key.front_id;
+        action.SessionID = key.session_id;
+        set_string(&mut action.OrderRef, &key.order_ref);
+        set_string(&mut action.ExchangeID, &order.exchange_id);
+        set_string(&mut action.OrderSysID, &order.order_sys_id);
+        set_string(&mut action.InstrumentID, &order.instrument_id);
+        action.ActionFlag = THOST_FTDC_AF_Delete as _;
+        Some(action)
+    }
+
+    /// `OnRspOrderInsert`, only called when CTP refuses the order.
+    pub fn on_rsp_order_insert(&mut self, input: &CThostFtdcInputOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Order> {
+        self.reject(input, info)
+    }
+
+    /// `OnErrRtnOrderInsert`, the exchange refused the order.
+    pub fn on_err_rtn_order_insert(&mut self, input: &CThostFtdcInputOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Order> {
+        self.reject(input, info)
+    }
+
+    pub fn on_rtn_order(&mut self, rtn: &CThostFtdcOrderField) -> Option<&Order> {
+        let key = OrderKey {
+            front_id: rtn.FrontID,
+            session_id: rtn.SessionID,
+            order_ref: to_string(&rtn.OrderRef),
+        };
+        let order = self.orders.entry(key.clone()).or_insert_with(|| Order::new(key.clone()));
+        order.exchange_id = to_string(&rtn.ExchangeID);
+        order.instrument_id = to_string(&rtn.InstrumentID);
+        order.direction = rtn.Direction as u8;
+        order.offset = rtn.CombOffsetFlag[0] as u8;
+        order.price = rtn.LimitPrice;
+        order.volume = rtn.VolumeTotalOriginal;
+        order.volume_traded = order.volume_traded.max(rtn.VolumeTraded);
+        order.status_msg = gbk_string(&rtn.StatusMsg);
+        order.insert_time = to_string(&rtn.InsertTime);
+
+        if rtn.OrderSubmitStatus as u8 == THOST_FTDC_OSS_InsertRejected {
+            order.status = OrderStatus::Rejected;
+            order.reject_reason = Some(order.status_msg.clone());
+        } else if let Some(status) = OrderStatus::from_ost(rtn.OrderStatus) {
+            // a late return must not revive a finished order
+            if !order.status.is_finished() || status.is_finished() {
+                order.status = status;
+            }
+        }
+
+        let finished = order.status.is_finished();
+        let sys_id = to_string(&rtn.OrderSysID);
+        if !sys_id.is_empty() {
+            order.order_sys_id = sys_id.clone();
+            let sys_key = (order.exchange_id.clone(), sys_id);
+            self.by_sys_id.insert(sys_key.clone(), key.clone());
+            for trade in self.early_trades.remove(&key).unwrap_or_default() {
+                if (to_string(&trade.ExchangeID), to_string(&trade.OrderSysID)) == sys_key {
+                    self.apply_trade(&key, &trade);
+                } else {
+                    debug!("held trade {} is not of order {:?}", to_string(&trade.TradeID), sys_key);
+                }
+            }
+        }
+        if finished {
+            self.early_trades.remove(&key);
+        }
+        self.orders.get(&key)
+    }
+
+    pub fn on_rtn_trade(&mut self, trade: &CThostFtdcTradeField) -> Option<&Order> {
+        let sys_key = (to_string(&trade.ExchangeID), to_string(&trade.OrderSysID));
+        match self.by_sys_id.get(&sys_key).cloned() {
+            Some(key) => {
+                self.apply_trade(&key, trade);
+                self.orders.get(&key)
+            }
+            None => {
+                let key = self.own_key(&to_string(&trade.OrderRef));
+                if self.orders.get(&key).is_some_and(|o| o.order_sys_id.is_empty() && o.is_active()) {
+                    debug!("trade {} before its order {:?}", to_string(&trade.TradeID), sys_key);
+                    self.early_trades.entry(key).or_default().push(*trade);
+                } else {
+                    debug!("trade {} of order {:?} not tracked here", to_string(&trade.TradeID), sys_key);
+                }
+                None
+            }
+        }
+    }
+
+    /// `OnRspOrderAction`, only called when CTP refuses the cancel.
+    pub fn on_rsp_order_action(&mut self, action: &CThostFtdcInputOrderActionField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Order> {
+        let key = self.action_key(action.FrontID, action.SessionID, &action.OrderRef, &action.ExchangeID, &action.OrderSysID)?;
+        self.cancel_failed(&key, info)
+    }
+
+    /// `OnErrRtnOrderAction`, the exchange refused the cancel.
+    pub fn on_err_rtn_order_action(&mut self, action: &CThostFtdcOrderActionField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Order> {
+        let key = self.action_key(action.FrontID, action.SessionID, &action.OrderRef, &action.ExchangeID, &action.OrderSysID)?;
+        self.cancel_failed(&key, info)
+    }
+
+    /// `OnRtnErrorConditionalOrder`, a touched conditional order failed to
+    /// insert, e.g. for lack of funds.
+    pub fn on_rtn_error_conditional_order(&mut self, rtn: &CThostFtdcErrorConditionalOrderField) -> Option<&Order> {
+        let key = OrderKey {
+            front_id: rtn.FrontID,
+            session_id: rtn.SessionID,
+            order_ref: to_string(&rtn.OrderRef),
+        };
+        let order = self.orders.get_mut(&key)?;
+        order.status = OrderStatus::Rejected;
+        order.reject_reason = Some(format!("{} {}", rtn.ErrorID, gbk_string(&rtn.ErrorMsg)));
+        warn!("conditional order {:?} failed: {:?}", key, order.reject_reason);
+        Some(order)
+    }
+
+    pub fn get(&self, key: &OrderKey) -> Option<&Order> {
+        self.orders.get(key)
+    }
+
+    pub fn get_by_sys_id(&self, exchange_id: &str, order_sys_id: &str) -> Option<&Order> {
+        let key = self.by_sys_id.get(&(exchange_id.to_string(), order_sys_id.to_string()))?;
+        self.orders.get(key)
+    }
+
+    pub fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.orders.values()
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &Order> {
+        self.orders.values().filter(|o| o.is_active())
+    }
+
+    fn own_key(&self, order_ref: &str) -> OrderKey {
+        OrderKey {
+            front_id: self.front_id,
+            session_id: self.session_id,
+            order_ref: order_ref.to_string(),
+        }
+    }
+
+    fn reject(&mut self, input: &CThostFtdcInputOrderField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Order> {
+        let key = self.own_key(&to_string(&input.OrderRef));
+        let order = self.orders.entry(key.clone()).or_insert_with(|| Order::new(key.clone()));
+        if order.volume == 0 {
+            order.set_input(input);
+        }
+        order.status = OrderStatus::Rejected;
+        order.reject_reason = Some(rsp_error(info).unwrap_or_else(|| "rejected".into()));
+        warn!("order {:?} rejected: {:?}", key, order.reject_reason);
+        self.early_trades.remove(&key);
+        self.orders.get(&key)
+    }
+
+    fn action_key(&self, front_id: i32, session_id: i32, order_ref: &[c_char], exchange_id: &[c_char], order_sys_id: &[c_char]) -> Option<OrderKey> {
+        let key = OrderKey {
+            front_id,
+            session_id,
+            order_ref: to_string(order_ref),
+        };
+        if self.orders.contains_key(&key) {
+            return Some(key);
+        }
+        self.by_sys_id.get(&(to_string(exchange_id), to_string(order_sys_id))).cloned()
+    }
+
+    fn cancel_failed(&mut self, key: &OrderKey, info: Option<&CThostFtdcRspInfoField>) -> Option<&Order> {
+        let order = self.orders.get_mut(key)?;
+        order.cancel_error = Some(rsp_error(info).unwrap_or_else(|| "cancel rejected".into()));
+        warn!("cancel of {:?} rejected: {:?}", key, order.cancel_error);
+        Some(order)
+    }
+
+    fn apply_trade(&mut self, key: &OrderKey, trade: &CThostFtdcTradeField) {
+        let order = match self.orders.get_mut(key) {
+            Some(order) => order,
+            None => return,
+        };
+        // trades are replayed on resume, count each once
+        if !order.trade_ids.insert(to_string(&trade.TradeID)) {
+            return;
+        }
+        order.trade_volume += trade.Volume;
+        order.trade_turnover += trade.Price * trade.Volume as f64;
+        if order.trade_volume >= order.volume && order.volume > 0 {
+            order.status = OrderStatus::AllTraded;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged_in() -> OrderManager {
+        let mut login: CThostFtdcRspUserLoginField = zeroed();
+        login.FrontID = 1;
+        login.SessionID = 2;
+        set_string(&mut login.MaxOrderRef, "        10");
+        let mut manager = OrderManager::new();
+        manager.on_rsp_user_login(&login);
+        manager
+    }
+
+    fn input(volume: i32) -> CThostFtdcInputOrderField {
+        let mut input: CThostFtdcInputOrderField = zeroed();
+        set_string(&mut input.InstrumentID, "rb2501");
+        set_string(&mut input.ExchangeID, "SHFE");
+        input.Direction = THOST_FTDC_D_Buy as _;
+        input.CombOffsetFlag[0] = THOST_FTDC_OF_Open as _;
+        input.LimitPrice = 3500.0;
+        input.VolumeTotalOriginal = volume;
+        input
+    }
+
+    fn rtn(key: &OrderKey, sys_id: &str, status: u8, traded: i32) -> CThostFtdcOrderField {
+        let mut rtn: CThostFtdcOrderField = zeroed();
+        rtn.FrontID = key.front_id;
+        rtn.SessionID = key.session_id;
+        set_string(&mut rtn.OrderRef, &key.order_ref);
+        set_string(&mut rtn.InstrumentID, "rb2501");
+        set_string(&mut rtn.ExchangeID, "SHFE");
+        set_string(&mut rtn.OrderSysID, sys_id);
+        rtn.Direction = THOST_FTDC_D_Buy as _;
+        rtn.CombOffsetFlag[0] = THOST_FTDC_OF_Open as _;
+        rtn.LimitPrice = 3500.0;
+        rtn.VolumeTotalOriginal = 3;
+        rtn.VolumeTraded = traded;
+        rtn.OrderSubmitStatus = THOST_FTDC_OSS_Accepted as _;
+        rtn.OrderStatus = status as _;
+        rtn
+    }
+
+    fn trade(key: &OrderKey, sys_id: &str, trade_id: &str, price: f64, volume: i32) -> CThostFtdcTradeField {
+        let mut trade: CThostFtdcTradeField = zeroed();
+        set_string(&mut trade.ExchangeID, "SHFE");
+        set_string(&mut trade.OrderSysID, sys_id);
+        set_string(&mut trade.OrderRef, &key.order_ref);
+        set_string(&mut trade.TradeID, trade_id);
+        trade.Price = price;
+        trade.Volume = volume;
+        trade
+    }
+
+    #[test]
+    fn insert_fill_and_cancel() {
+        let mut manager = logged_in();
+        let mut input = input(3);
+        let key = manager.insert(&mut input);
+        assert_eq!(
+            key,
+            OrderKey {
+                front_id: 1,
+                session_id: 2,
+                order_ref: "11".to_string(),
+            }
+        );
+        assert_eq!(to_string(&input.OrderRef), "11");
+        assert_eq!(manager.get(&key).unwrap().status, OrderStatus::Pending);
+
+        manager.on_rtn_order(&rtn(&key, "", THOST_FTDC_OST_Unknown, 0));
+        let order = manager.on_rtn_order(&rtn(&key, "  1001", THOST_FTDC_OST_NoTradeQueueing, 0)).unwrap();
+        assert_eq!(order.status, OrderStatus::NoTradeQueueing);
+        assert_eq!(manager.get_by_sys_id("SHFE", "  1001").unwrap().key, key);
+
+        let order = manager.on_rtn_trade(&trade(&key, "  1001", "1", 3500.0, 1)).unwrap();
+        assert_eq!((order.filled(), order.remaining()), (1, 2));
+        manager.on_rtn_order(&rtn(&key, "  1001", THOST_FTDC_OST_PartTradedQueueing, 1));
+
+        let action = manager.cancel(&key).unwrap();
+        assert_eq!(to_string(&action.OrderSysID), "  1001");
+        assert_eq!(to_string(&action.OrderRef), "11");
+        assert_eq!(action.ActionFlag as u8, THOST_FTDC_AF_Delete);
+
+        let order = manager.on_rtn_order(&rtn(&key, "  1001", THOST_FTDC_OST_Canceled, 1)).unwrap();
+        assert_eq!(order.status, OrderStatus::Canceled);
+        assert_eq!((order.filled(), order.remaining()), (1, 0));
+        assert_eq!(order.avg_price(), Some(3500.0));
+        assert!(manager.cancel(&key).is_none());
+        assert_eq!(manager.active().count(), 0);
+
+        // a late return does not revive it
+        manager.on_rtn_order(&rtn(&key, "  1001", THOST_FTDC_OST_PartTradedQueueing, 1));
+        assert_eq!(manager.get(&key).unwrap().status, OrderStatus::Canceled);
+    }
+
+    #[test]
+    fn trade_before_its_order() {
+        let mut manager = logged_in();
+        let key = manager.insert(&mut input(3));
+        assert!(manager.on_rtn_trade(&trade(&key, "  1001", "1", 3500.0, 2)).is_none());
+        assert!(manager.on_rtn_trade(&trade(&key, "  1001", "2", 3510.0, 1)).is_none());
+
+        let order = manager.on_rtn_order(&rtn(&key, "  1001", THOST_FTDC_OST_PartTradedQueueing, 2)).unwrap();
+        assert_eq!(order.trade_volume, 3);
+        assert_eq!(order.status, OrderStatus::AllTraded);
+        assert!((order.avg_price().unwrap() - 10510.0 / 3.0).abs() < 1e-9);
+
+        // trades replayed on resume count once
+        let order = manager.on_rtn_trade(&trade(&key, "  1001", "2", 3510.0, 1)).unwrap();
+        assert_eq!(order.trade_volume, 3);
+    }
+
+    #[test]
+    fn rejected_by_the_exchange() {
+        let mut manager = logged_in();
+        let mut first = input(1);
+        let key = manager.insert(&mut first);
+        let order = manager.on_err_rtn_order_insert(&first, None).unwrap();
+        assert_eq!(order.key, key);
+        assert_eq!(order.status, OrderStatus::Rejected);
+        assert_eq!(order.reject_reason.as_deref(), Some("rejected"));
+
+        let key = manager.insert(&mut input(1));
+        assert_eq!(key.order_ref, "12");
+        let mut rejected = rtn(&key, "", THOST_FTDC_OST_Canceled, 0);
+        rejected.OrderSubmitStatus = THOST_FTDC_OSS_InsertRejected as _;
+        set_string(&mut rejected.StatusMsg, "price out of range");
+        let order = manager.on_rtn_order(&rejected).unwrap();
+        assert_eq!(order.status, OrderStatus::Rejected);
+        assert_eq!(order.reject_reason.as_deref(), Some("price out of range"));
+    }
+
+    #[test]
+    fn refused_cancel_keeps_the_order() {
+        let mut manager = logged_in();
+        let key = manager.insert(&mut input(3));
+        manager.on_rtn_order(&rtn(&key, "  1001", THOST_FTDC_OST_NoTradeQueueing, 0));
+        let mut action = manager.cancel(&key).unwrap();
+        // refused by the exchange, found by its sys id
+        action.FrontID = 9;
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        info.ErrorID = 26;
+        set_string(&mut info.ErrorMsg, "order finished");
+        let order = manager.on_rsp_order_action(&action, Some(&info)).unwrap();
+        assert_eq!(order.cancel_error.as_deref(), Some("26 order finished"));
+        assert!(order.is_active());
+    }
+
+    #[test]
+    fn rejection_reasons_are_decoded_from_gbk() {
+        let mut info: CThostFtdcRspInfoField = zeroed();
+        info.ErrorID = 31;
+        let (msg, _, _) = encoding_rs::GBK.encode("CTP:资金不足");
+        for (dst, src) in info.ErrorMsg.iter_mut().zip(msg.iter()) {
+            *dst = *src as c_char;
+        }
+        assert_eq!(rsp_error(Some(&info)).as_deref(), Some("31 CTP:资金不足"));
+
+        let mut manager = OrderManager::new();
+        let mut input: CThostFtdcInputOrderField = zeroed();
+        input.VolumeTotalOriginal = 1;
+        let key = manager.insert(&mut input);
+        let order = manager.on_rsp_order_insert(&input, Some(&info)).unwrap();
+        assert_eq!(order.key, key);
+        assert_eq!(order.status, OrderStatus::Rejected);
+        assert_eq!(order.reject_reason.as_deref(), Some("31 CTP:资金不足"));
+    }
+
+    #[test]
+    fn only_trades_of_own_unlinked_orders_are_held() {
+        let mut manager = OrderManager::new();
+        let mut input: CThostFtdcInputOrderField = zeroed();
+        input.VolumeTotalOriginal = 1;
+        let key = manager.insert(&mut input);
+
+        let mut trade: CThostFtdcTradeField = zeroed();
+        set_string(&mut trade.ExchangeID, "SHFE");
+        set_string(&mut trade.OrderSysID, "  77");
+        set_string(&mut trade.OrderRef, "99");
+        manager.on_rtn_trade(&trade);
+        assert!(manager.early_trades.is_empty());
+
+        set_string(&mut trade.OrderRef, &key.order_ref);
+        manager.on_rtn_trade(&trade);
+        assert_eq!(manager.early_trades[&key].len(), 1);
+        manager.on_rsp_order_insert(&input, None);
+        assert!(manager.early_trades.is_empty());
+    }
+}
@@ -0,0 +1,509 @@
+//! Live positions per (instrument, direction, hedge flag), split into today's
+//! and yesterday's volume.
+//!
+//! SHFE and INE keep the two apart: `Close` there closes yesterday's position
+//! only and today's must be closed with `CloseToday`, otherwise the order is
+//! rejected with "平今仓位不足". Other exchanges take a plain `Close` and
+//! close yesterday's position first.
+
+use super::order::{OrderKey, OrderStatus};
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{HashMap, HashSet};
+use std::os::raw::c_char;
+
+use log::*;
+
+/// Whether `exchange_id` requires `CloseToday` and `CloseYesterday`.
+pub fn separates_today(exchange_id: &str) -> bool {
+    matches!(exchange_id, "SHFE" | "INE")
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum PosiDirection {
+    Long,
+    Short,
+}
+
+impl PosiDirection {
+    /// `THOST_FTDC_PD_*`, net positions are not tracked.
+    #[allow(non_upper_case_globals)]
+    pub fn from_pd(direction: c_char) -> Option<Self> {
+        match direction as u8 {
+            THOST_FTDC_PD_Long => Some(Self::Long),
+            THOST_FTDC_PD_Short => Some(Self::Short),
+            _ => None,
+        }
+    }
+
+    /// The position a buy or sell opens.
+    pub fn opened_by(direction: c_char) -> Self {
+        if direction as u8 == THOST_FTDC_D_Buy {
+            Self::Long
+        } else {
+            Self::Short
+        }
+    }
+
+    /// The position a buy or sell with `offset` acts on.
+    pub fn of_order(direction: c_char, offset: c_char) -> Self {
+        let opened = Self::opened_by(direction);
+        if is_open(offset) {
+            opened
+        } else {
+            opened.opposite()
+        }
+    }
+
+    pub fn opposite(&self) -> Self {
+        match self {
+            Self::Long => Self::Short,
+            Self::Short => Self::Long,
+        }
+    }
+}
+
+fn is_open(offset: c_char) -> bool {
+    offset as u8 == THOST_FTDC_OF_Open
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct PositionKey {
+    pub instrument_id: String,
+    pub direction: PosiDirection,
+    pub hedge_flag: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Position {
+    pub key: PositionKey,
+    pub exchange_id: String,
+    pub today: i32,
+    pub yesterday: i32,
+    /// Volume held by pending close orders.
+    pub today_frozen: i32,
+    pub yesterday_frozen: i32,
+    /// Open cost in price, price times volume without the multiplier.
+    pub open_cost: f64,
+    pub multiplier: f64,
+}
+
+impl Position {
+    fn new(key: PositionKey, exchange_id: &str, multiplier: f64) -> Self {
+        Self {
+            key,
+            exchange_id: exchange_id.to_string(),
+            today: 0,
+            yesterday: 0,
+            today_frozen: 0,
+            yesterday_frozen: 0,
+            open_cost: 0.0,
+            multiplier,
+        }
+    }
+
+    pub fn volume(&self) -> i32 {
+        self.today + self.yesterday
+    }
+
+    pub fn available_today(&self) -> i32 {
+        (self.today - self.today_frozen).max(0)
+    }
+
+    pub fn available_yesterday(&self) -> i32 {
+        (self.yesterday - self.yesterday_frozen).max(0)
+    }
+
+    pub fn available(&self) -> i32 {
+        self.available_today() + self.available_yesterday()
+    }
+
+    /// Average open price.
+    pub fn avg_price(&self) -> Option<f64> {
+        let volume = self.volume();
+        if volume == 0 {
+            return None;
+        }
+        Some(self.open_cost / volume as f64)
+    }
+
+    /// Offsets to close `volume` with, in the order to send them. Less than
+    /// `volume` is covered when not enough is available.
+    pub fn close_offsets(&self, volume: i32) -> Vec<(u8, i32)> {
+        let mut offsets = vec![];
+        if separates_today(&self.exchange_id) {
+            let yesterday = volume.min(self.available_yesterday());
+            let today = (volume - yesterday).min(self.available_today());
+            if yesterday > 0 {
+                offsets.push((THOST_FTDC_OF_CloseYesterday, yesterday));
+            }
+            if today > 0 {
+                offsets.push((THOST_FTDC_OF_CloseToday, today));
+            }
+        } else {
+            let volume = volume.min(self.available());
+            if volume > 0 {
+                offsets.push((THOST_FTDC_OF_Close, volume));
+            }
+        }
+        offsets
+    }
+
+    /// Split `volume` closed with `offset` into (today, yesterday), given
+    /// what yesterday's position holds.
+    #[allow(non_upper_case_globals)]
+    fn split_close(&self, offset: c_char, volume: i32, yesterday: i32) -> (i32, i32) {
+        match offset as u8 {
+            THOST_FTDC_OF_CloseToday => (volume, 0),
+            THOST_FTDC_OF_CloseYesterday => (0, volume),
+            _ if separates_today(&self.exchange_id) => (0, volume),
+            _ => {
+                let yesterday = volume.min(yesterday);
+                (volume - yesterday, yesterday)
+            }
+        }
+    }
+
+    fn open(&mut self, volume: i32, price: f64) {
+        self.today += volume;
+        self.open_cost += price * volume as f64;
+    }
+
+    fn close(&mut self, offset: c_char, volume: i32) {
+        let avg_cost = if self.volume() > 0 { self.open_cost / self.volume() as f64 } else { 0.0 };
+        let (today, yesterday) = self.split_close(offset, volume, self.yesterday);
+        self.today -= today;
+        self.yesterday -= yesterday;
+        if self.today < 0 || self.yesterday < 0 {
+            warn!("position {:?} closed beyond its bucket, today {} yesterday {}", self.key, self.today, self.yesterday);
+            // the book is off, spill into the other bucket
+            if self.today < 0 {
+                self.yesterday += self.today;
+                self.today = 0;
+            }
+            if self.yesterday < 0 {
+                self.today = (self.today + self.yesterday).max(0);
+                self.yesterday = 0;
+            }
+        }
+        self.open_cost = (self.open_cost - avg_cost * volume as f64).max(0.0);
+        if self.volume() == 0 {
+            self.open_cost = 0.0;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Freeze {
+    key: PositionKey,
+    today: i32,
+    yesterday: i32,
+}
+
+/// A queried book being accumulated until `bIsLast`.
+type Pending = Option<HashMap<PositionKey, Position>>;
+
+/// Positions of one account, fed from the trader spi.
+#[derive(Debug, Default)]
+pub struct PositionBook {
+    positions: HashMap<PositionKey, Position>,
+    multipliers: HashMap<String, f64>,
+    freezes: HashMap<OrderKey, Freeze>,
+    trade_ids: HashSet<(String, String, u8)>,
+    queried: Pending,
+    detailed: Pending,
+}
+
+impl PositionBook {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// `VolumeMultiple` of `instrument`, 1 until set. Set it before
+    /// `ReqQryInvestorPosition`, its `OpenCost` is divided by it.
+    pub fn set_multiplier(&mut self, instrument: &str, multiplier: f64) {
+        self.multipliers.insert(instrument.to_string(), multiplier);
+        for position in self.positions.values_mut().filter(|p| p.key.instrument_id == instrument) {
+            position.multiplier = multiplier;
+        }
+    }
+
+    pub fn get(&self, key: &PositionKey) -> Option<&Position> {
+        self.positions.get(key)
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = &Position> {
+        self.positions.values()
+    }
+
+    /// The position of `instrument` in `direction` with speculation hedge flag.
+    pub fn speculation(&self, instrument: &str, direction: PosiDirection) -> Option<&Position> {
+        self.get(&PositionKey {
+            instrument_id: instrument.to_string(),
+            direction,
+            hedge_flag: THOST_FTDC_HF_Speculation,
+        })
+    }
+
+    pub fn on_rtn_trade(&mut self, trade: &CThostFtdcTradeField) {
+        let trade_id = (to_string(&trade.ExchangeID), to_string(&trade.TradeID), trade.Direction as u8);
+        // trades are replayed on resume, count each once
+        if !self.trade_ids.insert(trade_id) {
+            return;
+        }
+        let key = PositionKey {
+            instrument_id: to_string(&trade.InstrumentID),
+            direction: PosiDirection::of_order(trade.Direction, trade.OffsetFlag),
+            hedge_flag: trade.HedgeFlag as u8,
+        };
+        let position = self.entry(&key, &to_string(&trade.ExchangeID));
+        if is_open(trade.OffsetFlag) {
+            position.open(trade.Volume, trade.Price);
+        } else {
+            position.close(trade.OffsetFlag, trade.Volume);
+        }
+    }
+
+    /// Freeze the position a close order is pending on, release it as the
+    /// order fills or ends.
+    pub fn on_rtn_order(&mut self, order: &CThostFtdcOrderField) {
+        let offset = order.CombOffsetFlag[0];
+        if is_open(offset) {
+            return;
+        }
+        let order_key = OrderKey {
+            front_id: order.FrontID,
+            session_id: order.SessionID,
+            order_ref: to_string(&order.OrderRef),
+        };
+        let finished = order.OrderSubmitStatus as u8 == THOST_FTDC_OSS_InsertRejected
+            || OrderStatus::from_ost(order.OrderStatus).is_some_and(|s| s.is_finished());
+        let remaining = if finished { 0 } else { order.VolumeTotal.max(0) };
+
+        let freeze = match self.freezes.remove(&order_key) {
+            Some(freeze) => freeze,
+            None if remaining == 0 => return,
+            None => {
+                let key = PositionKey {
+                    instrument_id: to_string(&order.InstrumentID),
+                    direction: PosiDirection::of_order(order.Direction, offset),
+                    hedge_flag: order.CombHedgeFlag[0] as u8,
+                };
+                let position = self.entry(&key, &to_string(&order.ExchangeID));
+                let (today, yesterday) = position.split_close(offset, remaining, position.available_yesterday());
+                position.today_frozen += today;
+                position.yesterday_frozen += yesterday;
+                Freeze { key, today, yesterday }
+            }
+        };
+
+        // fills take yesterday's position first, release in the same order
+        let mut release = (freeze.today + freeze.yesterday - remaining).max(0);
+        let yesterday = release.min(freeze.yesterday);
+        release -= yesterday;
+        let today = release.min(freeze.today);
+        if let Some(position) = self.positions.get_mut(&freeze.key) {
+            position.yesterday_frozen = (position.yesterday_frozen - yesterday).max(0);
+            position.today_frozen = (position.today_frozen - today).max(0);
+        }
+        if remaining > 0 {
+            self.freezes.insert(
+                order_key,
+                Freeze {
+                    today: freeze.today - today,
+                    yesterday: freeze.yesterday - yesterday,
+                    ..freeze
+                },
+            );
+        }
+    }
+
+    /// A row of `ReqQryInvestorPosition`, the book is replaced on the last one.
+    /// Returns the positions whose volume differed from the live book.
+    pub fn on_rsp_qry_investor_position(&mut self, row: Option<&CThostFtdcInvestorPositionField>, is_last: bool) -> Vec<PositionKey> {
+        let book = self.queried.get_or_insert_with(HashMap::new);
+        if let Some(row) = row {
+            match PosiDirection::from_pd(row.PosiDirection) {
+                Some(direction) => {
+                    let key = PositionKey {
+                        instrument_id: to_string(&row.InstrumentID),
+                        direction,
+                        hedge_flag: row.HedgeFlag as u8,
+                    };
+                    let multiplier = match self.multipliers.get(&key.instrument_id) {
+                        Some(multiplier) if *multiplier > 0.0 => *multiplier,
+                        _ => {
+                            warn!("no multiplier of {}, average price taken from OpenCost as is", key.instrument_id);
+                            1.0
+                        }
+                    };
+                    let position = book
+                        .entry(key.clone())
+                        .or_insert_with(|| Position::new(key, &to_string(&row.ExchangeID), multiplier));
+                    // SHFE and INE return a row per position date, others a single row
+                    if row.PositionDate as u8 == THOST_FTDC_PSD_History {
+                        position.yesterday += row.Position;
+                    } else {
+                        position.today += row.TodayPosition;
+                        position.yesterday += row.Position - row.TodayPosition;
+                    }
+                    // `OpenCost` is in money
+                    position.open_cost += row.OpenCost / multiplier;
+                }
+                None => debug!("skip net position of {}", to_string(&row.InstrumentID)),
+            }
+        }
+        if !is_last {
+            return vec![];
+        }
+        let book = self.queried.take().unwrap_or_default();
+        self.reconcile(book)
+    }
+
+    /// A row of `ReqQryInvestorPositionDetail`, the book is replaced on the last one.
+    pub fn on_rsp_qry_investor_position_detail(&mut self, row: Option<&CThostFtdcInvestorPositionDetailField>, is_last: bool) -> Vec<PositionKey> {
+        let book = self.detailed.get_or_insert_with(HashMap::new);
+        if let Some(row) = row.filter(|r| r.Volume > 0) {
+            let key = PositionKey {
+                instrument_id: to_string(&row.InstrumentID),
+                direction: PosiDirection::opened_by(row.Direction),
+                hedge_flag: row.HedgeFlag as u8,
+            };
+            let multiplier = self.multipliers.get(&key.instrument_id).copied().unwrap_or(1.0);
+            let position = book
+                .entry(key.clone())
+                .or_insert_with(|| Position::new(key, &to_string(&row.ExchangeID), multiplier));
+            if row.OpenDate == row.TradingDay {
+                position.today += row.Volume;
+            } else {
+                position.yesterday += row.Volume;
+            }
+            position.open_cost += row.OpenPrice * row.Volume as f64;
+        }
+        if !is_last {
+            return vec![];
+        }
+        let book = self.detailed.take().unwrap_or_default();
+        self.reconcile(book)
+    }
+
+    fn reconcile(&mut self, mut book: HashMap<PositionKey, Position>) -> Vec<PositionKey> {
+        let mut keys: Vec<&PositionKey> = self.positions.keys().chain(book.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        let mut differed = vec![];
+        for key in keys {
+            let live = self.positions.get(key).map_or((0, 0), |p| (p.today, p.yesterday));
+            let queried = book.get(key).map_or((0, 0), |p| (p.today, p.yesterday));
+            if live != queried {
+                warn!("position {:?} live {:?} queried {:?}, take queried", key, live, queried);
+                differed.push(key.clone());
+            }
+        }
+
+        // pending close orders still hold their volume
+        for freeze in self.freezes.values() {
+            if let Some(position) = book.get_mut(&freeze.key) {
+                position.today_frozen += freeze.today;
+                position.yesterday_frozen += freeze.yesterday;
+            }
+        }
+        self.positions = book;
+        differed
+    }
+
+    fn entry(&mut self, key: &PositionKey, exchange_id: &str) -> &mut Position {
+        let multiplier = self.multipliers.get(&key.instrument_id).copied().unwrap_or(1.0);
+        let position = self
+            .positions
+            .entry(key.clone())
+            .or_insert_with(|| Position::new(key.clone(), exchange_id, multiplier));
+        if position.exchange_id.is_empty() {
+            position.exchange_id = exchange_id.to_string();
+        }
+        position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(id: &str, direction: u8, offset: u8, price: f64, volume: i32) -> CThostFtdcTradeField {
+        let mut trade: CThostFtdcTradeField = zeroed();
+        set_string(&mut trade.InstrumentID, "rb2501");
+        set_string(&mut trade.ExchangeID, "SHFE");
+        set_string(&mut trade.TradeID, id);
+        trade.Direction = direction as _;
+        trade.OffsetFlag = offset as _;
+        trade.HedgeFlag = THOST_FTDC_HF_Speculation as _;
+        trade.Price = price;
+        trade.Volume = volume;
+        trade
+    }
+
+    fn close_order(order_ref: &str, volume: i32, status: u8) -> CThostFtdcOrderField {
+        let mut order: CThostFtdcOrderField = zeroed();
+        set_string(&mut order.InstrumentID, "rb2501");
+        set_string(&mut order.ExchangeID, "SHFE");
+        set_string(&mut order.OrderRef, order_ref);
+        order.Direction = THOST_FTDC_D_Sell as _;
+        order.CombOffsetFlag[0] = THOST_FTDC_OF_CloseToday as _;
+        order.CombHedgeFlag[0] = THOST_FTDC_HF_Speculation as _;
+        order.VolumeTotal = volume;
+        order.OrderStatus = status as _;
+        order
+    }
+
+    #[test]
+    fn trades_open_and_close() {
+        let mut book = PositionBook::new();
+        book.on_rtn_trade(&trade("1", THOST_FTDC_D_Buy, THOST_FTDC_OF_Open, 3500.0, 2));
+        book.on_rtn_trade(&trade("2", THOST_FTDC_D_Buy, THOST_FTDC_OF_Open, 3510.0, 2));
+        // replayed on resume
+        book.on_rtn_trade(&trade("2", THOST_FTDC_D_Buy, THOST_FTDC_OF_Open, 3510.0, 2));
+        let long = book.speculation("rb2501", PosiDirection::Long).unwrap();
+        assert_eq!((long.today, long.avg_price()), (4, Some(3505.0)));
+
+        book.on_rtn_trade(&trade("3", THOST_FTDC_D_Sell, THOST_FTDC_OF_CloseToday, 3520.0, 1));
+        let long = book.speculation("rb2501", PosiDirection::Long).unwrap();
+        assert_eq!((long.today, long.avg_price()), (3, Some(3505.0)));
+    }
+
+    #[test]
+    fn pending_closes_freeze_the_position() {
+        let mut book = PositionBook::new();
+        book.on_rtn_trade(&trade("1", THOST_FTDC_D_Buy, THOST_FTDC_OF_Open, 3500.0, 5));
+        book.on_rtn_order(&close_order("9", 3, THOST_FTDC_OST_NoTradeQueueing));
+        let long = book.speculation("rb2501", PosiDirection::Long).unwrap();
+        assert_eq!((long.today_frozen, long.available()), (3, 2));
+
+        book.on_rtn_order(&close_order("9", 1, THOST_FTDC_OST_PartTradedQueueing));
+        assert_eq!(book.speculation("rb2501", PosiDirection::Long).unwrap().today_frozen, 1);
+        book.on_rtn_order(&close_order("9", 1, THOST_FTDC_OST_Canceled));
+        assert_eq!(book.speculation("rb2501", PosiDirection::Long).unwrap().today_frozen, 0);
+    }
+
+    #[test]
+    fn queried_open_cost_is_not_scaled_twice() {
+        let mut book = PositionBook::new();
+        book.set_multiplier("rb2501", 10.0);
+        let mut row: CThostFtdcInvestorPositionField = zeroed();
+        set_string(&mut row.InstrumentID, "rb2501");
+        set_string(&mut row.ExchangeID, "SHFE");
+        row.PosiDirection = THOST_FTDC_PD_Long as _;
+        row.HedgeFlag = THOST_FTDC_HF_Speculation as _;
+        row.PositionDate = THOST_FTDC_PSD_Today as _;
+        row.Position = 2;
+        row.TodayPosition = 2;
+        row.OpenCost = 3500.0 * 2.0 * 10.0;
+        let differed = book.on_rsp_qry_investor_position(Some(&row), true);
+        assert_eq!(differed.len(), 1);
+        assert_eq!(book.speculation("rb2501", PosiDirection::Long).unwrap().avg_price(), Some(3500.0));
+
+        book.set_multiplier("rb2501", 10.0);
+        book.on_rtn_trade(&trade("1", THOST_FTDC_D_Buy, THOST_FTDC_OF_Open, 3530.0, 1));
+        assert_eq!(book.speculation("rb2501", PosiDirection::Long).unwrap().avg_price(), Some(3510.0));
+    }
+}
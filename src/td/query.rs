@@ -0,0 +1,239 @@
+//! Queued `ReqQry*` calls, spaced to CTP's flow control: one query in flight
+//! and at most `qry_freq` per second (1 when unset). Beyond that the api
+//! returns -2 or -3 and the query is retried after a backoff.
+//!
+//! A query is in flight until its last page arrives, report it with
+//! [`QueryDone::finish`] from the spi, the next one waits for it.
+
+use super::TdApi;
+
+use std::collections::{HashSet, VecDeque};
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+// a lost response must not stall the queue
+const IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Urgent queries jump ahead of normal ones, bulk ones wait for both.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub enum Priority {
+    /// e.g. positions after a fill.
+    Urgent,
+    #[default]
+    Normal,
+    /// e.g. the instrument list.
+    Bulk,
+}
+
+pub type SendQuery = Box<dyn FnMut(&mut TdApi) -> Result<c_int, String> + Send>;
+
+pub(crate) struct Query {
+    pub name: String,
+    pub priority: Priority,
+    pub attempts: u32,
+    pub send: SendQuery,
+}
+
+/// Marks the in-flight query done, shared with the spi thread. Only the
+/// exact request id of the query finishes it, errors of other requests sent
+/// meanwhile do not.
+#[derive(Debug, Clone, Default)]
+pub struct QueryDone {
+    // the last page may come before the queue learns the id it was sent with
+    finished: Arc<Mutex<HashSet<c_int>>>,
+}
+
+impl QueryDone {
+    /// Call from every `OnRspQry*` and `OnRspError`, only the last page counts.
+    pub fn finish(&self, request_id: c_int, is_last: bool) {
+        if is_last {
+            self.finished.lock().unwrap().insert(request_id);
+        }
+    }
+
+    fn is_done(&self, request_id: c_int) -> bool {
+        self.finished.lock().unwrap().contains(&request_id)
+    }
+
+    /// Forget ids older than `request_id`, none of them can be in flight.
+    fn forget_before(&self, request_id: c_int) {
+        self.finished.lock().unwrap().retain(|id| *id >= request_id);
+    }
+
+    fn clear(&self) {
+        self.finished.lock().unwrap().clear();
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct QueryQueue {
+    queues: [VecDeque<Query>; 3],
+    in_flight: Option<(c_int, String, Instant)>,
+    last_sent: Option<Instant>,
+    not_before: Option<Instant>,
+    done: QueryDone,
+}
+
+impl QueryQueue {
+    pub fn push(&mut self, query: Query) {
+        self.queues[query.priority as usize].push_back(query);
+    }
+
+    /// Put a query refused by flow control back at the head, retried after `backoff`.
+    pub fn retry(&mut self, mut query: Query, interval: Duration) {
+        query.attempts += 1;
+        let backoff = (interval * 2u32.pow(query.attempts.min(4))).min(MAX_BACKOFF);
+        warn!("query `{}` throttled, retry {} in {:?}", query.name, query.attempts, backoff);
+        self.not_before = Some(Instant::now() + backoff);
+        self.queues[query.priority as usize].push_front(query);
+    }
+
+    /// The next query when one may be sent now.
+    pub fn pop(&mut self, interval: Duration) -> Option<Query> {
+        let now = Instant::now();
+        if let Some((request_id, name, sent)) = &self.in_flight {
+            if self.done.is_done(*request_id) {
+                self.in_flight = None;
+            } else if now.duration_since(*sent) > IN_FLIGHT_TIMEOUT {
+                warn!("query `{}` ({}) got no last page, give up waiting", name, request_id);
+                self.in_flight = None;
+            } else {
+                return None;
+            }
+            self.done.clear();
+        }
+        if self.last_sent.is_some_and(|t| now.duration_since(t) < interval) || self.not_before.is_some_and(|t| now < t) {
+            return None;
+        }
+        self.queues.iter_mut().find_map(|q| q.pop_front())
+    }
+
+    pub fn sent(&mut self, request_id: Option<c_int>, name: &str) {
+        let now = Instant::now();
+        self.last_sent = Some(now);
+        if let Some(request_id) = request_id {
+            self.done.forget_before(request_id);
+            self.in_flight = Some((request_id, name.to_string(), now));
+            self.not_before = None;
+        }
+    }
+
+    pub fn done(&self) -> QueryDone {
+        self.done.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    pub fn in_flight(&self) -> Option<c_int> {
+        self.in_flight.as_ref().map(|(request_id, _, _)| *request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, priority: Priority) -> Query {
+        Query {
+            name: name.to_string(),
+            priority,
+            attempts: 0,
+            send: Box::new(|_| Ok(0)),
+        }
+    }
+
+    fn names(queue: &mut QueryQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop(Duration::ZERO)).map(|q| q.name).collect()
+    }
+
+    #[test]
+    fn urgent_first_bulk_last() {
+        let mut queue = QueryQueue::default();
+        queue.push(query("instruments", Priority::Bulk));
+        queue.push(query("account", Priority::Normal));
+        queue.push(query("positions", Priority::Urgent));
+        queue.push(query("orders", Priority::Normal));
+        assert_eq!(names(&mut queue), ["positions", "account", "orders", "instruments"]);
+    }
+
+    #[test]
+    fn waits_for_the_last_page() {
+        let mut queue = QueryQueue::default();
+        queue.push(query("account", Priority::Normal));
+        queue.push(query("positions", Priority::Normal));
+        let done = queue.done();
+
+        assert!(queue.pop(Duration::ZERO).is_some());
+        queue.sent(Some(3), "account");
+        assert!(queue.pop(Duration::ZERO).is_none());
+        done.finish(3, false);
+        assert!(queue.pop(Duration::ZERO).is_none());
+        done.finish(3, true);
+        assert_eq!(queue.pop(Duration::ZERO).unwrap().name, "positions");
+        assert_eq!(queue.in_flight(), None);
+    }
+
+    #[test]
+    fn only_the_in_flight_id_finishes_it() {
+        let mut queue = QueryQueue::default();
+        queue.push(query("account", Priority::Normal));
+        queue.push(query("positions", Priority::Normal));
+        let done = queue.done();
+
+        queue.pop(Duration::ZERO).unwrap();
+        queue.sent(Some(3), "account");
+        // errors of requests sent outside the queue, before and after it
+        done.finish(2, true);
+        done.finish(5, true);
+        assert!(queue.pop(Duration::ZERO).is_none());
+        assert_eq!(queue.in_flight(), Some(3));
+        done.finish(3, true);
+        assert_eq!(queue.pop(Duration::ZERO).unwrap().name, "positions");
+        assert_eq!(queue.in_flight(), None);
+    }
+
+    #[test]
+    fn a_last_page_before_the_id_is_known() {
+        let mut queue = QueryQueue::default();
+        queue.push(query("account", Priority::Normal));
+        queue.push(query("positions", Priority::Normal));
+        let done = queue.done();
+
+        queue.pop(Duration::ZERO).unwrap();
+        done.finish(7, true);
+        queue.sent(Some(7), "account");
+        assert_eq!(queue.pop(Duration::ZERO).unwrap().name, "positions");
+    }
+
+    #[test]
+    fn throttled_query_goes_back_to_the_head() {
+        let mut queue = QueryQueue::default();
+        queue.push(query("account", Priority::Normal));
+        queue.push(query("positions", Priority::Normal));
+
+        let account = queue.pop(Duration::ZERO).unwrap();
+        queue.sent(None, "account");
+        queue.retry(account, Duration::ZERO);
+        assert_eq!(queue.len(), 2);
+        let account = queue.pop(Duration::ZERO).unwrap();
+        assert_eq!((account.name.as_str(), account.attempts), ("account", 1));
+    }
+
+    #[test]
+    fn spaced_by_the_interval() {
+        let mut queue = QueryQueue::default();
+        queue.push(query("account", Priority::Normal));
+        queue.push(query("positions", Priority::Normal));
+
+        queue.pop(Duration::ZERO).unwrap();
+        queue.sent(None, "account");
+        assert!(queue.pop(Duration::from_secs(60)).is_none());
+        assert!(queue.pop(Duration::ZERO).is_some());
+    }
+}
@@ -0,0 +1,618 @@
+//! Two-sided market maker quotes (报价) from `ReqQuoteInsert`.
+//!
+//! A quote is known by `(FrontID, SessionID, QuoteRef)` and by `QuoteSysID`
+//! once the exchange accepts it. Each leg becomes a derived order with its own
+//! `AskOrderRef` / `BidOrderRef`, returned through `OnRtnOrder` and filled
+//! through `OnRtnTrade` like any other order. [`QuoteManager`] links those
+//! back to the quote, the [`super::order::OrderManager`] still sees them too.
+//!
+//! Answers to for-quote notices from [`crate::md::for_quote::ForQuoteHub`] are
+//! built with [`QuoteBuilder::answer`].
+
+use super::order::{rsp_error, OrderKey, OrderManager, OrderStatus};
+use crate::md::for_quote::ForQuote;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{HashMap, HashSet};
+use std::os::raw::c_char;
+use std::time::Duration;
+
+use log::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum QuoteSide {
+    Bid,
+    Ask,
+}
+
+/// A quote to send, both legs opening with the market maker hedge flag by
+/// default. A one-sided quote leaves the other leg at zero volume.
+#[derive(Debug, Clone)]
+pub struct QuoteBuilder {
+    instrument_id: String,
+    exchange_id: String,
+    bid: (f64, i32),
+    ask: (f64, i32),
+    bid_offset: u8,
+    ask_offset: u8,
+    hedge_flag: u8,
+    for_quote_sys_id: String,
+}
+
+impl QuoteBuilder {
+    pub fn new(instrument_id: &str, exchange_id: &str) -> Self {
+        Self {
+            instrument_id: instrument_id.to_string(),
+            exchange_id: exchange_id.to_string(),
+            bid: (0.0, 0),
+            ask: (0.0, 0),
+            bid_offset: THOST_FTDC_OF_Open,
+            ask_offset: THOST_FTDC_OF_Open,
+            hedge_flag: THOST_FTDC_HF_MarketMaker,
+            for_quote_sys_id: String::new(),
+        }
+    }
+
+    /// A quote answering `notice`, refused once `deadline` has passed since
+    /// the notice was received.
+    pub fn answer(notice: &ForQuote, deadline: Duration) -> Result<Self, String> {
+        if notice.remaining(deadline).is_none() {
+            return Err(format!("for quote {} of {} expired", notice.for_quote_sys_id, notice.instrument_id));
+        }
+        let mut builder = Self::new(&notice.instrument_id, &notice.exchange_id);
+        builder.for_quote_sys_id = notice.for_quote_sys_id.clone();
+        Ok(builder)
+    }
+
+    pub fn bid(mut self, price: f64, volume: i32) -> Self {
+        self.bid = (price, volume);
+        self
+    }
+
+    pub fn ask(mut self, price: f64, volume: i32) -> Self {
+        self.ask = (price, volume);
+        self
+    }
+
+    /// `THOST_FTDC_OF_*` of each leg.
+    pub fn offsets(mut self, bid_offset: u8, ask_offset: u8) -> Self {
+        self.bid_offset = bid_offset;
+        self.ask_offset = ask_offset;
+        self
+    }
+
+    /// `THOST_FTDC_HF_*` of both legs.
+    pub fn hedge_flag(mut self, hedge_flag: u8) -> Self {
+        self.hedge_flag = hedge_flag;
+        self
+    }
+
+    /// The request, to go through [`QuoteManager::insert`] and
+    /// `TdApi::req_quote_insert`.
+    pub fn build(&self) -> CThostFtdcInputQuoteField {
+        let mut field: CThostFtdcInputQuoteField = zeroed();
+        set_string(&mut field.InstrumentID, &self.instrument_id);
+        set_string(&mut field.ExchangeID, &self.exchange_id);
+        field.BidPrice = self.bid.0;
+        field.BidVolume = self.bid.1;
+        field.AskPrice = self.ask.0;
+        field.AskVolume = self.ask.1;
+        field.BidOffsetFlag = self.bid_offset as _;
+        field.AskOffsetFlag = self.ask_offset as _;
+        field.BidHedgeFlag = self.hedge_flag as _;
+        field.AskHedgeFlag = self.hedge_flag as _;
+        set_string(&mut field.ForQuoteSysID, &self.for_quote_sys_id);
+        field
+    }
+}
+
+/// One side of a quote, followed through its derived order.
+#[derive(Debug, Clone)]
+pub struct QuoteLeg {
+    /// `OrderRef` of the derived order, empty for a missing leg.
+    pub order_ref: String,
+    /// Empty until the exchange accepts the quote.
+    pub order_sys_id: String,
+    pub price: f64,
+    pub volume: i32,
+    pub offset: u8,
+    /// `OrderStatus` of the latest `OnRtnOrder`, `Pending` before it.
+    pub status: OrderStatus,
+    /// `VolumeTraded` of the latest `OnRtnOrder`.
+    pub volume_traded: i32,
+    /// Volume and turnover of the trades seen.
+    pub trade_volume: i32,
+    pub trade_turnover: f64,
+    pub trade_ids: HashSet<String>,
+}
+
+impl QuoteLeg {
+    fn new(order_ref: String, price: f64, volume: i32, offset: c_char) -> Self {
+        Self {
+            order_ref,
+            order_sys_id: String::new(),
+            price,
+            volume,
+            offset: offset as u8,
+            status: OrderStatus::Pending,
+            volume_traded: 0,
+            trade_volume: 0,
+            trade_turnover: 0.0,
+            trade_ids: HashSet::new(),
+        }
+    }
+
+    pub fn filled(&self) -> i32 {
+        self.volume_traded.max(self.trade_volume)
+    }
+
+    pub fn remaining(&self) -> i32 {
+        if self.status.is_finished() {
+            return 0;
+        }
+        (self.volume - self.filled()).max(0)
+    }
+
+    /// Volume weighted price of the trades seen.
+    pub fn avg_price(&self) -> Option<f64> {
+        if self.trade_volume == 0 {
+            return None;
+        }
+        Some(self.trade_turnover / self.trade_volume as f64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Quote {
+    /// `order_ref` is the `QuoteRef`.
+    pub key: OrderKey,
+    pub exchange_id: String,
+    pub instrument_id: String,
+    /// Empty until the exchange accepts the quote.
+    pub quote_sys_id: String,
+    /// The for-quote answered, if any.
+    pub for_quote_sys_id: String,
+    pub bid: QuoteLeg,
+    pub ask: QuoteLeg,
+    /// `QuoteStatus`, in `THOST_FTDC_OST_*` like orders.
+    pub status: OrderStatus,
+    /// The quote that replaced this one.
+    pub replaced_by: Option<OrderKey>,
+    pub reject_reason: Option<String>,
+    /// Reason of the latest refused cancel.
+    pub cancel_error: Option<String>,
+    pub status_msg: String,
+    pub insert_time: String,
+}
+
+impl Quote {
+    fn new(key: OrderKey) -> Self {
+        Self {
+            key,
+            exchange_id: String::new(),
+            instrument_id: String::new(),
+            quote_sys_id: String::new(),
+            for_quote_sys_id: String::new(),
+            bid: QuoteLeg::new(String::new(), 0.0, 0, 0),
+            ask: QuoteLeg::new(String::new(), 0.0, 0, 0),
+            status: OrderStatus::Pending,
+            replaced_by: None,
+            reject_reason: None,
+            cancel_error: None,
+            status_msg: String::new(),
+            insert_time: String::new(),
+        }
+    }
+
+    fn set_input(&mut self, input: &CThostFtdcInputQuoteField) {
+        self.exchange_id = to_string(&input.ExchangeID);
+        self.instrument_id = to_string(&input.InstrumentID);
+        self.for_quote_sys_id = to_string(&input.ForQuoteSysID);
+        self.bid = QuoteLeg::new(to_string(&input.BidOrderRef), input.BidPrice, input.BidVolume, input.BidOffsetFlag);
+        self.ask = QuoteLeg::new(to_string(&input.AskOrderRef), input.AskPrice, input.AskVolume, input.AskOffsetFlag);
+    }
+
+    pub fn leg(&self, side: QuoteSide) -> &QuoteLeg {
+        match side {
+            QuoteSide::Bid => &self.bid,
+            QuoteSide::Ask => &self.ask,
+        }
+    }
+
+    fn leg_mut(&mut self, side: QuoteSide) -> &mut QuoteLeg {
+        match side {
+            QuoteSide::Bid => &mut self.bid,
+            QuoteSide::Ask => &mut self.ask,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.status.is_finished()
+    }
+}
+
+/// All quotes of one trading session, fed from the trader spi.
+#[derive(Debug, Default)]
+pub struct QuoteManager {
+    front_id: i32,
+    session_id: i32,
+    quote_ref: i32,
+    quotes: HashMap<OrderKey, Quote>,
+    by_sys_id: HashMap<(String, String), OrderKey>,
+    /// Derived orders by `OrderRef` of this session and by `OrderSysID`.
+    legs: HashMap<OrderKey, (OrderKey, QuoteSide)>,
+    leg_sys_ids: HashMap<(String, String), (OrderKey, QuoteSide)>,
+    /// `OrderRef` of the legs of active quotes without `OrderSysID` yet, whose
+    /// trades may arrive first.
+    unlinked: HashSet<String>,
+    early_trades: HashMap<(String, String), Vec<CThostFtdcTradeField>>,
+}
+
+impl QuoteManager {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Take `FrontID`, `SessionID` and continue `QuoteRef` after `MaxOrderRef`.
+    pub fn on_rsp_user_login(&mut self, login: &CThostFtdcRspUserLoginField) {
+        self.front_id = login.FrontID;
+        self.session_id = login.SessionID;
+        self.quote_ref = to_string(&login.MaxOrderRef).trim().parse().unwrap_or(0);
+        debug!("quote session {}/{}, max order ref {}", self.front_id, self.session_id, self.quote_ref);
+    }
+
+    /// Track a quote about to be sent. `QuoteRef` is allocated when empty and
+    /// the refs of the legs from `orders`, so they never collide with orders.
+    pub fn insert(&mut self, input: &mut CThostFtdcInputQuoteField, orders: &mut OrderManager) -> OrderKey {
+        if to_string(&input.QuoteRef).is_empty() {
+            self.quote_ref += 1;
+            set_string(&mut input.QuoteRef, &self.quote_ref.to_string());
+        }
+        if input.BidVolume > 0 && to_string(&input.BidOrderRef).is_empty() {
+            set_string(&mut input.BidOrderRef, &orders.next_order_ref());
+        }
+        if input.AskVolume > 0 && to_string(&input.AskOrderRef).is_empty() {
+            set_string(&mut input.AskOrderRef, &orders.next_order_ref());
+        }
+        let key = self.own_key(&input.QuoteRef);
+        let mut quote = Quote::new(key.clone());
+        quote.set_input(input);
+        for side in [QuoteSide::Bid, QuoteSide::Ask] {
+            let order_ref = &quote.leg(side).order_ref;
+            if !order_ref.is_empty() {
+                self.legs.insert(self.own_key_str(order_ref), (key.clone(), side));
+                self.unlinked.insert(order_ref.clone());
+            }
+        }
+        self.quotes.insert(key.clone(), quote);
+        key
+    }
+
+    /// Replace the accepted quote `key` with `input`, tracked as by `insert`.
+    /// The exchange cancels the old quote when the new one is accepted.
+    pub fn replace(&mut self, key: &OrderKey, input: &mut CThostFtdcInputQuoteField, orders: &mut OrderManager) -> Result<OrderKey, String> {
+        let old = self.quotes.get(key).filter(|q| q.is_active()).ok_or_else(|| format!("quote {:?} not active", key))?;
+        if old.quote_sys_id.is_empty() {
+            return Err(format!("quote {:?} not accepted yet", key));
+        }
+        set_string(&mut input.ReplaceSysID, &old.quote_sys_id);
+        let new_key = self.insert(input, orders);
+        if let Some(old) = self.quotes.get_mut(key) {
+            old.replaced_by = Some(new_key.clone());
+        }
+        Ok(new_key)
+    }
+
+    /// A cancel request for `key`, to complete with broker and investor ids.
+    pub fn cancel(&self, key: &OrderKey) -> Option<CThostFtdcInputQuoteActionField> {
+        let quote = self.quotes.get(key).filter(|q| q.is_active())?;
+        let mut action: CThostFtdcInputQuoteActionField = zeroed();
+        action.FrontID = key.front_id;
+        action.SessionID = key.session_id;
+        set_string(&mut action.QuoteRef, &key.order_ref);
+        set_string(&mut action.ExchangeID, &quote.exchange_id);
+        set_string(&mut action.QuoteSysID, &quote.quote_sys_id);
+        set_string(&mut action.InstrumentID, &quote.instrument_id);
+        action.ActionFlag = THOST_FTDC_AF_Delete as _;
+        Some(action)
+    }
+
+    /// `OnRspQuoteInsert`, only called when CTP refuses the quote.
+    pub fn on_rsp_quote_insert(&mut self, input: &CThostFtdcInputQuoteField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Quote> {
+        self.reject(input, info)
+    }
+
+    /// `OnErrRtnQuoteInsert`, the exchange refused the quote.
+    pub fn on_err_rtn_quote_insert(&mut self, input: &CThostFtdcInputQuoteField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Quote> {
+        self.reject(input, info)
+    }
+
+    /// `OnRtnQuote`, and the rows of `OnRspQryQuote`.
+    pub fn on_rtn_quote(&mut self, rtn: &CThostFtdcQuoteField) -> Option<&Quote> {
+        let key = OrderKey {
+            front_id: rtn.FrontID,
+            session_id: rtn.SessionID,
+            order_ref: to_string(&rtn.QuoteRef),
+        };
+        let quote = self.quotes.entry(key.clone()).or_insert_with(|| Quote::new(key.clone()));
+        quote.exchange_id = to_string(&rtn.ExchangeID);
+        quote.instrument_id = to_string(&rtn.InstrumentID);
+        quote.for_quote_sys_id = to_string(&rtn.ForQuoteSysID);
+        quote.status_msg = gbk_string(&rtn.StatusMsg);
+        quote.insert_time = to_string(&rtn.InsertTime);
+        let legs = [
+            (QuoteSide::Bid, to_string(&rtn.BidOrderRef), to_string(&rtn.BidOrderSysID), rtn.BidPrice, rtn.BidVolume, rtn.BidOffsetFlag),
+            (QuoteSide::Ask, to_string(&rtn.AskOrderRef), to_string(&rtn.AskOrderSysID), rtn.AskPrice, rtn.AskVolume, rtn.AskOffsetFlag),
+        ];
+        for (side, order_ref, _, price, volume, offset) in &legs {
+            let leg = quote.leg_mut(*side);
+            if !order_ref.is_empty() {
+                leg.order_ref = order_ref.clone();
+            }
+            leg.price = *price;
+            leg.volume = *volume;
+            leg.offset = *offset as u8;
+        }
+
+        if rtn.OrderSubmitStatus as u8 == THOST_FTDC_OSS_InsertRejected {
+            quote.status = OrderStatus::Rejected;
+            quote.reject_reason = Some(quote.status_msg.clone());
+        } else if let Some(status) = OrderStatus::from_ost(rtn.QuoteStatus) {
+            // a late return must not revive a finished quote
+            if !quote.status.is_finished() || status.is_finished() {
+                quote.status = status;
+            }
+        }
+
+        let sys_id = to_string(&rtn.QuoteSysID);
+        if !sys_id.is_empty() {
+            quote.quote_sys_id = sys_id.clone();
+            self.by_sys_id.insert((quote.exchange_id.clone(), sys_id), key.clone());
+        }
+        let exchange_id = quote.exchange_id.clone();
+        for (side, order_ref, order_sys_id, ..) in legs {
+            if !order_ref.is_empty() {
+                self.legs.insert(OrderKey { order_ref, ..key.clone() }, (key.clone(), side));
+            }
+            if !order_sys_id.is_empty() {
+                self.link_sys_id(&key, side, &exchange_id, order_sys_id);
+            }
+        }
+        let quote = self.quotes.get(&key)?;
+        if !quote.is_active() {
+            self.unlinked.remove(&quote.bid.order_ref);
+            self.unlinked.remove(&quote.ask.order_ref);
+        }
+        Some(quote)
+    }
+
+    /// `OnRtnOrder` of a derived order, `None` for other orders.
+    pub fn on_rtn_order(&mut self, rtn: &CThostFtdcOrderField) -> Option<&Quote> {
+        let order_key = OrderKey {
+            front_id: rtn.FrontID,
+            session_id: rtn.SessionID,
+            order_ref: to_string(&rtn.OrderRef),
+        };
+        let exchange_id = to_string(&rtn.ExchangeID);
+        let order_sys_id = to_string(&rtn.OrderSysID);
+        let (key, side) = match self.legs.get(&order_key) {
+            Some(leg) => leg.clone(),
+            None => self.leg_sys_ids.get(&(exchange_id.clone(), order_sys_id.clone())).cloned()?,
+        };
+        let leg = self.quotes.get_mut(&key)?.leg_mut(side);
+        leg.volume_traded = leg.volume_traded.max(rtn.VolumeTraded);
+        if let Some(status) = OrderStatus::from_ost(rtn.OrderStatus) {
+            if !leg.status.is_finished() || status.is_finished() {
+                leg.status = status;
+            }
+        }
+        if !order_sys_id.is_empty() {
+            self.link_sys_id(&key, side, &exchange_id, order_sys_id);
+        }
+        self.quotes.get(&key)
+    }
+
+    /// `OnRtnTrade` of a derived order, `None` for other trades and for
+    /// trades held until their leg is known.
+    pub fn on_rtn_trade(&mut self, trade: &CThostFtdcTradeField) -> Option<&Quote> {
+        let sys_key = (to_string(&trade.ExchangeID), to_string(&trade.OrderSysID));
+        match self.leg_sys_ids.get(&sys_key).cloned() {
+            Some((key, side)) => {
+                self.apply_trade(&key, side, trade);
+                self.quotes.get(&key)
+            }
+            None => {
+                // most trades are of plain orders, only keep those of legs not linked yet
+                if self.unlinked.contains(&to_string(&trade.OrderRef)) {
+                    self.early_trades.entry(sys_key).or_default().push(*trade);
+                }
+                None
+            }
+        }
+    }
+
+    /// `OnRspQuoteAction`, only called when CTP refuses the cancel.
+    pub fn on_rsp_quote_action(&mut self, action: &CThostFtdcInputQuoteActionField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Quote> {
+        let key = self.action_key(action.FrontID, action.SessionID, &action.QuoteRef, &action.ExchangeID, &action.QuoteSysID)?;
+        self.cancel_failed(&key, info)
+    }
+
+    /// `OnErrRtnQuoteAction`, the exchange refused the cancel.
+    pub fn on_err_rtn_quote_action(&mut self, action: &CThostFtdcQuoteActionField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Quote> {
+        let key = self.action_key(action.FrontID, action.SessionID, &action.QuoteRef, &action.ExchangeID, &action.QuoteSysID)?;
+        self.cancel_failed(&key, info)
+    }
+
+    /// `OnRspQryQuote`, one row per call.
+    pub fn on_rsp_qry_quote(&mut self, field: Option<&CThostFtdcQuoteField>) {
+        if let Some(field) = field {
+            self.on_rtn_quote(field);
+        }
+    }
+
+    pub fn get(&self, key: &OrderKey) -> Option<&Quote> {
+        self.quotes.get(key)
+    }
+
+    pub fn get_by_sys_id(&self, exchange_id: &str, quote_sys_id: &str) -> Option<&Quote> {
+        let key = self.by_sys_id.get(&(exchange_id.to_string(), quote_sys_id.to_string()))?;
+        self.quotes.get(key)
+    }
+
+    /// The quote a derived order belongs to, by its `OrderKey`.
+    pub fn get_by_order(&self, order: &OrderKey) -> Option<(&Quote, QuoteSide)> {
+        let (key, side) = self.legs.get(order)?;
+        Some((self.quotes.get(key)?, *side))
+    }
+
+    /// Quotes answering the for-quote `for_quote_sys_id`.
+    pub fn answers<'a>(&'a self, for_quote_sys_id: &'a str) -> impl Iterator<Item = &'a Quote> {
+        self.quotes.values().filter(move |q| q.for_quote_sys_id == for_quote_sys_id)
+    }
+
+    pub fn quotes(&self) -> impl Iterator<Item = &Quote> {
+        self.quotes.values()
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &Quote> {
+        self.quotes.values().filter(|q| q.is_active())
+    }
+
+    fn own_key(&self, quote_ref: &[c_char]) -> OrderKey {
+        self.own_key_str(&to_string(quote_ref))
+    }
+
+    fn own_key_str(&self, order_ref: &str) -> OrderKey {
+        OrderKey {
+            front_id: self.front_id,
+            session_id: self.session_id,
+            order_ref: order_ref.to_string(),
+        }
+    }
+
+    fn link_sys_id(&mut self, key: &OrderKey, side: QuoteSide, exchange_id: &str, order_sys_id: String) {
+        if let Some(quote) = self.quotes.get_mut(key) {
+            let leg = quote.leg_mut(side);
+            leg.order_sys_id = order_sys_id.clone();
+            self.unlinked.remove(&leg.order_ref);
+        }
+        let sys_key = (exchange_id.to_string(), order_sys_id);
+        self.leg_sys_ids.insert(sys_key.clone(), (key.clone(), side));
+        for trade in self.early_trades.remove(&sys_key).unwrap_or_default() {
+            self.apply_trade(key, side, &trade);
+        }
+    }
+
+    fn apply_trade(&mut self, key: &OrderKey, side: QuoteSide, trade: &CThostFtdcTradeField) {
+        let leg = match self.quotes.get_mut(key) {
+            Some(quote) => quote.leg_mut(side),
+            None => return,
+        };
+        // trades are replayed on resume, count each once
+        if !leg.trade_ids.insert(to_string(&trade.TradeID)) {
+            return;
+        }
+        leg.trade_volume += trade.Volume;
+        leg.trade_turnover += trade.Price * trade.Volume as f64;
+        if leg.trade_volume >= leg.volume && leg.volume > 0 {
+            leg.status = OrderStatus::AllTraded;
+        }
+    }
+
+    fn reject(&mut self, input: &CThostFtdcInputQuoteField, info: Option<&CThostFtdcRspInfoField>) -> Option<&Quote> {
+        let key = self.own_key(&input.QuoteRef);
+        let quote = self.quotes.entry(key.clone()).or_insert_with(|| Quote::new(key.clone()));
+        if quote.instrument_id.is_empty() {
+            quote.set_input(input);
+        }
+        quote.status = OrderStatus::Rejected;
+        quote.reject_reason = Some(rsp_error(info).unwrap_or_else(|| "rejected".into()));
+        warn!("quote {:?} rejected: {:?}", key, quote.reject_reason);
+        self.unlinked.remove(&quote.bid.order_ref);
+        self.unlinked.remove(&quote.ask.order_ref);
+        self.quotes.get(&key)
+    }
+
+    fn action_key(&self, front_id: i32, session_id: i32, quote_ref: &[c_char], exchange_id: &[c_char], quote_sys_id: &[c_char]) -> Option<OrderKey> {
+        let key = OrderKey {
+            front_id,
+            session_id,
+            order_ref: to_string(quote_ref),
+        };
+        if self.quotes.contains_key(&key) {
+            return Some(key);
+        }
+        self.by_sys_id.get(&(to_string(exchange_id), to_string(quote_sys_id))).cloned()
+    }
+
+    fn cancel_failed(&mut self, key: &OrderKey, info: Option<&CThostFtdcRspInfoField>) -> Option<&Quote> {
+        let quote = self.quotes.get_mut(key)?;
+        quote.cancel_error = Some(rsp_error(info).unwrap_or_else(|| "cancel rejected".into()));
+        warn!("cancel of quote {:?} rejected: {:?}", key, quote.cancel_error);
+        Some(quote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login() -> CThostFtdcRspUserLoginField {
+        let mut login: CThostFtdcRspUserLoginField = zeroed();
+        login.FrontID = 1;
+        login.SessionID = 7;
+        set_string(&mut login.MaxOrderRef, "100");
+        login
+    }
+
+    fn trade(order_ref: &str, order_sys_id: &str, trade_id: &str) -> CThostFtdcTradeField {
+        let mut trade: CThostFtdcTradeField = zeroed();
+        set_string(&mut trade.ExchangeID, "CFFEX");
+        set_string(&mut trade.OrderRef, order_ref);
+        set_string(&mut trade.OrderSysID, order_sys_id);
+        set_string(&mut trade.TradeID, trade_id);
+        trade.Price = 10.0;
+        trade.Volume = 1;
+        trade
+    }
+
+    #[test]
+    fn trades_before_the_quote_return_are_held_for_legs_only() {
+        let mut orders = OrderManager::new();
+        let mut quotes = QuoteManager::new();
+        orders.on_rsp_user_login(&login());
+        quotes.on_rsp_user_login(&login());
+        let mut input = QuoteBuilder::new("IO2501-C-4000", "CFFEX").bid(10.0, 2).ask(11.0, 2).build();
+        let key = quotes.insert(&mut input, &mut orders);
+        let bid_ref = to_string(&input.BidOrderRef);
+
+        // a plain order trade is not kept
+        assert!(quotes.on_rtn_trade(&trade("55", "P1", "T0")).is_none());
+        assert!(quotes.on_rtn_trade(&trade(&bid_ref, "B1", "T1")).is_none());
+        assert_eq!(quotes.early_trades.len(), 1);
+
+        let mut rtn: CThostFtdcQuoteField = zeroed();
+        rtn.FrontID = 1;
+        rtn.SessionID = 7;
+        set_string(&mut rtn.QuoteRef, &key.order_ref);
+        set_string(&mut rtn.ExchangeID, "CFFEX");
+        set_string(&mut rtn.BidOrderRef, &bid_ref);
+        set_string(&mut rtn.AskOrderRef, &to_string(&input.AskOrderRef));
+        set_string(&mut rtn.BidOrderSysID, "B1");
+        set_string(&mut rtn.AskOrderSysID, "A1");
+        set_string(&mut rtn.QuoteSysID, "Q1");
+        rtn.BidVolume = 2;
+        rtn.AskVolume = 2;
+        rtn.QuoteStatus = THOST_FTDC_OST_NoTradeQueueing as _;
+        let quote = quotes.on_rtn_quote(&rtn).unwrap();
+        assert_eq!(quote.bid.filled(), 1);
+        assert!(quotes.early_trades.is_empty());
+        assert!(quotes.unlinked.is_empty());
+
+        // after the link plain trades are not even considered
+        assert!(quotes.on_rtn_trade(&trade(&bid_ref, "P2", "T2")).is_none());
+        assert!(quotes.early_trades.is_empty());
+        assert_eq!(quotes.on_rtn_trade(&trade("", "A1", "T3")).map(|q| q.ask.filled()), Some(1));
+    }
+}
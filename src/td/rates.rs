@@ -0,0 +1,511 @@
+//! Margin and commission rates of an instrument, and the amounts they charge.
+//!
+//! The rate queries answer at instrument level or product level, a row's
+//! `InstrumentID` being the instrument, its product, or empty for a default.
+//! [`RateResolver`] caches the rows as they come and picks the most specific
+//! one: instrument, then product, then the default of the exchange, then the
+//! default of everything.
+
+use super::instrument::InstrumentCatalog;
+use super::position::PosiDirection;
+use super::query::Priority;
+use super::TdApi;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MarginRate {
+    pub long_by_money: f64,
+    pub long_by_volume: f64,
+    pub short_by_money: f64,
+    pub short_by_volume: f64,
+}
+
+impl From<&CThostFtdcInstrumentMarginRateField> for MarginRate {
+    fn from(field: &CThostFtdcInstrumentMarginRateField) -> Self {
+        Self {
+            long_by_money: field.LongMarginRatioByMoney,
+            long_by_volume: field.LongMarginRatioByVolume,
+            short_by_money: field.ShortMarginRatioByMoney,
+            short_by_volume: field.ShortMarginRatioByVolume,
+        }
+    }
+}
+
+impl From<&CThostFtdcExchangeMarginRateField> for MarginRate {
+    fn from(field: &CThostFtdcExchangeMarginRateField) -> Self {
+        Self {
+            long_by_money: field.LongMarginRatioByMoney,
+            long_by_volume: field.LongMarginRatioByVolume,
+            short_by_money: field.ShortMarginRatioByMoney,
+            short_by_volume: field.ShortMarginRatioByVolume,
+        }
+    }
+}
+
+impl MarginRate {
+    /// The exchange part of an adjustment row.
+    pub fn exchange_of(field: &CThostFtdcExchangeMarginRateAdjustField) -> Self {
+        Self {
+            long_by_money: field.ExchLongMarginRatioByMoney,
+            long_by_volume: field.ExchLongMarginRatioByVolume,
+            short_by_money: field.ExchShortMarginRatioByMoney,
+            short_by_volume: field.ExchShortMarginRatioByVolume,
+        }
+    }
+
+    fn plus(&self, other: &Self) -> Self {
+        Self {
+            long_by_money: self.long_by_money + other.long_by_money,
+            long_by_volume: self.long_by_volume + other.long_by_volume,
+            short_by_money: self.short_by_money + other.short_by_money,
+            short_by_volume: self.short_by_volume + other.short_by_volume,
+        }
+    }
+
+    pub fn margin(&self, direction: PosiDirection, price: f64, volume: i32, multiplier: f64) -> f64 {
+        let (by_money, by_volume) = match direction {
+            PosiDirection::Long => (self.long_by_money, self.long_by_volume),
+            PosiDirection::Short => (self.short_by_money, self.short_by_volume),
+        };
+        let volume = volume as f64;
+        price * volume * multiplier * by_money + volume * by_volume
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommissionRate {
+    pub open_by_money: f64,
+    pub open_by_volume: f64,
+    pub close_by_money: f64,
+    pub close_by_volume: f64,
+    pub close_today_by_money: f64,
+    pub close_today_by_volume: f64,
+}
+
+impl From<&CThostFtdcInstrumentCommissionRateField> for CommissionRate {
+    fn from(field: &CThostFtdcInstrumentCommissionRateField) -> Self {
+        Self {
+            open_by_money: field.OpenRatioByMoney,
+            open_by_volume: field.OpenRatioByVolume,
+            close_by_money: field.CloseRatioByMoney,
+            close_by_volume: field.CloseRatioByVolume,
+            close_today_by_money: field.CloseTodayRatioByMoney,
+            close_today_by_volume: field.CloseTodayRatioByVolume,
+        }
+    }
+}
+
+impl CommissionRate {
+    /// Commission of a fill with `offset` (`THOST_FTDC_OF_*`).
+    #[allow(non_upper_case_globals)]
+    pub fn commission(&self, offset: u8, price: f64, volume: i32, multiplier: f64) -> f64 {
+        let (by_money, by_volume) = match offset {
+            THOST_FTDC_OF_Open => (self.open_by_money, self.open_by_volume),
+            THOST_FTDC_OF_CloseToday => (self.close_today_by_money, self.close_today_by_volume),
+            _ => (self.close_by_money, self.close_by_volume),
+        };
+        let volume = volume as f64;
+        price * volume * multiplier * by_money + volume * by_volume
+    }
+}
+
+/// Fees per order and per cancel (申报费), charged whether the order trades or not.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OrderCommRate {
+    pub insert_fee: f64,
+    pub cancel_fee: f64,
+}
+
+impl From<&CThostFtdcInstrumentOrderCommRateField> for OrderCommRate {
+    fn from(field: &CThostFtdcInstrumentOrderCommRateField) -> Self {
+        Self {
+            insert_fee: field.OrderCommByVolume,
+            cancel_fee: field.OrderActionCommByVolume,
+        }
+    }
+}
+
+/// Everything needed to price an instrument's margin and commission. The
+/// [`AccountTracker`](super::account::AccountTracker) is given one per
+/// instrument, [`RateResolver`] builds them from the queried rows.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rates {
+    /// `VolumeMultiple` of the instrument.
+    pub multiplier: f64,
+    pub margin: MarginRate,
+    pub commission: CommissionRate,
+}
+
+impl Default for Rates {
+    fn default() -> Self {
+        Self {
+            multiplier: 1.0,
+            margin: Default::default(),
+            commission: Default::default(),
+        }
+    }
+}
+
+/// What an order is expected to cost before it is sent.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct OrderCost {
+    /// Margin taken by an open order, 0 for closes.
+    pub margin: f64,
+    /// Commission when it fills completely.
+    pub commission: f64,
+    pub insert_fee: f64,
+    /// Fees of the cancels counted in.
+    pub cancel_fee: f64,
+}
+
+impl OrderCost {
+    /// Money spent, margin aside as it returns on close.
+    pub fn fees(&self) -> f64 {
+        self.commission + self.insert_fee + self.cancel_fee
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct RateKey {
+    /// Only set for defaults, the `InstrumentID` of the row otherwise.
+    exchange_id: String,
+    id: String,
+    hedge_flag: u8,
+}
+
+impl RateKey {
+    fn of_row(exchange_id: &str, id: &str, hedge_flag: u8) -> Self {
+        Self {
+            exchange_id: if id.is_empty() { exchange_id.to_string() } else { String::new() },
+            id: id.to_string(),
+            hedge_flag,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct InstrumentInfo {
+    product_id: String,
+    exchange_id: String,
+    multiplier: f64,
+}
+
+/// Rates cached from the rate queries, resolved per instrument.
+#[derive(Debug, Default)]
+pub struct RateResolver {
+    instruments: HashMap<String, InstrumentInfo>,
+    /// Investor margin rates, and whether they are relative to the exchange's.
+    margin: HashMap<RateKey, (MarginRate, bool)>,
+    exchange_margin: HashMap<RateKey, MarginRate>,
+    /// Exchange rates from `ReqQryExchangeMarginRateAdjust`, when the plain
+    /// exchange query has none.
+    exchange_adjust: HashMap<RateKey, MarginRate>,
+    commission: HashMap<RateKey, CommissionRate>,
+    order_comm: HashMap<RateKey, OrderCommRate>,
+}
+
+impl RateResolver {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Take products, exchanges and multipliers from the catalog. Instruments
+    /// missing there resolve through `product_of` and have no multiplier.
+    pub fn set_instruments(&mut self, catalog: &InstrumentCatalog) {
+        for instrument in catalog.instruments() {
+            self.instruments.insert(
+                instrument.instrument_id.clone(),
+                InstrumentInfo {
+                    product_id: instrument.product_id.clone(),
+                    exchange_id: instrument.exchange_id.clone(),
+                    multiplier: instrument.volume_multiple as f64,
+                },
+            );
+        }
+    }
+
+    /// Queue the investor rate queries of `instrument`.
+    pub fn queue_queries(api: &mut TdApi, instrument: &str, hedge_flag: u8) {
+        let id = instrument.to_string();
+        api.queue_query(Priority::Normal, "margin rate", move |api| api.req_qry_instrument_margin_rate(&id, hedge_flag));
+        let id = instrument.to_string();
+        api.queue_query(Priority::Normal, "commission rate", move |api| api.req_qry_instrument_commission_rate(&id));
+        let id = instrument.to_string();
+        api.queue_query(Priority::Normal, "order comm rate", move |api| api.req_qry_instrument_order_comm_rate(&id));
+    }
+
+    /// Queue the exchange margin queries, needed for relative investor rates.
+    pub fn queue_exchange_queries(api: &mut TdApi, hedge_flag: u8) {
+        api.queue_query(Priority::Bulk, "exchange margin rate", move |api| api.req_qry_exchange_margin_rate("", hedge_flag));
+        api.queue_query(Priority::Bulk, "exchange margin rate adjust", move |api| api.req_qry_exchange_margin_rate_adjust("", hedge_flag));
+    }
+
+    pub fn on_rsp_qry_instrument_margin_rate(&mut self, row: &CThostFtdcInstrumentMarginRateField) {
+        let key = RateKey::of_row(&to_string(&row.ExchangeID), &to_string(&row.InstrumentID), row.HedgeFlag as u8);
+        self.margin.insert(key, (MarginRate::from(row), row.IsRelative != 0));
+    }
+
+    pub fn on_rsp_qry_instrument_commission_rate(&mut self, row: &CThostFtdcInstrumentCommissionRateField) {
+        let key = RateKey::of_row(&to_string(&row.ExchangeID), &to_string(&row.InstrumentID), 0);
+        self.commission.insert(key, CommissionRate::from(row));
+    }
+
+    pub fn on_rsp_qry_instrument_order_comm_rate(&mut self, row: &CThostFtdcInstrumentOrderCommRateField) {
+        let key = RateKey::of_row(&to_string(&row.ExchangeID), &to_string(&row.InstrumentID), row.HedgeFlag as u8);
+        self.order_comm.insert(key, OrderCommRate::from(row));
+    }
+
+    pub fn on_rsp_qry_exchange_margin_rate(&mut self, row: &CThostFtdcExchangeMarginRateField) {
+        let key = RateKey::of_row(&to_string(&row.ExchangeID), &to_string(&row.InstrumentID), row.HedgeFlag as u8);
+        self.exchange_margin.insert(key, MarginRate::from(row));
+    }
+
+    pub fn on_rsp_qry_exchange_margin_rate_adjust(&mut self, row: &CThostFtdcExchangeMarginRateAdjustField) {
+        let key = RateKey::of_row("", &to_string(&row.InstrumentID), row.HedgeFlag as u8);
+        self.exchange_adjust.insert(key, MarginRate::exchange_of(row));
+    }
+
+    /// `VolumeMultiple` of `instrument`, `None` when it is not in the catalog.
+    pub fn multiplier(&self, instrument: &str) -> Option<f64> {
+        self.instruments.get(instrument).map(|i| i.multiplier)
+    }
+
+    /// The investor's margin rate, a relative rate added to the exchange's.
+    pub fn margin_rate(&self, instrument: &str, hedge_flag: u8) -> Option<MarginRate> {
+        let (rate, relative) = *self.resolve(&self.margin, instrument, hedge_flag)?;
+        if !relative {
+            return Some(rate);
+        }
+        match self.exchange_margin_rate(instrument, hedge_flag) {
+            Some(exchange) => Some(rate.plus(&exchange)),
+            None => {
+                debug!("margin rate of {} is relative, but no exchange rate", instrument);
+                None
+            }
+        }
+    }
+
+    pub fn exchange_margin_rate(&self, instrument: &str, hedge_flag: u8) -> Option<MarginRate> {
+        self.resolve(&self.exchange_margin, instrument, hedge_flag)
+            .or_else(|| self.resolve(&self.exchange_adjust, instrument, hedge_flag))
+            .copied()
+    }
+
+    pub fn commission_rate(&self, instrument: &str) -> Option<CommissionRate> {
+        self.resolve(&self.commission, instrument, 0).copied()
+    }
+
+    /// Order fees, none when the broker has set none.
+    pub fn order_comm_rate(&self, instrument: &str, hedge_flag: u8) -> OrderCommRate {
+        self.resolve(&self.order_comm, instrument, hedge_flag).copied().unwrap_or_default()
+    }
+
+    /// Rates for the [`super::account::AccountTracker`], `None` until the
+    /// multiplier and both margin and commission rates are known.
+    pub fn rates(&self, instrument: &str, hedge_flag: u8) -> Option<Rates> {
+        Some(Rates {
+            multiplier: self.multiplier(instrument)?,
+            margin: self.margin_rate(instrument, hedge_flag)?,
+            commission: self.commission_rate(instrument)?,
+        })
+    }
+
+    /// Expected cost of `order` filling completely, with `cancels` cancels
+    /// counted in. Limit orders are priced at their `LimitPrice`, market
+    /// orders at `market_price`.
+    pub fn order_cost(&self, order: &CThostFtdcInputOrderField, market_price: f64, cancels: u32) -> Result<OrderCost, String> {
+        let instrument = to_string(&order.InstrumentID);
+        let hedge_flag = order.CombHedgeFlag[0] as u8;
+        let offset = order.CombOffsetFlag[0] as u8;
+        let volume = order.VolumeTotalOriginal;
+        let multiplier = self.multiplier(&instrument).ok_or_else(|| format!("no multiplier of {}", instrument))?;
+        let price = if order.OrderPriceType as u8 == THOST_FTDC_OPT_LimitPrice {
+            order.LimitPrice
+        } else {
+            market_price
+        };
+
+        let commission = self.commission_rate(&instrument).ok_or_else(|| format!("no commission rate of {}", instrument))?;
+        let margin = if offset == THOST_FTDC_OF_Open {
+            let rate = self.margin_rate(&instrument, hedge_flag).ok_or_else(|| format!("no margin rate of {}", instrument))?;
+            rate.margin(PosiDirection::opened_by(order.Direction), price, volume, multiplier)
+        } else {
+            0.0
+        };
+        let fees = self.order_comm_rate(&instrument, hedge_flag);
+        Ok(OrderCost {
+            margin,
+            commission: commission.commission(offset, price, volume, multiplier),
+            insert_fee: fees.insert_fee,
+            cancel_fee: fees.cancel_fee * cancels as f64,
+        })
+    }
+
+    fn resolve<'a, T>(&self, rates: &'a HashMap<RateKey, T>, instrument: &str, hedge_flag: u8) -> Option<&'a T> {
+        let (product, exchange) = match self.instruments.get(instrument) {
+            Some(info) => (info.product_id.as_str(), info.exchange_id.as_str()),
+            None => (product_of(instrument), ""),
+        };
+        let key = |exchange: &str, id: &str| RateKey {
+            exchange_id: exchange.to_string(),
+            id: id.to_string(),
+            hedge_flag,
+        };
+        [key("", instrument), key("", product), key(exchange, ""), key("", "")]
+            .iter()
+            .find_map(|k| rates.get(k))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> InstrumentCatalog {
+        let mut catalog = InstrumentCatalog::new();
+        for (n, (instrument, product, exchange, multiplier)) in
+            [("rb2501", "rb", "SHFE", 10), ("hc2501", "hc", "SHFE", 10), ("m2501", "m", "DCE", 10)].iter().enumerate()
+        {
+            let mut field: CThostFtdcInstrumentField = zeroed();
+            set_string(&mut field.InstrumentID, instrument);
+            set_string(&mut field.ProductID, product);
+            set_string(&mut field.ExchangeID, exchange);
+            field.ProductClass = THOST_FTDC_PC_Futures as _;
+            field.VolumeMultiple = *multiplier;
+            catalog.on_rsp_qry_instrument("20250102", Some(&field), n == 2);
+        }
+        catalog
+    }
+
+    fn margin_row(exchange: &str, id: &str, by_money: f64, relative: bool) -> CThostFtdcInstrumentMarginRateField {
+        let mut row: CThostFtdcInstrumentMarginRateField = zeroed();
+        set_string(&mut row.ExchangeID, exchange);
+        set_string(&mut row.InstrumentID, id);
+        row.HedgeFlag = THOST_FTDC_HF_Speculation as _;
+        row.LongMarginRatioByMoney = by_money;
+        row.ShortMarginRatioByMoney = by_money;
+        row.IsRelative = relative as _;
+        row
+    }
+
+    fn commission_row(exchange: &str, id: &str, by_volume: f64) -> CThostFtdcInstrumentCommissionRateField {
+        let mut row: CThostFtdcInstrumentCommissionRateField = zeroed();
+        set_string(&mut row.ExchangeID, exchange);
+        set_string(&mut row.InstrumentID, id);
+        row.OpenRatioByVolume = by_volume;
+        row
+    }
+
+    fn resolver() -> RateResolver {
+        let mut resolver = RateResolver::new();
+        resolver.set_instruments(&catalog());
+        resolver.on_rsp_qry_instrument_margin_rate(&margin_row("", "rb2501", 0.1, false));
+        let mut commission = commission_row("", "rb2501", 0.0);
+        commission.OpenRatioByMoney = 0.0001;
+        resolver.on_rsp_qry_instrument_commission_rate(&commission);
+        resolver
+    }
+
+    fn order(price_type: u8) -> CThostFtdcInputOrderField {
+        let mut order: CThostFtdcInputOrderField = zeroed();
+        set_string(&mut order.InstrumentID, "rb2501");
+        order.Direction = THOST_FTDC_D_Buy as _;
+        order.CombOffsetFlag[0] = THOST_FTDC_OF_Open as _;
+        order.CombHedgeFlag[0] = THOST_FTDC_HF_Speculation as _;
+        order.OrderPriceType = price_type as _;
+        order.LimitPrice = 3000.0;
+        order.VolumeTotalOriginal = 2;
+        order
+    }
+
+    #[test]
+    fn limit_orders_are_priced_at_their_limit() {
+        let resolver = resolver();
+        let cost = resolver.order_cost(&order(THOST_FTDC_OPT_LimitPrice), 4000.0, 0).unwrap();
+        assert!((cost.margin - 6000.0).abs() < 1e-9);
+        assert!((cost.commission - 6.0).abs() < 1e-9);
+
+        let cost = resolver.order_cost(&order(THOST_FTDC_OPT_AnyPrice), 4000.0, 0).unwrap();
+        assert!((cost.margin - 8000.0).abs() < 1e-9);
+        assert!((cost.commission - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn instruments_without_a_multiplier_are_not_priced() {
+        let resolver = resolver();
+        let mut order = order(THOST_FTDC_OPT_LimitPrice);
+        set_string(&mut order.InstrumentID, "rb2505");
+        assert_eq!(resolver.order_cost(&order, 0.0, 0).unwrap_err(), "no multiplier of rb2505");
+        assert_eq!(resolver.multiplier("rb2505"), None);
+        assert!(resolver.rates("rb2505", THOST_FTDC_HF_Speculation).is_none());
+        assert_eq!(resolver.rates("rb2501", THOST_FTDC_HF_Speculation).unwrap().multiplier, 10.0);
+    }
+
+    #[test]
+    fn most_specific_rate_wins() {
+        let mut resolver = RateResolver::new();
+        resolver.set_instruments(&catalog());
+        resolver.on_rsp_qry_instrument_commission_rate(&commission_row("", "", 1.0));
+        resolver.on_rsp_qry_instrument_commission_rate(&commission_row("SHFE", "", 2.0));
+        resolver.on_rsp_qry_instrument_commission_rate(&commission_row("", "rb", 3.0));
+        resolver.on_rsp_qry_instrument_commission_rate(&commission_row("", "rb2501", 4.0));
+        let open = |instrument: &str| resolver.commission_rate(instrument).unwrap().open_by_volume;
+        assert_eq!(open("rb2501"), 4.0);
+        // product of an instrument missing from the catalog
+        assert_eq!(open("rb2505"), 3.0);
+        assert_eq!(open("hc2501"), 2.0);
+        assert_eq!(open("m2501"), 1.0);
+    }
+
+    #[test]
+    fn relative_margin_adds_the_exchange_rate() {
+        let mut resolver = RateResolver::new();
+        resolver.set_instruments(&catalog());
+        let hedge = THOST_FTDC_HF_Speculation;
+        resolver.on_rsp_qry_instrument_margin_rate(&margin_row("", "rb2501", 0.02, true));
+        assert!(resolver.margin_rate("rb2501", hedge).is_none());
+
+        let mut adjust: CThostFtdcExchangeMarginRateAdjustField = zeroed();
+        set_string(&mut adjust.InstrumentID, "rb");
+        adjust.HedgeFlag = hedge as _;
+        adjust.ExchLongMarginRatioByMoney = 0.07;
+        resolver.on_rsp_qry_exchange_margin_rate_adjust(&adjust);
+        assert!((resolver.margin_rate("rb2501", hedge).unwrap().long_by_money - 0.09).abs() < 1e-9);
+
+        // the plain exchange rate comes first
+        let mut exchange: CThostFtdcExchangeMarginRateField = zeroed();
+        set_string(&mut exchange.InstrumentID, "rb2501");
+        exchange.HedgeFlag = hedge as _;
+        exchange.LongMarginRatioByMoney = 0.08;
+        resolver.on_rsp_qry_exchange_margin_rate(&exchange);
+        let rate = resolver.margin_rate("rb2501", hedge).unwrap();
+        assert!((rate.long_by_money - 0.1).abs() < 1e-9);
+        assert!((rate.margin(PosiDirection::Long, 3000.0, 1, 10.0) - 3000.0).abs() < 1e-9);
+
+        resolver.on_rsp_qry_instrument_margin_rate(&margin_row("", "hc2501", 0.12, false));
+        assert_eq!(resolver.margin_rate("hc2501", hedge).unwrap().short_by_money, 0.12);
+    }
+
+    #[test]
+    fn order_and_cancel_fees() {
+        let mut resolver = resolver();
+        let mut fees: CThostFtdcInstrumentOrderCommRateField = zeroed();
+        set_string(&mut fees.InstrumentID, "rb");
+        fees.HedgeFlag = THOST_FTDC_HF_Speculation as _;
+        fees.OrderCommByVolume = 1.0;
+        fees.OrderActionCommByVolume = 0.5;
+        resolver.on_rsp_qry_instrument_order_comm_rate(&fees);
+
+        let cost = resolver.order_cost(&order(THOST_FTDC_OPT_LimitPrice), 0.0, 3).unwrap();
+        assert_eq!((cost.insert_fee, cost.cancel_fee), (1.0, 1.5));
+        assert!((cost.fees() - (6.0 + 1.0 + 1.5)).abs() < 1e-9);
+        // none set for the other hedge flag
+        assert_eq!(resolver.order_comm_rate("rb2501", THOST_FTDC_HF_Hedge), OrderCommRate::default());
+    }
+}
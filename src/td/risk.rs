@@ -0,0 +1,580 @@
+//! Pre-trade risk checks, run on every `CThostFtdcInputOrderField` before it
+//! is sent.
+//!
+//! A [`RiskPipeline`] runs its checks in order and stops at the first one that
+//! rejects. The standard checks are built from a [`RiskConfig`], others can be
+//! added by implementing [`RiskCheck`].
+
+use super::order::OrderManager;
+use super::position::{PositionBook, PosiDirection};
+use crate::md::quote::Quote;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+/// What a check may look at besides the order.
+pub struct RiskContext<'a> {
+    /// Latest quote of the instrument, if any.
+    pub quote: Option<&'a Quote>,
+    /// `VolumeMultiple` of the instrument, see [`RiskPipeline::set_multiplier`].
+    pub multiplier: Option<f64>,
+    pub orders: &'a OrderManager,
+    pub positions: &'a PositionBook,
+}
+
+pub trait RiskCheck: Send {
+    fn name(&self) -> &str;
+
+    /// The reason when `order` must not be sent.
+    fn check(&self, order: &CThostFtdcInputOrderField, ctx: &RiskContext) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RiskReject {
+    /// Name of the check that rejected.
+    pub check: String,
+    pub reason: String,
+}
+
+impl fmt::Display for RiskReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rejected by {}: {}", self.check, self.reason)
+    }
+}
+
+impl std::error::Error for RiskReject {}
+
+impl From<RiskReject> for String {
+    fn from(e: RiskReject) -> Self {
+        e.to_string()
+    }
+}
+
+/// Price of a limit order, `None` for market orders.
+fn limit_price(order: &CThostFtdcInputOrderField) -> Option<f64> {
+    if order.OrderPriceType as u8 == THOST_FTDC_OPT_LimitPrice {
+        Some(order.LimitPrice)
+    } else {
+        None
+    }
+}
+
+fn is_buy(order: &CThostFtdcInputOrderField) -> bool {
+    order.Direction as u8 == THOST_FTDC_D_Buy
+}
+
+fn quote<'a>(ctx: &RiskContext<'a>, order: &CThostFtdcInputOrderField) -> Result<&'a Quote, String> {
+    ctx.quote.ok_or_else(|| format!("no quote of {}", to_string(&order.InstrumentID)))
+}
+
+/// Volume per order.
+#[derive(Debug, Copy, Clone)]
+pub struct MaxVolume(pub i32);
+
+impl RiskCheck for MaxVolume {
+    fn name(&self) -> &str {
+        "max_volume"
+    }
+
+    fn check(&self, order: &CThostFtdcInputOrderField, _: &RiskContext) -> Result<(), String> {
+        if order.VolumeTotalOriginal > self.0 {
+            return Err(format!("volume {} over {}", order.VolumeTotalOriginal, self.0));
+        }
+        Ok(())
+    }
+}
+
+/// Price times volume times multiplier per order, market orders are valued
+/// at the last price. Orders of instruments without a known multiplier are
+/// rejected.
+#[derive(Debug, Copy, Clone)]
+pub struct MaxNotional(pub f64);
+
+impl RiskCheck for MaxNotional {
+    fn name(&self) -> &str {
+        "max_notional"
+    }
+
+    fn check(&self, order: &CThostFtdcInputOrderField, ctx: &RiskContext) -> Result<(), String> {
+        let price = match limit_price(order) {
+            Some(price) => price,
+            None => quote(ctx, order)?.last_price().ok_or("no last price to value a market order")?,
+        };
+        let multiplier = ctx
+            .multiplier
+            .ok_or_else(|| format!("no multiplier of {} to value the order", to_string(&order.InstrumentID)))?;
+        let notional = price * order.VolumeTotalOriginal as f64 * multiplier;
+        if notional > self.0 {
+            return Err(format!("notional {:.2} over {:.2}", notional, self.0));
+        }
+        Ok(())
+    }
+}
+
+/// Limit prices within `[LowerLimitPrice, UpperLimitPrice]` of the latest
+/// tick, and within `max_from_last` (a ratio, 0 for no check) of the last price.
+#[derive(Debug, Copy, Clone)]
+pub struct PriceLimits {
+    pub max_from_last: f64,
+}
+
+impl RiskCheck for PriceLimits {
+    fn name(&self) -> &str {
+        "price_limits"
+    }
+
+    fn check(&self, order: &CThostFtdcInputOrderField, ctx: &RiskContext) -> Result<(), String> {
+        let price = match limit_price(order) {
+            Some(price) => price,
+            None => return Ok(()),
+        };
+        let quote = quote(ctx, order)?;
+        if let Some(upper) = quote.upper_limit_price().filter(|upper| price > *upper) {
+            return Err(format!("price {} above upper limit {}", price, upper));
+        }
+        if let Some(lower) = quote.lower_limit_price().filter(|lower| price < *lower) {
+            return Err(format!("price {} below lower limit {}", price, lower));
+        }
+        if self.max_from_last > 0.0 {
+            if let Some(last) = quote.last_price() {
+                if (price - last).abs() > last * self.max_from_last {
+                    return Err(format!("price {} more than {} away from last {}", price, self.max_from_last, last));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Limit prices crossing the book by more than `max_deviation` (a ratio): a
+/// buy above the best ask or a sell below the best bid. The last price stands
+/// in for a missing side.
+#[derive(Debug, Copy, Clone)]
+pub struct FatFinger {
+    pub max_deviation: f64,
+}
+
+impl RiskCheck for FatFinger {
+    fn name(&self) -> &str {
+        "fat_finger"
+    }
+
+    fn check(&self, order: &CThostFtdcInputOrderField, ctx: &RiskContext) -> Result<(), String> {
+        let price = match limit_price(order) {
+            Some(price) => price,
+            None => return Ok(()),
+        };
+        let quote = quote(ctx, order)?;
+        let touch = if is_buy(order) { quote.ask_price() } else { quote.bid_price() };
+        let reference = match touch.or_else(|| quote.last_price()) {
+            Some(reference) => reference,
+            None => return Err("no price to compare with".to_string()),
+        };
+        let deviation = if is_buy(order) { price - reference } else { reference - price };
+        if deviation > reference * self.max_deviation {
+            return Err(format!("price {} deviates from {} by more than {}", price, reference, self.max_deviation));
+        }
+        Ok(())
+    }
+}
+
+/// Orders that would trade against our own resting orders.
+#[derive(Debug, Copy, Clone)]
+pub struct SelfTrade;
+
+impl RiskCheck for SelfTrade {
+    fn name(&self) -> &str {
+        "self_trade"
+    }
+
+    fn check(&self, order: &CThostFtdcInputOrderField, ctx: &RiskContext) -> Result<(), String> {
+        let instrument = to_string(&order.InstrumentID);
+        let buy = is_buy(order);
+        let price = limit_price(order);
+        let resting = ctx.orders.active().filter(|o| {
+            o.instrument_id == instrument && (o.direction == THOST_FTDC_D_Buy) != buy && o.price > 0.0 && o.remaining() > 0
+        });
+        for other in resting {
+            let crosses = match price {
+                None => true,
+                Some(price) if buy => price >= other.price,
+                Some(price) => price <= other.price,
+            };
+            if crosses {
+                return Err(format!("crosses own order {} at {}", other.key.order_ref, other.price));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Volume per direction per product (e.g. `rb`), counting positions, pending
+/// open orders and the order itself. Only opening orders are checked.
+#[derive(Debug, Clone)]
+pub struct PositionLimits {
+    pub limits: HashMap<String, i32>,
+}
+
+impl RiskCheck for PositionLimits {
+    fn name(&self) -> &str {
+        "position_limits"
+    }
+
+    fn check(&self, order: &CThostFtdcInputOrderField, ctx: &RiskContext) -> Result<(), String> {
+        if order.CombOffsetFlag[0] as u8 != THOST_FTDC_OF_Open {
+            return Ok(());
+        }
+        let instrument = to_string(&order.InstrumentID);
+        let product = product_of(&instrument);
+        let limit = match self.limits.get(product) {
+            Some(limit) => *limit,
+            None => return Ok(()),
+        };
+        let direction = PosiDirection::opened_by(order.Direction);
+        let held: i32 = ctx
+            .positions
+            .positions()
+            .filter(|p| p.key.direction == direction && product_of(&p.key.instrument_id) == product)
+            .map(|p| p.volume())
+            .sum();
+        let pending: i32 = ctx
+            .orders
+            .active()
+            .filter(|o| o.offset == THOST_FTDC_OF_Open && product_of(&o.instrument_id) == product)
+            .filter(|o| PosiDirection::opened_by(o.direction as _) == direction)
+            .map(|o| o.remaining())
+            .sum();
+        let total = held + pending + order.VolumeTotalOriginal;
+        if total > limit {
+            return Err(format!("{:?} {} would reach {} over {} (held {}, pending {})", direction, product, total, limit, held, pending));
+        }
+        Ok(())
+    }
+}
+
+/// The standard checks, each off when left at its default.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RiskConfig {
+    #[serde(default)]
+    pub max_volume: Option<i32>,
+    #[serde(default)]
+    pub max_notional: Option<f64>,
+    /// Check limit prices against the limit band of the latest tick.
+    #[serde(default)]
+    pub check_limits: bool,
+    /// Max distance of a limit price from the last price, as a ratio.
+    #[serde(default)]
+    pub max_from_last: Option<f64>,
+    /// Max distance a limit price may cross the book by, as a ratio.
+    #[serde(default)]
+    pub max_deviation: Option<f64>,
+    #[serde(default)]
+    pub self_trade: bool,
+    /// Volume per direction per product.
+    #[serde(default)]
+    pub position_limits: HashMap<String, i32>,
+}
+
+#[derive(Default)]
+pub struct RiskPipeline {
+    checks: Vec<Box<dyn RiskCheck>>,
+    multipliers: HashMap<String, f64>,
+}
+
+impl RiskPipeline {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn from_config(config: &RiskConfig) -> Self {
+        let mut pipeline = Self::new();
+        if let Some(max) = config.max_volume {
+            pipeline.push(MaxVolume(max));
+        }
+        if let Some(max) = config.max_notional {
+            pipeline.push(MaxNotional(max));
+        }
+        if config.check_limits || config.max_from_last.is_some() {
+            pipeline.push(PriceLimits {
+                max_from_last: config.max_from_last.unwrap_or(0.0),
+            });
+        }
+        if let Some(max_deviation) = config.max_deviation {
+            pipeline.push(FatFinger { max_deviation });
+        }
+        if config.self_trade {
+            pipeline.push(SelfTrade);
+        }
+        if !config.position_limits.is_empty() {
+            pipeline.push(PositionLimits {
+                limits: config.position_limits.clone(),
+            });
+        }
+        pipeline
+    }
+
+    /// Add a check, run after the ones already there.
+    pub fn push(&mut self, check: impl RiskCheck + 'static) {
+        self.checks.push(Box::new(check));
+    }
+
+    /// `VolumeMultiple` of `instrument`, needed by [`MaxNotional`].
+    pub fn set_multiplier(&mut self, instrument: &str, multiplier: f64) {
+        self.multipliers.insert(instrument.to_string(), multiplier);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.checks.iter().map(|c| c.name()).collect()
+    }
+
+    /// Run every check on `order`, `quote` being the latest of its instrument.
+    pub fn check(
+        &self,
+        order: &CThostFtdcInputOrderField,
+        quote: Option<&Quote>,
+        orders: &OrderManager,
+        positions: &PositionBook,
+    ) -> Result<(), RiskReject> {
+        let instrument = to_string(&order.InstrumentID);
+        let ctx = RiskContext {
+            quote,
+            multiplier: self.multipliers.get(&instrument).copied(),
+            orders,
+            positions,
+        };
+        for check in &self.checks {
+            if let Err(reason) = check.check(order, &ctx) {
+                let reject = RiskReject {
+                    check: check.name().to_string(),
+                    reason,
+                };
+                warn!("order {} {}", instrument, reject);
+                return Err(reject);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(volume: i32) -> CThostFtdcInputOrderField {
+        let mut order: CThostFtdcInputOrderField = zeroed();
+        set_string(&mut order.InstrumentID, "rb2501");
+        order.Direction = THOST_FTDC_D_Buy as _;
+        order.CombOffsetFlag[0] = THOST_FTDC_OF_Open as _;
+        order.OrderPriceType = THOST_FTDC_OPT_LimitPrice as _;
+        order.LimitPrice = 3500.0;
+        order.VolumeTotalOriginal = volume;
+        order
+    }
+
+    fn sell(volume: i32, price: f64) -> CThostFtdcInputOrderField {
+        let mut order = order(volume);
+        order.Direction = THOST_FTDC_D_Sell as _;
+        order.LimitPrice = price;
+        order
+    }
+
+    fn market(order: &mut CThostFtdcInputOrderField) {
+        order.OrderPriceType = THOST_FTDC_OPT_AnyPrice as _;
+        order.LimitPrice = 0.0;
+    }
+
+    fn quote(last: f64, bid: f64, ask: f64) -> Quote {
+        let mut tick: CThostFtdcDepthMarketDataField = zeroed();
+        set_string(&mut tick.InstrumentID, "rb2501");
+        // CTP leaves missing prices at f64::MAX
+        let price = |price: f64| if price > 0.0 { price } else { f64::MAX };
+        tick.LastPrice = price(last);
+        tick.BidPrice1 = price(bid);
+        tick.BidVolume1 = if bid > 0.0 { 5 } else { 0 };
+        tick.AskPrice1 = price(ask);
+        tick.AskVolume1 = if ask > 0.0 { 5 } else { 0 };
+        tick.UpperLimitPrice = 3700.0;
+        tick.LowerLimitPrice = 3300.0;
+        Quote {
+            tick,
+            source: crate::md::quote::Source::Md,
+            received: std::time::Instant::now(),
+        }
+    }
+
+    fn open(instrument: &str, direction: u8, volume: i32) -> CThostFtdcTradeField {
+        let mut trade: CThostFtdcTradeField = zeroed();
+        set_string(&mut trade.InstrumentID, instrument);
+        set_string(&mut trade.ExchangeID, "SHFE");
+        set_string(&mut trade.TradeID, &format!("{}{}", instrument, volume));
+        trade.Direction = direction as _;
+        trade.OffsetFlag = THOST_FTDC_OF_Open as _;
+        trade.HedgeFlag = THOST_FTDC_HF_Speculation as _;
+        trade.Price = 3500.0;
+        trade.Volume = volume;
+        trade
+    }
+
+    fn run(check: impl RiskCheck, order: &CThostFtdcInputOrderField, quote: Option<&Quote>, orders: &OrderManager, positions: &PositionBook) -> Result<(), String> {
+        check.check(
+            order,
+            &RiskContext {
+                quote,
+                multiplier: None,
+                orders,
+                positions,
+            },
+        )
+    }
+
+    #[test]
+    fn price_limits() {
+        let (orders, positions) = (OrderManager::new(), PositionBook::new());
+        let quote = quote(3500.0, 3499.0, 3501.0);
+        let check = PriceLimits { max_from_last: 0.02 };
+        assert!(run(check, &order(1), Some(&quote), &orders, &positions).is_ok());
+        assert_eq!(
+            run(check, &sell(1, 3750.0), Some(&quote), &orders, &positions),
+            Err("price 3750 above upper limit 3700".to_string())
+        );
+        assert_eq!(
+            run(check, &sell(1, 3250.0), Some(&quote), &orders, &positions),
+            Err("price 3250 below lower limit 3300".to_string())
+        );
+        assert!(run(check, &sell(1, 3600.0), Some(&quote), &orders, &positions).unwrap_err().contains("away from last 3500"));
+        assert!(run(PriceLimits { max_from_last: 0.0 }, &sell(1, 3600.0), Some(&quote), &orders, &positions).is_ok());
+        assert_eq!(run(check, &order(1), None, &orders, &positions), Err("no quote of rb2501".to_string()));
+        let mut order = order(1);
+        market(&mut order);
+        assert!(run(check, &order, None, &orders, &positions).is_ok());
+    }
+
+    #[test]
+    fn fat_finger() {
+        let (orders, positions) = (OrderManager::new(), PositionBook::new());
+        let check = FatFinger { max_deviation: 0.01 };
+        let book = quote(3500.0, 3490.0, 3510.0);
+        let mut buy = order(1);
+        buy.LimitPrice = 3545.0;
+        assert!(run(check, &buy, Some(&book), &orders, &positions).is_ok());
+        buy.LimitPrice = 3546.0;
+        assert!(run(check, &buy, Some(&book), &orders, &positions).unwrap_err().contains("deviates from 3510"));
+        assert!(run(check, &sell(1, 3456.0), Some(&book), &orders, &positions).is_ok());
+        assert!(run(check, &sell(1, 3450.0), Some(&book), &orders, &positions).unwrap_err().contains("deviates from 3490"));
+
+        // the last price stands in for an empty side
+        let one_sided = quote(3500.0, 3490.0, 0.0);
+        assert!(run(check, &buy, Some(&one_sided), &orders, &positions).unwrap_err().contains("deviates from 3500"));
+        assert_eq!(
+            run(check, &buy, Some(&quote(0.0, 0.0, 0.0)), &orders, &positions),
+            Err("no price to compare with".to_string())
+        );
+    }
+
+    #[test]
+    fn self_trade() {
+        let mut orders = OrderManager::new();
+        let positions = PositionBook::new();
+        let mut resting = sell(2, 3510.0);
+        set_string(&mut resting.ExchangeID, "SHFE");
+        orders.insert(&mut resting);
+
+        let mut buy = order(1);
+        assert!(run(SelfTrade, &buy, None, &orders, &positions).is_ok());
+        buy.LimitPrice = 3510.0;
+        assert_eq!(run(SelfTrade, &buy, None, &orders, &positions), Err("crosses own order 1 at 3510".to_string()));
+        // a market order crosses any resting order on the other side
+        let mut buy = order(1);
+        market(&mut buy);
+        assert!(run(SelfTrade, &buy, None, &orders, &positions).is_err());
+        assert!(run(SelfTrade, &sell(1, 3400.0), None, &orders, &positions).is_ok());
+        let mut other = order(1);
+        set_string(&mut other.InstrumentID, "hc2501");
+        market(&mut other);
+        assert!(run(SelfTrade, &other, None, &orders, &positions).is_ok());
+    }
+
+    #[test]
+    fn position_limits() {
+        let mut orders = OrderManager::new();
+        let mut positions = PositionBook::new();
+        positions.on_rtn_trade(&open("rb2501", THOST_FTDC_D_Buy, 3));
+        positions.on_rtn_trade(&open("rb2505", THOST_FTDC_D_Buy, 2));
+        positions.on_rtn_trade(&open("rb2501", THOST_FTDC_D_Sell, 4));
+        orders.insert(&mut order(2));
+
+        let check = || PositionLimits {
+            limits: vec![("rb".to_string(), 10)].into_iter().collect(),
+        };
+        let mut buy = order(3);
+        assert!(run(check(), &buy, None, &orders, &positions).is_ok());
+        buy.VolumeTotalOriginal = 4;
+        assert_eq!(
+            run(check(), &buy, None, &orders, &positions),
+            Err("Long rb would reach 11 over 10 (held 5, pending 2)".to_string())
+        );
+        // closing orders and other products are not limited
+        buy.CombOffsetFlag[0] = THOST_FTDC_OF_Close as _;
+        assert!(run(check(), &buy, None, &orders, &positions).is_ok());
+        buy.CombOffsetFlag[0] = THOST_FTDC_OF_Open as _;
+        set_string(&mut buy.InstrumentID, "hc2501");
+        assert!(run(check(), &buy, None, &orders, &positions).is_ok());
+        assert!(run(check(), &sell(6, 3500.0), None, &orders, &positions).is_ok());
+    }
+
+    #[test]
+    fn config_builds_the_checks_in_order() {
+        let config = RiskConfig {
+            max_volume: Some(10),
+            max_notional: Some(1e6),
+            max_from_last: Some(0.05),
+            max_deviation: Some(0.01),
+            self_trade: true,
+            position_limits: vec![("rb".to_string(), 10)].into_iter().collect(),
+            ..Default::default()
+        };
+        let pipeline = RiskPipeline::from_config(&config);
+        assert_eq!(
+            pipeline.names(),
+            ["max_volume", "max_notional", "price_limits", "fat_finger", "self_trade", "position_limits"]
+        );
+        assert!(RiskPipeline::from_config(&RiskConfig::default()).names().is_empty());
+        let config = RiskConfig {
+            check_limits: true,
+            ..Default::default()
+        };
+        assert_eq!(RiskPipeline::from_config(&config).names(), ["price_limits"]);
+
+        // the first check to reject stops the pipeline
+        let (orders, positions) = (OrderManager::new(), PositionBook::new());
+        let reject = RiskPipeline::from_config(&RiskConfig {
+            max_volume: Some(1),
+            max_notional: Some(1.0),
+            ..Default::default()
+        })
+        .check(&order(2), None, &orders, &positions)
+        .unwrap_err();
+        assert_eq!(reject.to_string(), "rejected by max_volume: volume 2 over 1");
+    }
+
+    #[test]
+    fn max_notional_needs_the_multiplier() {
+        let (orders, positions) = (OrderManager::new(), PositionBook::new());
+        let mut pipeline = RiskPipeline::new();
+        pipeline.push(MaxNotional(100_000.0));
+
+        let reject = pipeline.check(&order(1), None, &orders, &positions).unwrap_err();
+        assert_eq!(reject.check, "max_notional");
+        assert!(reject.reason.contains("no multiplier of rb2501"), "{}", reject.reason);
+
+        pipeline.set_multiplier("rb2501", 10.0);
+        assert!(pipeline.check(&order(2), None, &orders, &positions).is_ok());
+        let reject = pipeline.check(&order(3), None, &orders, &positions).unwrap_err();
+        assert_eq!(reject.reason, "notional 105000.00 over 100000.00");
+    }
+}
@@ -0,0 +1,536 @@
+//! The daily settlement statement (结算单) from `ReqQrySettlementInfo`.
+//!
+//! The statement comes as `Content` chunks of GBK text, and a chunk may end
+//! in the middle of a character. [`StatementAssembler`] orders the chunks by
+//! `SequenceNo` and decodes the joined bytes, [`Statement::parse`] reads the
+//! standard sections of the text.
+
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::BTreeMap;
+
+use log::*;
+
+/// Collects the chunks of one statement.
+#[derive(Debug, Default)]
+pub struct StatementAssembler {
+    chunks: BTreeMap<i32, Vec<u8>>,
+    trading_day: String,
+    settlement_id: i32,
+}
+
+impl StatementAssembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A chunk of the statement, the whole statement on the last one.
+    pub fn on_rsp_qry_settlement_info(&mut self, field: Option<&CThostFtdcSettlementInfoField>, is_last: bool) -> Option<Statement> {
+        if let Some(field) = field {
+            let bytes: Vec<u8> = field.Content.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+            // a repeated chunk replaces the earlier one
+            self.chunks.insert(field.SequenceNo, bytes);
+            self.trading_day = to_string(&field.TradingDay);
+            self.settlement_id = field.SettlementID;
+        }
+        if !is_last {
+            return None;
+        }
+
+        let chunks = std::mem::take(&mut self.chunks);
+        let bytes: Vec<u8> = chunks.into_values().flatten().collect();
+        debug!("settlement statement of {} in {} bytes", self.trading_day, bytes.len());
+        Some(Statement {
+            trading_day: std::mem::take(&mut self.trading_day),
+            settlement_id: self.settlement_id,
+            text: decode_gbk(&bytes),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Statement {
+    /// Empty when the broker has no statement for the day.
+    pub trading_day: String,
+    pub settlement_id: i32,
+    pub text: String,
+}
+
+/// A table of the statement, cells trimmed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    pub title: String,
+    /// Header rows, usually one in Chinese and one in English.
+    pub headers: Vec<Vec<String>>,
+    /// Data rows, the total row left out.
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Index of the first column named by any of `names`.
+    pub fn column(&self, names: &[&str]) -> Option<usize> {
+        self.headers.iter().find_map(|header| header.iter().position(|cell| names.contains(&cell.as_str())))
+    }
+}
+
+/// The 资金状况 (Account Summary) figures, named by their English label.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccountSummary {
+    /// Every `label：value` pair, in order.
+    pub items: Vec<(String, f64)>,
+}
+
+impl AccountSummary {
+    /// The figure whose label contains `name`, e.g. `Balance b/f`.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.items.iter().find(|(label, _)| label.contains(name)).map(|(_, value)| *value)
+    }
+
+    pub fn balance_bf(&self) -> Option<f64> {
+        self.get("Balance b/f")
+    }
+
+    pub fn balance_cf(&self) -> Option<f64> {
+        self.get("Balance c/f")
+    }
+
+    pub fn deposit_withdrawal(&self) -> Option<f64> {
+        self.get("Deposit/Withdrawal")
+    }
+
+    pub fn realized_pl(&self) -> Option<f64> {
+        self.get("Realized P/L")
+    }
+
+    pub fn mtm_pl(&self) -> Option<f64> {
+        self.get("MTM P/L")
+    }
+
+    pub fn commission(&self) -> Option<f64> {
+        self.get("Commission")
+    }
+
+    pub fn client_equity(&self) -> Option<f64> {
+        self.get("Client Equity")
+    }
+
+    pub fn margin_occupied(&self) -> Option<f64> {
+        self.get("Margin Occupied")
+    }
+
+    pub fn fund_available(&self) -> Option<f64> {
+        self.get("Fund Avail")
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TradeRecord {
+    pub date: String,
+    pub exchange: String,
+    pub product: String,
+    pub instrument_id: String,
+    /// `THOST_FTDC_D_*`.
+    pub direction: u8,
+    /// 投, 保 or 套 as printed.
+    pub hedge: String,
+    pub price: f64,
+    pub volume: i32,
+    pub turnover: f64,
+    /// `THOST_FTDC_OF_*`.
+    pub offset: u8,
+    pub fee: f64,
+    pub realized_pl: f64,
+    pub premium: f64,
+    pub trade_id: String,
+}
+
+/// A row of 持仓汇总 (Positions).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PositionRecord {
+    pub product: String,
+    pub instrument_id: String,
+    pub long: i32,
+    pub avg_buy_price: f64,
+    pub short: i32,
+    pub avg_sell_price: f64,
+    pub prev_settlement: f64,
+    pub settlement: f64,
+    pub mtm_pl: f64,
+    pub margin: f64,
+    pub hedge: String,
+}
+
+/// A fee charged on the day, by its label in the account summary.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fee {
+    pub name: String,
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settlement {
+    pub account: AccountSummary,
+    pub trades: Vec<TradeRecord>,
+    pub positions: Vec<PositionRecord>,
+    pub fees: Vec<Fee>,
+    /// Every table, the ones above included.
+    pub tables: Vec<Table>,
+}
+
+fn parse_number(s: &str) -> f64 {
+    s.trim().replace(',', "").parse().unwrap_or(0.0)
+}
+
+fn is_rule(line: &str) -> bool {
+    !line.is_empty() && line.chars().all(|c| c == '-' || c == '=')
+}
+
+fn cells(line: &str) -> Vec<String> {
+    let line = line.trim().trim_start_matches('|').trim_end_matches('|');
+    line.split('|').map(|cell| cell.trim().to_string()).collect()
+}
+
+fn direction(s: &str) -> u8 {
+    if s.starts_with('买') || s.eq_ignore_ascii_case("B") {
+        THOST_FTDC_D_Buy
+    } else {
+        THOST_FTDC_D_Sell
+    }
+}
+
+fn offset(s: &str) -> u8 {
+    match s {
+        s if s.starts_with('开') || s.eq_ignore_ascii_case("Open") => THOST_FTDC_OF_Open,
+        s if s.contains("平今") => THOST_FTDC_OF_CloseToday,
+        s if s.contains("平昨") => THOST_FTDC_OF_CloseYesterday,
+        _ => THOST_FTDC_OF_Close,
+    }
+}
+
+/// `label：value` pairs of a line, the value being the first word after the colon.
+fn summary_items(line: &str, items: &mut Vec<(String, f64)>) {
+    let mut parts = line.split(['：', ':']);
+    let mut label = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let part = part.trim_start();
+        // `：：` in some statements
+        if part.is_empty() {
+            continue;
+        }
+        let end = part.find(char::is_whitespace).unwrap_or(part.len());
+        let value = part[..end].trim_end_matches('%').replace(',', "");
+        if let (Ok(value), false) = (value.parse::<f64>(), label.trim().is_empty()) {
+            items.push((label.trim().to_string(), value));
+        }
+        label = part[end..].to_string();
+    }
+}
+
+impl Statement {
+    pub fn parse(&self) -> Settlement {
+        let mut settlement = Settlement::default();
+        let mut title = String::new();
+        let mut in_summary = false;
+        let mut table: Option<Table> = None;
+        let mut rules = 0;
+
+        for line in self.text.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with('|') {
+                in_summary = false;
+                let table = table.get_or_insert_with(|| Table {
+                    title: title.clone(),
+                    ..Default::default()
+                });
+                let row = cells(trimmed);
+                if rules < 2 {
+                    table.headers.push(row);
+                } else if !row.first().is_some_and(|cell| cell.starts_with('共')) {
+                    table.rows.push(row);
+                }
+            } else if is_rule(trimmed) {
+                if table.is_some() {
+                    rules += 1;
+                } else {
+                    rules = 1;
+                }
+            } else {
+                if let Some(table) = table.take() {
+                    settlement.tables.push(table);
+                }
+                rules = 0;
+                if trimmed.is_empty() {
+                    continue;
+                }
+                if trimmed.contains("资金状况") || trimmed.contains("Account Summary") {
+                    in_summary = true;
+                } else if in_summary && (trimmed.contains('：') || trimmed.contains(':')) {
+                    summary_items(trimmed, &mut settlement.account.items);
+                } else {
+                    in_summary = false;
+                    title = trimmed.to_string();
+                }
+            }
+        }
+        if let Some(table) = table.take() {
+            settlement.tables.push(table);
+        }
+
+        for table in &settlement.tables {
+            if table.title.contains("成交记录") || table.title.contains("Transaction Record") {
+                settlement.trades.extend(trades(table));
+            } else if table.title.contains("持仓汇总") || (table.title.contains("Positions") && !table.title.contains("Detail")) {
+                settlement.positions.extend(positions(table));
+            }
+        }
+        settlement.fees = settlement
+            .account
+            .items
+            .iter()
+            .filter(|(label, _)| label.contains("Commission") || label.contains("Fee") || label.contains("费"))
+            .map(|(name, amount)| Fee {
+                name: name.clone(),
+                amount: *amount,
+            })
+            .collect();
+        settlement
+    }
+}
+
+/// Cells of a row by column, empty where the table lacks the column.
+struct Columns<'a> {
+    table: &'a Table,
+}
+
+impl Columns<'_> {
+    fn get<'r>(&self, row: &'r [String], names: &[&str]) -> &'r str {
+        self.table.column(names).and_then(|i| row.get(i)).map_or("", |cell| cell.as_str())
+    }
+}
+
+fn trades(table: &Table) -> Vec<TradeRecord> {
+    let c = Columns { table };
+    table
+        .rows
+        .iter()
+        .map(|row| TradeRecord {
+            date: c.get(row, &["成交日期", "Date"]).to_string(),
+            exchange: c.get(row, &["交易所", "Exchange"]).to_string(),
+            product: c.get(row, &["品种", "Product"]).to_string(),
+            instrument_id: c.get(row, &["合约", "Instrument"]).to_string(),
+            direction: direction(c.get(row, &["买/卖", "B/S"])),
+            hedge: c.get(row, &["投/保", "S/H"]).to_string(),
+            price: parse_number(c.get(row, &["成交价", "Price"])),
+            volume: parse_number(c.get(row, &["手数", "Lots"])) as i32,
+            turnover: parse_number(c.get(row, &["成交额", "Turnover"])),
+            offset: offset(c.get(row, &["开平", "O/C"])),
+            fee: parse_number(c.get(row, &["手续费", "Fee"])),
+            realized_pl: parse_number(c.get(row, &["平仓盈亏", "Realized P/L"])),
+            premium: parse_number(c.get(row, &["权利金收支", "Premium Received/Paid"])),
+            trade_id: c.get(row, &["成交序号", "Trans.No."]).to_string(),
+        })
+        .collect()
+}
+
+fn positions(table: &Table) -> Vec<PositionRecord> {
+    let c = Columns { table };
+    table
+        .rows
+        .iter()
+        .map(|row| PositionRecord {
+            product: c.get(row, &["品种", "Product"]).to_string(),
+            instrument_id: c.get(row, &["合约", "Instrument"]).to_string(),
+            long: parse_number(c.get(row, &["买持", "Long Pos."])) as i32,
+            avg_buy_price: parse_number(c.get(row, &["买均价", "Avg Buy Price"])),
+            short: parse_number(c.get(row, &["卖持", "Short Pos."])) as i32,
+            avg_sell_price: parse_number(c.get(row, &["卖均价", "Avg Sell Price"])),
+            prev_settlement: parse_number(c.get(row, &["昨结算", "Prev. Sttl"])),
+            settlement: parse_number(c.get(row, &["今结算", "Sttl Today"])),
+            mtm_pl: parse_number(c.get(row, &["持仓盯市盈亏", "MTM P/L"])),
+            margin: parse_number(c.get(row, &["保证金占用", "Margin Occupied"])),
+            hedge: c.get(row, &["投/保", "S/H"]).to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATEMENT: &str = "\
+                                          某某期货有限公司
+                                                                  制表时间 Creation Date：20240603
+----------------------------------------------------------------------------------------------------
+                                  交易结算单(盯市) Settlement Statement(MTM)
+客户号 Client ID：  8001001          客户名称 Client Name：张三
+日期 Date：20240603
+
+                 资金状况  币种：人民币  Account Summary  Currency：CNY
+----------------------------------------------------------------------------------------------------
+期初结存 Balance b/f：                  100,000.00  基础保证金 Initial Margin：                 0.00
+出 入 金 Deposit/Withdrawal：                 0.00  期末结存 Balance c/f：              100,491.00
+平仓盈亏 Realized P/L：                     200.00  质 押 金 Pledge Amount：                0.00
+持仓盯市盈亏 MTM P/L：                      300.00  客户权益 Client Equity：：          100,491.00
+手 续 费 Commission：：                       9.00  保证金占用 Margin Occupied：         3,530.00
+交割手续费 Delivery Fee：                     0.00  可用资金 Fund Avail.：             96,961.00
+风 险 度 Risk Degree：                       3.51%
+
+                                                成交记录 Transaction Record
+----------------------------------------------------------------------------------------------------
+|成交日期| 交易所 |  品种  |   合约   |买/卖|投/保|  成交价  |手数|   成交额   |开平|手续费|平仓盈亏|权利金收支|成交序号|
+|  Date  |Exchange|Product |Instrument| B/S | S/H |  Price   |Lots|  Turnover  |O/C | Fee  |Realized P/L|Premium Received/Paid|Trans.No.|
+----------------------------------------------------------------------------------------------------
+|20240603| 上期所 | 螺纹钢 |  rb2410  |买   |投   | 3,500.000|   2|   70,000.00|开  |  6.00|    0.00|      0.00|  1001  |
+|20240603| 上期所 | 螺纹钢 |  rb2410  |   卖|投   | 3,520.000|   1|   35,200.00|平今|  3.00|  200.00|      0.00|  1002  |
+----------------------------------------------------------------------------------------------------
+|共   2条|        |        |          |     |     |          |   3|  105,200.00|    |  9.00|  200.00|      0.00|        |
+----------------------------------------------------------------------------------------------------
+
+                                                持仓明细 Positions Detail
+----------------------------------------------------------------------------------------------------
+| 交易所 |  品种  |   合约   |开仓日期|投/保|买/卖|持仓量|开仓价|
+|Exchange|Product |Instrument|Open Date| S/H | B/S |Positon|Pos. Open Price|
+----------------------------------------------------------------------------------------------------
+| 上期所 | 螺纹钢 |  rb2410  |20240603|投   |买   |     1|3,500.000|
+----------------------------------------------------------------------------------------------------
+|共   1条|        |          |        |     |     |     1|         |
+----------------------------------------------------------------------------------------------------
+
+                                                持仓汇总 Positions
+----------------------------------------------------------------------------------------------------
+|  品种  |   合约   |买持|  买均价  |卖持|  卖均价  |  昨结算  |  今结算  |持仓盯市盈亏|保证金占用|投/保|
+|Product |Instrument|Long Pos.|Avg Buy Price|Short Pos.|Avg Sell Price|Prev. Sttl|Sttl Today|MTM P/L|Margin Occupied|S/H|
+----------------------------------------------------------------------------------------------------
+| 螺纹钢 |  rb2410  |   1| 3,500.000|   0|     0.000| 3,480.000| 3,530.000|      300.00|  3,530.00|投   |
+----------------------------------------------------------------------------------------------------
+|共   1条|          |   1|          |   0|          |          |          |      300.00|  3,530.00|     |
+----------------------------------------------------------------------------------------------------
+";
+
+    fn chunk(sequence_no: i32, bytes: &[u8]) -> CThostFtdcSettlementInfoField {
+        let mut field: CThostFtdcSettlementInfoField = zeroed();
+        set_string(&mut field.TradingDay, "20240603");
+        field.SettlementID = 1;
+        field.SequenceNo = sequence_no;
+        for (c, b) in field.Content.iter_mut().zip(bytes) {
+            *c = *b as _;
+        }
+        field
+    }
+
+    #[test]
+    fn chunks_split_mid_character_are_joined() {
+        let (bytes, _, _) = encoding_rs::GBK.encode(STATEMENT);
+        // end the first chunk after the lead byte of the first character
+        let first = bytes.iter().position(|b| *b >= 0x80).unwrap() + 1;
+        let mut chunks = vec![&bytes[..first]];
+        chunks.extend(bytes[first..].chunks(400));
+
+        let mut assembler = StatementAssembler::new();
+        // out of order, the last one given first
+        let last = chunks.len() - 1;
+        assert!(assembler.on_rsp_qry_settlement_info(Some(&chunk(last as i32, chunks[last])), false).is_none());
+        for (n, bytes) in chunks[..last].iter().enumerate() {
+            assert!(assembler.on_rsp_qry_settlement_info(Some(&chunk(n as i32, bytes)), false).is_none());
+        }
+        let statement = assembler.on_rsp_qry_settlement_info(None, true).unwrap();
+        assert_eq!(statement.trading_day, "20240603");
+        assert_eq!(statement.settlement_id, 1);
+        assert_eq!(statement.text, STATEMENT);
+    }
+
+    #[test]
+    fn parses_the_standard_sections() {
+        let statement = Statement {
+            trading_day: "20240603".to_string(),
+            settlement_id: 1,
+            text: STATEMENT.to_string(),
+        };
+        let settlement = statement.parse();
+
+        let account = &settlement.account;
+        assert_eq!(account.balance_bf(), Some(100_000.0));
+        assert_eq!(account.deposit_withdrawal(), Some(0.0));
+        assert_eq!(account.balance_cf(), Some(100_491.0));
+        assert_eq!(account.realized_pl(), Some(200.0));
+        assert_eq!(account.mtm_pl(), Some(300.0));
+        assert_eq!(account.client_equity(), Some(100_491.0));
+        assert_eq!(account.commission(), Some(9.0));
+        assert_eq!(account.margin_occupied(), Some(3_530.0));
+        assert_eq!(account.fund_available(), Some(96_961.0));
+        assert_eq!(account.get("Risk Degree"), Some(3.51));
+        assert_eq!(
+            settlement.fees,
+            [
+                Fee {
+                    name: "手 续 费 Commission".to_string(),
+                    amount: 9.0,
+                },
+                Fee {
+                    name: "交割手续费 Delivery Fee".to_string(),
+                    amount: 0.0,
+                },
+            ]
+        );
+
+        assert_eq!(settlement.tables.len(), 3);
+        assert!(settlement.tables.iter().all(|t| t.headers.len() == 2));
+        assert_eq!(settlement.tables[1].rows.len(), 1);
+
+        assert_eq!(
+            settlement.trades,
+            [
+                TradeRecord {
+                    date: "20240603".to_string(),
+                    exchange: "上期所".to_string(),
+                    product: "螺纹钢".to_string(),
+                    instrument_id: "rb2410".to_string(),
+                    direction: THOST_FTDC_D_Buy,
+                    hedge: "投".to_string(),
+                    price: 3500.0,
+                    volume: 2,
+                    turnover: 70_000.0,
+                    offset: THOST_FTDC_OF_Open,
+                    fee: 6.0,
+                    realized_pl: 0.0,
+                    premium: 0.0,
+                    trade_id: "1001".to_string(),
+                },
+                TradeRecord {
+                    date: "20240603".to_string(),
+                    exchange: "上期所".to_string(),
+                    product: "螺纹钢".to_string(),
+                    instrument_id: "rb2410".to_string(),
+                    direction: THOST_FTDC_D_Sell,
+                    hedge: "投".to_string(),
+                    price: 3520.0,
+                    volume: 1,
+                    turnover: 35_200.0,
+                    offset: THOST_FTDC_OF_CloseToday,
+                    fee: 3.0,
+                    realized_pl: 200.0,
+                    premium: 0.0,
+                    trade_id: "1002".to_string(),
+                },
+            ]
+        );
+
+        assert_eq!(
+            settlement.positions,
+            [PositionRecord {
+                product: "螺纹钢".to_string(),
+                instrument_id: "rb2410".to_string(),
+                long: 1,
+                avg_buy_price: 3500.0,
+                short: 0,
+                avg_sell_price: 0.0,
+                prev_settlement: 3480.0,
+                settlement: 3530.0,
+                mtm_pl: 300.0,
+                margin: 3530.0,
+                hedge: "投".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! Orders that move a net position to a target, with the offsets resolved.
+//!
+//! Buying closes the short position before opening a long one and selling
+//! the reverse. Pending close orders count as done: the net position leaves
+//! out the volume they freeze, and only the rest is closed. Pending open
+//! orders are not counted, wait for them before splitting again.
+
+use super::position::{PositionBook, PosiDirection, PositionKey};
+use crate::sys::*;
+use crate::utils::*;
+
+use serde::{Deserialize, Serialize};
+
+/// When to open the opposite side (锁仓) instead of closing.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Locking {
+    /// Always close what is available first.
+    #[default]
+    Never,
+    /// Close yesterday's position only, lock instead of closing today's,
+    /// for instruments where closing today costs more than opening.
+    Today,
+    /// Never close, always open.
+    Always,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub struct OrderSplitter {
+    #[serde(default)]
+    pub locking: Locking,
+    /// `THOST_FTDC_HF_*` of the positions and orders.
+    pub hedge_flag: u8,
+}
+
+impl Default for OrderSplitter {
+    fn default() -> Self {
+        Self {
+            locking: Locking::Never,
+            hedge_flag: THOST_FTDC_HF_Speculation,
+        }
+    }
+}
+
+impl OrderSplitter {
+    /// Limit orders at `price` taking the net position of `instrument` (long
+    /// minus short, after pending closes) to `target`. Broker, investor and
+    /// `OrderRef` are left empty.
+    pub fn split(&self, book: &PositionBook, instrument: &str, exchange: &str, target: i32, price: f64) -> Vec<CThostFtdcInputOrderField> {
+        let key = |direction| PositionKey {
+            instrument_id: instrument.to_string(),
+            direction,
+            hedge_flag: self.hedge_flag,
+        };
+        let long = book.get(&key(PosiDirection::Long));
+        let short = book.get(&key(PosiDirection::Short));
+        let net = long.map_or(0, |p| p.available()) - short.map_or(0, |p| p.available());
+
+        let (direction, closing, mut volume) = match target - net {
+            0 => return vec![],
+            delta if delta > 0 => (THOST_FTDC_D_Buy, short, delta),
+            delta => (THOST_FTDC_D_Sell, long, -delta),
+        };
+
+        let mut orders = vec![];
+        if let Some(position) = closing {
+            let closable = match self.locking {
+                Locking::Never => volume,
+                Locking::Today => volume.min(position.available_yesterday()),
+                Locking::Always => 0,
+            };
+            for (offset, close) in position.close_offsets(closable) {
+                orders.push(self.order(instrument, exchange, direction, offset, close, price));
+                volume -= close;
+            }
+        }
+        if volume > 0 {
+            orders.push(self.order(instrument, exchange, direction, THOST_FTDC_OF_Open, volume, price));
+        }
+        orders
+    }
+
+    fn order(&self, instrument: &str, exchange: &str, direction: u8, offset: u8, volume: i32, price: f64) -> CThostFtdcInputOrderField {
+        let mut field: CThostFtdcInputOrderField = zeroed();
+        set_string(&mut field.InstrumentID, instrument);
+        set_string(&mut field.ExchangeID, exchange);
+        field.Direction = direction as _;
+        field.CombOffsetFlag[0] = offset as _;
+        field.CombHedgeFlag[0] = self.hedge_flag as _;
+        field.OrderPriceType = THOST_FTDC_OPT_LimitPrice as _;
+        field.LimitPrice = price;
+        field.VolumeTotalOriginal = volume;
+        field.TimeCondition = THOST_FTDC_TC_GFD as _;
+        field.VolumeCondition = THOST_FTDC_VC_AV as _;
+        field.MinVolume = 1;
+        field.ContingentCondition = THOST_FTDC_CC_Immediately as _;
+        field.ForceCloseReason = THOST_FTDC_FCC_NotForceClose as _;
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_long(book: &mut PositionBook, volume: i32) {
+        let mut trade: CThostFtdcTradeField = zeroed();
+        set_string(&mut trade.InstrumentID, "rb2501");
+        set_string(&mut trade.ExchangeID, "SHFE");
+        set_string(&mut trade.TradeID, "1");
+        trade.Direction = THOST_FTDC_D_Buy as _;
+        trade.OffsetFlag = THOST_FTDC_OF_Open as _;
+        trade.HedgeFlag = THOST_FTDC_HF_Speculation as _;
+        trade.Volume = volume;
+        book.on_rtn_trade(&trade);
+    }
+
+    fn pending_close(book: &mut PositionBook, volume: i32) {
+        let mut order: CThostFtdcOrderField = zeroed();
+        set_string(&mut order.InstrumentID, "rb2501");
+        set_string(&mut order.ExchangeID, "SHFE");
+        set_string(&mut order.OrderRef, "9");
+        order.Direction = THOST_FTDC_D_Sell as _;
+        order.CombOffsetFlag[0] = THOST_FTDC_OF_CloseToday as _;
+        order.CombHedgeFlag[0] = THOST_FTDC_HF_Speculation as _;
+        order.VolumeTotal = volume;
+        order.OrderStatus = THOST_FTDC_OST_NoTradeQueueing as _;
+        book.on_rtn_order(&order);
+    }
+
+    fn summary(orders: &[CThostFtdcInputOrderField]) -> Vec<(u8, u8, i32)> {
+        orders.iter().map(|o| (o.Direction as u8, o.CombOffsetFlag[0] as u8, o.VolumeTotalOriginal)).collect()
+    }
+
+    #[test]
+    fn closes_before_opening() {
+        let mut book = PositionBook::new();
+        open_long(&mut book, 10);
+        let orders = OrderSplitter::default().split(&book, "rb2501", "SHFE", -3, 3500.0);
+        assert_eq!(
+            summary(&orders),
+            vec![(THOST_FTDC_D_Sell, THOST_FTDC_OF_CloseToday, 10), (THOST_FTDC_D_Sell, THOST_FTDC_OF_Open, 3)]
+        );
+    }
+
+    #[test]
+    fn pending_closes_count_as_done() {
+        let mut book = PositionBook::new();
+        open_long(&mut book, 10);
+        pending_close(&mut book, 10);
+        let splitter = OrderSplitter::default();
+        assert!(splitter.split(&book, "rb2501", "SHFE", 0, 3500.0).is_empty());
+        assert_eq!(summary(&splitter.split(&book, "rb2501", "SHFE", -2, 3500.0)), vec![(THOST_FTDC_D_Sell, THOST_FTDC_OF_Open, 2)]);
+    }
+
+    #[test]
+    fn locking_today_opens_the_other_side() {
+        let mut book = PositionBook::new();
+        open_long(&mut book, 4);
+        let splitter = OrderSplitter {
+            locking: Locking::Today,
+            ..Default::default()
+        };
+        assert_eq!(summary(&splitter.split(&book, "rb2501", "SHFE", 0, 3500.0)), vec![(THOST_FTDC_D_Sell, THOST_FTDC_OF_Open, 4)]);
+    }
+}
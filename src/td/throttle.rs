@@ -0,0 +1,530 @@
+//! Outbound order throttling, in front of `ReqOrderInsert` and `ReqOrderAction`.
+//!
+//! Token buckets cap inserts and actions per session and per instrument,
+//! cancels are counted per instrument for the trading day. Over a rate limit
+//! a request is queued until tokens return, rejected with a [`ThrottleError`]
+//! or sent anyway with an [`Alert`]; the daily cancel limit always rejects.
+//! Nearing a limit raises an alert in every mode.
+
+use super::TdApi;
+use crate::sys::*;
+use crate::utils::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::os::raw::c_int;
+use std::time::{Duration, Instant};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Default)]
+pub enum OnLimit {
+    /// Hold the request until the limit allows it, see [`Throttle::poll`].
+    Queue,
+    #[default]
+    Reject,
+    /// Send the request anyway, only raise [`Alert::Throttled`].
+    Alert,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    /// Inserts and actions per second per session, 0 for no limit.
+    #[serde(default)]
+    pub session_rate: f64,
+    #[serde(default)]
+    pub session_burst: u32,
+    /// Inserts and actions per second per instrument, 0 for no limit.
+    #[serde(default)]
+    pub instrument_rate: f64,
+    #[serde(default)]
+    pub instrument_burst: u32,
+    /// Cancels per instrument per trading day, 0 for no limit.
+    #[serde(default)]
+    pub max_cancels: u32,
+    /// Share of `max_cancels`, or of a rate burst, used from which every
+    /// cancel or request raises an alert.
+    #[serde(default = "default_alert_ratio")]
+    pub alert_ratio: f64,
+    #[serde(default)]
+    pub on_limit: OnLimit,
+}
+
+fn default_alert_ratio() -> f64 {
+    0.8
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            session_rate: 0.0,
+            session_burst: 0,
+            instrument_rate: 0.0,
+            instrument_burst: 0,
+            max_cancels: 0,
+            alert_ratio: default_alert_ratio(),
+            on_limit: OnLimit::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThrottleError {
+    /// The session rate is exhausted, tokens return after `retry_after`.
+    SessionRate { retry_after: Duration },
+    InstrumentRate { instrument: String, retry_after: Duration },
+    /// The instrument reached its cancels for the trading day.
+    CancelLimit { instrument: String, limit: u32 },
+}
+
+impl fmt::Display for ThrottleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SessionRate { retry_after } => write!(f, "session order rate exceeded, retry after {:?}", retry_after),
+            Self::InstrumentRate { instrument, retry_after } => {
+                write!(f, "order rate of {} exceeded, retry after {:?}", instrument, retry_after)
+            }
+            Self::CancelLimit { instrument, limit } => write!(f, "{} reached its {} cancels of the day", instrument, limit),
+        }
+    }
+}
+
+impl std::error::Error for ThrottleError {}
+
+impl From<ThrottleError> for String {
+    fn from(e: ThrottleError) -> Self {
+        e.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// A cancel of `instrument` brought it within `alert_ratio` of the limit.
+    CancelsNear { instrument: String, count: u32, limit: u32 },
+    /// A request went over a rate limit, held, refused or sent anyway
+    /// depending on [`OnLimit`].
+    Throttled(ThrottleError),
+    /// A request used `alert_ratio` of the burst of the session rate, or of
+    /// the rate of `instrument`.
+    RateNear { instrument: Option<String>, used: f64, burst: f64 },
+}
+
+/// A request for the trader api.
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Insert(CThostFtdcInputOrderField),
+    Action(CThostFtdcInputOrderActionField),
+}
+
+impl Outbound {
+    pub fn instrument_id(&self) -> String {
+        match self {
+            Self::Insert(field) => to_string(&field.InstrumentID),
+            Self::Action(field) => to_string(&field.InstrumentID),
+        }
+    }
+
+    fn is_cancel(&self) -> bool {
+        matches!(self, Self::Action(field) if field.ActionFlag as u8 == THOST_FTDC_AF_Delete)
+    }
+
+    /// Hand the request to the api.
+    pub fn send(mut self, api: &mut TdApi) -> Result<c_int, String> {
+        match &mut self {
+            Self::Insert(field) => api.req_order_insert(field),
+            Self::Action(field) => api.req_order_action(field),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        let burst = (burst as f64).max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// How long until a token is available, zero when one is.
+    fn wait(&mut self, now: Instant) -> Duration {
+        if self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    fn take(&mut self) {
+        if self.rate > 0.0 {
+            // sent over the limit in alert mode, start from empty
+            self.tokens = (self.tokens - 1.0).max(0.0);
+        }
+    }
+
+    /// Tokens used of the burst, `None` below `ratio` of it.
+    fn near(&self, ratio: f64) -> Option<f64> {
+        let used = self.burst - self.tokens;
+        (self.rate > 0.0 && used >= self.burst * ratio).then_some(used)
+    }
+}
+
+pub struct Throttle {
+    config: ThrottleConfig,
+    session: TokenBucket,
+    instruments: HashMap<String, TokenBucket>,
+    trading_day: String,
+    cancels: HashMap<String, u32>,
+    queue: VecDeque<Outbound>,
+    alerts: Vec<Alert>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            session: TokenBucket::new(config.session_rate, config.session_burst),
+            config,
+            instruments: HashMap::new(),
+            trading_day: String::new(),
+            cancels: HashMap::new(),
+            queue: VecDeque::new(),
+            alerts: vec![],
+        }
+    }
+
+    /// Cancel counters restart when the trading day changes.
+    pub fn set_trading_day(&mut self, trading_day: &str) {
+        if self.trading_day != trading_day {
+            debug!("throttle trading day {} -> {}", self.trading_day, trading_day);
+            self.trading_day = trading_day.to_string();
+            self.cancels.clear();
+        }
+    }
+
+    /// The request when it may be sent now, `None` when queued.
+    pub fn submit(&mut self, outbound: Outbound) -> Result<Option<Outbound>, ThrottleError> {
+        let instrument = outbound.instrument_id();
+        if outbound.is_cancel() {
+            self.check_cancels(&instrument)?;
+        }
+
+        // keep the order of requests on the same instrument
+        let behind = self.queue.iter().any(|o| o.instrument_id() == instrument);
+        let limited = if behind { None } else { self.limited(&instrument) };
+        if !behind && limited.is_none() {
+            self.sent(&outbound);
+            return Ok(Some(outbound));
+        }
+        if let Some(e) = &limited {
+            self.alerts.push(Alert::Throttled(e.clone()));
+        }
+        match (self.config.on_limit, limited) {
+            (OnLimit::Reject, Some(e)) => Err(e),
+            (OnLimit::Alert, _) => {
+                self.sent(&outbound);
+                Ok(Some(outbound))
+            }
+            _ => {
+                self.queue.push_back(outbound);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Queued requests the limits now allow, in submit order per instrument.
+    pub fn poll(&mut self) -> Vec<Outbound> {
+        let mut ready = vec![];
+        let mut blocked: Vec<String> = vec![];
+        let mut i = 0;
+        while i < self.queue.len() {
+            let instrument = self.queue[i].instrument_id();
+            if blocked.contains(&instrument) {
+                i += 1;
+                continue;
+            }
+            match self.limited(&instrument) {
+                None => {
+                    let outbound = self.queue.remove(i).expect("index checked");
+                    self.sent(&outbound);
+                    ready.push(outbound);
+                }
+                Some(ThrottleError::SessionRate { .. }) => break,
+                Some(_) => {
+                    blocked.push(instrument);
+                    i += 1;
+                }
+            }
+        }
+        ready
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Cancels of `instrument` sent this trading day.
+    pub fn cancels(&self, instrument: &str) -> u32 {
+        self.cancels.get(instrument).copied().unwrap_or(0)
+    }
+
+    pub fn take_alerts(&mut self) -> Vec<Alert> {
+        std::mem::take(&mut self.alerts)
+    }
+
+    fn check_cancels(&mut self, instrument: &str) -> Result<(), ThrottleError> {
+        let limit = self.config.max_cancels;
+        if limit == 0 {
+            return Ok(());
+        }
+        let queued = self.queue.iter().filter(|o| o.is_cancel() && o.instrument_id() == instrument).count() as u32;
+        if self.cancels(instrument) + queued >= limit {
+            let e = ThrottleError::CancelLimit {
+                instrument: instrument.to_string(),
+                limit,
+            };
+            warn!("{}", e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn limited(&mut self, instrument: &str) -> Option<ThrottleError> {
+        let now = Instant::now();
+        let retry_after = self.session.wait(now);
+        if !retry_after.is_zero() {
+            return Some(ThrottleError::SessionRate { retry_after });
+        }
+        let (rate, burst) = (self.config.instrument_rate, self.config.instrument_burst);
+        let bucket = self.instruments.entry(instrument.to_string()).or_insert_with(|| TokenBucket::new(rate, burst));
+        let retry_after = bucket.wait(now);
+        if !retry_after.is_zero() {
+            return Some(ThrottleError::InstrumentRate {
+                instrument: instrument.to_string(),
+                retry_after,
+            });
+        }
+        None
+    }
+
+    fn sent(&mut self, outbound: &Outbound) {
+        let instrument = outbound.instrument_id();
+        let ratio = self.config.alert_ratio;
+        self.session.take();
+        if let Some(used) = self.session.near(ratio) {
+            debug!("session order rate at {:.1} of {}", used, self.session.burst);
+            self.alerts.push(Alert::RateNear {
+                instrument: None,
+                used,
+                burst: self.session.burst,
+            });
+        }
+        if let Some(bucket) = self.instruments.get_mut(&instrument) {
+            bucket.take();
+            if let Some(used) = bucket.near(ratio) {
+                debug!("order rate of {} at {:.1} of {}", instrument, used, bucket.burst);
+                self.alerts.push(Alert::RateNear {
+                    instrument: Some(instrument.clone()),
+                    used,
+                    burst: bucket.burst,
+                });
+            }
+        }
+        if !outbound.is_cancel() {
+            return;
+        }
+
+        let count = self.cancels.entry(instrument.clone()).or_default();
+        *count += 1;
+        let (count, limit) = (*count, self.config.max_cancels);
+        if limit > 0 && count as f64 >= limit as f64 * self.config.alert_ratio {
+            warn!("{} cancels of {} today, limit {}", count, instrument, limit);
+            self.alerts.push(Alert::CancelsNear { instrument, count, limit });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(instrument: &str) -> Outbound {
+        let mut field: CThostFtdcInputOrderField = zeroed();
+        set_string(&mut field.InstrumentID, instrument);
+        Outbound::Insert(field)
+    }
+
+    fn cancel(instrument: &str) -> Outbound {
+        let mut field: CThostFtdcInputOrderActionField = zeroed();
+        set_string(&mut field.InstrumentID, instrument);
+        field.ActionFlag = THOST_FTDC_AF_Delete as _;
+        Outbound::Action(field)
+    }
+
+    fn instruments(outbound: &[Outbound]) -> Vec<String> {
+        outbound.iter().map(|o| o.instrument_id()).collect()
+    }
+
+    // as if the buckets had refilled
+    fn refill(throttle: &mut Throttle) {
+        throttle.session.tokens = throttle.session.burst;
+        for bucket in throttle.instruments.values_mut() {
+            bucket.tokens = bucket.burst;
+        }
+    }
+
+    #[test]
+    fn rejects_over_the_session_rate() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            session_rate: 0.01,
+            session_burst: 2,
+            ..Default::default()
+        });
+        assert!(throttle.submit(insert("rb2501")).unwrap().is_some());
+        assert!(throttle.take_alerts().is_empty());
+        assert!(throttle.submit(insert("ag2502")).unwrap().is_some());
+        assert!(matches!(throttle.take_alerts().as_slice(), [Alert::RateNear { instrument: None, .. }]));
+        let e = throttle.submit(insert("rb2501")).unwrap_err();
+        assert!(matches!(e, ThrottleError::SessionRate { retry_after } if retry_after > Duration::from_secs(90)));
+        assert_eq!(throttle.take_alerts(), [Alert::Throttled(e)]);
+        assert_eq!(throttle.queued(), 0);
+    }
+
+    #[test]
+    fn alert_mode_sends_over_the_limit() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            instrument_rate: 0.01,
+            instrument_burst: 5,
+            alert_ratio: 0.5,
+            on_limit: OnLimit::Alert,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            assert!(throttle.submit(insert("rb2501")).unwrap().is_some());
+        }
+        assert!(throttle.take_alerts().is_empty());
+        assert!(throttle.submit(insert("rb2501")).unwrap().is_some());
+        assert!(matches!(
+            throttle.take_alerts().as_slice(),
+            [Alert::RateNear { instrument: Some(instrument), used, burst }]
+                if instrument == "rb2501" && *used >= 2.99 && *burst == 5.0
+        ));
+
+        for _ in 0..3 {
+            assert!(throttle.submit(insert("rb2501")).unwrap().is_some());
+        }
+        let alerts = throttle.take_alerts();
+        assert!(alerts.iter().any(|a| matches!(a, Alert::Throttled(ThrottleError::InstrumentRate { .. }))));
+        assert_eq!(throttle.queued(), 0);
+    }
+
+    #[test]
+    fn instrument_rate_holds_only_that_instrument() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            instrument_rate: 0.01,
+            instrument_burst: 1,
+            on_limit: OnLimit::Queue,
+            ..Default::default()
+        });
+        assert!(throttle.submit(insert("rb2501")).unwrap().is_some());
+        assert!(throttle.submit(insert("rb2501")).unwrap().is_none());
+        assert!(throttle.submit(insert("ag2502")).unwrap().is_some());
+        assert_eq!(throttle.queued(), 1);
+        assert!(throttle.poll().is_empty());
+
+        refill(&mut throttle);
+        assert_eq!(instruments(&throttle.poll()), ["rb2501"]);
+        assert_eq!(throttle.queued(), 0);
+    }
+
+    #[test]
+    fn queued_requests_keep_their_order() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            session_rate: 0.01,
+            session_burst: 1,
+            on_limit: OnLimit::Queue,
+            ..Default::default()
+        });
+        assert!(throttle.submit(insert("rb2501")).unwrap().is_some());
+        assert!(throttle.submit(insert("ag2502")).unwrap().is_none());
+        assert!(throttle.submit(cancel("ag2502")).unwrap().is_none());
+        assert!(throttle.submit(insert("rb2501")).unwrap().is_none());
+
+        // one token, the rest waits behind the session limit
+        refill(&mut throttle);
+        assert_eq!(instruments(&throttle.poll()), ["ag2502"]);
+        refill(&mut throttle);
+        let ready = throttle.poll();
+        assert!(matches!(ready.as_slice(), [Outbound::Action(_)]));
+        refill(&mut throttle);
+        assert_eq!(instruments(&throttle.poll()), ["rb2501"]);
+    }
+
+    #[test]
+    fn a_request_behind_a_queued_one_waits() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            instrument_rate: 0.01,
+            instrument_burst: 1,
+            on_limit: OnLimit::Queue,
+            ..Default::default()
+        });
+        throttle.submit(insert("rb2501")).unwrap();
+        throttle.submit(insert("rb2501")).unwrap();
+        refill(&mut throttle);
+        // tokens are back, but the queued insert goes first
+        assert!(throttle.submit(cancel("rb2501")).unwrap().is_none());
+        assert_eq!(throttle.poll().len(), 1);
+        assert_eq!(throttle.queued(), 1);
+    }
+
+    #[test]
+    fn daily_cancel_limit() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            max_cancels: 4,
+            alert_ratio: 0.75,
+            on_limit: OnLimit::Queue,
+            ..Default::default()
+        });
+        throttle.set_trading_day("20250102");
+        for _ in 0..4 {
+            assert!(throttle.submit(cancel("rb2501")).unwrap().is_some());
+        }
+        assert!(throttle.submit(insert("rb2501")).unwrap().is_some());
+        assert_eq!(throttle.cancels("rb2501"), 4);
+        assert_eq!(
+            throttle.take_alerts(),
+            [3, 4].map(|count| Alert::CancelsNear {
+                instrument: "rb2501".to_string(),
+                count,
+                limit: 4,
+            })
+        );
+        assert_eq!(
+            throttle.submit(cancel("rb2501")).unwrap_err(),
+            ThrottleError::CancelLimit {
+                instrument: "rb2501".to_string(),
+                limit: 4,
+            }
+        );
+        assert!(throttle.submit(cancel("ag2502")).unwrap().is_some());
+
+        throttle.set_trading_day("20250103");
+        assert_eq!(throttle.cancels("rb2501"), 0);
+        assert!(throttle.submit(cancel("rb2501")).unwrap().is_some());
+    }
+}
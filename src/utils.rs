@@ -0,0 +1,156 @@
+//! Helpers for the plain C structs exposed by `sys`.
+
+use std::os::raw::{c_char, c_int};
+
+/// Read a NUL terminated `c_char` array, such as `InstrumentID`, into a `String`.
+pub fn to_string(buf: &[c_char]) -> String {
+    let bytes: Vec<u8> = buf.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Decode GBK text, as CTP sends Chinese messages and statements.
+pub fn decode_gbk(bytes: &[u8]) -> String {
+    let (text, _, _) = encoding_rs::GBK.decode(bytes);
+    text.into_owned()
+}
+
+/// Read a NUL terminated GBK `c_char` array, such as `ErrorMsg` or `StatusMsg`.
+pub fn gbk_string(buf: &[c_char]) -> String {
+    let bytes: Vec<u8> = buf.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+    decode_gbk(&bytes)
+}
+
+/// Copy `s` into a fixed size `c_char` array, truncating it and keeping the trailing NUL.
+pub fn set_string(buf: &mut [c_char], s: &str) {
+    if buf.is_empty() {
+        return;
+    }
+    let len = s.len().min(buf.len() - 1);
+    for (dst, src) in buf.iter_mut().zip(s.as_bytes()[..len].iter()) {
+        *dst = *src as c_char;
+    }
+    for c in buf[len..].iter_mut() {
+        *c = 0;
+    }
+}
+
+/// Product of a futures instrument, the leading letters: `rb` for `rb2305`.
+pub fn product_of(instrument: &str) -> &str {
+    let end = instrument.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(instrument.len());
+    &instrument[..end]
+}
+
+/// CTP marks missing prices with DBL_MAX, map them to `None`.
+pub fn valid_price(price: f64) -> Option<f64> {
+    if price.is_finite() && price != f64::MAX {
+        Some(price)
+    } else {
+        None
+    }
+}
+
+/// Requests return 0 on success, -1 network failure, -2/-3 flow control.
+pub(crate) fn check_rtn(name: &str, rtn: c_int) -> Result<(), String> {
+    if rtn != 0 {
+        return Err(format!("Fail to req `{}`: {}", name, rtn));
+    }
+    Ok(())
+}
+
+/// All CTP structs are plain old data, an all zero value is their natural default.
+pub(crate) fn zeroed<T: Copy>() -> T {
+    unsafe { std::mem::zeroed() }
+}
+
+/// Milliseconds since the unix epoch for a `YYYYMMDD` date and a `HH:MM:SS` time,
+/// both taken as exchange local time.
+pub fn parse_timestamp(date: &str, time: &str, millisec: i32) -> Option<i64> {
+    let date = date.trim();
+    // a multi-byte char would make the slices below panic
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let year: i64 = date[0..4].parse().ok()?;
+    let month: i64 = date[4..6].parse().ok()?;
+    let day: i64 = date[6..8].parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let seconds = parse_seconds(time)?;
+
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some((days * 86400 + seconds) * 1000 + millisec as i64)
+}
+
+/// Seconds since midnight for a `HH:MM:SS` time.
+pub fn parse_seconds(time: &str) -> Option<i64> {
+    let parts: Vec<&str> = time.trim().split(':').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let h: i64 = parts[0].parse().ok()?;
+    let m: i64 = parts[1].parse().ok()?;
+    let s: i64 = parts[2].parse().ok()?;
+    if h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+    Some(h * 3600 + m * 60 + s)
+}
+
+/// A `YYYYMMDD` date as used throughout CTP.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Parse `YYYYMMDD`, `None` when empty or malformed.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.len() != 8 || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let date = Self {
+            year: s[0..4].parse().ok()?,
+            month: s[4..6].parse().ok()?,
+            day: s[6..8].parse().ok()?,
+        };
+        if !(1..=12).contains(&date.month) || !(1..=31).contains(&date.day) {
+            return None;
+        }
+        Some(date)
+    }
+}
+
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}{:02}{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("19700101", "00:00:01", 500), Some(1500));
+        assert_eq!(parse_timestamp("20221121", "09:00:00", 0), Some(1_669_021_200_000));
+        assert_eq!(parse_timestamp(" 20240229 ", "23:59:59", 0), Some(1_709_251_199_000));
+        assert_eq!(parse_timestamp("20221321", "09:00:00", 0), None);
+        assert_eq!(parse_timestamp("2022112", "09:00:00", 0), None);
+        assert_eq!(parse_timestamp("+2022112", "09:00:00", 0), None);
+        assert_eq!(parse_timestamp("2022年1", "09:00:00", 0), None);
+        assert_eq!(parse_timestamp("20221121", "9:00", 0), None);
+    }
+}